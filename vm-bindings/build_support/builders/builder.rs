@@ -326,6 +326,10 @@ pub trait Builder: Debug {
             .allowlist_function("exportStatFullGCUsecs")
             .allowlist_function("exportStatScavengeGCUsecs")
             .allowlist_function("exportClassOrNilAtIndex")
+            .allowlist_function("exportNewCallbackContext")
+            .allowlist_function("exportFreeCallbackContext")
+            .allowlist_function("exportEnterInterpreterFromCallback")
+            .allowlist_function("exportExitInterpreterToCallback")
             .allowlist_function("exportIsOopForwarded")
             .allowlist_function("setVmRunOnWorkerThread")
             .allowlist_function("setLogger")
@@ -352,6 +356,8 @@ pub trait Builder: Debug {
             .allowlist_function("stSizeOf")
             .allowlist_function("addressCouldBeClassObj")
             .allowlist_function("getThisContext")
+            .allowlist_function("getClassNameIndex")
+            .allowlist_function("getThisClassIndex")
             // CoInterpreter
             .allowlist_function("instVarofContext")
             // SpurMemoryManager
@@ -359,6 +365,7 @@ pub trait Builder: Debug {
             .allowlist_function("trueObject")
             .allowlist_function("nilObject")
             .allowlist_function("classArray")
+            .allowlist_function("classByteArray")
             .allowlist_function("classExternalAddress")
             .allowlist_function("classString")
             .allowlist_function("firstIndexableField")
@@ -373,6 +380,7 @@ pub trait Builder: Debug {
             .allowlist_function("isFloatInstance")
             .allowlist_function("newHashBitsOf")
             .allowlist_function("hashBitsOf")
+            .allowlist_function("rawHashBitsOf")
             .allowlist_function("ensureBehaviorHash")
             .allowlist_function("firstBytePointerOfDataObject")
             .allowlist_function("isOopForwarded")
@@ -390,6 +398,10 @@ pub trait Builder: Debug {
             .allowlist_function("getEdenSpaceMemoryEnd")
             .allowlist_function("getPastSpaceMemoryStart")
             .allowlist_function("getPastSpaceMemoryEnd")
+            .allowlist_function("getOldSpaceSegmentsCount")
+            .allowlist_function("getOldSpaceSegmentStart")
+            .allowlist_function("getOldSpaceSegmentEnd")
+            .allowlist_function("getRememberedSetSize")
             .allowlist_type("sqInt")
             .allowlist_type("usqInt")
             .allowlist_type("sqExport")
@@ -1,4 +1,5 @@
 #include "exported.h"
+#include "vmCallback.h"
 
 void* exportGetHandler(sqInt anOop) {
     return getHandler(anOop);
@@ -31,3 +32,23 @@ usqLong exportStatScavengeGCUsecs() {
 sqInt exportClassOrNilAtIndex(sqInt classIndex) {
     return classOrNilAtIndex(classIndex);
 }
+
+void* exportNewCallbackContext() {
+    return calloc(1, sizeof(VMCallbackContext));
+}
+
+void exportFreeCallbackContext(void* aCallbackContext) {
+    free(aCallbackContext);
+}
+
+/*
+ * Run the interpreter until the callback returns with exportExitInterpreterToCallback.
+ * Answers true if the callback returned and false if the interpreter could not be entered.
+ */
+sqInt exportEnterInterpreterFromCallback(void* aCallbackContext) {
+    return sqGetInterpreterProxy()->ptEnterInterpreterFromCallback((VMCallbackContext*) aCallbackContext);
+}
+
+void exportExitInterpreterToCallback(void* aCallbackContext) {
+    sqGetInterpreterProxy()->ptExitInterpreterToCallback((VMCallbackContext*) aCallbackContext);
+}
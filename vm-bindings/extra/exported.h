@@ -17,6 +17,10 @@ EXPORT(VirtualMachine*) exportSqGetInterpreterProxy();
 EXPORT(usqLong) exportStatFullGCUsecs();
 EXPORT(usqLong) exportStatScavengeGCUsecs();
 EXPORT(sqInt) exportClassOrNilAtIndex(sqInt classIndex);
+EXPORT(void*) exportNewCallbackContext();
+EXPORT(void) exportFreeCallbackContext(void* aCallbackContext);
+EXPORT(sqInt) exportEnterInterpreterFromCallback(void* aCallbackContext);
+EXPORT(void) exportExitInterpreterToCallback(void* aCallbackContext);
 
 // Custom
 EXPORT(sqInt) createNewMethodheaderbytecodeCount(sqInt class, sqInt header, sqInt bytecodeCount);
@@ -42,6 +46,8 @@ EXPORT(sqInt) stSizeOf(sqInt oop);
 EXPORT(sqInt) addressCouldBeClassObj(sqInt oop);
 EXPORT(sqInt) isKindOfClass(sqInt oop, sqInt aClass);
 EXPORT(sqInt) getThisContext(void);
+EXPORT(sqInt) getClassNameIndex(void);
+EXPORT(sqInt) getThisClassIndex(void);
 
 // CoInterpreter
 EXPORT(sqInt) instVarofContext(sqInt offset, sqInt oop);
@@ -51,6 +57,7 @@ EXPORT(sqInt) falseObject();
 EXPORT(sqInt) trueObject();
 EXPORT(sqInt) nilObject();
 EXPORT(sqInt) classArray();
+EXPORT(sqInt) classByteArray();
 EXPORT(sqInt) classExternalAddress();
 EXPORT(sqInt) classString();
 EXPORT(void *) firstIndexableField(sqInt oop);
@@ -67,6 +74,7 @@ EXPORT(double) floatValueOf(sqInt objOop);
 EXPORT(sqInt) isFloatInstance(sqInt objOop);
 EXPORT(sqInt) newHashBitsOf(sqInt objOop);
 EXPORT(sqInt) hashBitsOf(sqInt objOop);
+EXPORT(sqInt) rawHashBitsOf(sqInt objOop);
 EXPORT(sqInt) ensureBehaviorHash(sqInt objOop);
 EXPORT(void *) firstBytePointerOfDataObject(sqInt objOop);
 EXPORT(sqInt) isOopForwarded(sqInt oop);
@@ -1,8 +1,13 @@
 use crate::bindings::{
-    calloc, exportClassOrNilAtIndex as classOrNilAtIndex, exportGetHandler as getHandler, free,
-    malloc, sqInt, VirtualMachine as sqInterpreterProxy,
+    calloc, exportClassOrNilAtIndex as classOrNilAtIndex,
+    exportEnterInterpreterFromCallback as enterInterpreterFromCallback,
+    exportExitInterpreterToCallback as exitInterpreterToCallback,
+    exportFreeCallbackContext as freeCallbackContext, exportGetHandler as getHandler,
+    exportNewCallbackContext as newCallbackContext, free, malloc, sqInt,
+    VirtualMachine as sqInterpreterProxy,
 };
 use std::any::type_name;
+use std::sync::{Arc, RwLock};
 
 use crate::prelude::{Handle, NativeAccess, NativeDrop, NativeTransmutable};
 use crate::Smalltalk;
@@ -10,6 +15,15 @@ use std::ffi::{CStr, CString};
 use std::fmt::Display;
 use std::os::raw::{c_char, c_double, c_void};
 
+/// Notified about memory allocated and freed through the interpreter proxy
+pub trait AllocationObserver: Send + Sync {
+    fn allocated(&self, address: *mut c_void, size: usize);
+    /// Return false to keep the memory from being freed, for example when it is already freed
+    fn should_free(&self, address: *mut c_void) -> bool;
+}
+
+static ALLOCATION_OBSERVER: RwLock<Option<Arc<dyn AllocationObserver>>> = RwLock::new(None);
+
 pub type InterpreterProxy = Handle<sqInterpreterProxy>;
 impl NativeDrop for sqInterpreterProxy {
     fn drop(&mut self) {}
@@ -130,10 +144,38 @@ impl InterpreterProxy {
         unsafe { function(object.into_native(), class.into_native()) != 0 }
     }
 
+    /// The array of objects known to the VM, like the process scheduler and the class table roots
+    pub fn special_objects_array(&self) -> ObjectPointer {
+        let function = self.native().specialObjectsOop.unwrap();
+        unsafe { ObjectPointer::from_native_c(function()) }
+    }
+
     pub fn class_or_nil_at_index(&self, class_index: sqInt) -> ObjectPointer {
         unsafe { ObjectPointer::from_native_c(classOrNilAtIndex(class_index)) }
     }
 
+    /// Allocate a context that saves the C stack of a callback while it runs in the interpreter.
+    /// Must be released with [`InterpreterProxy::free_callback_context`]
+    pub fn new_callback_context(&self) -> *mut c_void {
+        unsafe { newCallbackContext() }
+    }
+
+    pub fn free_callback_context(&self, context: *mut c_void) {
+        unsafe { freeCallbackContext(context) }
+    }
+
+    /// Run the interpreter from within a callback until [`InterpreterProxy::exit_interpreter_to_callback`]
+    /// is called with the same context. Returns false if the interpreter could not be entered
+    pub fn enter_interpreter_from_callback(&self, context: *mut c_void) -> bool {
+        unsafe { enterInterpreterFromCallback(context) != 0 }
+    }
+
+    /// Return from the interpreter to the callback that entered it with a given context.
+    /// Does not return, the C stack is unwound up to the callback without running destructors
+    pub fn exit_interpreter_to_callback(&self, context: *mut c_void) {
+        unsafe { exitInterpreterToCallback(context) }
+    }
+
     pub fn new_string(&self, string: impl AsRef<str>) -> ObjectPointer {
         let function = self.native().stringForCString.unwrap();
         let rust_str = string.as_ref();
@@ -165,19 +207,42 @@ impl InterpreterProxy {
     }
 
     pub fn malloc(&self, bytes: usize) -> *mut c_void {
-        unsafe { malloc(cast_integer(bytes)) }
+        let address = unsafe { malloc(cast_integer(bytes)) };
+        self.notify_allocated(address, bytes);
+        address
     }
 
     pub fn calloc(&self, amount: usize, size: usize) -> *mut c_void {
-        unsafe { calloc(cast_integer(amount), cast_integer(size)) }
+        let address = unsafe { calloc(cast_integer(amount), cast_integer(size)) };
+        self.notify_allocated(address, amount * size);
+        address
     }
 
     pub fn free(&self, address: *mut c_void) {
+        if let Some(observer) = ALLOCATION_OBSERVER.read().unwrap().as_ref() {
+            if !observer.should_free(address) {
+                return;
+            }
+        }
         unsafe {
             free(address);
         }
     }
 
+    /// Observe allocations made through the proxy, or stop observing them when None
+    pub fn set_allocation_observer(&self, observer: Option<Arc<dyn AllocationObserver>>) {
+        *ALLOCATION_OBSERVER.write().unwrap() = observer;
+    }
+
+    fn notify_allocated(&self, address: *mut c_void, size: usize) {
+        if address.is_null() {
+            return;
+        }
+        if let Some(observer) = ALLOCATION_OBSERVER.read().unwrap().as_ref() {
+            observer.allocated(address, size);
+        }
+    }
+
     pub fn is_failed(&self) -> bool {
         let function = self.native().failed.unwrap();
         unsafe { function() != 0 }
@@ -1,21 +1,22 @@
 use crate::bindings::{
-    addressCouldBeClassObj, classArray, classExternalAddress, classString,
+    addressCouldBeClassObj, classArray, classByteArray, classExternalAddress, classString,
     createNewMethodheaderbytecodeCount, ensureBehaviorHash, exportReadAddress as readAddress,
     falseObject, fetchClassOfNonImm, fetchPointerofObject, firstBytePointerOfDataObject,
-    firstFixedField, firstIndexableField, floatObjectOf, floatValueOf, getEdenSpaceMemoryEnd,
-    getEdenSpaceMemoryStart, getObjectAfterlimit, getOldSpaceMemoryEnd, getOldSpaceMemoryStart,
-    getPastSpaceMemoryEnd, getPastSpaceMemoryStart, getThisContext, hashBitsOf, instVarofContext,
-    instantiateClassindexableSize, instantiateClassindexableSizeisPinned, instantiateClassisPinned,
-    integerObjectOf, isFloatInstance, isKindOfClass, isOld, isOopForwarded, isYoung,
-    methodArgumentCount, methodReturnInteger, methodReturnValue, nilObject,
-    possibleOldObjectStoreInto, possiblePermObjectStoreIntovalue, primitiveFail, primitiveFailFor,
-    sqInt, stContextSize, stObjectat, stObjectatput, stSizeOf, stackIntegerValue, stackValue,
-    trueObject,
+    firstFixedField, firstIndexableField, floatObjectOf, floatValueOf, getClassNameIndex,
+    getEdenSpaceMemoryEnd, getEdenSpaceMemoryStart, getObjectAfterlimit, getOldSpaceMemoryEnd,
+    getOldSpaceMemoryStart, getOldSpaceSegmentEnd, getOldSpaceSegmentStart,
+    getOldSpaceSegmentsCount, getPastSpaceMemoryEnd, getPastSpaceMemoryStart, getRememberedSetSize,
+    getThisClassIndex, getThisContext, hashBitsOf, instVarofContext, instantiateClassindexableSize,
+    instantiateClassindexableSizeisPinned, instantiateClassisPinned, integerObjectOf,
+    isFloatInstance, isKindOfClass, isOld, isOopForwarded, isYoung, methodArgumentCount,
+    methodReturnInteger, methodReturnValue, nilObject, possibleOldObjectStoreInto,
+    possiblePermObjectStoreIntovalue, primitiveFail, primitiveFailFor, rawHashBitsOf, sqInt,
+    stContextSize, stObjectat, stObjectatput, stSizeOf, stackIntegerValue, stackValue, trueObject,
 };
 use crate::prelude::NativeTransmutable;
 use crate::{ObjectFieldIndex, ObjectPointer, StackOffset};
 use std::os::raw::c_void;
-use vm_object_model::{AnyObjectRef, ObjectRef, RawObjectPointer};
+use vm_object_model::{AnyObjectRef, ObjectFormat, ObjectRef, RawObjectPointer};
 
 pub struct Smalltalk {}
 
@@ -203,6 +204,12 @@ impl Smalltalk {
         .unwrap()
     }
 
+    pub fn class_byte_array() -> ObjectRef {
+        AnyObjectRef::from(RawObjectPointer::from(unsafe { classByteArray() }))
+            .as_object()
+            .unwrap()
+    }
+
     pub fn class_external_address() -> ObjectRef {
         AnyObjectRef::from(RawObjectPointer::from(
             Self::primitive_class_external_address().into_native(),
@@ -383,6 +390,55 @@ impl Smalltalk {
         (hash as u64) << 8
     }
 
+    /// Return the identity hash of an object without assigning one,
+    /// None if the object was never asked for its identity hash
+    pub fn raw_identity_hash(object: ObjectPointer) -> Option<u64> {
+        let hash = unsafe { rawHashBitsOf(object.into_native()) };
+        (hash != 0).then(|| (hash as u64) << 8)
+    }
+
+    /// Return the index of the class in the class table,
+    /// instances of the class have it in their object header.
+    /// The index is read from the raw identity hash of the class, which is not assigned if missing,
+    /// so it is 0 for classes that were never instantiated and None if the hash is invalid
+    pub fn class_index_of_class(class: ObjectRef) -> Option<u32> {
+        let hash = unsafe { rawHashBitsOf(ObjectPointer::from(class.as_ptr()).into_native()) };
+        u32::try_from(hash).ok()
+    }
+
+    /// Return the name of a class, or of the class of a metaclass followed by " class".
+    /// The VM knows the indices of the corresponding instance variables.
+    pub fn class_name(class: ObjectRef) -> String {
+        let name_index = unsafe { getClassNameIndex() } as usize;
+        if let Some(name) = class.inst_var_at(name_index).and_then(Self::symbol_string) {
+            return name;
+        }
+
+        let this_class_index = unsafe { getThisClassIndex() } as usize;
+        class
+            .inst_var_at(this_class_index)
+            .and_then(|this_class| this_class.as_object().ok())
+            .and_then(|this_class| this_class.inst_var_at(name_index))
+            .and_then(Self::symbol_string)
+            .map(|name| format!("{} class", name))
+            .unwrap_or_else(|| "<unknown class>".to_string())
+    }
+
+    /// Return the characters of a byte symbol or a byte string
+    pub fn symbol_string(symbol: AnyObjectRef) -> Option<String> {
+        let symbol = symbol.as_object().ok()?;
+        if !matches!(symbol.object_format(), ObjectFormat::Indexable8(_)) {
+            return None;
+        }
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                symbol.first_fixed_field_ptr() as *const u8,
+                symbol.amount_of_indexable_units(),
+            )
+        };
+        Some(String::from_utf8_lossy(bytes).to_string())
+    }
+
     pub fn could_oop_be_class(object: ObjectPointer) -> bool {
         (unsafe { addressCouldBeClassObj(object.into_native()) }) != 0
     }
@@ -552,4 +608,25 @@ impl Smalltalk {
     pub fn past_space_end() -> RawObjectPointer {
         RawObjectPointer::new(unsafe { getPastSpaceMemoryEnd() as i64 })
     }
+
+    pub fn old_space_segments_count() -> usize {
+        unsafe { getOldSpaceSegmentsCount() as usize }
+    }
+
+    /// Return the first object of an old space segment with a given zero-based index
+    pub fn old_space_segment_start(segment_index: usize) -> ObjectRef {
+        unsafe {
+            ObjectRef::from_raw_pointer_unchecked(RawObjectPointer::new(getOldSpaceSegmentStart(
+                segment_index as sqInt,
+            ) as i64))
+        }
+    }
+
+    pub fn old_space_segment_end(segment_index: usize) -> RawObjectPointer {
+        RawObjectPointer::new(unsafe { getOldSpaceSegmentEnd(segment_index as sqInt) as i64 })
+    }
+
+    pub fn remembered_set_size() -> usize {
+        unsafe { getRememberedSetSize() as usize }
+    }
 }
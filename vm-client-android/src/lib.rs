@@ -147,6 +147,8 @@ pub extern "C" fn android_main(app: android_activity::AndroidApp) {
     Constellation::for_android(app).run(VirtualMachineConfiguration {
         interpreter_configuration,
         log_signals: Some(vec![]),
+        ffi_worker_threads: None,
+        ffi_policy: None,
     });
     std::thread::sleep(Duration::from_secs(1));
 }
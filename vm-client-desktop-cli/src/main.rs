@@ -5,13 +5,14 @@ extern crate log;
 
 use std::env;
 use std::ffi::OsString;
+use std::path::PathBuf;
 
 use clap::builder::PossibleValue;
 use clap::{arg, value_parser, Arg, Command, ValueEnum};
 
 use vm_runtime::vm_bindings::InterpreterConfiguration;
 use vm_runtime::{
-    print_short_version, print_version, validate_user_image_file, Constellation,
+    print_short_version, print_version, validate_user_image_file, Constellation, FfiPolicy,
     VirtualMachineConfiguration,
 };
 
@@ -77,6 +78,22 @@ fn main() {
                 .action(clap::ArgAction::SetTrue)
                 .help("Pablos questionable command line parameter"),
         )
+        .arg(
+            Arg::new("ffi-worker-threads")
+                .long("ffi-worker-threads")
+                .value_name("AMOUNT")
+                .value_parser(value_parser!(usize))
+                .help(
+                    "Amount of threads running asynchronous FFI callouts, one per CPU by default",
+                ),
+        )
+        .arg(
+            Arg::new("ffi-policy")
+                .long("ffi-policy")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf))
+                .help("A json file describing libraries and symbols available to FFI"),
+        )
         .arg(
             Arg::new("version")
                 .long("version")
@@ -193,9 +210,26 @@ fn main() {
             }
         });
 
+    let ffi_policy = match matches.get_one::<PathBuf>("ffi-policy") {
+        None => None,
+        Some(path) => match FfiPolicy::from_file(path) {
+            Ok(policy) => Some(policy),
+            Err(error) => {
+                eprintln!(
+                    "Could not load the FFI policy from {}: {}",
+                    path.display(),
+                    error
+                );
+                return;
+            }
+        },
+    };
+
     Constellation::new().run(VirtualMachineConfiguration {
         interpreter_configuration,
         log_signals,
+        ffi_worker_threads: matches.get_one::<usize>("ffi-worker-threads").copied(),
+        ffi_policy,
     });
 }
 
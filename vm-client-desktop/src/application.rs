@@ -61,6 +61,8 @@ impl Application {
         Constellation::new().run(VirtualMachineConfiguration {
             interpreter_configuration,
             log_signals: None,
+            ffi_worker_threads: None,
+            ffi_policy: None,
         });
         Ok(())
     }
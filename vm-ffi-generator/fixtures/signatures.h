@@ -0,0 +1,45 @@
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct {
+    int x;
+    int y;
+} Point;
+
+struct Rect {
+    Point origin;
+    Point corner;
+};
+
+struct Name {
+    char letters[3];
+    const char* label;
+};
+
+typedef enum { Red, Green, Blue } Color;
+
+union Value {
+    int i;
+    float f;
+};
+
+struct Opaque;
+
+struct Flags {
+    unsigned int is_visible : 1;
+};
+
+int32_t fixed_size(int8_t a, uint16_t b, uint64_t c, size_t d, ptrdiff_t e);
+long platform_sized(char a, unsigned char b, short c, unsigned long d, long long e);
+double floats(float value);
+_Bool boolean(void);
+const char* strings(const char* name, char* buffer);
+void pointers(void* pointer, struct Opaque* opaque);
+Color enums(Color color);
+Point points(Point point, struct Rect rect);
+struct Name arrays(struct Name name);
+int variadic(const char* format, ...);
+void unions(union Value value);
+void opaque(struct Opaque opaque);
+void bit_fields(struct Flags flags);
+void unnamed(int, int);
@@ -0,0 +1,272 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::path::Path;
+use std::ptr;
+
+use clang_sys::*;
+
+use crate::Error;
+
+/// Convert and dispose a string owned by libclang
+fn into_string(string: CXString) -> String {
+    let c_string = unsafe { clang_getCString(string) };
+    let result = if c_string.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(c_string) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    unsafe { clang_disposeString(string) };
+    result
+}
+
+pub struct Index {
+    raw: CXIndex,
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self {
+            raw: unsafe { clang_createIndex(0, 0) },
+        }
+    }
+
+    /// Parse a header as C with given extra clang arguments (include paths, defines)
+    pub fn parse(&self, header: &Path, arguments: &[String]) -> Result<TranslationUnit, Error> {
+        let file_name = CString::new(header.display().to_string())
+            .map_err(|_| Error::InvalidPath(header.to_path_buf()))?;
+        let arguments = [String::from("-xc")]
+            .iter()
+            .chain(arguments)
+            .map(|each| {
+                CString::new(each.as_str()).map_err(|_| Error::InvalidArgument(each.clone()))
+            })
+            .collect::<Result<Vec<CString>, Error>>()?;
+        let argument_pointers = arguments
+            .iter()
+            .map(|each| each.as_ptr())
+            .collect::<Vec<*const c_char>>();
+
+        let raw = unsafe {
+            clang_parseTranslationUnit(
+                self.raw,
+                file_name.as_ptr(),
+                argument_pointers.as_ptr(),
+                argument_pointers.len() as c_int,
+                ptr::null_mut(),
+                0,
+                CXTranslationUnit_SkipFunctionBodies,
+            )
+        };
+        if raw.is_null() {
+            return Err(Error::FailedToParse(header.to_path_buf()));
+        }
+        Ok(TranslationUnit { raw })
+    }
+}
+
+impl Drop for Index {
+    fn drop(&mut self) {
+        unsafe { clang_disposeIndex(self.raw) };
+    }
+}
+
+pub struct TranslationUnit {
+    raw: CXTranslationUnit,
+}
+
+impl TranslationUnit {
+    pub fn cursor(&self) -> Cursor {
+        Cursor::new(unsafe { clang_getTranslationUnitCursor(self.raw) })
+    }
+
+    /// Formatted errors reported while parsing, warnings are ignored
+    pub fn errors(&self) -> Vec<String> {
+        let amount = unsafe { clang_getNumDiagnostics(self.raw) };
+        (0..amount)
+            .filter_map(|index| {
+                let diagnostic = unsafe { clang_getDiagnostic(self.raw, index) };
+                let severity = unsafe { clang_getDiagnosticSeverity(diagnostic) };
+                let message = if severity >= CXDiagnostic_Error {
+                    Some(into_string(unsafe {
+                        clang_formatDiagnostic(diagnostic, clang_defaultDiagnosticDisplayOptions())
+                    }))
+                } else {
+                    None
+                };
+                unsafe { clang_disposeDiagnostic(diagnostic) };
+                message
+            })
+            .collect()
+    }
+}
+
+impl Drop for TranslationUnit {
+    fn drop(&mut self) {
+        unsafe { clang_disposeTranslationUnit(self.raw) };
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Cursor {
+    raw: CXCursor,
+}
+
+extern "C" fn collect_child(
+    cursor: CXCursor,
+    _parent: CXCursor,
+    children: CXClientData,
+) -> CXChildVisitResult {
+    let children = unsafe { &mut *(children as *mut Vec<Cursor>) };
+    children.push(Cursor::new(cursor));
+    CXChildVisit_Continue
+}
+
+impl Cursor {
+    fn new(raw: CXCursor) -> Self {
+        Self { raw }
+    }
+
+    pub fn kind(&self) -> CXCursorKind {
+        unsafe { clang_getCursorKind(self.raw) }
+    }
+
+    pub fn is_null(&self) -> bool {
+        unsafe { clang_Cursor_isNull(self.raw) != 0 }
+    }
+
+    pub fn spelling(&self) -> String {
+        into_string(unsafe { clang_getCursorSpelling(self.raw) })
+    }
+
+    /// Declarations of unnamed structs and enums have no spelling in older libclang,
+    /// and a spelling like `struct (unnamed at file.h:1:9)` in newer
+    pub fn is_anonymous(&self) -> bool {
+        let spelling = self.spelling();
+        spelling.is_empty() || spelling.contains("(unnamed") || spelling.contains("(anonymous")
+    }
+
+    pub fn ty(&self) -> Type {
+        Type::new(unsafe { clang_getCursorType(self.raw) })
+    }
+
+    pub fn definition(&self) -> Option<Cursor> {
+        let definition = Cursor::new(unsafe { clang_getCursorDefinition(self.raw) });
+        if definition.is_null() {
+            None
+        } else {
+            Some(definition)
+        }
+    }
+
+    pub fn is_in_main_file(&self) -> bool {
+        unsafe { clang_Location_isFromMainFile(clang_getCursorLocation(self.raw)) != 0 }
+    }
+
+    pub fn is_bit_field(&self) -> bool {
+        unsafe { clang_Cursor_isBitField(self.raw) != 0 }
+    }
+
+    /// Names of the arguments of a function declaration, empty for unnamed ones
+    pub fn argument_names(&self) -> Vec<String> {
+        let amount = unsafe { clang_Cursor_getNumArguments(self.raw) }.max(0) as u32;
+        (0..amount)
+            .map(|index| Cursor::new(unsafe { clang_Cursor_getArgument(self.raw, index) }))
+            .map(|argument| argument.spelling())
+            .collect()
+    }
+
+    pub fn typedef_underlying_type(&self) -> Type {
+        Type::new(unsafe { clang_getTypedefDeclUnderlyingType(self.raw) })
+    }
+
+    pub fn enum_integer_type(&self) -> Type {
+        Type::new(unsafe { clang_getEnumDeclIntegerType(self.raw) })
+    }
+
+    pub fn children(&self) -> Vec<Cursor> {
+        let mut children: Vec<Cursor> = vec![];
+        unsafe {
+            clang_visitChildren(
+                self.raw,
+                collect_child,
+                &mut children as *mut Vec<Cursor> as CXClientData,
+            )
+        };
+        children
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Type {
+    raw: CXType,
+}
+
+impl Type {
+    fn new(raw: CXType) -> Self {
+        Self { raw }
+    }
+
+    pub fn kind(&self) -> CXTypeKind {
+        self.raw.kind
+    }
+
+    pub fn spelling(&self) -> String {
+        into_string(unsafe { clang_getTypeSpelling(self.raw) })
+    }
+
+    pub fn typedef_name(&self) -> String {
+        into_string(unsafe { clang_getTypedefName(self.raw) })
+    }
+
+    pub fn is_const(&self) -> bool {
+        unsafe { clang_isConstQualifiedType(self.raw) != 0 }
+    }
+
+    pub fn canonical(&self) -> Type {
+        Type::new(unsafe { clang_getCanonicalType(self.raw) })
+    }
+
+    /// The type behind an elaborated type such as `struct Point` or `enum Color`
+    pub fn named(&self) -> Type {
+        Type::new(unsafe { clang_Type_getNamedType(self.raw) })
+    }
+
+    pub fn pointee(&self) -> Type {
+        Type::new(unsafe { clang_getPointeeType(self.raw) })
+    }
+
+    pub fn declaration(&self) -> Cursor {
+        Cursor::new(unsafe { clang_getTypeDeclaration(self.raw) })
+    }
+
+    pub fn result(&self) -> Type {
+        Type::new(unsafe { clang_getResultType(self.raw) })
+    }
+
+    /// Argument types of a function type, None if the function has no prototype
+    pub fn arguments(&self) -> Option<Vec<Type>> {
+        let amount = unsafe { clang_getNumArgTypes(self.raw) };
+        if amount < 0 {
+            return None;
+        }
+        Some(
+            (0..amount as u32)
+                .map(|index| Type::new(unsafe { clang_getArgType(self.raw, index) }))
+                .collect(),
+        )
+    }
+
+    pub fn is_variadic(&self) -> bool {
+        unsafe { clang_isFunctionTypeVariadic(self.raw) != 0 }
+    }
+
+    pub fn array_element(&self) -> Type {
+        Type::new(unsafe { clang_getArrayElementType(self.raw) })
+    }
+
+    pub fn array_size(&self) -> usize {
+        unsafe { clang_getArraySize(self.raw) }.max(0) as usize
+    }
+}
@@ -0,0 +1,106 @@
+mod clang;
+mod marshall_type;
+mod signatures;
+
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use thiserror::Error;
+
+use crate::clang::Index;
+pub use crate::marshall_type::MarshallType;
+use crate::signatures::HeaderSignatures;
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum OutputFormat {
+    /// Human readable signatures
+    Text,
+    /// Signatures with the ordinals of the BareFFI types, to be read by the image
+    Json,
+}
+
+/// Print bare FFI signatures (marshall types of arguments and return values)
+/// of the functions declared in a C header, along with layouts of the structs passed by value
+#[derive(Parser, Debug)]
+struct Options {
+    /// A C header to generate signatures for
+    header: PathBuf,
+    /// Add a directory to the include search paths
+    #[arg(short = 'I', long = "include", value_name = "DIRECTORY")]
+    include_directories: Vec<PathBuf>,
+    /// Define a macro, as `NAME` or `NAME=VALUE`
+    #[arg(short = 'D', long = "define", value_name = "MACRO")]
+    defines: Vec<String>,
+    /// Only generate signatures of functions which names start with a prefix
+    #[arg(long)]
+    prefix: Option<String>,
+    /// Also generate signatures of functions declared in included headers
+    #[arg(long)]
+    all_files: bool,
+    /// The format of the generated signatures
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+    /// Extra arguments passed to clang as is
+    #[arg(last = true)]
+    clang_arguments: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to load libclang: {0}")]
+    FailedToLoadClang(String),
+    #[error("Path {} contains a NUL character", .0.display())]
+    InvalidPath(PathBuf),
+    #[error("Argument {0} contains a NUL character")]
+    InvalidArgument(String),
+    #[error("Failed to parse {}", .0.display())]
+    FailedToParse(PathBuf),
+    #[error("Failed to compile {}:\n{}", .header.display(), .errors.join("\n"))]
+    CompilationErrors {
+        header: PathBuf,
+        errors: Vec<String>,
+    },
+}
+
+fn generate(options: &Options) -> Result<HeaderSignatures, Error> {
+    clang_sys::load().map_err(Error::FailedToLoadClang)?;
+
+    let arguments = options
+        .include_directories
+        .iter()
+        .map(|directory| format!("-I{}", directory.display()))
+        .chain(options.defines.iter().map(|define| format!("-D{}", define)))
+        .chain(options.clang_arguments.iter().cloned())
+        .collect::<Vec<String>>();
+
+    let index = Index::new();
+    let translation_unit = index.parse(&options.header, &arguments)?;
+    let errors = translation_unit.errors();
+    if !errors.is_empty() {
+        return Err(Error::CompilationErrors {
+            header: options.header.clone(),
+            errors,
+        });
+    }
+
+    Ok(HeaderSignatures::collect(
+        translation_unit.cursor(),
+        options.all_files,
+        options.prefix.as_deref(),
+    ))
+}
+
+fn main() {
+    let options: Options = Options::parse();
+
+    match generate(&options) {
+        Ok(signatures) => match options.format {
+            OutputFormat::Text => print!("{}", signatures),
+            OutputFormat::Json => println!("{}", signatures.to_json().pretty(2)),
+        },
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    }
+}
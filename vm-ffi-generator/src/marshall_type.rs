@@ -0,0 +1,138 @@
+use std::fmt::{Display, Formatter};
+
+/// Marshall types understood by bare FFI, see `vm_runtime::ffi::MarshallType`.
+/// The generator does not depend on the runtime to avoid building the whole virtual machine,
+/// so the variants must be kept in the same order for their ordinals to match the image types
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MarshallType {
+    Void,
+    Bool,
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    USize,
+    ISize,
+    SChar,
+    UChar,
+    Short,
+    UShort,
+    Int,
+    UInt,
+    Long,
+    ULong,
+    LongLong,
+    ULongLong,
+    F32,
+    F64,
+    Pointer,
+    Struct,
+    String,
+    /// array arguments are never generated from C types, they only keep the ordinals in sync
+    Buffer,
+    FloatArray,
+    WordArray,
+}
+
+impl MarshallType {
+    /// The value of the corresponding BareFFI type in the image
+    pub fn ordinal(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Typedefs from `stdint.h` and `stddef.h` that have a fixed size
+    /// and must not be resolved to the platform dependent C type they are defined with
+    pub fn from_typedef_name(name: &str) -> Option<Self> {
+        let marshall_type = match name {
+            "int8_t" => Self::I8,
+            "uint8_t" => Self::U8,
+            "int16_t" => Self::I16,
+            "uint16_t" => Self::U16,
+            "int32_t" => Self::I32,
+            "uint32_t" => Self::U32,
+            "int64_t" => Self::I64,
+            "uint64_t" => Self::U64,
+            "size_t" | "uintptr_t" => Self::USize,
+            "ssize_t" | "intptr_t" | "ptrdiff_t" => Self::ISize,
+            _ => return None,
+        };
+        Some(marshall_type)
+    }
+}
+
+impl Display for MarshallType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_size_typedefs() {
+        let typedefs = [
+            ("int8_t", MarshallType::I8),
+            ("uint8_t", MarshallType::U8),
+            ("int16_t", MarshallType::I16),
+            ("uint16_t", MarshallType::U16),
+            ("int32_t", MarshallType::I32),
+            ("uint32_t", MarshallType::U32),
+            ("int64_t", MarshallType::I64),
+            ("uint64_t", MarshallType::U64),
+        ];
+        for (name, marshall_type) in typedefs {
+            assert_eq!(MarshallType::from_typedef_name(name), Some(marshall_type));
+        }
+    }
+
+    #[test]
+    fn pointer_sized_typedefs() {
+        for name in ["size_t", "uintptr_t"] {
+            assert_eq!(
+                MarshallType::from_typedef_name(name),
+                Some(MarshallType::USize)
+            );
+        }
+        for name in ["ssize_t", "intptr_t", "ptrdiff_t"] {
+            assert_eq!(
+                MarshallType::from_typedef_name(name),
+                Some(MarshallType::ISize)
+            );
+        }
+    }
+
+    #[test]
+    fn other_typedefs_are_resolved() {
+        for name in [
+            "",
+            "int",
+            "uint128_t",
+            "int_least32_t",
+            "Int32_t",
+            "my_size_t",
+        ] {
+            assert_eq!(MarshallType::from_typedef_name(name), None);
+        }
+    }
+
+    #[test]
+    fn ordinals_match_image_types() {
+        assert_eq!(MarshallType::Void.ordinal(), 0);
+        assert_eq!(MarshallType::Bool.ordinal(), 1);
+        assert_eq!(MarshallType::USize.ordinal(), 10);
+        assert_eq!(MarshallType::SChar.ordinal(), 12);
+        assert_eq!(MarshallType::Int.ordinal(), 16);
+        assert_eq!(MarshallType::F64.ordinal(), 23);
+        assert_eq!(MarshallType::Pointer.ordinal(), 24);
+        assert_eq!(MarshallType::Struct.ordinal(), 25);
+        assert_eq!(MarshallType::String.ordinal(), 26);
+        assert_eq!(MarshallType::WordArray.ordinal(), 29);
+    }
+}
@@ -0,0 +1,707 @@
+#![allow(non_upper_case_globals)]
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+use clang_sys::*;
+use json::{object, JsonValue};
+
+use crate::clang::{Cursor, Type};
+use crate::MarshallType;
+
+/// The marshall type of an argument, a return value or a struct field.
+/// Structs passed by value refer to their layout by name
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SignatureType {
+    marshall_type: MarshallType,
+    struct_name: Option<String>,
+}
+
+impl SignatureType {
+    fn new(marshall_type: MarshallType) -> Self {
+        Self {
+            marshall_type,
+            struct_name: None,
+        }
+    }
+
+    fn new_struct(struct_name: String) -> Self {
+        Self {
+            marshall_type: MarshallType::Struct,
+            struct_name: Some(struct_name),
+        }
+    }
+}
+
+impl SignatureType {
+    /// `{ "type": name, "ordinal": value of the BareFFI type, "struct": layout name }`,
+    /// where the struct is only present for structs passed by value
+    fn to_json(&self) -> JsonValue {
+        let mut json = object! {
+            "type": self.marshall_type.to_string(),
+            "ordinal": self.marshall_type.ordinal(),
+        };
+        if let Some(struct_name) = &self.struct_name {
+            json["struct"] = struct_name.as_str().into();
+        }
+        json
+    }
+}
+
+impl Display for SignatureType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.struct_name {
+            None => write!(f, "{}", self.marshall_type),
+            Some(struct_name) => write!(f, "{}({})", self.marshall_type, struct_name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StructLayout {
+    pub name: String,
+    pub fields: Vec<(String, SignatureType)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionSignature {
+    pub name: String,
+    pub arguments: Vec<(String, SignatureType)>,
+    pub is_variadic: bool,
+    pub result: SignatureType,
+}
+
+/// A function declared in a header that can't be called through bare FFI
+#[derive(Debug, Clone)]
+pub struct SkippedFunction {
+    pub name: String,
+    pub reason: String,
+}
+
+/// Bare FFI signatures of the functions declared in a header
+/// together with the layouts of all structs they pass by value
+#[derive(Debug, Default)]
+pub struct HeaderSignatures {
+    pub structs: Vec<StructLayout>,
+    pub functions: Vec<FunctionSignature>,
+    pub skipped: Vec<SkippedFunction>,
+    struct_names: HashSet<String>,
+    function_names: HashSet<String>,
+}
+
+impl HeaderSignatures {
+    /// Collect functions declared at the top level of a translation unit.
+    /// Functions declared in included headers are ignored unless `include_all_files` is set
+    pub fn collect(
+        translation_unit: Cursor,
+        include_all_files: bool,
+        prefix: Option<&str>,
+    ) -> Self {
+        let mut signatures = Self::default();
+
+        for cursor in translation_unit.children() {
+            if cursor.kind() != CXCursor_FunctionDecl {
+                continue;
+            }
+            if !include_all_files && !cursor.is_in_main_file() {
+                continue;
+            }
+            let name = cursor.spelling();
+            if prefix.is_some_and(|prefix| !name.starts_with(prefix)) {
+                continue;
+            }
+            // the same function may be declared several times
+            if !signatures.function_names.insert(name.clone()) {
+                continue;
+            }
+
+            match signatures.function_signature_of(cursor) {
+                Ok(function) => signatures.functions.push(function),
+                Err(reason) => signatures.skipped.push(SkippedFunction { name, reason }),
+            }
+        }
+
+        signatures
+    }
+
+    /// Describe the signatures as json, ready to be read by the image:
+    /// ```json
+    /// {
+    ///   "structs": [ { "name": "Point", "fields": [ { "name": "x", "type": "Int", "ordinal": 16 } ] } ],
+    ///   "functions": [ {
+    ///     "name": "move",
+    ///     "arguments": [ { "name": "point", "type": "Struct", "ordinal": 25, "struct": "Point" } ],
+    ///     "variadic": false,
+    ///     "result": { "type": "Void", "ordinal": 0 }
+    ///   } ],
+    ///   "skipped": [ { "name": "area", "reason": "unions are not supported: `union Shape`" } ]
+    /// }
+    /// ```
+    pub fn to_json(&self) -> JsonValue {
+        let structs = self
+            .structs
+            .iter()
+            .map(|struct_layout| {
+                object! {
+                    "name": struct_layout.name.as_str(),
+                    "fields": named_types_to_json(&struct_layout.fields),
+                }
+            })
+            .collect::<Vec<JsonValue>>();
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| {
+                object! {
+                    "name": function.name.as_str(),
+                    "arguments": named_types_to_json(&function.arguments),
+                    "variadic": function.is_variadic,
+                    "result": function.result.to_json(),
+                }
+            })
+            .collect::<Vec<JsonValue>>();
+        let skipped = self
+            .skipped
+            .iter()
+            .map(|skipped| {
+                object! {
+                    "name": skipped.name.as_str(),
+                    "reason": skipped.reason.as_str(),
+                }
+            })
+            .collect::<Vec<JsonValue>>();
+
+        object! {
+            "structs": structs,
+            "functions": functions,
+            "skipped": skipped,
+        }
+    }
+
+    fn function_signature_of(&mut self, function: Cursor) -> Result<FunctionSignature, String> {
+        let function_type = function.ty();
+        let argument_types = function_type
+            .arguments()
+            .ok_or_else(|| "the function has no prototype".to_string())?;
+
+        let mut arguments = vec![];
+        for (index, (argument_name, argument_type)) in function
+            .argument_names()
+            .into_iter()
+            .zip(argument_types)
+            .enumerate()
+        {
+            let argument_name = if argument_name.is_empty() {
+                format!("arg{}", index)
+            } else {
+                argument_name
+            };
+            arguments.push((argument_name, self.signature_type_of(argument_type, None)?));
+        }
+
+        Ok(FunctionSignature {
+            name: function.spelling(),
+            arguments,
+            is_variadic: function_type.is_variadic(),
+            result: self.signature_type_of(function_type.result(), None)?,
+        })
+    }
+
+    /// Map a C type to a marshall type resolving typedefs, enums and elaborated types.
+    /// `name_hint` names an anonymous struct after the typedef or the field it is declared in
+    fn signature_type_of(
+        &mut self,
+        c_type: Type,
+        name_hint: Option<String>,
+    ) -> Result<SignatureType, String> {
+        let marshall_type = match c_type.kind() {
+            CXType_Elaborated => return self.signature_type_of(c_type.named(), name_hint),
+            CXType_Typedef => {
+                let typedef_name = c_type.typedef_name();
+                if let Some(marshall_type) = MarshallType::from_typedef_name(&typedef_name) {
+                    return Ok(SignatureType::new(marshall_type));
+                }
+                return self.signature_type_of(
+                    c_type.declaration().typedef_underlying_type(),
+                    Some(typedef_name),
+                );
+            }
+            CXType_Enum => {
+                return self.signature_type_of(c_type.declaration().enum_integer_type(), None)
+            }
+            CXType_Record => return self.struct_type_of(c_type, name_hint),
+            CXType_Void => MarshallType::Void,
+            CXType_Bool => MarshallType::Bool,
+            CXType_Char_S | CXType_SChar => MarshallType::SChar,
+            CXType_Char_U | CXType_UChar => MarshallType::UChar,
+            CXType_Short => MarshallType::Short,
+            CXType_UShort => MarshallType::UShort,
+            CXType_Int => MarshallType::Int,
+            CXType_UInt => MarshallType::UInt,
+            CXType_Long => MarshallType::Long,
+            CXType_ULong => MarshallType::ULong,
+            CXType_LongLong => MarshallType::LongLong,
+            CXType_ULongLong => MarshallType::ULongLong,
+            CXType_Float => MarshallType::F32,
+            CXType_Double => MarshallType::F64,
+            CXType_Pointer if is_c_string(c_type.pointee()) => MarshallType::String,
+            CXType_Pointer | CXType_BlockPointer => MarshallType::Pointer,
+            _ => return Err(format!("unsupported type `{}`", c_type.spelling())),
+        };
+        Ok(SignatureType::new(marshall_type))
+    }
+
+    fn struct_type_of(
+        &mut self,
+        c_type: Type,
+        name_hint: Option<String>,
+    ) -> Result<SignatureType, String> {
+        let declaration = c_type.declaration();
+        if declaration.kind() == CXCursor_UnionDecl {
+            return Err(format!("unions are not supported: `{}`", c_type.spelling()));
+        }
+
+        let name = if declaration.is_anonymous() {
+            name_hint.ok_or_else(|| format!("`{}` has no name", c_type.spelling()))?
+        } else {
+            declaration.spelling()
+        };
+
+        if !self.struct_names.contains(&name) {
+            let definition = declaration.definition().ok_or_else(|| {
+                format!("struct `{}` is opaque and can't be passed by value", name)
+            })?;
+            // nested structs are added before the struct that contains them
+            let fields = self.fields_of(&name, definition)?;
+            self.struct_names.insert(name.clone());
+            self.structs.push(StructLayout {
+                name: name.clone(),
+                fields,
+            });
+        }
+
+        Ok(SignatureType::new_struct(name))
+    }
+
+    fn fields_of(
+        &mut self,
+        struct_name: &str,
+        definition: Cursor,
+    ) -> Result<Vec<(String, SignatureType)>, String> {
+        let mut fields = vec![];
+        for field in definition
+            .children()
+            .into_iter()
+            .filter(|each| each.kind() == CXCursor_FieldDecl)
+        {
+            let field_name = field.spelling();
+            if field.is_bit_field() {
+                return Err(format!(
+                    "bit field `{}` of `{}` is not supported",
+                    field_name, struct_name
+                ));
+            }
+            let name_hint = Some(format!("{}_{}", struct_name, field_name));
+
+            let field_type = field.ty();
+            if field_type.kind() == CXType_ConstantArray {
+                // a fixed size array has the layout of as many consecutive fields
+                let element_type =
+                    as_field_type(self.signature_type_of(field_type.array_element(), name_hint)?);
+                for index in 0..field_type.array_size() {
+                    fields.push((format!("{}[{}]", field_name, index), element_type.clone()));
+                }
+            } else {
+                let field_type = as_field_type(self.signature_type_of(field_type, name_hint)?);
+                fields.push((field_name, field_type));
+            }
+        }
+
+        if fields.is_empty() {
+            return Err(format!("struct `{}` has no fields", struct_name));
+        }
+        Ok(fields)
+    }
+}
+
+fn named_types_to_json(named_types: &[(String, SignatureType)]) -> JsonValue {
+    named_types
+        .iter()
+        .map(|(name, signature_type)| {
+            let mut json = object! { "name": name.as_str() };
+            for (key, value) in signature_type.to_json().entries() {
+                json[key] = value.clone();
+            }
+            json
+        })
+        .collect::<Vec<JsonValue>>()
+        .into()
+}
+
+/// `const char*` is marshalled from and to Smalltalk strings
+fn is_c_string(pointee: Type) -> bool {
+    pointee.is_const() && matches!(pointee.canonical().kind(), CXType_Char_S | CXType_Char_U)
+}
+
+/// Strings are only marshalled as arguments and return values, struct fields hold plain pointers
+fn as_field_type(signature_type: SignatureType) -> SignatureType {
+    if signature_type.marshall_type == MarshallType::String {
+        SignatureType::new(MarshallType::Pointer)
+    } else {
+        signature_type
+    }
+}
+
+impl Display for HeaderSignatures {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for struct_layout in &self.structs {
+            writeln!(f, "struct {} {{", struct_layout.name)?;
+            for (field_name, field_type) in &struct_layout.fields {
+                writeln!(f, "    {}: {},", field_name, field_type)?;
+            }
+            writeln!(f, "}}")?;
+            writeln!(f)?;
+        }
+
+        for function in &self.functions {
+            let mut arguments = function
+                .arguments
+                .iter()
+                .map(|(argument_name, argument_type)| {
+                    format!("{}: {}", argument_name, argument_type)
+                })
+                .collect::<Vec<String>>();
+            if function.is_variadic {
+                arguments.push("...".to_string());
+            }
+            writeln!(
+                f,
+                "fn {}({}) -> {}",
+                function.name,
+                arguments.join(", "),
+                function.result
+            )?;
+        }
+
+        if !self.skipped.is_empty() {
+            writeln!(f)?;
+        }
+        for skipped in &self.skipped {
+            writeln!(f, "// skipped {}: {}", skipped.name, skipped.reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clang::Index;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/signatures.h");
+
+    /// Signatures of the fixture header, None if libclang is not available
+    fn fixture_signatures() -> Option<HeaderSignatures> {
+        if let Err(error) = clang_sys::load() {
+            eprintln!("Skipping, libclang is not available: {}", error);
+            return None;
+        }
+        let index = Index::new();
+        let translation_unit = index.parse(std::path::Path::new(FIXTURE), &[]).unwrap();
+        assert_eq!(translation_unit.errors(), Vec::<String>::new());
+        Some(HeaderSignatures::collect(
+            translation_unit.cursor(),
+            false,
+            None,
+        ))
+    }
+
+    fn function<'a>(signatures: &'a HeaderSignatures, name: &str) -> &'a FunctionSignature {
+        signatures
+            .functions
+            .iter()
+            .find(|function| function.name == name)
+            .unwrap_or_else(|| panic!("{} is not generated", name))
+    }
+
+    fn argument_types(function: &FunctionSignature) -> Vec<SignatureType> {
+        function
+            .arguments
+            .iter()
+            .map(|(_, argument_type)| argument_type.clone())
+            .collect()
+    }
+
+    fn skipped_reason<'a>(signatures: &'a HeaderSignatures, name: &str) -> &'a str {
+        signatures
+            .skipped
+            .iter()
+            .find(|skipped| skipped.name == name)
+            .map(|skipped| skipped.reason.as_str())
+            .unwrap_or_else(|| panic!("{} is not skipped", name))
+    }
+
+    fn types(marshall_types: &[MarshallType]) -> Vec<SignatureType> {
+        marshall_types
+            .iter()
+            .map(|marshall_type| SignatureType::new(*marshall_type))
+            .collect()
+    }
+
+    #[test]
+    fn display_signature_types() {
+        assert_eq!(SignatureType::new(MarshallType::Int).to_string(), "Int");
+        assert_eq!(
+            SignatureType::new_struct("Point".to_string()).to_string(),
+            "Struct(Point)"
+        );
+    }
+
+    #[test]
+    fn string_fields_are_pointers() {
+        assert_eq!(
+            as_field_type(SignatureType::new(MarshallType::String)),
+            SignatureType::new(MarshallType::Pointer)
+        );
+        assert_eq!(
+            as_field_type(SignatureType::new(MarshallType::Int)),
+            SignatureType::new(MarshallType::Int)
+        );
+    }
+
+    #[test]
+    fn json_signature_types() {
+        assert_eq!(
+            SignatureType::new(MarshallType::String).to_json(),
+            object! { "type": "String", "ordinal": 26 }
+        );
+        assert_eq!(
+            SignatureType::new_struct("Point".to_string()).to_json(),
+            object! { "type": "Struct", "ordinal": 25, "struct": "Point" }
+        );
+    }
+
+    #[test]
+    fn json_signatures() {
+        let signatures = HeaderSignatures {
+            structs: vec![StructLayout {
+                name: "Point".to_string(),
+                fields: vec![("x".to_string(), SignatureType::new(MarshallType::Int))],
+            }],
+            functions: vec![FunctionSignature {
+                name: "move".to_string(),
+                arguments: vec![(
+                    "point".to_string(),
+                    SignatureType::new_struct("Point".to_string()),
+                )],
+                is_variadic: false,
+                result: SignatureType::new(MarshallType::Void),
+            }],
+            skipped: vec![SkippedFunction {
+                name: "area".to_string(),
+                reason: "unsupported".to_string(),
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            signatures.to_json(),
+            object! {
+                "structs": [ { "name": "Point", "fields": [ { "name": "x", "type": "Int", "ordinal": 16 } ] } ],
+                "functions": [ {
+                    "name": "move",
+                    "arguments": [ { "name": "point", "type": "Struct", "ordinal": 25, "struct": "Point" } ],
+                    "variadic": false,
+                    "result": { "type": "Void", "ordinal": 0 }
+                } ],
+                "skipped": [ { "name": "area", "reason": "unsupported" } ]
+            }
+        );
+    }
+
+    #[test]
+    fn fixed_size_typedefs_are_kept() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        let function = function(&signatures, "fixed_size");
+        assert_eq!(
+            argument_types(function),
+            types(&[
+                MarshallType::I8,
+                MarshallType::U16,
+                MarshallType::U64,
+                MarshallType::USize,
+                MarshallType::ISize
+            ])
+        );
+        assert_eq!(function.result, SignatureType::new(MarshallType::I32));
+    }
+
+    #[test]
+    fn platform_sized_types() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        let function = function(&signatures, "platform_sized");
+        let arguments = argument_types(function);
+        assert!(matches!(
+            arguments[0].marshall_type,
+            MarshallType::SChar | MarshallType::UChar
+        ));
+        assert_eq!(
+            arguments[1..],
+            types(&[
+                MarshallType::UChar,
+                MarshallType::Short,
+                MarshallType::ULong,
+                MarshallType::LongLong
+            ])
+        );
+        assert_eq!(function.result, SignatureType::new(MarshallType::Long));
+    }
+
+    #[test]
+    fn floats_and_booleans() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        let floats = function(&signatures, "floats");
+        assert_eq!(argument_types(floats), types(&[MarshallType::F32]));
+        assert_eq!(floats.result, SignatureType::new(MarshallType::F64));
+
+        let boolean = function(&signatures, "boolean");
+        assert!(boolean.arguments.is_empty());
+        assert_eq!(boolean.result, SignatureType::new(MarshallType::Bool));
+    }
+
+    #[test]
+    fn only_const_char_pointers_are_strings() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        let strings = function(&signatures, "strings");
+        assert_eq!(
+            argument_types(strings),
+            types(&[MarshallType::String, MarshallType::Pointer])
+        );
+        assert_eq!(strings.result, SignatureType::new(MarshallType::String));
+
+        let pointers = function(&signatures, "pointers");
+        assert_eq!(
+            argument_types(pointers),
+            types(&[MarshallType::Pointer, MarshallType::Pointer])
+        );
+    }
+
+    #[test]
+    fn enums_are_their_integer_type() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        let function = function(&signatures, "enums");
+        assert!(matches!(
+            function.result.marshall_type,
+            MarshallType::UInt | MarshallType::Int
+        ));
+        assert_eq!(argument_types(function), vec![function.result.clone()]);
+    }
+
+    #[test]
+    fn structs_passed_by_value() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        let function = function(&signatures, "points");
+        assert_eq!(
+            argument_types(function),
+            vec![
+                SignatureType::new_struct("Point".to_string()),
+                SignatureType::new_struct("Rect".to_string())
+            ]
+        );
+        assert_eq!(
+            function.result,
+            SignatureType::new_struct("Point".to_string())
+        );
+
+        let struct_names = signatures
+            .structs
+            .iter()
+            .map(|struct_layout| struct_layout.name.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(struct_names, vec!["Point", "Rect", "Name"]);
+
+        let rect = &signatures.structs[1];
+        assert_eq!(
+            rect.fields,
+            vec![
+                (
+                    "origin".to_string(),
+                    SignatureType::new_struct("Point".to_string())
+                ),
+                (
+                    "corner".to_string(),
+                    SignatureType::new_struct("Point".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn arrays_are_consecutive_fields() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        let name = signatures
+            .structs
+            .iter()
+            .find(|struct_layout| struct_layout.name == "Name")
+            .unwrap();
+        let field_names = name
+            .fields
+            .iter()
+            .map(|(field_name, _)| field_name.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            field_names,
+            vec!["letters[0]", "letters[1]", "letters[2]", "label"]
+        );
+        assert_eq!(name.fields[3].1, SignatureType::new(MarshallType::Pointer));
+    }
+
+    #[test]
+    fn variadic_functions() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        let variadic = function(&signatures, "variadic");
+        assert!(variadic.is_variadic);
+        assert_eq!(argument_types(variadic), types(&[MarshallType::String]));
+        assert!(!function(&signatures, "floats").is_variadic);
+    }
+
+    #[test]
+    fn unnamed_arguments() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        let argument_names = function(&signatures, "unnamed")
+            .arguments
+            .iter()
+            .map(|(argument_name, _)| argument_name.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(argument_names, vec!["arg0", "arg1"]);
+    }
+
+    #[test]
+    fn unsupported_functions_are_skipped() {
+        let Some(signatures) = fixture_signatures() else {
+            return;
+        };
+        assert!(skipped_reason(&signatures, "unions").contains("unions are not supported"));
+        assert!(skipped_reason(&signatures, "opaque").contains("is opaque"));
+        assert!(skipped_reason(&signatures, "bit_fields").contains("bit field `is_visible`"));
+        assert_eq!(signatures.functions.len(), 11);
+    }
+}
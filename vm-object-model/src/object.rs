@@ -8,6 +8,7 @@ use std::os::raw::c_void;
 pub struct Object(ObjectHeader);
 
 impl Object {
+    const FREE_OBJECT_CLASS_INDEX_PUN: u32 = 0;
     const FORWARDED_OBJECT_CLASS_INDEX_PUN: u32 = 8;
     const SHIFT_FOR_WORD: u32 = 3;
 
@@ -67,6 +68,11 @@ impl Object {
         self.0.class_index() <= Self::FORWARDED_OBJECT_CLASS_INDEX_PUN
     }
 
+    /// Return true if the object is a free chunk of memory
+    pub fn is_free(&self) -> bool {
+        self.0.class_index() == Self::FREE_OBJECT_CLASS_INDEX_PUN
+    }
+
     pub fn is_identical(&self, second: &Object) -> Option<bool> {
         if self.is_forwarded() {
             return None;
@@ -0,0 +1,608 @@
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::os::raw::c_void;
+use std::sync::{Arc, Mutex, Once};
+use std::thread::ThreadId;
+
+use vm_bindings::{AllocationObserver, Smalltalk};
+use vm_object_model::{AnyObjectRef, Immediate, ObjectFormat, RawObjectPointer};
+
+use crate::objects::{Array, ByteString};
+use crate::{copy_stack, vm};
+
+/// The amount of literals is stored in the lower bits of a compiled method header
+const LITERAL_COUNT_MASK: i64 = 0x7FFF;
+/// How many freed tracked addresses are remembered to tell a double free from a free of an unknown pointer
+const AMOUNT_OF_REMEMBERED_FREES: usize = 4096;
+
+lazy_static! {
+    static ref ALLOCATION_TRACKER: Mutex<Option<Arc<AllocationTracker>>> = Mutex::new(None);
+}
+
+static REPORT_AT_EXIT: Once = Once::new();
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AllocationKind {
+    /// allocated with `InterpreterProxy::malloc` or `InterpreterProxy::calloc`
+    Proxy,
+    /// returned by a bare FFI function that passes the ownership of the memory to the caller
+    OwnedReturn,
+}
+
+#[derive(Debug)]
+pub struct Allocation {
+    address: usize,
+    /// unknown for memory returned by external functions
+    size: Option<usize>,
+    kind: AllocationKind,
+    backtrace: Backtrace,
+    /// methods of the Smalltalk stack of the primitive that allocated the memory,
+    /// empty if the primitive does not capture its stack or outside of the interpreter thread
+    smalltalk_stack: Vec<String>,
+}
+
+impl Allocation {
+    pub fn address(&self) -> *mut c_void {
+        self.address as *mut c_void
+    }
+
+    pub fn size(&self) -> Option<usize> {
+        self.size
+    }
+
+    pub fn kind(&self) -> AllocationKind {
+        self.kind
+    }
+
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+
+    pub fn smalltalk_stack(&self) -> &[String] {
+        self.smalltalk_stack.as_slice()
+    }
+
+    fn description(&self) -> String {
+        let size = self
+            .size
+            .map(|size| format!("{} bytes", size))
+            .unwrap_or_else(|| "unknown size".to_string());
+        format!(
+            "{:?} allocation at {:#x} of {}\nSmalltalk stack:\n\t{}\nBacktrace:\n{}",
+            self.kind,
+            self.address,
+            size,
+            self.smalltalk_stack.join("\n\t"),
+            self.backtrace
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Frees {
+    backtraces: HashMap<usize, Backtrace>,
+    order: VecDeque<usize>,
+}
+
+impl Frees {
+    fn remember(&mut self, address: usize, backtrace: Backtrace) {
+        if self.backtraces.insert(address, backtrace).is_none() {
+            self.order.push_back(address);
+        }
+        while self.order.len() > AMOUNT_OF_REMEMBERED_FREES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.backtraces.remove(&oldest);
+            }
+        }
+    }
+
+    fn forget(&mut self, address: usize) {
+        if self.backtraces.remove(&address).is_some() {
+            self.order.retain(|each| *each != address);
+        }
+    }
+}
+
+/// Records external memory allocated by the VM and bare FFI to find leaks and invalid frees.
+/// Meant for debugging, as it captures a backtrace for every allocation and free.
+#[derive(Debug)]
+pub struct AllocationTracker {
+    /// the Smalltalk stack can only be copied on the interpreter thread
+    vm_thread: ThreadId,
+    /// ordered by address to find an allocation that contains a given address
+    live_allocations: Mutex<BTreeMap<usize, Allocation>>,
+    frees: Mutex<Frees>,
+    /// methods of the Smalltalk stack of the running primitive, see [`AllocationTracker::enter_primitive`]
+    primitive_stack: Mutex<Option<Vec<String>>>,
+}
+
+/// Attributes allocations to the Smalltalk stack of a primitive until dropped
+#[must_use]
+pub struct PrimitiveStackGuard {
+    tracker: Option<Arc<AllocationTracker>>,
+    /// the stack of an outer primitive, in case a callback runs another one
+    previous_stack: Option<Vec<String>>,
+}
+
+impl Drop for PrimitiveStackGuard {
+    fn drop(&mut self) {
+        if let Some(tracker) = self.tracker.as_ref() {
+            *tracker.primitive_stack.lock().unwrap() = self.previous_stack.take();
+        }
+    }
+}
+
+impl AllocationTracker {
+    fn new() -> Self {
+        Self {
+            vm_thread: std::thread::current().id(),
+            live_allocations: Default::default(),
+            frees: Default::default(),
+            primitive_stack: Default::default(),
+        }
+    }
+
+    /// Copy the Smalltalk stack to attribute the allocations of a primitive to it.
+    /// Must be called on the interpreter thread when the primitive starts, before it holds
+    /// any objects, because copying the stack creates contexts which may trigger a garbage collection.
+    /// Does nothing unless allocations are tracked
+    pub fn enter_primitive() -> PrimitiveStackGuard {
+        let Some(tracker) = Self::current() else {
+            return PrimitiveStackGuard {
+                tracker: None,
+                previous_stack: None,
+            };
+        };
+
+        let stack = copy_stack(Smalltalk::this_context())
+            .iter()
+            .map(|each| method_description(*each))
+            .collect();
+        let previous_stack = tracker.primitive_stack.lock().unwrap().replace(stack);
+        PrimitiveStackGuard {
+            tracker: Some(tracker),
+            previous_stack,
+        }
+    }
+
+    /// Start tracking allocations, must be called from the interpreter thread.
+    /// Live allocations are reported when the process exits
+    pub fn start() {
+        let mut current = ALLOCATION_TRACKER.lock().unwrap();
+        if current.is_some() {
+            return;
+        }
+        let tracker = Arc::new(Self::new());
+        vm().proxy().set_allocation_observer(Some(tracker.clone()));
+        *current = Some(tracker);
+
+        REPORT_AT_EXIT.call_once(|| unsafe {
+            libc::atexit(report_live_allocations_at_exit);
+        });
+    }
+
+    /// Stop tracking allocations and forget the recorded ones
+    pub fn stop() {
+        if ALLOCATION_TRACKER.lock().unwrap().take().is_some() {
+            vm().proxy().set_allocation_observer(None);
+        }
+    }
+
+    pub fn current() -> Option<Arc<AllocationTracker>> {
+        ALLOCATION_TRACKER.lock().unwrap().clone()
+    }
+
+    /// Record memory returned by an external function that the caller is responsible for freeing
+    pub fn record_owned_return(address: *mut c_void) {
+        if address.is_null() {
+            return;
+        }
+        if let Some(tracker) = Self::current() {
+            tracker.record(address, None, AllocationKind::OwnedReturn);
+        }
+    }
+
+    /// Record that memory returned by an external function is about to be freed
+    /// by a deallocator of its library. Returns false if it must not be freed, because it is
+    /// already freed. Unlike [`AllocationTracker::record_free`] memory that was never recorded
+    /// is not logged, since only the returns of functions marked as owned are recorded
+    pub fn record_deallocation(address: *mut c_void) -> bool {
+        Self::current()
+            .map(|tracker| tracker.record_free_of(address, false))
+            .unwrap_or(true)
+    }
+
+    /// Record that memory is freed. Returns false and reports an error only if the memory
+    /// was recorded as allocated and is already freed. Memory that was never recorded,
+    /// for example allocated before tracking started or by an allocator of a library
+    /// that reused a freed address, is logged and may be freed
+    pub fn record_free(&self, address: *mut c_void) -> bool {
+        self.record_free_of(address, true)
+    }
+
+    fn record_free_of(&self, address: *mut c_void, should_log_unknown: bool) -> bool {
+        if address.is_null() {
+            return true;
+        }
+        let address = address as usize;
+        let backtrace = Backtrace::force_capture();
+        let is_live = self
+            .live_allocations
+            .lock()
+            .unwrap()
+            .remove(&address)
+            .is_some();
+
+        let mut frees = self.frees.lock().unwrap();
+        if is_live {
+            // only the frees of recorded allocations tell a double free
+            frees.remember(address, backtrace);
+            return true;
+        }
+
+        if let Some(first_free) = frees.backtraces.get(&address) {
+            error!(
+                "Double free of {:#x}\nBacktrace:\n{}\nFirst freed at:\n{}",
+                address, backtrace, first_free
+            );
+            return false;
+        }
+        if should_log_unknown {
+            warn!(
+                "Free of an unknown pointer {:#x}\nBacktrace:\n{}",
+                address, backtrace
+            );
+        }
+        true
+    }
+
+    /// Evaluate a block with the allocations that are not freed yet, ordered by address
+    pub fn with_live_allocations<T>(&self, block: impl FnOnce(&[&Allocation]) -> T) -> T {
+        let live_allocations = self.live_allocations.lock().unwrap();
+        let allocations: Vec<&Allocation> = live_allocations.values().collect();
+        block(allocations.as_slice())
+    }
+
+    /// Return the start address and the size of a live allocation of a known size
+    /// that overlaps a range of addresses without containing all of it
+    pub fn allocation_overlapping(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        let live_allocations = self.live_allocations.lock().unwrap();
+        for (allocation_start, allocation) in live_allocations.range(..end).rev() {
+            let allocation_start = *allocation_start;
+            let allocation_end = allocation
+                .size
+                .filter(|size| *size > 0)
+                .map(|size| allocation_start + size);
+            if let Some(allocation_end) = allocation_end {
+                if allocation_start <= start && end <= allocation_end {
+                    return None;
+                }
+                if allocation_end > start {
+                    return Some((allocation_start, allocation_end - allocation_start));
+                }
+            }
+            // allocations do not overlap, so the ones before the range start end before it
+            if allocation_start <= start {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Log every allocation that is not freed yet, returning their amount
+    pub fn report_live_allocations(&self) -> usize {
+        let live_allocations = self.live_allocations.lock().unwrap();
+        for allocation in live_allocations.values() {
+            error!("Leaked {}", allocation.description());
+        }
+        live_allocations.len()
+    }
+
+    fn record(&self, address: *mut c_void, size: Option<usize>, kind: AllocationKind) {
+        // the stack is captured when a primitive starts, copying it here could move
+        // the objects held by the primitive that allocates
+        let smalltalk_stack = if std::thread::current().id() == self.vm_thread {
+            self.primitive_stack
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or_default()
+        } else {
+            vec![]
+        };
+
+        let allocation = Allocation {
+            address: address as usize,
+            size,
+            kind,
+            backtrace: Backtrace::force_capture(),
+            smalltalk_stack,
+        };
+
+        // the address may be reused by the allocator once freed
+        self.frees.lock().unwrap().forget(allocation.address);
+        self.live_allocations
+            .lock()
+            .unwrap()
+            .insert(allocation.address, allocation);
+    }
+}
+
+impl AllocationObserver for AllocationTracker {
+    fn allocated(&self, address: *mut c_void, size: usize) {
+        self.record(address, Some(size), AllocationKind::Proxy);
+    }
+
+    fn should_free(&self, address: *mut c_void) -> bool {
+        self.record_free(address)
+    }
+}
+
+extern "C" fn report_live_allocations_at_exit() {
+    if let Some(tracker) = AllocationTracker::current() {
+        let amount_of_leaks = tracker.report_live_allocations();
+        if amount_of_leaks > 0 {
+            error!("{} external allocations were not freed", amount_of_leaks);
+        }
+    }
+}
+
+/// Describe a compiled method as `Class>>selector` and a compiled block as `[] in Class>>selector`
+fn method_description(method: AnyObjectRef) -> String {
+    let Ok(method) = method.as_object() else {
+        return format!("{:?}", method);
+    };
+    let amount_of_literals = method
+        .inst_var_at(0)
+        .and_then(|header| header.as_immediate().ok())
+        .and_then(|header| header.as_integer())
+        .map(|header| (header & LITERAL_COUNT_MASK) as usize);
+    let Some(amount_of_literals) = amount_of_literals else {
+        return "<unknown method>".to_string();
+    };
+
+    // the last literal of a method is its class binding, of a block - the outer code
+    let Some(last_literal) = method
+        .inst_var_at(amount_of_literals)
+        .and_then(|literal| literal.as_object().ok())
+    else {
+        return "<unknown method>".to_string();
+    };
+    if matches!(
+        last_literal.object_format(),
+        ObjectFormat::CompiledMethod(_)
+    ) {
+        return format!("[] in {}", method_description(last_literal.into()));
+    }
+
+    let class_name = last_literal
+        .inst_var_at(1)
+        .and_then(|class| class.as_object().ok())
+        .map(Smalltalk::class_name)
+        .unwrap_or_else(|| "<unknown class>".to_string());
+
+    // the selector is either a symbol or a part of additional method state
+    let selector = method
+        .inst_var_at(amount_of_literals - 1)
+        .and_then(|literal| {
+            Smalltalk::symbol_string(literal).or_else(|| {
+                literal
+                    .as_object()
+                    .ok()
+                    .and_then(|state| state.inst_var_at(1))
+                    .and_then(Smalltalk::symbol_string)
+            })
+        })
+        .unwrap_or_else(|| "<unknown selector>".to_string());
+
+    format!("{}>>{}", class_name, selector)
+}
+
+/// Start tracking external allocations
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveAllocationTrackerStart() {
+    AllocationTracker::start();
+    Smalltalk::method_return_boolean(true);
+}
+
+/// Stop tracking external allocations
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveAllocationTrackerStop() {
+    AllocationTracker::stop();
+    Smalltalk::method_return_boolean(true);
+}
+
+/// Answer an array of `{ address . size or nil . backtrace . smalltalk stack }`
+/// of allocations that are not freed yet, or nil if allocations are not tracked
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveAllocationTrackerLiveAllocations() -> Result<(), vm_object_model::Error> {
+    let Some(tracker) = AllocationTracker::current() else {
+        Smalltalk::method_return(Smalltalk::nil_object());
+        return Ok(());
+    };
+
+    let array = tracker.with_live_allocations(|allocations| {
+        let mut array = Array::new(allocations.len())?;
+        for (index, allocation) in allocations.iter().enumerate() {
+            let mut stack = Array::new(allocation.smalltalk_stack().len())?;
+            for (method_index, method) in allocation.smalltalk_stack().iter().enumerate() {
+                stack.insert(method_index, ByteString::new(method.as_bytes())?);
+            }
+
+            let size = allocation
+                .size()
+                .map(|size| AnyObjectRef::from(Immediate::new_i64(size as i64)))
+                .unwrap_or_else(Smalltalk::nil_object);
+            let backtrace = allocation.backtrace().to_string();
+
+            let mut description = Array::new(4)?;
+            description.insert(
+                0,
+                AnyObjectRef::from(RawObjectPointer::from(
+                    Smalltalk::new_external_address(allocation.address()).as_i64(),
+                )),
+            );
+            description.insert(1, size);
+            description.insert(2, ByteString::new(backtrace.as_bytes())?);
+            description.insert(3, stack);
+            array.insert(index, description);
+        }
+        Ok::<_, vm_object_model::Error>(array)
+    })?;
+
+    Smalltalk::method_return(array);
+    Ok(())
+}
+
+/// Log the allocations that are not freed yet and answer their amount
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveAllocationTrackerReport() {
+    let amount_of_live_allocations = AllocationTracker::current()
+        .map(|tracker| tracker.report_live_allocations())
+        .unwrap_or(0);
+    Smalltalk::method_return_integer(amount_of_live_allocations as i64);
+}
+
+/// Record that the image is about to free memory returned by an external function.
+/// Answers false if the memory must not be freed, because it is already freed
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveAllocationTrackerFree() {
+    let Ok(address) = Smalltalk::get_method_argument(0).as_object() else {
+        return Smalltalk::primitive_fail();
+    };
+    let address = Smalltalk::read_external_address(address);
+
+    let should_free = AllocationTracker::current()
+        .map(|tracker| tracker.record_free(address))
+        .unwrap_or(true);
+    Smalltalk::method_return_boolean(should_free);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker_with_allocations(allocations: &[(usize, Option<usize>)]) -> AllocationTracker {
+        let tracker = AllocationTracker::new();
+        for (address, size) in allocations {
+            tracker.live_allocations.lock().unwrap().insert(
+                *address,
+                Allocation {
+                    address: *address,
+                    size: *size,
+                    kind: AllocationKind::Proxy,
+                    backtrace: Backtrace::disabled(),
+                    smalltalk_stack: vec![],
+                },
+            );
+        }
+        tracker
+    }
+
+    #[test]
+    fn range_within_allocation_does_not_overlap() {
+        let tracker = tracker_with_allocations(&[(0x1000, Some(16))]);
+        assert_eq!(tracker.allocation_overlapping(0x1000, 0x1010), None);
+        assert_eq!(tracker.allocation_overlapping(0x1008, 0x1010), None);
+    }
+
+    #[test]
+    fn range_crossing_allocation_end_overlaps() {
+        let tracker = tracker_with_allocations(&[(0x1000, Some(16))]);
+        assert_eq!(
+            tracker.allocation_overlapping(0x1008, 0x1011),
+            Some((0x1000, 16))
+        );
+    }
+
+    #[test]
+    fn range_crossing_allocation_start_overlaps() {
+        let tracker = tracker_with_allocations(&[(0x1000, Some(16))]);
+        assert_eq!(
+            tracker.allocation_overlapping(0xff8, 0x1004),
+            Some((0x1000, 16))
+        );
+        assert_eq!(
+            tracker.allocation_overlapping(0xff0, 0x1020),
+            Some((0x1000, 16))
+        );
+    }
+
+    #[test]
+    fn range_outside_allocations_does_not_overlap() {
+        let tracker = tracker_with_allocations(&[(0x1000, Some(16)), (0x2000, Some(16))]);
+        assert_eq!(tracker.allocation_overlapping(0xff0, 0x1000), None);
+        assert_eq!(tracker.allocation_overlapping(0x1010, 0x2000), None);
+        assert_eq!(tracker.allocation_overlapping(0x3000, 0x3010), None);
+    }
+
+    #[test]
+    fn range_spanning_allocations_overlaps() {
+        let tracker = tracker_with_allocations(&[(0x1000, Some(16)), (0x1010, Some(16))]);
+        assert_eq!(
+            tracker.allocation_overlapping(0x1008, 0x1018),
+            Some((0x1010, 16))
+        );
+    }
+
+    #[test]
+    fn allocations_of_unknown_size_are_ignored() {
+        let tracker = tracker_with_allocations(&[(0x1000, None), (0x1008, None)]);
+        assert_eq!(tracker.allocation_overlapping(0xff8, 0x1100), None);
+    }
+
+    #[test]
+    fn free_of_unknown_pointer_is_allowed() {
+        let tracker = AllocationTracker::new();
+        assert!(tracker.record_free(0x1000 as *mut c_void));
+    }
+
+    #[test]
+    fn free_of_null_is_allowed() {
+        let tracker = AllocationTracker::new();
+        assert!(tracker.record_free(std::ptr::null_mut()));
+        assert!(tracker.record_free(std::ptr::null_mut()));
+    }
+
+    #[test]
+    fn double_free_is_rejected() {
+        let tracker = tracker_with_allocations(&[(0x1000, Some(16)), (0x2000, Some(16))]);
+        assert!(tracker.record_free(0x1000 as *mut c_void));
+        assert!(!tracker.record_free(0x1000 as *mut c_void));
+        assert!(tracker.record_free(0x2000 as *mut c_void));
+    }
+
+    #[test]
+    fn repeated_free_of_unknown_pointer_is_allowed() {
+        let tracker = AllocationTracker::new();
+        assert!(tracker.record_free(0x1000 as *mut c_void));
+        assert!(tracker.record_free(0x1000 as *mut c_void));
+    }
+
+    #[test]
+    fn forgotten_frees_are_not_double_frees() {
+        let allocations = (1..=AMOUNT_OF_REMEMBERED_FREES + 1)
+            .map(|address| (address * 16, Some(16)))
+            .collect::<Vec<_>>();
+        let tracker = tracker_with_allocations(&allocations);
+        for (address, _) in &allocations {
+            assert!(tracker.record_free(*address as *mut c_void));
+        }
+        let last_free = (AMOUNT_OF_REMEMBERED_FREES + 1) * 16;
+        assert!(!tracker.record_free(last_free as *mut c_void));
+        assert!(tracker.record_free(16 as *mut c_void));
+    }
+
+    #[test]
+    fn deallocated_owned_return_is_not_live() {
+        let tracker = tracker_with_allocations(&[(0x1000, None)]);
+        assert!(tracker.record_free_of(0x1000 as *mut c_void, false));
+        assert_eq!(tracker.with_live_allocations(|each| each.len()), 0);
+        assert!(!tracker.record_free_of(0x1000 as *mut c_void, false));
+    }
+}
@@ -1,6 +1,6 @@
 use std::ffi::OsString;
 use std::path::PathBuf;
-use std::sync::mpsc::{RecvError, TryRecvError};
+use std::sync::mpsc::{RecvError, RecvTimeoutError, TryRecvError};
 use thiserror::Error;
 
 pub type Result<T> = core::result::Result<T, ApplicationError>;
@@ -38,8 +38,12 @@ pub enum ApplicationError {
     EventLoopReceiverError(#[from] RecvError),
     #[error("Failed to receive an event loop message")]
     EventLoopTryReceiverError(#[from] TryRecvError),
+    #[error("Failed to receive an event loop message")]
+    EventLoopReceiverTimeoutError(#[from] RecvTimeoutError),
     #[error("Failed to join a thread")]
     JoinHandleError,
+    #[error("Invalid FFI policy: {0}")]
+    InvalidFfiPolicy(String),
     #[error("unknown data store error")]
     Unknown,
 }
@@ -1,37 +1,228 @@
+use std::cell::RefCell;
 use std::fmt::{Debug, Formatter};
 use std::intrinsics::transmute;
 use std::os::raw::c_void;
-use std::sync::mpsc::{channel, Receiver, RecvError, Sender, TryRecvError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{
+    channel, Receiver, RecvError, RecvTimeoutError, SendError, Sender, TryRecvError,
+};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use crate::{ApplicationError, Result};
+use crate::{ApplicationError, EventLoopMetrics, Result};
 
 #[derive(Debug, Clone)]
 pub enum EventLoopMessage {
     Terminate,
     #[cfg(feature = "ffi")]
     Call(Arc<Mutex<crate::EventLoopCallout>>),
+    #[cfg(feature = "ffi")]
+    CallBatch(Arc<Mutex<crate::EventLoopCalloutBatch>>),
     WakeUp,
+    ScheduleTimer(EventLoopTimer),
+    CancelTimer(EventLoopTimerId),
+}
+
+impl EventLoopMessage {
+    /// The name of the message kind the event loop statistics are grouped by
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EventLoopMessage::Terminate => "Terminate",
+            #[cfg(feature = "ffi")]
+            EventLoopMessage::Call(_) => "Call",
+            #[cfg(feature = "ffi")]
+            EventLoopMessage::CallBatch(_) => "CallBatch",
+            EventLoopMessage::WakeUp => "WakeUp",
+            EventLoopMessage::ScheduleTimer(_) => "ScheduleTimer",
+            EventLoopMessage::CancelTimer(_) => "CancelTimer",
+        }
+    }
+}
+
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct EventLoopTimerId(u64);
+
+impl EventLoopTimerId {
+    fn next() -> Self {
+        Self(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for EventLoopTimerId {
+    fn from(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+/// Messages that a timer may process, possibly many times if it repeats.
+/// Callouts are not among them, as their result is read and released by the image after one call,
+/// see [`EventLoopTimer::call_once`] instead
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EventLoopTimerMessage {
+    Terminate,
+    WakeUp,
+    CancelTimer(EventLoopTimerId),
+}
+
+impl From<EventLoopTimerMessage> for EventLoopMessage {
+    fn from(message: EventLoopTimerMessage) -> Self {
+        match message {
+            EventLoopTimerMessage::Terminate => EventLoopMessage::Terminate,
+            EventLoopTimerMessage::WakeUp => EventLoopMessage::WakeUp,
+            EventLoopTimerMessage::CancelTimer(id) => EventLoopMessage::CancelTimer(id),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum EventLoopTimerAction {
+    /// Process a message on the event loop thread
+    Message(EventLoopTimerMessage),
+    /// Signal a semaphore given by its index in the external objects array
+    SignalSemaphore(usize),
+}
+
+/// What a timer does when it is due, only actions may be repeated
+#[derive(Debug, Clone)]
+enum EventLoopTimerTask {
+    Action(EventLoopTimerAction),
+    #[cfg(feature = "ffi")]
+    Call(Arc<Mutex<crate::EventLoopCallout>>),
+}
+
+/// An action performed by the event loop after a delay, and then repeatedly at an interval if given
+#[derive(Debug, Clone)]
+pub struct EventLoopTimer {
+    id: EventLoopTimerId,
+    delay: Duration,
+    interval: Option<Duration>,
+    task: EventLoopTimerTask,
+}
+
+impl EventLoopTimer {
+    /// The shortest interval of a repeating timer, a zero interval would keep the event loop busy
+    pub const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+    pub fn once(delay: Duration, action: EventLoopTimerAction) -> Self {
+        Self {
+            id: EventLoopTimerId::next(),
+            delay,
+            interval: None,
+            task: EventLoopTimerTask::Action(action),
+        }
+    }
+
+    /// An interval shorter than [`EventLoopTimer::MIN_INTERVAL`] is clamped to it
+    pub fn repeating(delay: Duration, interval: Duration, action: EventLoopTimerAction) -> Self {
+        Self {
+            id: EventLoopTimerId::next(),
+            delay,
+            interval: Some(interval.max(Self::MIN_INTERVAL)),
+            task: EventLoopTimerTask::Action(action),
+        }
+    }
+
+    /// Perform a callout once after a delay, as if it was sent to the event loop at that moment.
+    /// If the timer is cancelled before, the callout is not performed and its callback is not called
+    #[cfg(feature = "ffi")]
+    pub fn call_once(delay: Duration, callout: Arc<Mutex<crate::EventLoopCallout>>) -> Self {
+        Self {
+            id: EventLoopTimerId::next(),
+            delay,
+            interval: None,
+            task: EventLoopTimerTask::Call(callout),
+        }
+    }
+
+    /// The id to cancel the timer with, known before the timer is scheduled
+    pub fn id(&self) -> EventLoopTimerId {
+        self.id
+    }
+}
+
+/// A message together with the moment it was sent, to measure how long it waited in the queue
+#[derive(Debug)]
+struct EnqueuedMessage {
+    message: EventLoopMessage,
+    enqueued_at: Instant,
+}
+
+/// Sends messages to an event loop, counting them in the metrics of the event loop
+#[derive(Debug, Clone)]
+pub struct EventLoopSender {
+    sender: Sender<EnqueuedMessage>,
+    metrics: Arc<EventLoopMetrics>,
+}
+
+impl EventLoopSender {
+    pub fn send(
+        &self,
+        message: EventLoopMessage,
+    ) -> std::result::Result<(), SendError<EventLoopMessage>> {
+        self.metrics.message_enqueued();
+        self.sender
+            .send(EnqueuedMessage {
+                message,
+                enqueued_at: Instant::now(),
+            })
+            .map_err(|error| {
+                self.metrics.message_dropped();
+                SendError(error.0.message)
+            })
+    }
+}
+
+#[derive(Debug)]
+struct ScheduledTimer {
+    timer: EventLoopTimer,
+    deadline: Instant,
 }
 
 #[derive(Debug)]
 pub struct EventLoop {
-    receiver: Receiver<EventLoopMessage>,
+    receiver: Receiver<EnqueuedMessage>,
+    timers: RefCell<Vec<ScheduledTimer>>,
+    metrics: Arc<EventLoopMetrics>,
 }
 
 impl EventLoop {
-    pub fn new() -> (Self, Sender<EventLoopMessage>) {
-        let (sender, receiver) = channel::<EventLoopMessage>();
-        let event_loop = Self { receiver };
+    pub fn new() -> (Self, EventLoopSender) {
+        let (sender, receiver) = channel::<EnqueuedMessage>();
+        let metrics = Arc::new(EventLoopMetrics::default());
+        let event_loop = Self {
+            receiver,
+            timers: RefCell::new(vec![]),
+            metrics: metrics.clone(),
+        };
 
-        (event_loop, sender)
+        (event_loop, EventLoopSender { sender, metrics })
     }
 
     pub fn run(&self) -> Result<()> {
         loop {
-            match self.receiver.recv() {
-                Ok(message) => match self.process_message(message) {
+            let message = match self.time_until_next_timer() {
+                None => self.receiver.recv().map_err(|error| error.into()),
+                Some(timeout) => match self.receiver.recv_timeout(timeout) {
+                    Ok(message) => Ok(message),
+                    Err(RecvTimeoutError::Timeout) => {
+                        if !self.fire_due_timers()? {
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(error) => Err(error.into()),
+                },
+            };
+
+            match message {
+                Ok(message) => match self.receive_message(message) {
                     Ok(should_continue) => {
                         if !should_continue {
                             break;
@@ -39,7 +230,11 @@ impl EventLoop {
                     }
                     Err(error) => return Err(error),
                 },
-                Err(error) => return Err(error.into()),
+                Err(error) => return Err(error),
+            }
+
+            if !self.fire_due_timers()? {
+                break;
             }
         }
         Ok(())
@@ -49,8 +244,8 @@ impl EventLoop {
         loop {
             match self.receiver.try_recv() {
                 Ok(message) => {
-                    trace!("Received {:?}", &message);
-                    match self.process_message(message) {
+                    trace!("Received {:?}", &message.message);
+                    match self.receive_message(message) {
                         Ok(should_continue) => {
                             if !should_continue {
                                 break;
@@ -64,7 +259,7 @@ impl EventLoop {
                 }
                 Err(error) => {
                     return match error {
-                        TryRecvError::Empty => Ok(()),
+                        TryRecvError::Empty => self.fire_due_timers().map(|_| ()),
                         TryRecvError::Disconnected => Err(error.into()),
                     };
                 }
@@ -73,7 +268,101 @@ impl EventLoop {
         Ok(())
     }
 
+    /// How long until the next timer is due, or None if there are no timers.
+    /// Hosts that drive the event loop with `try_recv` must call it again within that time
+    pub fn time_until_next_timer(&self) -> Option<Duration> {
+        self.timers
+            .borrow()
+            .iter()
+            .map(|scheduled| scheduled.deadline)
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn schedule_timer(&self, timer: EventLoopTimer) {
+        let deadline = Instant::now() + timer.delay;
+        self.timers
+            .borrow_mut()
+            .push(ScheduledTimer { timer, deadline });
+    }
+
+    fn cancel_timer(&self, id: EventLoopTimerId) {
+        self.timers
+            .borrow_mut()
+            .retain(|scheduled| scheduled.timer.id != id);
+    }
+
+    /// Perform the actions of the timers that are due, rescheduling the repeating ones.
+    /// Answers false if one of the actions terminated the event loop
+    fn fire_due_timers(&self) -> Result<bool> {
+        let now = Instant::now();
+
+        let due_timers = {
+            let mut timers = self.timers.borrow_mut();
+            let (mut due_timers, pending_timers): (Vec<ScheduledTimer>, Vec<ScheduledTimer>) =
+                timers
+                    .drain(..)
+                    .partition(|scheduled| scheduled.deadline <= now);
+            *timers = pending_timers;
+            due_timers.sort_by_key(|scheduled| scheduled.deadline);
+            due_timers
+        };
+
+        for mut scheduled in due_timers {
+            // the action may schedule or cancel timers, so the timers must not be borrowed
+            let should_continue = match &scheduled.timer.task {
+                EventLoopTimerTask::Action(EventLoopTimerAction::Message(message)) => {
+                    self.process_message((*message).into())?
+                }
+                EventLoopTimerTask::Action(EventLoopTimerAction::SignalSemaphore(
+                    semaphore_index,
+                )) => {
+                    crate::vm().proxy().signal_semaphore(*semaphore_index);
+                    true
+                }
+                #[cfg(feature = "ffi")]
+                EventLoopTimerTask::Call(callout) => {
+                    self.process_message(EventLoopMessage::Call(callout.clone()))?
+                }
+            };
+            if !should_continue {
+                return Ok(false);
+            }
+
+            if let Some(interval) = scheduled.timer.interval {
+                // keep a fixed rate, unless the event loop fell behind by more than an interval
+                let next_deadline = scheduled.deadline + interval;
+                scheduled.deadline = if next_deadline < now {
+                    now + interval
+                } else {
+                    next_deadline
+                };
+                self.timers.borrow_mut().push(scheduled);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn metrics(&self) -> &EventLoopMetrics {
+        &self.metrics
+    }
+
+    /// Process a message that was waiting in the queue
+    fn receive_message(&self, message: EnqueuedMessage) -> Result<bool> {
+        self.metrics
+            .message_received(message.message.kind(), message.enqueued_at);
+        self.process_message(message.message)
+    }
+
     fn process_message(&self, message: EventLoopMessage) -> Result<bool> {
+        let kind = message.kind();
+        let started_at = Instant::now();
+        let should_continue = self.perform_message(message)?;
+        self.metrics.message_processed(kind, started_at.elapsed());
+        Ok(should_continue)
+    }
+
+    fn perform_message(&self, message: EventLoopMessage) -> Result<bool> {
         match message {
             EventLoopMessage::Terminate => {
                 return Ok(false);
@@ -81,11 +370,36 @@ impl EventLoop {
             EventLoopMessage::WakeUp => {
                 // wake up!
             }
+            EventLoopMessage::ScheduleTimer(timer) => self.schedule_timer(timer),
+            EventLoopMessage::CancelTimer(id) => self.cancel_timer(id),
             #[cfg(feature = "ffi")]
-            EventLoopMessage::Call(callout) => callout.lock().unwrap().call(),
+            EventLoopMessage::Call(callout) => self.perform_callout(&mut callout.lock().unwrap()),
+            #[cfg(feature = "ffi")]
+            EventLoopMessage::CallBatch(batch) => batch
+                .lock()
+                .unwrap()
+                .call_each(|callout| self.perform_callout(callout)),
         }
         Ok(true)
     }
+
+    #[cfg(feature = "ffi")]
+    fn perform_callout(&self, callout: &mut crate::EventLoopCallout) {
+        let started_at = Instant::now();
+        callout.call();
+        let duration = started_at.elapsed();
+
+        let name_of = |name: &Option<std::ffi::CString>| {
+            name.as_ref()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        self.metrics.callout_performed(
+            &name_of(&callout.function_name),
+            &name_of(&callout.module_name),
+            duration,
+        );
+    }
 }
 
 #[derive(Debug)]
@@ -106,3 +420,141 @@ impl EventLoopWaker {
         (self.waker)(self.waker_thunk, 0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sent_messages_are_counted_until_received() {
+        let (event_loop, sender) = EventLoop::new();
+        sender.send(EventLoopMessage::WakeUp).unwrap();
+        sender.send(EventLoopMessage::WakeUp).unwrap();
+        assert_eq!(event_loop.metrics().queue_depth(), 2);
+
+        event_loop.try_recv().unwrap();
+        assert_eq!(event_loop.metrics().queue_depth(), 0);
+        assert_eq!(event_loop.metrics().max_queue_depth(), 2);
+        event_loop.metrics().with_message_statistics(|messages| {
+            assert_eq!(messages["WakeUp"].wait.count(), 2);
+            assert_eq!(messages["WakeUp"].execution.count(), 2);
+        });
+    }
+
+    #[test]
+    fn messages_sent_to_a_dropped_event_loop_are_not_counted() {
+        let (event_loop, sender) = EventLoop::new();
+        let metrics = sender.metrics.clone();
+        drop(event_loop);
+
+        assert!(sender.send(EventLoopMessage::WakeUp).is_err());
+        assert_eq!(metrics.queue_depth(), 0);
+        assert_eq!(metrics.max_queue_depth(), 1);
+    }
+
+    #[test]
+    fn timer_messages() {
+        let id = EventLoopTimerId::from(42);
+        assert!(matches!(
+            EventLoopMessage::from(EventLoopTimerMessage::CancelTimer(id)),
+            EventLoopMessage::CancelTimer(cancelled) if cancelled == id
+        ));
+        assert!(matches!(
+            EventLoopMessage::from(EventLoopTimerMessage::WakeUp),
+            EventLoopMessage::WakeUp
+        ));
+    }
+
+    #[test]
+    fn repeating_timer_messages_are_processed_each_time() {
+        let (event_loop, _sender) = EventLoop::new();
+        event_loop.schedule_timer(EventLoopTimer::repeating(
+            Duration::ZERO,
+            EventLoopTimer::MIN_INTERVAL,
+            EventLoopTimerAction::Message(EventLoopTimerMessage::WakeUp),
+        ));
+
+        assert!(event_loop.fire_due_timers().unwrap());
+        std::thread::sleep(EventLoopTimer::MIN_INTERVAL * 2);
+        assert!(event_loop.fire_due_timers().unwrap());
+        event_loop.metrics().with_message_statistics(|messages| {
+            assert_eq!(messages["WakeUp"].execution.count(), 2);
+        });
+    }
+
+    #[test]
+    fn zero_interval_is_clamped() {
+        let timer = EventLoopTimer::repeating(
+            Duration::ZERO,
+            Duration::ZERO,
+            EventLoopTimerAction::Message(EventLoopTimerMessage::WakeUp),
+        );
+        assert_eq!(timer.interval, Some(EventLoopTimer::MIN_INTERVAL));
+    }
+
+    #[test]
+    fn terminating_timer_stops_the_event_loop() {
+        let (event_loop, _sender) = EventLoop::new();
+        event_loop.schedule_timer(EventLoopTimer::once(
+            Duration::ZERO,
+            EventLoopTimerAction::Message(EventLoopTimerMessage::Terminate),
+        ));
+
+        assert!(!event_loop.fire_due_timers().unwrap());
+        assert_eq!(event_loop.time_until_next_timer(), None);
+    }
+
+    #[cfg(feature = "ffi")]
+    extern "C" fn do_nothing() {}
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn timer_callout_is_performed_once() {
+        use std::sync::atomic::AtomicBool;
+
+        let cif = libffi::middle::Cif::new(vec![], libffi::middle::Type::void());
+        let is_called = Arc::new(AtomicBool::new(false));
+        let callback_is_called = is_called.clone();
+        let callout = crate::EventLoopCallout {
+            function_name: None,
+            module_name: None,
+            cif: cif.as_raw_ptr(),
+            func: libffi::low::CodePtr(do_nothing as *mut c_void),
+            args: None,
+            result: None,
+            callback: Some(Box::new(move || {
+                callback_is_called.store(true, Ordering::SeqCst)
+            })),
+            should_capture_errno: false,
+            errno: None,
+        };
+
+        let (event_loop, _sender) = EventLoop::new();
+        event_loop.schedule_timer(EventLoopTimer::call_once(
+            Duration::ZERO,
+            Arc::new(Mutex::new(callout)),
+        ));
+
+        assert!(event_loop.fire_due_timers().unwrap());
+        assert!(is_called.load(Ordering::SeqCst));
+        assert_eq!(event_loop.time_until_next_timer(), None);
+        event_loop.metrics().with_message_statistics(|messages| {
+            assert_eq!(messages["Call"].execution.count(), 1);
+        });
+    }
+
+    #[test]
+    fn cancelled_timer_is_not_fired() {
+        let (event_loop, _sender) = EventLoop::new();
+        let timer = EventLoopTimer::once(
+            Duration::ZERO,
+            EventLoopTimerAction::Message(EventLoopTimerMessage::Terminate),
+        );
+        let id = timer.id();
+        event_loop.schedule_timer(timer);
+        event_loop.cancel_timer(id);
+
+        assert!(event_loop.fire_due_timers().unwrap());
+        assert_eq!(event_loop.time_until_next_timer(), None);
+    }
+}
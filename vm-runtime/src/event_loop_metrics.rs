@@ -0,0 +1,499 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use vm_bindings::{Smalltalk, StackOffset};
+use vm_object_model::{AnyObjectRef, Immediate, Result};
+
+use crate::objects::{Array, ArrayRef, ByteString};
+use crate::{vm, LogSignal, VM_LOGGER};
+
+/// The type of log signals reporting slow callouts and event loop statistics
+pub const EVENT_LOOP_LOG_TYPE: &str = "EVENT_LOOP";
+
+/// Callouts taking longer than a frame are considered slow by default
+const DEFAULT_SLOW_CALLOUT_THRESHOLD: Duration = Duration::from_millis(16);
+/// The amount of most recent durations the rolling statistics are computed from
+const RECENT_DURATIONS_WINDOW: usize = 128;
+/// The amount of most recent slow callouts that are kept
+const SLOW_CALLOUTS_LOG_SIZE: usize = 256;
+
+/// Totals over all recorded durations and rolling statistics over the most recent ones
+#[derive(Debug, Default, Clone)]
+pub struct DurationStatistics {
+    count: u64,
+    total: Duration,
+    max: Duration,
+    recent: VecDeque<Duration>,
+}
+
+impl DurationStatistics {
+    pub fn record(&mut self, duration: Duration) {
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+
+        if self.recent.len() == RECENT_DURATIONS_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(duration);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::ZERO
+        } else {
+            self.total.div_f64(self.count as f64)
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn recent_mean(&self) -> Duration {
+        if self.recent.is_empty() {
+            Duration::ZERO
+        } else {
+            self.recent.iter().sum::<Duration>() / self.recent.len() as u32
+        }
+    }
+
+    pub fn recent_max(&self) -> Duration {
+        self.recent.iter().max().copied().unwrap_or_default()
+    }
+
+    fn as_array(&self) -> Result<ArrayRef> {
+        let mut array = Array::new(5)?;
+        array.insert(0, integer(self.count as i64));
+        array.insert(1, microseconds(self.mean()));
+        array.insert(2, microseconds(self.max()));
+        array.insert(3, microseconds(self.recent_mean()));
+        array.insert(4, microseconds(self.recent_max()));
+        Ok(array)
+    }
+}
+
+/// How long messages of one kind wait in the queue and how long they take to process
+#[derive(Debug, Default, Clone)]
+pub struct MessageStatistics {
+    pub wait: DurationStatistics,
+    pub execution: DurationStatistics,
+}
+
+#[derive(Debug, Clone)]
+pub struct SlowCallout {
+    pub function_name: String,
+    pub module_name: String,
+    pub duration: Duration,
+    pub timestamp: SystemTime,
+}
+
+#[derive(Debug)]
+struct EventLoopMetricsState {
+    queue_depth: usize,
+    max_queue_depth: usize,
+    messages: BTreeMap<&'static str, MessageStatistics>,
+    callouts: BTreeMap<String, DurationStatistics>,
+    slow_callouts: VecDeque<SlowCallout>,
+    slow_callout_threshold: Duration,
+}
+
+/// Queue depth, wait latency and execution time of the messages processed by the event loop.
+/// Messages carry the moment they were enqueued, see `EventLoopSender`
+#[derive(Debug)]
+pub struct EventLoopMetrics {
+    state: Mutex<EventLoopMetricsState>,
+}
+
+impl Default for EventLoopMetrics {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(EventLoopMetricsState {
+                queue_depth: 0,
+                max_queue_depth: 0,
+                messages: BTreeMap::new(),
+                callouts: BTreeMap::new(),
+                slow_callouts: VecDeque::new(),
+                slow_callout_threshold: DEFAULT_SLOW_CALLOUT_THRESHOLD,
+            }),
+        }
+    }
+}
+
+impl EventLoopMetrics {
+    /// Must be called right before a message is sent to the event loop
+    pub fn message_enqueued(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.queue_depth += 1;
+        state.max_queue_depth = state.max_queue_depth.max(state.queue_depth);
+    }
+
+    /// Must be called when a message could not be sent after it was counted as enqueued
+    pub fn message_dropped(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.queue_depth = state.queue_depth.saturating_sub(1);
+    }
+
+    /// Must be called when the event loop receives a message from the queue
+    pub fn message_received(&self, kind: &'static str, enqueued_at: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.queue_depth = state.queue_depth.saturating_sub(1);
+        state
+            .messages
+            .entry(kind)
+            .or_default()
+            .wait
+            .record(enqueued_at.elapsed());
+    }
+
+    pub fn message_processed(&self, kind: &'static str, duration: Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .messages
+            .entry(kind)
+            .or_default()
+            .execution
+            .record(duration);
+    }
+
+    pub fn callout_performed(&self, function_name: &str, module_name: &str, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .callouts
+            .entry(function_name.to_string())
+            .or_default()
+            .record(duration);
+
+        if duration < state.slow_callout_threshold {
+            return;
+        }
+        if state.slow_callouts.len() == SLOW_CALLOUTS_LOG_SIZE {
+            state.slow_callouts.pop_front();
+        }
+        state.slow_callouts.push_back(SlowCallout {
+            function_name: function_name.to_string(),
+            module_name: module_name.to_string(),
+            duration,
+            timestamp: SystemTime::now(),
+        });
+        drop(state);
+
+        log_event_loop(format!(
+            "Slow callout of `{}` from `{}` took {:?}",
+            function_name, module_name, duration
+        ));
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.state.lock().unwrap().queue_depth
+    }
+
+    pub fn max_queue_depth(&self) -> usize {
+        self.state.lock().unwrap().max_queue_depth
+    }
+
+    pub fn set_slow_callout_threshold(&self, threshold: Duration) {
+        self.state.lock().unwrap().slow_callout_threshold = threshold;
+    }
+
+    pub fn with_message_statistics<T>(
+        &self,
+        block: impl FnOnce(&BTreeMap<&'static str, MessageStatistics>) -> T,
+    ) -> T {
+        block(&self.state.lock().unwrap().messages)
+    }
+
+    pub fn with_callout_statistics<T>(
+        &self,
+        block: impl FnOnce(&BTreeMap<String, DurationStatistics>) -> T,
+    ) -> T {
+        block(&self.state.lock().unwrap().callouts)
+    }
+
+    /// A copy of the statistics of each message kind, so that no lock is held while using them
+    pub fn message_statistics(&self) -> Vec<(&'static str, MessageStatistics)> {
+        self.with_message_statistics(|messages| {
+            messages
+                .iter()
+                .map(|(kind, statistics)| (*kind, statistics.clone()))
+                .collect()
+        })
+    }
+
+    /// A copy of the statistics of each callout function, so that no lock is held while using them
+    pub fn callout_statistics(&self) -> Vec<(String, DurationStatistics)> {
+        self.with_callout_statistics(|callouts| {
+            callouts
+                .iter()
+                .map(|(function_name, statistics)| (function_name.clone(), statistics.clone()))
+                .collect()
+        })
+    }
+
+    pub fn slow_callouts(&self) -> Vec<SlowCallout> {
+        self.state
+            .lock()
+            .unwrap()
+            .slow_callouts
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Forget all statistics, messages that are still in the queue remain counted
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.max_queue_depth = state.queue_depth;
+        state.messages.clear();
+        state.callouts.clear();
+        state.slow_callouts.clear();
+    }
+
+    /// Log the queue depth and the statistics of each message kind and callout function
+    pub fn report(&self) {
+        let state = self.state.lock().unwrap();
+        let mut lines = vec![format!(
+            "Queue depth: {} (max {})",
+            state.queue_depth, state.max_queue_depth
+        )];
+        for (kind, statistics) in &state.messages {
+            lines.push(format!(
+                "{}: {} received, wait {:?} (max {:?}), execution {:?} (max {:?})",
+                kind,
+                statistics.execution.count(),
+                statistics.wait.recent_mean(),
+                statistics.wait.recent_max(),
+                statistics.execution.recent_mean(),
+                statistics.execution.recent_max()
+            ));
+        }
+        for (function_name, statistics) in &state.callouts {
+            lines.push(format!(
+                "{}: {} calls, {:?} (max {:?})",
+                function_name,
+                statistics.count(),
+                statistics.recent_mean(),
+                statistics.recent_max()
+            ));
+        }
+        drop(state);
+
+        log_event_loop(lines.join("\n"));
+    }
+}
+
+fn log_event_loop(message: String) {
+    VM_LOGGER.lock().unwrap().log(LogSignal {
+        log_type: EVENT_LOOP_LOG_TYPE.to_string(),
+        file_name: file!().to_string(),
+        function_name: "log_event_loop".to_string(),
+        line: line!() as usize,
+        message,
+    });
+}
+
+fn integer(value: i64) -> AnyObjectRef {
+    AnyObjectRef::from(Immediate::new_i64(value))
+}
+
+fn microseconds(duration: Duration) -> AnyObjectRef {
+    integer(duration.as_micros() as i64)
+}
+
+/// Answer `{ queue depth . max queue depth . messages . callouts . slow callouts }` where
+/// - messages are `{ kind . wait statistics . execution statistics }`
+/// - callouts are `{ function name . statistics }`
+/// - statistics are `{ count . mean . max . recent mean . recent max }` with durations in microseconds
+/// - slow callouts are `{ function name . module name . duration . milliseconds since the epoch }`
+///
+/// Answers nil if there is no event loop
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopMetrics() -> Result<()> {
+    let Some(event_loop) = vm().event_loop() else {
+        Smalltalk::method_return(Smalltalk::nil_object());
+        return Ok(());
+    };
+    let metrics = event_loop.metrics();
+
+    // objects are only allocated once the statistics are copied, allocating may fail
+    let message_statistics = metrics.message_statistics();
+    let callout_statistics = metrics.callout_statistics();
+    let slow_callouts = metrics.slow_callouts();
+
+    let mut messages = Array::new(message_statistics.len())?;
+    for (index, (kind, statistics)) in message_statistics.iter().enumerate() {
+        let mut description = Array::new(3)?;
+        description.insert(0, ByteString::new(kind.as_bytes())?);
+        description.insert(1, statistics.wait.as_array()?);
+        description.insert(2, statistics.execution.as_array()?);
+        messages.insert(index, description);
+    }
+
+    let mut callouts = Array::new(callout_statistics.len())?;
+    for (index, (function_name, statistics)) in callout_statistics.iter().enumerate() {
+        let mut description = Array::new(2)?;
+        description.insert(0, ByteString::new(function_name.as_bytes())?);
+        description.insert(1, statistics.as_array()?);
+        callouts.insert(index, description);
+    }
+
+    let mut slow_callouts_array = Array::new(slow_callouts.len())?;
+    for (index, slow_callout) in slow_callouts.iter().enumerate() {
+        let timestamp = slow_callout
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut description = Array::new(4)?;
+        description.insert(0, ByteString::new(slow_callout.function_name.as_bytes())?);
+        description.insert(1, ByteString::new(slow_callout.module_name.as_bytes())?);
+        description.insert(2, microseconds(slow_callout.duration));
+        description.insert(3, integer(timestamp.as_millis() as i64));
+        slow_callouts_array.insert(index, description);
+    }
+
+    let mut array = Array::new(5)?;
+    array.insert(0, integer(metrics.queue_depth() as i64));
+    array.insert(1, integer(metrics.max_queue_depth() as i64));
+    array.insert(2, messages);
+    array.insert(3, callouts);
+    array.insert(4, slow_callouts_array);
+
+    Smalltalk::method_return(array);
+    Ok(())
+}
+
+/// Log the event loop statistics with the `EVENT_LOOP` signal type
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopMetricsReport() {
+    if let Some(event_loop) = vm().event_loop() {
+        event_loop.metrics().report();
+    }
+    Smalltalk::method_return_boolean(true);
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopMetricsReset() {
+    if let Some(event_loop) = vm().event_loop() {
+        event_loop.metrics().reset();
+    }
+    Smalltalk::method_return_boolean(true);
+}
+
+/// Set the duration in microseconds after which a callout is logged as slow
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopSetSlowCalloutThreshold() {
+    let threshold = Smalltalk::stack_integer_value(StackOffset::new(0));
+    if threshold < 0 {
+        return Smalltalk::primitive_fail();
+    }
+    if let Some(event_loop) = vm().event_loop() {
+        event_loop
+            .metrics()
+            .set_slow_callout_threshold(Duration::from_micros(threshold as u64));
+    }
+    Smalltalk::method_return_boolean(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn milliseconds(amount: u64) -> Duration {
+        Duration::from_millis(amount)
+    }
+
+    #[test]
+    fn empty_statistics() {
+        let statistics = DurationStatistics::default();
+        assert_eq!(statistics.count(), 0);
+        assert_eq!(statistics.mean(), Duration::ZERO);
+        assert_eq!(statistics.max(), Duration::ZERO);
+        assert_eq!(statistics.recent_mean(), Duration::ZERO);
+        assert_eq!(statistics.recent_max(), Duration::ZERO);
+    }
+
+    #[test]
+    fn statistics_of_recorded_durations() {
+        let mut statistics = DurationStatistics::default();
+        for amount in [10, 30, 20] {
+            statistics.record(milliseconds(amount));
+        }
+        assert_eq!(statistics.count(), 3);
+        assert_eq!(statistics.mean(), milliseconds(20));
+        assert_eq!(statistics.max(), milliseconds(30));
+        assert_eq!(statistics.recent_mean(), milliseconds(20));
+        assert_eq!(statistics.recent_max(), milliseconds(30));
+    }
+
+    #[test]
+    fn recent_statistics_are_limited_to_a_window() {
+        let mut statistics = DurationStatistics::default();
+        statistics.record(milliseconds(1000));
+        for _ in 0..RECENT_DURATIONS_WINDOW {
+            statistics.record(milliseconds(10));
+        }
+        assert_eq!(statistics.count(), RECENT_DURATIONS_WINDOW as u64 + 1);
+        assert_eq!(statistics.max(), milliseconds(1000));
+        assert!(statistics.mean() > milliseconds(10));
+        assert_eq!(statistics.recent_max(), milliseconds(10));
+        assert_eq!(statistics.recent_mean(), milliseconds(10));
+    }
+
+    #[test]
+    fn queue_depth() {
+        let metrics = EventLoopMetrics::default();
+        metrics.message_enqueued();
+        metrics.message_enqueued();
+        metrics.message_enqueued();
+        metrics.message_received("WakeUp", Instant::now());
+        metrics.message_dropped();
+        assert_eq!(metrics.queue_depth(), 1);
+        assert_eq!(metrics.max_queue_depth(), 3);
+
+        metrics.reset();
+        assert_eq!(metrics.max_queue_depth(), 1);
+        metrics.message_received("WakeUp", Instant::now());
+        metrics.message_received("WakeUp", Instant::now());
+        assert_eq!(metrics.queue_depth(), 0);
+    }
+
+    #[test]
+    fn wait_is_measured_from_the_enqueue_time() {
+        let metrics = EventLoopMetrics::default();
+        let enqueued_at = Instant::now() - milliseconds(50);
+        metrics.message_enqueued();
+        metrics.message_received("WakeUp", enqueued_at);
+
+        metrics.with_message_statistics(|messages| {
+            let wait = &messages["WakeUp"].wait;
+            assert_eq!(wait.count(), 1);
+            assert!(wait.max() >= milliseconds(50));
+        });
+    }
+
+    #[test]
+    fn execution_is_grouped_by_kind() {
+        let metrics = EventLoopMetrics::default();
+        metrics.message_processed("WakeUp", milliseconds(1));
+        metrics.message_processed("Call", milliseconds(2));
+        metrics.message_processed("Call", milliseconds(4));
+
+        metrics.with_message_statistics(|messages| {
+            assert_eq!(messages["WakeUp"].execution.count(), 1);
+            assert_eq!(messages["Call"].execution.count(), 2);
+            assert_eq!(messages["Call"].execution.mean(), milliseconds(3));
+            assert_eq!(messages["Call"].wait.count(), 0);
+        });
+    }
+}
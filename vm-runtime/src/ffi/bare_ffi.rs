@@ -1,19 +1,27 @@
 use enum_display::EnumDisplay;
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::convert::Infallible;
-use std::ffi::OsString;
+use std::ffi::{CStr, OsString};
 use std::num::TryFromIntError;
 use std::ops::Deref;
 use std::os::raw::*;
+use std::sync::Arc;
 use vm_bindings::{ObjectPointer, Smalltalk};
 
-use crate::objects::{ArrayRef, ByteStringRef, ExternalAddressRef};
+use crate::ffi::{
+    capture_errno, string_result_bytes, BareFFIStringTypeRef, BareFFIStructTypeRef, BufferValue,
+    LibraryRegistry, SharedLibrary, StructType, StructValue,
+};
+use crate::objects::{Array, ArrayRef, ByteArray, ByteString, ByteStringRef, ExternalAddressRef};
+use crate::{AllocationTracker, FfiPolicy};
+use libffi::low::{ffi_abi_FFI_DEFAULT_ABI, ffi_cif, ffi_type};
 use libffi::middle::{Cif, CodePtr, Type};
-use libloading::Library;
 use num_derive::FromPrimitive;
 use num_traits::{FromPrimitive, ToPrimitive};
 use thiserror::Error;
 use vm_bindings::bindings::sqInt;
-use vm_object_model::{AnyObjectRef, Immediate, Object, ObjectRef, RawObjectPointer};
+use vm_object_model::{AnyObjectRef, Immediate, Object, ObjectFormat, ObjectRef, RawObjectPointer};
 
 #[derive(Debug, PharoObject)]
 #[repr(C)]
@@ -26,6 +34,10 @@ pub struct ExternalFunction {
     return_type: BareFFITypeRef,
     external_object_class: ObjectRef,
     external_enumeration_class: ObjectRef,
+    /// true if errno is read right after each call, survives releasing the callout
+    captures_errno: ObjectRef,
+    /// true if the caller is responsible for freeing returned pointers, survives releasing the callout
+    returns_owned_memory: ObjectRef,
 }
 
 impl ExternalFunction {
@@ -33,9 +45,12 @@ impl ExternalFunction {
         unsafe { &mut *(self.callout.read_address() as *mut Callout) }
     }
 
-    fn invalidate(&mut self) -> Result<(), Error> {
+    /// Create the callout if it is released, restoring the flags stored in the external function
+    pub(crate) fn invalidate(&mut self) -> Result<(), Error> {
         if self.callout.is_null() {
-            let callout = self.new_callout()?;
+            let mut callout = self.new_callout()?;
+            callout.set_should_capture_errno(self.captures_errno());
+            callout.set_returns_owned_memory(self.returns_owned_memory());
             let callout_ptr = Box::into_raw(Box::new(callout));
             self.callout.set_address(callout_ptr as *mut c_void);
         }
@@ -43,37 +58,106 @@ impl ExternalFunction {
     }
 
     fn new_callout(&self) -> Result<Callout, Error> {
-        let return_type = MarshallType::try_from(self.return_type)?;
+        let return_type = ReturnType::try_from(AnyObjectRef::from(self.return_type))?;
 
-        let mut argument_types = vec![MarshallType::Void; self.argument_types.len()];
-        for (index, each_type) in self.argument_types.iter().enumerate() {
-            let each_type = BareFFITypeRef::try_from(*each_type)?;
-            let marshall_type = MarshallType::try_from(each_type)?;
-            argument_types[index] = marshall_type;
+        let mut argument_types = Vec::with_capacity(self.argument_types.len());
+        for each_type in self.argument_types.iter() {
+            argument_types.push(ArgumentType::try_from(*each_type)?);
         }
 
-        Callout::new(
+        Callout::with_types(
             OsString::from(self.module_name.to_string()),
             OsString::from(self.function_name.to_string()),
             argument_types,
             return_type,
         )
     }
+
+    /// The class of external structures returned by value,
+    /// nil if the struct fields are returned as an array
+    pub(crate) fn return_structure_class(&self) -> AnyObjectRef {
+        BareFFIStructTypeRef::try_from(AnyObjectRef::from(self.return_type))
+            .map(|struct_type| struct_type.structure_class())
+            .unwrap_or_else(|_| Smalltalk::nil_object())
+    }
+
+    pub(crate) fn marshaller(&self) -> ArgumentMarshall {
+        ArgumentMarshall::new(self.external_object_class, self.external_enumeration_class)
+    }
+
+    pub(crate) fn captures_errno(&self) -> bool {
+        is_true_object(self.captures_errno)
+    }
+
+    /// Enable or disable capturing errno both in the external function and in its callout
+    pub(crate) fn set_should_capture_errno(
+        &mut self,
+        should_capture_errno: bool,
+    ) -> Result<(), Error> {
+        self.set_captures_errno(Smalltalk::bool_object(should_capture_errno));
+        self.invalidate()?;
+        self.callout_mut()
+            .set_should_capture_errno(should_capture_errno);
+        Ok(())
+    }
+
+    pub(crate) fn returns_owned_memory(&self) -> bool {
+        is_true_object(self.returns_owned_memory)
+    }
+
+    /// Mark returned pointers as owned or not both in the external function and in its callout
+    pub(crate) fn set_should_return_owned_memory(
+        &mut self,
+        returns_owned_memory: bool,
+    ) -> Result<(), Error> {
+        self.set_returns_owned_memory(Smalltalk::bool_object(returns_owned_memory));
+        self.invalidate()?;
+        self.callout_mut()
+            .set_returns_owned_memory(returns_owned_memory);
+        Ok(())
+    }
+}
+
+/// Whether the class of an object or one of its superclasses has one of the given names
+pub(crate) fn is_kind_of_class_named(object: ObjectRef, class_names: &[&str]) -> bool {
+    let nil_object = Smalltalk::nil_object();
+    let mut class = Some(Smalltalk::class_of_object(object));
+    while let Some(each) = class {
+        if class_names.contains(&Smalltalk::class_name(each).as_str()) {
+            return true;
+        }
+        class = each
+            .inst_var_at(0)
+            .filter(|superclass| *superclass != nil_object)
+            .and_then(|superclass| superclass.as_object().ok());
+    }
+    false
+}
+
+fn is_true_object(object: ObjectRef) -> bool {
+    Smalltalk::true_object() == ObjectPointer::from(AnyObjectRef::from(object).as_i64())
 }
 
 #[derive(Debug)]
 pub struct Callout {
     function_name: OsString,
     module_name: OsString,
-    library: Library,
+    library: Arc<SharedLibrary>,
     function: CodePtr,
     argument_types: Vec<ArgumentType>,
     result: ReturnType,
+    /// frees strings returned by the function
+    deallocator: Option<unsafe extern "C" fn(*mut c_void)>,
     cif: Cif,
-    // runtime state.
-    // Since pharo is single threaded, we don't need to care about synchronization here
-    marshalled_arguments: Vec<MarshalledValue>,
-    arguments: Vec<*mut c_void>,
+    /// call interfaces of a variadic function for each distinct list of variadic argument types
+    variadic_cifs: HashMap<Vec<MarshallType>, VariadicCif>,
+    /// read errno right after each call, disabled by default.
+    /// The external function keeps the flag and restores it when the callout is recreated
+    should_capture_errno: bool,
+    errno: Cell<Option<i32>>,
+    /// the caller is responsible for freeing a returned pointer or string.
+    /// The external function keeps the flag and restores it when the callout is recreated
+    returns_owned_memory: bool,
 }
 
 impl Callout {
@@ -83,37 +167,367 @@ impl Callout {
         argument_marshall_types: Vec<MarshallType>,
         return_type: MarshallType,
     ) -> Result<Self, Error> {
-        let args = argument_marshall_types.iter().map(|each| Type::from(*each));
-        let ret = Type::from(return_type);
-        let cif = Cif::new(args, ret);
+        let argument_types = argument_marshall_types
+            .into_iter()
+            .map(ArgumentType::new)
+            .collect();
 
-        let library = unsafe { Library::new(&module_name) }?;
-        let function =
-            unsafe { library.get::<unsafe extern "C" fn()>(function_name.as_encoded_bytes()) }?;
-        let function = CodePtr::from_ptr(unsafe { function.into_raw() }.as_raw_ptr());
+        Self::with_types(
+            module_name,
+            function_name,
+            argument_types,
+            ReturnType::new(return_type),
+        )
+    }
 
-        let amount_of_arguments = argument_marshall_types.len();
-        let mut argument_types = Vec::with_capacity(amount_of_arguments);
-        for each in argument_marshall_types {
-            let each_type = ArgumentType::new(each);
-            argument_types.push(each_type);
+    /// Create a callout from argument and return types that may describe structs passed by value
+    pub fn with_types(
+        module_name: OsString,
+        function_name: OsString,
+        argument_types: Vec<ArgumentType>,
+        result: ReturnType,
+    ) -> Result<Self, Error> {
+        let is_missing_struct_layout = argument_types
+            .iter()
+            .map(|each| (each.marshall_type, &each.struct_type))
+            .chain([(result.marshall_type, &result.struct_type)])
+            .any(|(marshall_type, struct_type)| {
+                marshall_type == MarshallType::Struct && struct_type.is_none()
+            });
+        if is_missing_struct_layout {
+            return Err(Error::MissingStructLayout);
+        }
+        if matches!(
+            result.marshall_type,
+            MarshallType::Buffer | MarshallType::FloatArray | MarshallType::WordArray
+        ) {
+            return Err(Error::IllegalReturnType(result.marshall_type));
         }
 
+        let args = argument_types.iter().map(|each| each.ffi_type.clone());
+        let cif = Cif::new(args, result.ffi_type.clone());
+
+        // check the library that would be loaded rather than the name the image asked for
+        let module_path = LibraryRegistry::global().resolved_path(&module_name);
+        let module_path = module_path.to_string_lossy();
+        let symbol_names = std::iter::once(function_name.to_string_lossy().to_string())
+            .chain(result.deallocator_name.clone());
+        for symbol_name in symbol_names {
+            if !FfiPolicy::global().check_symbol(&module_path, &symbol_name) {
+                return Err(Error::FfiPolicyViolation(symbol_name));
+            }
+        }
+
+        let library = LibraryRegistry::global().load(&module_name, false)?;
+        let function = unsafe {
+            library
+                .library()
+                .get::<unsafe extern "C" fn()>(function_name.as_encoded_bytes())
+        }?;
+        let function = CodePtr::from_ptr(unsafe { function.into_raw() }.as_raw_ptr());
+
+        let deallocator = match &result.deallocator_name {
+            Some(deallocator_name) => Some(*unsafe {
+                library
+                    .library()
+                    .get::<unsafe extern "C" fn(*mut c_void)>(deallocator_name.as_bytes())
+            }?),
+            None => None,
+        };
+
         Ok(Self {
             argument_types,
-            result: ReturnType::new(return_type),
+            result,
+            deallocator,
             cif,
+            variadic_cifs: Default::default(),
             function_name,
             module_name,
             library,
             function,
-            marshalled_arguments: vec![MarshalledValue::Void; amount_of_arguments],
-            arguments: vec![&0 as *const i32 as *mut c_void; amount_of_arguments],
+            should_capture_errno: false,
+            errno: Cell::new(None),
+            returns_owned_memory: false,
         })
     }
 
-    pub fn call<T>(&self) -> T {
-        unsafe { self.cif.call::<T>(self.function, std::mem::transmute(self.arguments.as_slice())) }
+    /// The amount of declared arguments, which are the fixed arguments of a variadic function
+    pub fn amount_of_fixed_arguments(&self) -> usize {
+        self.argument_types.len()
+    }
+
+    pub fn argument_types(&self) -> &[ArgumentType] {
+        self.argument_types.as_slice()
+    }
+
+    pub fn return_type(&self) -> &ReturnType {
+        &self.result
+    }
+
+    /// Enable or disable capturing errno after each call.
+    /// Use [`ExternalFunction::set_should_capture_errno`] to keep the setting
+    /// when the callout is released, for example after the image is restarted
+    pub fn set_should_capture_errno(&mut self, should_capture_errno: bool) {
+        self.should_capture_errno = should_capture_errno;
+        if !should_capture_errno {
+            self.errno.set(None);
+        }
+    }
+
+    /// The errno captured right after the last call, if capturing is enabled
+    pub fn errno(&self) -> Option<i32> {
+        self.errno.get()
+    }
+
+    /// Remember the errno captured by a call made outside of the callout, like an asynchronous one
+    pub(crate) fn set_errno(&self, errno: Option<i32>) {
+        if self.should_capture_errno {
+            self.errno.set(errno);
+        }
+    }
+
+    /// Mark returned pointers as owned by the caller, so that they are recorded by the allocation tracker.
+    /// Use [`ExternalFunction::set_should_return_owned_memory`] to keep the setting
+    /// when the callout is released
+    pub fn set_returns_owned_memory(&mut self, returns_owned_memory: bool) {
+        self.returns_owned_memory = returns_owned_memory;
+    }
+
+    fn track_returned_pointer(&self, pointer: *mut c_void) {
+        if self.returns_owned_memory {
+            AllocationTracker::record_owned_return(pointer);
+        }
+    }
+
+    /// How the return value is converted to an object, independent of the lifetime of the callout
+    pub(crate) fn result_marshall(&self) -> ResultMarshall {
+        ResultMarshall {
+            return_type: self.result.clone(),
+            deallocator: self.deallocator,
+            returns_owned_memory: self.returns_owned_memory,
+        }
+    }
+
+    /// Prepare the call interface of a variadic function for given (promoted) types
+    /// of the variadic arguments, once per distinct signature
+    pub fn prepare_variadic_call(&mut self, variadic_types: &[MarshallType]) -> Result<(), Error> {
+        if !self.variadic_cifs.contains_key(variadic_types) {
+            let cif = VariadicCif::new(&self.argument_types, variadic_types, &self.result)?;
+            self.variadic_cifs.insert(variadic_types.to_vec(), cif);
+        }
+        Ok(())
+    }
+
+    fn raw_cif(&self, arguments: &CallArguments) -> *mut ffi_cif {
+        arguments
+            .variadic_signature
+            .as_ref()
+            .and_then(|signature| self.variadic_cifs.get(signature))
+            .map(|variadic_cif| variadic_cif.as_raw_ptr())
+            .unwrap_or_else(|| self.cif.as_raw_ptr())
+    }
+
+    /// Call the function with given arguments.
+    /// Buffers are not copied back, see [`CallArguments::copy_back_buffers_to`]
+    pub fn call<T>(&self, arguments: &CallArguments) -> T {
+        let result = if arguments.variadic_signature.is_none() {
+            unsafe {
+                self.cif.call::<T>(
+                    self.function,
+                    std::mem::transmute(arguments.arguments.as_slice()),
+                )
+            }
+        } else {
+            // libffi widens integral return values to the size of a register
+            let mut result = [0u64; 1];
+            assert!(std::mem::size_of::<T>() <= std::mem::size_of_val(&result));
+            self.raw_call_into(arguments, result.as_mut_ptr() as *mut c_void);
+            unsafe { std::ptr::read(result.as_ptr() as *const T) }
+        };
+        self.capture_errno();
+        result
+    }
+
+    /// Call the function writing its return value into a given buffer,
+    /// which must be large enough to hold the return type
+    pub fn call_into(&self, arguments: &CallArguments, result: *mut c_void) {
+        self.raw_call_into(arguments, result);
+        self.capture_errno();
+    }
+
+    /// Must be called before anything else that may change errno
+    fn capture_errno(&self) {
+        if self.should_capture_errno {
+            self.errno.set(Some(capture_errno()));
+        }
+    }
+
+    /// A call of the function through the fixed call interface that owns everything it needs,
+    /// so that it can run on another thread even if the callout is released meanwhile
+    pub fn detached_call(&self) -> DetachedCall {
+        DetachedCall {
+            cif: self.cif.clone(),
+            function: self.function,
+            _library: self.library.clone(),
+            should_capture_errno: self.should_capture_errno,
+        }
+    }
+
+    fn raw_call_into(&self, arguments: &CallArguments, result: *mut c_void) {
+        unsafe {
+            libffi::raw::ffi_call(
+                self.raw_cif(arguments),
+                Some(*self.function.as_safe_fun()),
+                result,
+                arguments.arguments.as_ptr() as *mut *mut c_void,
+            )
+        }
+    }
+}
+
+/// Arguments of one call of a callout.
+///
+/// A callback may enter the interpreter while the function runs, and the image may call
+/// the same external function again from there, for example a comparator of `qsort`
+/// that sorts a nested collection with `qsort` too. Therefore the arguments belong
+/// to the primitive performing the call rather than to the callout shared by all calls.
+/// For the same reason the objects the arguments were marshalled from may move during the call,
+/// they must be read again from the stack before the buffers are copied back to them.
+#[derive(Debug)]
+pub struct CallArguments {
+    variadic_signature: Option<Vec<MarshallType>>,
+    marshalled_arguments: Vec<MarshalledValue>,
+    arguments: Vec<*mut c_void>,
+}
+
+impl CallArguments {
+    /// Arguments of a call through the fixed call interface, or through the variadic one
+    /// prepared with [`Callout::prepare_variadic_call`] for a given signature
+    pub fn new(
+        marshalled_arguments: Vec<MarshalledValue>,
+        variadic_signature: Option<Vec<MarshallType>>,
+    ) -> Self {
+        // the pointers refer to the values within the vector, which is not changed afterwards
+        let arguments = marshalled_arguments
+            .iter()
+            .map(|each| each.as_arg())
+            .collect();
+        Self {
+            variadic_signature,
+            marshalled_arguments,
+            arguments,
+        }
+    }
+
+    /// Write the buffers that were passed as copies back to the objects they were made from,
+    /// given in the order of the arguments as they are after the call
+    pub fn copy_back_buffers_to(&self, objects: impl IntoIterator<Item = AnyObjectRef>) {
+        for (each_argument, each_object) in self.marshalled_arguments.iter().zip(objects) {
+            if let MarshalledValue::Buffer(buffer) = each_argument {
+                buffer.copy_back_to(each_object);
+            }
+        }
+    }
+}
+
+/// See [`Callout::result_marshall`]
+#[derive(Debug, Clone)]
+pub(crate) struct ResultMarshall {
+    return_type: ReturnType,
+    deallocator: Option<unsafe extern "C" fn(*mut c_void)>,
+    returns_owned_memory: bool,
+}
+
+impl ResultMarshall {
+    pub(crate) fn return_type(&self) -> &ReturnType {
+        &self.return_type
+    }
+}
+
+/// See [`Callout::detached_call`]
+#[derive(Debug)]
+pub struct DetachedCall {
+    cif: Cif,
+    function: CodePtr,
+    /// keeps the library loaded while the call runs
+    _library: Arc<SharedLibrary>,
+    should_capture_errno: bool,
+}
+
+impl DetachedCall {
+    /// Call the function with given arguments writing its return value into a buffer.
+    /// Answers errno read right after the call if capturing is enabled
+    pub fn call_into(&self, arguments: &[*mut c_void], result: *mut c_void) -> Option<i32> {
+        unsafe {
+            libffi::raw::ffi_call(
+                self.cif.as_raw_ptr(),
+                Some(*self.function.as_safe_fun()),
+                result,
+                arguments.as_ptr() as *mut *mut c_void,
+            )
+        };
+        self.should_capture_errno.then(capture_errno)
+    }
+}
+
+/// A call interface of a variadic function prepared with `ffi_prep_cif_var`
+/// for one list of variadic argument types
+#[derive(Debug)]
+struct VariadicCif {
+    cif: Box<ffi_cif>,
+    // the cif refers to the types, so they must live as long as the cif
+    argument_types: Vec<Type>,
+    argument_type_pointers: Vec<*mut ffi_type>,
+    result_type: Type,
+}
+
+impl VariadicCif {
+    fn new(
+        fixed_argument_types: &[ArgumentType],
+        variadic_types: &[MarshallType],
+        result: &ReturnType,
+    ) -> Result<Self, Error> {
+        if let Some(illegal_type) = variadic_types
+            .iter()
+            .find(|each| matches!(each, MarshallType::Void | MarshallType::Struct))
+        {
+            return Err(Error::IllegalArgumentType(*illegal_type));
+        }
+
+        let argument_types: Vec<Type> = fixed_argument_types
+            .iter()
+            .map(|each| each.ffi_type.clone())
+            .chain(variadic_types.iter().map(|each| Type::from(*each)))
+            .collect();
+        let mut argument_type_pointers: Vec<*mut ffi_type> = argument_types
+            .iter()
+            .map(|each| each.as_raw_ptr())
+            .collect();
+        let result_type = result.ffi_type.clone();
+
+        let mut cif: Box<ffi_cif> = Box::new(unsafe { std::mem::zeroed() });
+        unsafe {
+            libffi::low::prep_cif_var(
+                cif.as_mut(),
+                ffi_abi_FFI_DEFAULT_ABI,
+                fixed_argument_types.len(),
+                argument_types.len(),
+                result_type.as_raw_ptr(),
+                argument_type_pointers.as_mut_ptr(),
+            )
+        }
+        .map_err(|error| Error::PrepareCif(format!("{:?}", error)))?;
+
+        Ok(Self {
+            cif,
+            argument_types,
+            argument_type_pointers,
+            result_type,
+        })
+    }
+
+    fn as_raw_ptr(&self) -> *mut ffi_cif {
+        self.cif.as_ref() as *const ffi_cif as *mut ffi_cif
     }
 }
 
@@ -121,6 +535,7 @@ impl Callout {
 pub struct ArgumentType {
     marshall_type: MarshallType,
     ffi_type: Type,
+    struct_type: Option<StructType>,
 }
 
 impl ArgumentType {
@@ -130,14 +545,50 @@ impl ArgumentType {
         Self {
             marshall_type,
             ffi_type,
+            struct_type: None,
+        }
+    }
+
+    pub fn new_struct(struct_type: StructType) -> Self {
+        Self {
+            marshall_type: MarshallType::Struct,
+            ffi_type: struct_type.ffi_type(),
+            struct_type: Some(struct_type),
         }
     }
+
+    pub fn marshall_type(&self) -> MarshallType {
+        self.marshall_type
+    }
+
+    pub fn ffi_type(&self) -> &Type {
+        &self.ffi_type
+    }
+
+    pub fn struct_type(&self) -> Option<&StructType> {
+        self.struct_type.as_ref()
+    }
 }
 
-#[derive(Debug)]
+impl TryFrom<AnyObjectRef> for ArgumentType {
+    type Error = Error;
+
+    fn try_from(object: AnyObjectRef) -> Result<Self, Self::Error> {
+        match StructType::from_type_object(object)? {
+            Some(struct_type) => Ok(Self::new_struct(struct_type)),
+            None => Ok(Self::new(MarshallType::try_from(
+                BareFFITypeRef::try_from(object)?,
+            )?)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ReturnType {
     marshall_type: MarshallType,
     ffi_type: Type,
+    struct_type: Option<StructType>,
+    deallocator_name: Option<String>,
 }
 
 impl ReturnType {
@@ -145,8 +596,55 @@ impl ReturnType {
         Self {
             marshall_type,
             ffi_type: marshall_type.into(),
+            struct_type: None,
+            deallocator_name: None,
+        }
+    }
+
+    pub fn new_struct(struct_type: StructType) -> Self {
+        Self {
+            marshall_type: MarshallType::Struct,
+            ffi_type: struct_type.ffi_type(),
+            struct_type: Some(struct_type),
+            deallocator_name: None,
+        }
+    }
+
+    /// A returned string that is freed by a given function after it is copied
+    pub fn new_string(deallocator_name: Option<String>) -> Self {
+        Self {
+            deallocator_name,
+            ..Self::new(MarshallType::String)
         }
     }
+
+    pub fn marshall_type(&self) -> MarshallType {
+        self.marshall_type
+    }
+
+    pub fn ffi_type(&self) -> &Type {
+        &self.ffi_type
+    }
+
+    pub fn struct_type(&self) -> Option<&StructType> {
+        self.struct_type.as_ref()
+    }
+}
+
+impl TryFrom<AnyObjectRef> for ReturnType {
+    type Error = Error;
+
+    fn try_from(object: AnyObjectRef) -> Result<Self, Self::Error> {
+        if let Some(struct_type) = StructType::from_type_object(object)? {
+            return Ok(Self::new_struct(struct_type));
+        }
+        if let Ok(string_type) = BareFFIStringTypeRef::try_from(object) {
+            return Ok(Self::new_string(string_type.deallocator_name()));
+        }
+        Ok(Self::new(MarshallType::try_from(
+            BareFFITypeRef::try_from(object)?,
+        )?))
+    }
 }
 
 #[derive(Error, Debug)]
@@ -169,16 +667,79 @@ pub enum Error {
     InstanceNotFloat,
     #[error("Not an integer")]
     InstanceNotInteger,
+    #[error("{0} can't be used as a struct field type")]
+    IllegalStructFieldType(MarshallType),
+    #[error("Struct type must describe its fields")]
+    MissingStructLayout,
+    #[error("Wrong number of struct fields: expected {0}, got {1}")]
+    WrongNumberOfStructFields(usize, usize),
+    #[error("Can't marshall a null pointer as a struct")]
+    NullStruct,
+    #[error("Struct of {0} bytes doesn't fit in an object of {1} bytes")]
+    StructTooLarge(usize, usize),
+    #[error("Failed to prepare a call interface: {0}")]
+    PrepareCif(String),
+    #[error("{0} can't be used as a return type")]
+    IllegalReturnType(MarshallType),
+    #[error("Not a {0}")]
+    InstanceNotOfType(MarshallType),
+    #[error("String contains a NUL character at {0}")]
+    InteriorNulCharacter(usize),
+    #[error("{0} is not a valid character")]
+    InvalidCharacter(u32),
+    #[error("Callback is released")]
+    CallbackReleased,
+    #[error("Callback invocation is null")]
+    NullCallbackInvocation,
+    #[error("Callback invocation is not pending")]
+    UnknownCallbackInvocation,
+    #[error("Callback is being invoked on the VM thread")]
+    CallbackInUse,
+    #[error("Failed to return from a callback")]
+    CallbackReturn,
+    #[error("Asynchronous callout is null")]
+    NullAsyncCallout,
+    #[error("Asynchronous callout is not running")]
+    UnknownAsyncCallout,
+    #[error("Asynchronous callout is not finished")]
+    AsyncCalloutNotFinished,
+    #[error("Object passed as {0} to an asynchronous callout must be pinned")]
+    UnpinnedAsyncArgument(MarshallType),
+    #[error("Event loop callout is null")]
+    NullEventLoopCallout,
+    #[error("Not a TFExternalFunction")]
+    NotATFExternalFunction,
+    #[error("External function has no slot to enable capturing errno")]
+    NoCapturesErrnoSlot,
+    #[error("Access to {0} is denied by the FFI policy")]
+    FfiPolicyViolation(String),
+    #[error("Not an external address")]
+    NotAnExternalAddress,
+    #[error("Can't access memory at a null address")]
+    NullAddress,
+    #[error("Access of {amount_of_bytes} bytes at {address:#x} overflows the address space")]
+    AddressOverflow {
+        address: usize,
+        amount_of_bytes: usize,
+    },
+    #[error("Access of {amount_of_bytes} bytes at {address:#x} is out of bounds of the allocation of {allocation_size} bytes at {allocation:#x}")]
+    OutOfBounds {
+        address: usize,
+        amount_of_bytes: usize,
+        allocation: usize,
+        allocation_size: usize,
+    },
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub fn primitiveBareFfiCallout() -> Result<(), Error> {
+    let _stack = AllocationTracker::enter_primitive();
     let mut external_function =
         unsafe { ExternalFunctionRef::from_any_object_unchecked(Smalltalk::method_receiver()) };
     external_function.invalidate()?;
 
-    let mut marshaller = ArgumentMarshall {
+    let marshaller = ArgumentMarshall {
         external_object_class: external_function.external_object_class,
         external_enumeration_class: external_function.external_enumeration_class,
     };
@@ -190,17 +751,94 @@ pub fn primitiveBareFfiCallout() -> Result<(), Error> {
         Err(Error::WrongNumberOfArguments(amount_of_function_arguments))?;
     }
 
+    let mut marshalled_arguments = Vec::with_capacity(callout.argument_types.len());
     for (each_argument_index, each_arg_type) in callout.argument_types.iter().enumerate() {
         let each_object = Smalltalk::get_method_argument(each_argument_index);
-        let each_arg = marshaller.marshall(each_object, each_arg_type)?;
+        marshalled_arguments.push(marshaller.marshall(each_object, each_arg_type)?);
+    }
+    let arguments = CallArguments::new(marshalled_arguments, None);
+
+    let result = call_and_marshall_result(callout, &arguments, receiver_return_structure_class);
+    arguments.copy_back_buffers_to(
+        (0..amount_of_function_arguments).map(Smalltalk::get_method_argument),
+    );
+    Smalltalk::method_return_value(ObjectPointer::from(result?.as_i64()));
+
+    Ok(())
+}
 
-        callout.marshalled_arguments[each_argument_index] = each_arg;
-        callout.arguments[each_argument_index] =
-            callout.marshalled_arguments[each_argument_index].as_arg();
+/// The structure class of the external function that received the current primitive.
+/// It is read from the stack after a call, as callbacks may run the garbage collector during the call
+fn receiver_return_structure_class() -> AnyObjectRef {
+    unsafe { ExternalFunctionRef::from_any_object_unchecked(Smalltalk::method_receiver()) }
+        .return_structure_class()
+}
+
+/// Call a variadic function. The declared argument types are the fixed arguments,
+/// they are followed by an array of variadic arguments and an array of their types.
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiVariadicCallout() -> Result<(), Error> {
+    let _stack = AllocationTracker::enter_primitive();
+    let mut external_function =
+        unsafe { ExternalFunctionRef::from_any_object_unchecked(Smalltalk::method_receiver()) };
+    external_function.invalidate()?;
+
+    let marshaller = ArgumentMarshall {
+        external_object_class: external_function.external_object_class,
+        external_enumeration_class: external_function.external_enumeration_class,
+    };
+
+    let callout = external_function.callout_mut();
+    let amount_of_fixed_arguments = callout.amount_of_fixed_arguments();
+
+    let amount_of_function_arguments = Smalltalk::method_argument_count();
+    if amount_of_function_arguments != amount_of_fixed_arguments + 2 {
+        Err(Error::WrongNumberOfArguments(amount_of_function_arguments))?;
     }
 
-    let result = call_and_marshall_result(callout)?;
-    Smalltalk::method_return_value(ObjectPointer::from(result.as_i64()));
+    let variadic_arguments =
+        ArrayRef::try_from(Smalltalk::get_method_argument(amount_of_fixed_arguments))?;
+    let variadic_types = ArrayRef::try_from(Smalltalk::get_method_argument(
+        amount_of_fixed_arguments + 1,
+    ))?;
+    if variadic_arguments.len() != variadic_types.len() {
+        Err(Error::WrongNumberOfArguments(variadic_arguments.len()))?;
+    }
+
+    let mut variadic_marshall_types = Vec::with_capacity(variadic_types.len());
+    for each_type in variadic_types.iter() {
+        variadic_marshall_types.push(MarshallType::try_from(BareFFITypeRef::try_from(
+            *each_type,
+        )?)?);
+    }
+    let variadic_signature: Vec<MarshallType> = variadic_marshall_types
+        .iter()
+        .map(|each| each.promoted())
+        .collect();
+    callout.prepare_variadic_call(&variadic_signature)?;
+
+    let mut marshalled_arguments =
+        Vec::with_capacity(amount_of_fixed_arguments + variadic_arguments.len());
+    for (each_argument_index, each_arg_type) in callout.argument_types.iter().enumerate() {
+        let each_object = Smalltalk::get_method_argument(each_argument_index);
+        marshalled_arguments.push(marshaller.marshall(each_object, each_arg_type)?);
+    }
+    for (each_object, each_type) in variadic_arguments.iter().zip(variadic_marshall_types) {
+        marshalled_arguments.push(marshaller.marshall_as(*each_object, each_type)?.promoted());
+    }
+    let arguments = CallArguments::new(marshalled_arguments, Some(variadic_signature));
+
+    let result = call_and_marshall_result(callout, &arguments, receiver_return_structure_class);
+    // the array of variadic arguments may have moved during the call
+    let variadic_arguments =
+        ArrayRef::try_from(Smalltalk::get_method_argument(amount_of_fixed_arguments))?;
+    arguments.copy_back_buffers_to(
+        (0..amount_of_fixed_arguments)
+            .map(Smalltalk::get_method_argument)
+            .chain(variadic_arguments.iter().copied()),
+    );
+    Smalltalk::method_return_value(ObjectPointer::from(result?.as_i64()));
 
     Ok(())
 }
@@ -216,6 +854,19 @@ pub fn primitiveBareFfiCalloutInvalidate() -> Result<(), Error> {
     Ok(())
 }
 
+/// Mark pointers returned by the receiver external function as owned by the caller
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiCalloutReturnsOwned() -> Result<(), Error> {
+    let mut external_function = ExternalFunctionRef::try_from(Smalltalk::method_receiver())?;
+    let returns_owned_memory =
+        Smalltalk::true_object() == ObjectPointer::from(Smalltalk::get_method_argument(0).as_i64());
+    external_function.set_should_return_owned_memory(returns_owned_memory)?;
+
+    Smalltalk::method_return(external_function);
+    Ok(())
+}
+
 #[no_mangle]
 #[allow(non_snake_case)]
 pub fn primitiveBareFfiCalloutRelease() -> Result<(), Error> {
@@ -230,15 +881,46 @@ pub fn primitiveBareFfiCalloutRelease() -> Result<(), Error> {
     Ok(())
 }
 
-struct ArgumentMarshall {
+pub(crate) struct ArgumentMarshall {
     external_object_class: ObjectRef,
     external_enumeration_class: ObjectRef,
 }
 
 impl ArgumentMarshall {
-    fn marshall(&self, object: AnyObjectRef, ty: &ArgumentType) -> Result<MarshalledValue, Error> {
-        match ty.marshall_type {
-            MarshallType::Void => Err(Error::IllegalArgumentType(ty.marshall_type)),
+    pub(crate) fn new(
+        external_object_class: ObjectRef,
+        external_enumeration_class: ObjectRef,
+    ) -> Self {
+        Self {
+            external_object_class,
+            external_enumeration_class,
+        }
+    }
+
+    pub(crate) fn marshall(
+        &self,
+        object: AnyObjectRef,
+        ty: &ArgumentType,
+    ) -> Result<MarshalledValue, Error> {
+        match &ty.struct_type {
+            Some(struct_type) => {
+                let mut value = StructValue::new(struct_type.size());
+                self.marshall_struct_into(object, struct_type, value.as_mut_ptr())?;
+                Ok(MarshalledValue::Struct(value))
+            }
+            None => self.marshall_as(object, ty.marshall_type),
+        }
+    }
+
+    pub(crate) fn marshall_as(
+        &self,
+        object: AnyObjectRef,
+        marshall_type: MarshallType,
+    ) -> Result<MarshalledValue, Error> {
+        match marshall_type {
+            MarshallType::Void | MarshallType::Struct => {
+                Err(Error::IllegalArgumentType(marshall_type))
+            }
             MarshallType::Bool => Ok(MarshalledValue::Bool(
                 Smalltalk::true_object() == ObjectPointer::from(object.as_i64()),
             )),
@@ -310,7 +992,100 @@ impl ArgumentMarshall {
                 let ptr = marshall_object_as_pointer(object, self.external_object_class)?;
                 Ok(MarshalledValue::Pointer(ptr))
             }
+            MarshallType::String => Ok(MarshalledValue::Buffer(BufferValue::from_string(object)?)),
+            MarshallType::Buffer | MarshallType::FloatArray | MarshallType::WordArray => Ok(
+                MarshalledValue::Buffer(BufferValue::from_object(object, marshall_type)?),
+            ),
+        }
+    }
+
+    /// Return the heap object whose memory the marshalled argument points to instead of a copy.
+    /// Such memory may be used while the garbage collector runs, for example by an asynchronous
+    /// callout, so the object must be pinned and it is an error if it is not
+    pub(crate) fn pinned_object_of(
+        &self,
+        object: AnyObjectRef,
+        ty: &ArgumentType,
+    ) -> Result<Option<ObjectRef>, Error> {
+        if ty.struct_type.is_some() || Smalltalk::nil_object() == object {
+            return Ok(None);
+        }
+
+        match ty.marshall_type {
+            MarshallType::Pointer => match heap_object_of(object, self.external_object_class)? {
+                Some(heap_object) if !heap_object.header().is_pinned() => {
+                    Err(Error::UnpinnedAsyncArgument(ty.marshall_type))
+                }
+                heap_object => Ok(heap_object),
+            },
+            // buffers that are not pinned are passed as copies
+            MarshallType::Buffer | MarshallType::FloatArray | MarshallType::WordArray => Ok(object
+                .as_object()
+                .ok()
+                .filter(|object| object.header().is_pinned())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Write a struct value given either as an array of field values
+    /// or as an external structure, address or byte object holding its bytes
+    pub(crate) fn marshall_struct_into(
+        &self,
+        object: AnyObjectRef,
+        struct_type: &StructType,
+        destination: *mut u8,
+    ) -> Result<(), Error> {
+        let is_array = object
+            .as_object()
+            .is_ok_and(|object| Smalltalk::class_of_object(object) == Smalltalk::class_array());
+
+        if !is_array {
+            // the size of memory behind an external address is unknown
+            if let Some(heap_object) = heap_object_of(object, self.external_object_class)? {
+                let byte_size = data_byte_size(heap_object);
+                if byte_size < struct_type.size() {
+                    return Err(Error::StructTooLarge(struct_type.size(), byte_size));
+                }
+            }
+            let source = marshall_object_as_pointer(object, self.external_object_class)?;
+            if source.is_null() {
+                return Err(Error::NullStruct);
+            }
+            unsafe {
+                std::ptr::copy_nonoverlapping(source as *const u8, destination, struct_type.size())
+            };
+            return Ok(());
         }
+
+        let values = ArrayRef::try_from(object)?;
+        if values.len() != struct_type.fields().len() {
+            return Err(Error::WrongNumberOfStructFields(
+                struct_type.fields().len(),
+                values.len(),
+            ));
+        }
+
+        for (field, value) in struct_type.fields().iter().zip(values.iter()) {
+            let field_destination = unsafe { destination.add(field.offset()) };
+            match field.struct_type() {
+                Some(nested) => self.marshall_struct_into(*value, nested, field_destination)?,
+                None => {
+                    let marshalled_value = self.marshall_as(*value, field.marshall_type())?;
+                    let (field_size, _) = field
+                        .marshall_type()
+                        .layout()
+                        .ok_or(Error::IllegalStructFieldType(field.marshall_type()))?;
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(
+                            marshalled_value.as_arg() as *const u8,
+                            field_destination,
+                            field_size,
+                        )
+                    };
+                }
+            }
+        }
+        Ok(())
     }
 
     fn try_marshall_as_rust_integer<T: TryFrom<i64>, F>(
@@ -369,108 +1144,322 @@ fn marshall_object_as_pointer(
     Ok(object.first_fixed_field_ptr())
 }
 
+/// Return the heap object whose contents [`marshall_object_as_pointer`] points to,
+/// or None for nil and external addresses
+fn heap_object_of(
+    any_object: AnyObjectRef,
+    external_object_class: ObjectRef,
+) -> Result<Option<ObjectRef>, Error> {
+    if Smalltalk::nil_object() == any_object {
+        return Ok(None);
+    }
+
+    let object = any_object.as_object()?;
+
+    if Smalltalk::class_of_object(object) == Smalltalk::class_external_address() {
+        return Ok(None);
+    }
+
+    if Smalltalk::is_kind_of(any_object, external_object_class) {
+        let handle = object
+            .inst_var_at(0)
+            .ok_or_else(|| Error::WrongNumberOfArguments(0))?;
+        return heap_object_of(handle, external_object_class);
+    }
+
+    Ok(Some(object))
+}
+
+/// The amount of bytes available from the first field of an object
+pub(crate) fn data_byte_size(object: ObjectRef) -> usize {
+    match object.object_format() {
+        ObjectFormat::Indexable8(_) => object.amount_of_indexable_units(),
+        ObjectFormat::Indexable16(_) => object.amount_of_indexable_units() * 2,
+        ObjectFormat::Indexable32(_) => object.amount_of_indexable_units() * 4,
+        _ => object.amount_of_slots() * std::mem::size_of::<u64>(),
+    }
+}
+
 macro_rules! call_and_marshall_integer {
-    ($callout:expr, $ty:ty) => {{
-        let value = $callout.call::<$ty>();
+    ($callout:expr, $arguments:expr, $ty:ty) => {{
+        let value = $callout.call::<$ty>($arguments);
         Ok(Smalltalk::new_integer(value))
     }};
 }
 
 macro_rules! call_and_try_marshall_integer {
-    ($callout:expr, $ty:ty) => {{
-        let value: sqInt = $callout.call::<$ty>().try_into()?;
+    ($callout:expr, $arguments:expr, $ty:ty) => {{
+        let value: sqInt = $callout.call::<$ty>($arguments).try_into()?;
+        Ok(Smalltalk::new_integer(value))
+    }};
+}
+
+macro_rules! read_and_marshall_integer {
+    ($source:expr, $ty:ty) => {{
+        let value: sqInt = std::ptr::read_unaligned($source as *const $ty).try_into()?;
         Ok(Smalltalk::new_integer(value))
     }};
 }
 
-fn call_and_marshall_result(callout: &Callout) -> Result<AnyObjectRef, Error> {
+/// Convert a scalar value stored at a given address to a Smalltalk object
+pub(crate) unsafe fn read_and_marshall_value(
+    marshall_type: MarshallType,
+    source: *const u8,
+) -> Result<AnyObjectRef, Error> {
+    match marshall_type {
+        MarshallType::Void
+        | MarshallType::Struct
+        | MarshallType::String
+        | MarshallType::Buffer
+        | MarshallType::FloatArray
+        | MarshallType::WordArray => Err(Error::IllegalStructFieldType(marshall_type)),
+        MarshallType::Bool => Ok(Smalltalk::bool_object(*source != 0).into()),
+        MarshallType::U8 => read_and_marshall_integer!(source, u8),
+        MarshallType::I8 => read_and_marshall_integer!(source, i8),
+        MarshallType::U16 => read_and_marshall_integer!(source, u16),
+        MarshallType::I16 => read_and_marshall_integer!(source, i16),
+        MarshallType::U32 => read_and_marshall_integer!(source, u32),
+        MarshallType::I32 => read_and_marshall_integer!(source, i32),
+        MarshallType::U64 => read_and_marshall_integer!(source, u64),
+        MarshallType::I64 => read_and_marshall_integer!(source, i64),
+        MarshallType::USize => read_and_marshall_integer!(source, usize),
+        MarshallType::ISize => read_and_marshall_integer!(source, isize),
+        MarshallType::SChar => read_and_marshall_integer!(source, c_schar),
+        MarshallType::UChar => read_and_marshall_integer!(source, c_uchar),
+        MarshallType::Short => read_and_marshall_integer!(source, c_short),
+        MarshallType::UShort => read_and_marshall_integer!(source, c_ushort),
+        MarshallType::Int => read_and_marshall_integer!(source, c_int),
+        MarshallType::UInt => read_and_marshall_integer!(source, c_uint),
+        MarshallType::Long => read_and_marshall_integer!(source, c_long),
+        MarshallType::ULong => read_and_marshall_integer!(source, c_ulong),
+        MarshallType::LongLong => read_and_marshall_integer!(source, c_longlong),
+        MarshallType::ULongLong => read_and_marshall_integer!(source, c_ulonglong),
+        MarshallType::F32 => {
+            let value = std::ptr::read_unaligned(source as *const f32);
+            Ok(Smalltalk::float_object_of(value as f64))
+        }
+        MarshallType::F64 => {
+            let value = std::ptr::read_unaligned(source as *const f64);
+            Ok(Smalltalk::float_object_of(value))
+        }
+        MarshallType::Pointer => {
+            let value = std::ptr::read_unaligned(source as *const *mut c_void);
+            Ok(AnyObjectRef::from(RawObjectPointer::from(
+                Smalltalk::new_external_address(value).as_i64(),
+            )))
+        }
+    }
+}
+
+/// Convert struct fields to an array of values, nested structs become nested arrays
+pub(crate) fn marshall_struct_as_array(
+    source: *const u8,
+    struct_type: &StructType,
+) -> Result<AnyObjectRef, Error> {
+    let mut values = Array::new(struct_type.fields().len())?;
+    for (index, field) in struct_type.fields().iter().enumerate() {
+        let field_source = unsafe { source.add(field.offset()) };
+        let value = match field.struct_type() {
+            Some(nested) => marshall_struct_as_array(field_source, nested)?,
+            None => unsafe { read_and_marshall_value(field.marshall_type(), field_source)? },
+        };
+        values.insert(index, value);
+    }
+    Ok(values.into())
+}
+
+/// Wrap a returned struct into an instance of the structure class with a ByteArray handle,
+/// or convert it to an array of field values if the class is nil
+fn marshall_struct_result(
+    value: &StructValue,
+    struct_type: &StructType,
+    structure_class: AnyObjectRef,
+) -> Result<AnyObjectRef, Error> {
+    if structure_class == Smalltalk::nil_object() {
+        return marshall_struct_as_array(value.as_ptr(), struct_type);
+    }
+
+    let mut handle = ByteArray::new(struct_type.size())?;
+    handle.as_slice_mut().copy_from_slice(value.as_bytes());
+
+    let mut structure = Smalltalk::instantiate_class(structure_class.as_object()?).as_object()?;
+    Smalltalk::prepare_to_store(
+        ObjectPointer::from(structure.as_ptr()),
+        ObjectPointer::from(handle.as_ptr()),
+    );
+    structure.inst_var_at_put(0, handle);
+    Ok(structure.into())
+}
+
+/// Call the function and convert its return value to a Smalltalk object.
+/// The structure class is only asked for after the call, since objects may move meanwhile
+fn call_and_marshall_result(
+    callout: &Callout,
+    arguments: &CallArguments,
+    structure_class: impl FnOnce() -> AnyObjectRef,
+) -> Result<AnyObjectRef, Error> {
     match callout.result.marshall_type {
         MarshallType::Void => {
-            callout.call::<c_void>();
+            callout.call::<c_void>(arguments);
             Ok(Smalltalk::nil_object())
         }
         MarshallType::Bool => {
-            let result = callout.call::<bool>();
+            let result = callout.call::<bool>(arguments);
             Ok(Smalltalk::bool_object(result).into())
         }
         MarshallType::U8 => {
-            call_and_marshall_integer!(callout, u8)
+            call_and_marshall_integer!(callout, arguments, u8)
         }
         MarshallType::I8 => {
-            call_and_marshall_integer!(callout, i8)
+            call_and_marshall_integer!(callout, arguments, i8)
         }
         MarshallType::U16 => {
-            call_and_marshall_integer!(callout, u16)
+            call_and_marshall_integer!(callout, arguments, u16)
         }
         MarshallType::I16 => {
-            call_and_marshall_integer!(callout, i16)
+            call_and_marshall_integer!(callout, arguments, i16)
         }
         MarshallType::U32 => {
-            call_and_try_marshall_integer!(callout, u32)
+            call_and_try_marshall_integer!(callout, arguments, u32)
         }
         MarshallType::I32 => {
-            call_and_marshall_integer!(callout, i32)
+            call_and_marshall_integer!(callout, arguments, i32)
         }
         MarshallType::U64 => {
-            call_and_try_marshall_integer!(callout, u64)
+            call_and_try_marshall_integer!(callout, arguments, u64)
         }
         MarshallType::I64 => {
-            call_and_try_marshall_integer!(callout, i64)
+            call_and_try_marshall_integer!(callout, arguments, i64)
         }
         MarshallType::USize => {
-            call_and_try_marshall_integer!(callout, usize)
+            call_and_try_marshall_integer!(callout, arguments, usize)
         }
         MarshallType::ISize => {
-            call_and_try_marshall_integer!(callout, isize)
+            call_and_try_marshall_integer!(callout, arguments, isize)
         }
         MarshallType::SChar => {
-            call_and_try_marshall_integer!(callout, c_schar)
+            call_and_try_marshall_integer!(callout, arguments, c_schar)
         }
         MarshallType::UChar => {
-            call_and_try_marshall_integer!(callout, c_uchar)
+            call_and_try_marshall_integer!(callout, arguments, c_uchar)
         }
         MarshallType::Short => {
-            call_and_try_marshall_integer!(callout, c_short)
+            call_and_try_marshall_integer!(callout, arguments, c_short)
         }
         MarshallType::UShort => {
-            call_and_try_marshall_integer!(callout, c_ushort)
+            call_and_try_marshall_integer!(callout, arguments, c_ushort)
         }
         MarshallType::Int => {
-            call_and_try_marshall_integer!(callout, c_int)
+            call_and_try_marshall_integer!(callout, arguments, c_int)
         }
         MarshallType::UInt => {
-            call_and_try_marshall_integer!(callout, c_uint)
+            call_and_try_marshall_integer!(callout, arguments, c_uint)
         }
         MarshallType::Long => {
-            call_and_try_marshall_integer!(callout, c_long)
+            call_and_try_marshall_integer!(callout, arguments, c_long)
         }
         MarshallType::ULong => {
-            call_and_try_marshall_integer!(callout, c_ulong)
+            call_and_try_marshall_integer!(callout, arguments, c_ulong)
         }
         MarshallType::LongLong => {
-            call_and_try_marshall_integer!(callout, c_longlong)
+            call_and_try_marshall_integer!(callout, arguments, c_longlong)
         }
         MarshallType::ULongLong => {
-            call_and_try_marshall_integer!(callout, c_longlong)
+            call_and_try_marshall_integer!(callout, arguments, c_longlong)
         }
         MarshallType::F32 => {
-            let result = callout.call::<f32>();
+            let result = callout.call::<f32>(arguments);
             Ok(Smalltalk::float_object_of(result as f64))
         }
         MarshallType::F64 => {
-            let result = callout.call::<f64>();
+            let result = callout.call::<f64>(arguments);
             Ok(Smalltalk::float_object_of(result))
         }
         MarshallType::Pointer => {
-            let result = callout.call::<*mut c_void>();
+            let result = callout.call::<*mut c_void>(arguments);
+            callout.track_returned_pointer(result);
             Ok(AnyObjectRef::from(RawObjectPointer::from(
                 Smalltalk::new_external_address(result).as_i64(),
             )))
         }
+        MarshallType::Struct => {
+            let struct_type = callout
+                .result
+                .struct_type
+                .as_ref()
+                .ok_or(Error::MissingStructLayout)?;
+            let mut value = StructValue::new(struct_type.size());
+            callout.call_into(arguments, value.as_mut_ptr() as *mut c_void);
+            marshall_struct_result(&value, struct_type, structure_class())
+        }
+        MarshallType::String => {
+            let result = callout.call::<*mut c_char>(arguments);
+            callout.track_returned_pointer(result as *mut c_void);
+            marshall_string_result(result, callout.deallocator)
+        }
+        MarshallType::Buffer | MarshallType::FloatArray | MarshallType::WordArray => {
+            Err(Error::IllegalReturnType(callout.result.marshall_type))
+        }
+    }
+}
+
+/// Convert a return value that libffi wrote into a given buffer to a Smalltalk object
+pub(crate) fn marshall_result_from(
+    result_marshall: &ResultMarshall,
+    value: &StructValue,
+    structure_class: AnyObjectRef,
+) -> Result<AnyObjectRef, Error> {
+    let return_type = &result_marshall.return_type;
+    match return_type.marshall_type {
+        MarshallType::Void => Ok(Smalltalk::nil_object()),
+        MarshallType::Struct => {
+            let struct_type = return_type
+                .struct_type
+                .as_ref()
+                .ok_or(Error::MissingStructLayout)?;
+            marshall_struct_result(value, struct_type, structure_class)
+        }
+        MarshallType::String => {
+            let string = unsafe { std::ptr::read(value.as_ptr() as *const *mut c_char) };
+            if result_marshall.returns_owned_memory {
+                AllocationTracker::record_owned_return(string as *mut c_void);
+            }
+            marshall_string_result(string, result_marshall.deallocator)
+        }
+        MarshallType::Pointer => {
+            let pointer = unsafe { std::ptr::read(value.as_ptr() as *const *mut c_void) };
+            if result_marshall.returns_owned_memory {
+                AllocationTracker::record_owned_return(pointer);
+            }
+            unsafe { read_and_marshall_value(MarshallType::Pointer, value.as_ptr()) }
+        }
+        MarshallType::Buffer | MarshallType::FloatArray | MarshallType::WordArray => {
+            Err(Error::IllegalReturnType(return_type.marshall_type))
+        }
+        marshall_type => unsafe { read_and_marshall_value(marshall_type, value.as_ptr()) },
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, FromPrimitive, EnumDisplay)]
+/// Copy a returned NUL-terminated string into a ByteString, nil for a null pointer
+pub(crate) fn marshall_string_result(
+    string: *mut c_char,
+    deallocator: Option<unsafe extern "C" fn(*mut c_void)>,
+) -> Result<AnyObjectRef, Error> {
+    if string.is_null() {
+        return Ok(Smalltalk::nil_object());
+    }
+
+    let bytes = string_result_bytes(unsafe { CStr::from_ptr(string) }.to_bytes());
+    if let Some(deallocator) = deallocator {
+        if AllocationTracker::record_deallocation(string as *mut c_void) {
+            unsafe { deallocator(string as *mut c_void) };
+        }
+    }
+
+    Ok(ByteString::new(bytes.as_slice())?.into())
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, FromPrimitive, EnumDisplay)]
 #[repr(u8)]
 pub enum MarshallType {
     Void,
@@ -498,9 +1487,76 @@ pub enum MarshallType {
     F32,
     F64,
     Pointer,
+    Struct,
+    /// NUL-terminated UTF-8 string, from a ByteString or a WideString
+    String,
+    /// bytes of a ByteArray
+    Buffer,
+    /// 32-bit floats of a FloatArray
+    FloatArray,
+    /// 32-bit words of a WordArray
+    WordArray,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl MarshallType {
+    /// The type of a variadic argument after the C default argument promotions
+    pub fn promoted(&self) -> MarshallType {
+        match self {
+            MarshallType::Bool
+            | MarshallType::U8
+            | MarshallType::I8
+            | MarshallType::U16
+            | MarshallType::I16
+            | MarshallType::SChar
+            | MarshallType::UChar
+            | MarshallType::Short
+            | MarshallType::UShort => MarshallType::Int,
+            MarshallType::F32 => MarshallType::F64,
+            _ => *self,
+        }
+    }
+
+    /// Size and alignment in bytes of a scalar type when stored in memory, None for void and structs
+    pub fn layout(&self) -> Option<(usize, usize)> {
+        fn layout_of<T>() -> Option<(usize, usize)> {
+            Some((std::mem::size_of::<T>(), std::mem::align_of::<T>()))
+        }
+
+        match self {
+            MarshallType::Void
+            | MarshallType::Struct
+            | MarshallType::String
+            | MarshallType::Buffer
+            | MarshallType::FloatArray
+            | MarshallType::WordArray => None,
+            MarshallType::Bool | MarshallType::U8 => layout_of::<u8>(),
+            MarshallType::I8 => layout_of::<i8>(),
+            MarshallType::U16 => layout_of::<u16>(),
+            MarshallType::I16 => layout_of::<i16>(),
+            MarshallType::U32 => layout_of::<u32>(),
+            MarshallType::I32 => layout_of::<i32>(),
+            MarshallType::U64 => layout_of::<u64>(),
+            MarshallType::I64 => layout_of::<i64>(),
+            MarshallType::USize => layout_of::<usize>(),
+            MarshallType::ISize => layout_of::<isize>(),
+            MarshallType::SChar => layout_of::<c_schar>(),
+            MarshallType::UChar => layout_of::<c_uchar>(),
+            MarshallType::Short => layout_of::<c_short>(),
+            MarshallType::UShort => layout_of::<c_ushort>(),
+            MarshallType::Int => layout_of::<c_int>(),
+            MarshallType::UInt => layout_of::<c_uint>(),
+            MarshallType::Long => layout_of::<c_long>(),
+            MarshallType::ULong => layout_of::<c_ulong>(),
+            MarshallType::LongLong => layout_of::<c_longlong>(),
+            MarshallType::ULongLong => layout_of::<c_ulonglong>(),
+            MarshallType::F32 => layout_of::<f32>(),
+            MarshallType::F64 => layout_of::<f64>(),
+            MarshallType::Pointer => layout_of::<*const c_void>(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum MarshalledValue {
     Void,
     Bool(bool),
@@ -527,9 +1583,28 @@ pub enum MarshalledValue {
     F32(f32),
     F64(f64),
     Pointer(*const c_void),
+    Struct(StructValue),
+    Buffer(BufferValue),
 }
 
 impl MarshalledValue {
+    /// Apply the C default argument promotions to a variadic argument
+    pub fn promoted(self) -> MarshalledValue {
+        match self {
+            MarshalledValue::Bool(v) => MarshalledValue::Int(v as c_int),
+            MarshalledValue::U8(v) => MarshalledValue::Int(v as c_int),
+            MarshalledValue::I8(v) => MarshalledValue::Int(v as c_int),
+            MarshalledValue::U16(v) => MarshalledValue::Int(v as c_int),
+            MarshalledValue::I16(v) => MarshalledValue::Int(v as c_int),
+            MarshalledValue::SChar(v) => MarshalledValue::Int(v as c_int),
+            MarshalledValue::UChar(v) => MarshalledValue::Int(v as c_int),
+            MarshalledValue::Short(v) => MarshalledValue::Int(v as c_int),
+            MarshalledValue::UShort(v) => MarshalledValue::Int(v as c_int),
+            MarshalledValue::F32(v) => MarshalledValue::F64(v as f64),
+            value => value,
+        }
+    }
+
     pub fn as_arg(&self) -> *mut c_void {
         fn as_ptr<T>(arg: &T) -> *mut c_void {
             arg as *const T as *mut c_void
@@ -563,6 +1638,8 @@ impl MarshalledValue {
             MarshalledValue::F32(v) => as_ptr(v),
             MarshalledValue::F64(v) => as_ptr(v),
             MarshalledValue::Pointer(v) => as_ptr(v),
+            MarshalledValue::Struct(v) => v.as_ptr() as *mut c_void,
+            MarshalledValue::Buffer(v) => v.as_arg(),
         }
     }
 }
@@ -595,6 +1672,12 @@ impl From<MarshallType> for Type {
             MarshallType::F32 => Self::f32(),
             MarshallType::F64 => Self::f64(),
             MarshallType::Pointer => Self::pointer(),
+            // the actual layout is described by a StructType
+            MarshallType::Struct => Self::structure(std::iter::empty::<Type>()),
+            MarshallType::String
+            | MarshallType::Buffer
+            | MarshallType::FloatArray
+            | MarshallType::WordArray => Self::pointer(),
         }
     }
 }
@@ -631,3 +1714,73 @@ impl TryFrom<BareFFITypeRef> for MarshallType {
         Ok(value.try_into()?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static NESTED_QSORT: Cell<Option<*const Callout>> = const { Cell::new(None) };
+        static NESTED_ARRAY: RefCell<Vec<i32>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Sorts another array through the same callout the first time it is called,
+    /// like a callback that enters the image which calls the same external function again
+    extern "C" fn compare_and_sort_nested(a: *const c_void, b: *const c_void) -> c_int {
+        if let Some(callout) = NESTED_QSORT.take() {
+            NESTED_ARRAY.with_borrow_mut(|nested| {
+                let arguments = qsort_arguments(nested, compare);
+                unsafe { &*callout }.call::<c_void>(&arguments);
+            });
+        }
+        compare(a, b)
+    }
+
+    extern "C" fn compare(a: *const c_void, b: *const c_void) -> c_int {
+        let (a, b) = unsafe { (*(a as *const i32), *(b as *const i32)) };
+        a.cmp(&b) as c_int
+    }
+
+    fn qsort_arguments(
+        array: &mut [i32],
+        comparator: extern "C" fn(*const c_void, *const c_void) -> c_int,
+    ) -> CallArguments {
+        CallArguments::new(
+            vec![
+                MarshalledValue::Pointer(array.as_mut_ptr() as *const c_void),
+                MarshalledValue::USize(array.len()),
+                MarshalledValue::USize(std::mem::size_of::<i32>()),
+                MarshalledValue::Pointer(comparator as *const c_void),
+            ],
+            None,
+        )
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn nested_call_of_the_same_callout_keeps_outer_arguments() {
+        let callout = Callout::new(
+            "libc.so.6".into(),
+            "qsort".into(),
+            vec![
+                MarshallType::Pointer,
+                MarshallType::USize,
+                MarshallType::USize,
+                MarshallType::Pointer,
+            ],
+            MarshallType::Void,
+        )
+        .unwrap();
+
+        NESTED_QSORT.set(Some(&callout));
+        NESTED_ARRAY.set(vec![9, 7, 8, 6, 5]);
+
+        let mut outer = vec![3, 1, 4, 1, 5, 9, 2, 6];
+        let arguments = qsort_arguments(&mut outer, compare_and_sort_nested);
+        callout.call::<c_void>(&arguments);
+
+        assert_eq!(outer, vec![1, 1, 2, 3, 4, 5, 6, 9]);
+        assert_eq!(NESTED_ARRAY.take(), vec![5, 6, 7, 8, 9]);
+    }
+}
@@ -0,0 +1,285 @@
+use std::cell::UnsafeCell;
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use vm_bindings::Smalltalk;
+use vm_object_model::{AnyObjectRef, RawObjectPointer};
+
+use crate::ffi::{
+    marshall_result_from, Callout, DetachedCall, Error, ExternalFunctionRef, MarshalledValue,
+    ResultMarshall, StructValue,
+};
+use crate::objects::{Array, ExternalAddressRef};
+use crate::{vm, AllocationTracker};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Asynchronous calls whose result is not read yet,
+/// their addresses are the handles the image reads the results with
+static RUNNING_ASYNC_CALLOUTS: Mutex<Vec<Arc<AsyncCallout>>> = Mutex::new(Vec::new());
+
+/// A fixed amount of threads running asynchronous callouts
+#[derive(Debug)]
+pub struct FfiWorkerPool {
+    sender: Mutex<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl FfiWorkerPool {
+    pub fn new(amount_of_threads: usize) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..amount_of_threads.max(1))
+            .map(|index| {
+                let receiver = receiver.clone();
+                std::thread::Builder::new()
+                    .name(format!("FFI worker {}", index))
+                    .spawn(move || Self::run_worker(receiver))
+                    .expect("Failed to spawn an FFI worker thread")
+            })
+            .collect();
+
+        Self {
+            sender: Mutex::new(sender),
+            workers,
+        }
+    }
+
+    /// One thread per available CPU
+    pub fn default_amount_of_threads() -> usize {
+        std::thread::available_parallelism()
+            .map(|amount| amount.get())
+            .unwrap_or(1)
+    }
+
+    pub fn amount_of_threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.sender
+            .lock()
+            .unwrap()
+            .send(Box::new(job))
+            .expect("FFI workers must be running");
+    }
+
+    fn run_worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = receiver.lock().unwrap().recv();
+            match job {
+                Ok(job) => job(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// A call of a bare FFI function on a worker thread.
+/// Each call owns its call interface, arguments and the result, so that several calls
+/// of the same function can run at the same time and the callout can be released meanwhile.
+#[derive(Debug)]
+pub struct AsyncCallout {
+    call: DetachedCall,
+    /// the return type of the callout that started the call
+    result_marshall: ResultMarshall,
+    marshalled_arguments: Vec<MarshalledValue>,
+    arguments: Vec<*mut c_void>,
+    result: UnsafeCell<StructValue>,
+    errno: Mutex<Option<i32>>,
+    semaphore_index: usize,
+    is_finished: AtomicBool,
+}
+
+// the arguments and the result are not accessed by the VM thread until the call is finished
+unsafe impl Send for AsyncCallout {}
+unsafe impl Sync for AsyncCallout {}
+
+impl AsyncCallout {
+    fn new(
+        callout: &Callout,
+        marshalled_arguments: Vec<MarshalledValue>,
+        semaphore_index: usize,
+    ) -> Self {
+        let result_marshall = callout.result_marshall();
+        let result_size = result_marshall
+            .return_type()
+            .struct_type()
+            .map(|struct_type| struct_type.size())
+            .unwrap_or(0);
+
+        let mut async_callout = Self {
+            call: callout.detached_call(),
+            result_marshall,
+            marshalled_arguments,
+            arguments: vec![],
+            // libffi widens integral return values to the size of a register
+            result: UnsafeCell::new(StructValue::new(result_size.max(size_of::<u64>()))),
+            errno: Mutex::new(None),
+            semaphore_index,
+            is_finished: AtomicBool::new(false),
+        };
+        async_callout.arguments = async_callout
+            .marshalled_arguments
+            .iter()
+            .map(|each| each.as_arg())
+            .collect();
+        async_callout
+    }
+
+    fn call(&self) {
+        let result = unsafe { (*self.result.get()).as_mut_ptr() };
+        let errno = self
+            .call
+            .call_into(self.arguments.as_slice(), result as *mut c_void);
+        *self.errno.lock().unwrap() = errno;
+        self.is_finished.store(true, Ordering::Release);
+        vm().proxy().signal_semaphore(self.semaphore_index);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.is_finished.load(Ordering::Acquire)
+    }
+
+    /// The return value written by libffi, only valid once the call is finished
+    fn result(&self) -> &StructValue {
+        unsafe { &*self.result.get() }
+    }
+
+    /// The errno captured right after the call, if the callout captures errno
+    fn errno(&self) -> Option<i32> {
+        *self.errno.lock().unwrap()
+    }
+
+    /// Keep a call until its result is read, answers the handle that identifies it
+    fn register(async_callout: Arc<AsyncCallout>) -> *const AsyncCallout {
+        let handle = Arc::as_ptr(&async_callout);
+        RUNNING_ASYNC_CALLOUTS.lock().unwrap().push(async_callout);
+        handle
+    }
+
+    /// Remove a finished call with a given handle, fails if there is no such call
+    /// or if it is not finished yet, in which case it stays registered
+    fn take_finished(handle: *const AsyncCallout) -> Result<Arc<AsyncCallout>, Error> {
+        let mut async_callouts = RUNNING_ASYNC_CALLOUTS.lock().unwrap();
+        let index = async_callouts
+            .iter()
+            .position(|each| Arc::as_ptr(each) == handle)
+            .ok_or(Error::UnknownAsyncCallout)?;
+        if !async_callouts[index].is_finished() {
+            return Err(Error::AsyncCalloutNotFinished);
+        }
+        Ok(async_callouts.swap_remove(index))
+    }
+}
+
+/// Start a call of an external function on an FFI worker thread.
+/// The declared arguments are followed by an index of a semaphore signalled when the call finishes.
+/// Answers an array with a handle to read the result with `primitiveBareFfiAsyncCalloutResult`
+/// and an array of the pinned objects whose memory the call uses.
+/// The garbage collector runs during the call, so objects passed as pointers must be pinned
+/// and the image must keep the answered array until the result is read.
+/// Buffers that are not pinned are passed as copies and are not written back.
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiAsyncCallout() -> Result<(), Error> {
+    let mut external_function =
+        unsafe { ExternalFunctionRef::from_any_object_unchecked(Smalltalk::method_receiver()) };
+    external_function.invalidate()?;
+
+    let marshaller = external_function.marshaller();
+    let callout = external_function.callout_mut();
+
+    let amount_of_function_arguments = Smalltalk::method_argument_count();
+    if amount_of_function_arguments != callout.argument_types().len() + 1 {
+        Err(Error::WrongNumberOfArguments(amount_of_function_arguments))?;
+    }
+
+    let semaphore_index: usize = Smalltalk::get_method_argument(callout.argument_types().len())
+        .as_immediate()?
+        .try_as_integer()?
+        .try_into()?;
+
+    let mut marshalled_arguments = Vec::with_capacity(callout.argument_types().len());
+    let mut pinned_objects = vec![];
+    for (each_argument_index, each_arg_type) in callout.argument_types().iter().enumerate() {
+        let each_object = Smalltalk::get_method_argument(each_argument_index);
+        pinned_objects.extend(marshaller.pinned_object_of(each_object, each_arg_type)?);
+        marshalled_arguments.push(marshaller.marshall(each_object, each_arg_type)?);
+    }
+
+    // pinned objects do not move, so they can be collected before the call starts
+    let mut pinned_objects_array = Array::new(pinned_objects.len())?;
+    for (index, each_object) in pinned_objects.into_iter().enumerate() {
+        pinned_objects_array.insert(index, each_object);
+    }
+
+    let async_callout = Arc::new(AsyncCallout::new(
+        callout,
+        marshalled_arguments,
+        semaphore_index,
+    ));
+
+    // the registry keeps the call alive until its result is read, the handle only identifies it
+    let async_callout_ptr = AsyncCallout::register(async_callout.clone());
+    vm().ffi_worker_pool().execute(move || async_callout.call());
+
+    let mut result = Array::new(2)?;
+    result.insert(
+        0,
+        AnyObjectRef::from(RawObjectPointer::from(
+            Smalltalk::new_external_address(async_callout_ptr).as_i64(),
+        )),
+    );
+    result.insert(1, pinned_objects_array);
+    Smalltalk::method_return(result);
+    Ok(())
+}
+
+/// Answer the result of a finished asynchronous call, release it and null its handle.
+/// The result is converted with the return type of the external function that started the call,
+/// the receiver only provides the structure class if it returns the same struct.
+/// The errno captured after the call becomes the errno of the external function.
+/// Fails if the call is not finished yet or its result was already read
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiAsyncCalloutResult() -> Result<(), Error> {
+    let _stack = AllocationTracker::enter_primitive();
+    let mut external_function =
+        unsafe { ExternalFunctionRef::from_any_object_unchecked(Smalltalk::method_receiver()) };
+    external_function.invalidate()?;
+
+    let mut handle = ExternalAddressRef::try_from(Smalltalk::get_method_argument(0))?;
+    let async_callout_ptr = handle.read_address() as *const AsyncCallout;
+    if async_callout_ptr.is_null() {
+        return Err(Error::NullAsyncCallout);
+    }
+    let async_callout = AsyncCallout::take_finished(async_callout_ptr)?;
+    handle.set_address(std::ptr::null());
+
+    let callout = external_function.callout_mut();
+    callout.set_errno(async_callout.errno());
+
+    let result_struct_type = async_callout.result_marshall.return_type().struct_type();
+    let structure_class = if result_struct_type.is_some()
+        && result_struct_type == callout.return_type().struct_type()
+    {
+        external_function.return_structure_class()
+    } else {
+        Smalltalk::nil_object()
+    };
+
+    let result = marshall_result_from(
+        &async_callout.result_marshall,
+        async_callout.result(),
+        structure_class,
+    )?;
+
+    Smalltalk::method_return(result);
+    Ok(())
+}
@@ -0,0 +1,331 @@
+use std::mem::size_of;
+use std::os::raw::c_void;
+
+use vm_bindings::Smalltalk;
+use vm_object_model::{AnyObjectRef, Immediate, Object, ObjectFormat, ObjectRef};
+
+use crate::ffi::{data_byte_size, is_kind_of_class_named, Error, MarshallType};
+use crate::objects::ByteStringRef;
+
+/// Describes a `char*` return type converted to a ByteString.
+/// If `deallocator` is a name of a function (such as `free`) exported by the same library,
+/// it is called with the returned pointer after the string is copied.
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct BareFFIStringType {
+    this: Object,
+    value: Immediate,
+    name: ByteStringRef,
+    deallocator: AnyObjectRef,
+}
+
+impl BareFFIStringType {
+    pub fn deallocator_name(&self) -> Option<String> {
+        ByteStringRef::try_from(self.deallocator)
+            .ok()
+            .map(|name| name.to_string())
+    }
+}
+
+/// Data passed to C by pointer. Pinned objects are passed directly,
+/// other objects are copied and the copy is written back to the object after the call,
+/// so that the object can not be moved by the garbage collector while C holds its address.
+/// Strings are always converted to a NUL-terminated UTF-8 copy.
+#[derive(Debug)]
+pub struct BufferValue {
+    words: Vec<u64>,
+    size: usize,
+    pointer: *const c_void,
+    /// whether the copy is written back after the call, strings are not
+    should_copy_back: bool,
+}
+
+impl BufferValue {
+    pub fn null() -> Self {
+        Self {
+            words: vec![],
+            size: 0,
+            pointer: std::ptr::null(),
+            should_copy_back: false,
+        }
+    }
+
+    /// Marshall a ByteString or a WideString (or a symbol) as a NUL-terminated UTF-8 string.
+    /// Fails if the string contains a NUL character, C would only see the part before it
+    pub fn from_string(object: AnyObjectRef) -> Result<Self, Error> {
+        if object == Smalltalk::nil_object() {
+            return Ok(Self::null());
+        }
+
+        let object = object
+            .as_object()
+            .map_err(|_| Error::InstanceNotOfType(MarshallType::String))?;
+        if !is_kind_of_class_named(object, &["String"]) {
+            return Err(Error::InstanceNotOfType(MarshallType::String));
+        }
+
+        let bytes = match object.object_format() {
+            ObjectFormat::Indexable8(_) => {
+                nul_terminated_utf8(object_bytes(object).iter().map(|each| Ok(*each as char)))?
+            }
+            ObjectFormat::Indexable32(_) => nul_terminated_utf8(
+                object_words(object)
+                    .iter()
+                    .map(|each| char::from_u32(*each).ok_or(Error::InvalidCharacter(*each))),
+            )?,
+            _ => return Err(Error::InstanceNotOfType(MarshallType::String)),
+        };
+
+        Ok(Self::copy_of(&bytes, false))
+    }
+
+    /// Marshall a byte object (`Buffer`) or a 32-bit word object (`FloatArray` and `WordArray`)
+    pub fn from_object(object: AnyObjectRef, marshall_type: MarshallType) -> Result<Self, Error> {
+        if object == Smalltalk::nil_object() {
+            return Ok(Self::null());
+        }
+
+        let object = object
+            .as_object()
+            .map_err(|_| Error::InstanceNotOfType(marshall_type))?;
+
+        // the format alone would accept strings or bitmaps as numeric arrays
+        let (class_names, unit_size): (&[&str], usize) =
+            match (marshall_type, object.object_format()) {
+                (MarshallType::Buffer, ObjectFormat::Indexable8(_)) => {
+                    (&["ByteArray"], size_of::<u8>())
+                }
+                (MarshallType::FloatArray, ObjectFormat::Indexable32(_)) => {
+                    (&["FloatArray", "Float32Array"], size_of::<u32>())
+                }
+                (MarshallType::WordArray, ObjectFormat::Indexable32(_)) => {
+                    (&["WordArray"], size_of::<u32>())
+                }
+                _ => return Err(Error::InstanceNotOfType(marshall_type)),
+            };
+        if !is_kind_of_class_named(object, class_names) {
+            return Err(Error::InstanceNotOfType(marshall_type));
+        }
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                object.first_fixed_field_ptr() as *const u8,
+                object.amount_of_indexable_units() * unit_size,
+            )
+        };
+
+        if object.header().is_pinned() {
+            return Ok(Self {
+                words: vec![],
+                size: bytes.len(),
+                pointer: object.first_fixed_field_ptr(),
+                should_copy_back: false,
+            });
+        }
+
+        Ok(Self::copy_of(bytes, true))
+    }
+
+    fn copy_of(bytes: &[u8], should_copy_back: bool) -> Self {
+        let mut words = vec![0u64; bytes.len().div_ceil(size_of::<u64>()).max(1)];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                words.as_mut_ptr() as *mut u8,
+                bytes.len(),
+            )
+        };
+
+        Self {
+            pointer: words.as_ptr() as *const c_void,
+            words,
+            size: bytes.len(),
+            should_copy_back,
+        }
+    }
+
+    pub fn as_arg(&self) -> *mut c_void {
+        &self.pointer as *const *const c_void as *mut c_void
+    }
+
+    /// Write the possibly modified copy back to the object it was made from.
+    /// The object must be read again after the call, as it may have moved meanwhile,
+    /// and is left as is if it no longer holds as many bytes as the copy
+    pub fn copy_back_to(&self, object: AnyObjectRef) {
+        if !self.should_copy_back {
+            return;
+        }
+        let Ok(object) = object.as_object() else {
+            return;
+        };
+        if data_byte_size(object) < self.size {
+            return;
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.words.as_ptr() as *const u8,
+                object.first_fixed_field_ptr() as *mut u8,
+                self.size,
+            )
+        };
+    }
+}
+
+impl Clone for BufferValue {
+    fn clone(&self) -> Self {
+        // a copy must point to its own words
+        let words = self.words.clone();
+        let pointer = if self.words.is_empty() {
+            self.pointer
+        } else {
+            words.as_ptr() as *const c_void
+        };
+
+        Self {
+            words,
+            size: self.size,
+            pointer,
+            should_copy_back: self.should_copy_back,
+        }
+    }
+}
+
+/// Convert bytes returned from C to the contents of a ByteString.
+/// UTF-8 is decoded if all characters fit into a byte, otherwise the bytes are kept as is.
+pub fn string_result_bytes(bytes: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(bytes) {
+        Ok(string) if string.chars().all(|each| (each as u32) <= u8::MAX as u32) => {
+            string.chars().map(|each| each as u8).collect()
+        }
+        _ => bytes.to_vec(),
+    }
+}
+
+/// Encode characters as UTF-8 followed by a NUL, failing on a NUL character within the string
+fn nul_terminated_utf8(
+    characters: impl Iterator<Item = Result<char, Error>>,
+) -> Result<Vec<u8>, Error> {
+    let mut string = String::new();
+    for (index, character) in characters.enumerate() {
+        let character = character?;
+        if character == '\0' {
+            return Err(Error::InteriorNulCharacter(index + 1));
+        }
+        string.push(character);
+    }
+
+    let mut bytes = string.into_bytes();
+    bytes.push(0);
+    Ok(bytes)
+}
+
+fn object_bytes<'a>(object: ObjectRef) -> &'a [u8] {
+    unsafe {
+        std::slice::from_raw_parts(
+            object.first_fixed_field_ptr() as *const u8,
+            object.amount_of_indexable_units(),
+        )
+    }
+}
+
+fn object_words<'a>(object: ObjectRef) -> &'a [u32] {
+    unsafe {
+        std::slice::from_raw_parts(
+            object.first_fixed_field_ptr() as *const u32,
+            object.amount_of_indexable_units(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes_of(buffer: &BufferValue) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(buffer.pointer as *const u8, buffer.size) }
+    }
+
+    fn characters(string: &str) -> impl Iterator<Item = Result<char, Error>> + '_ {
+        string.chars().map(Ok)
+    }
+
+    #[test]
+    fn ascii_result_is_kept() {
+        assert_eq!(string_result_bytes(b"hello"), b"hello".to_vec());
+    }
+
+    #[test]
+    fn latin1_result_is_decoded() {
+        assert_eq!(
+            string_result_bytes("caf\u{e9}".as_bytes()),
+            vec![b'c', b'a', b'f', 0xE9]
+        );
+    }
+
+    #[test]
+    fn wide_result_keeps_utf8_bytes() {
+        let string = "\u{1F600}";
+        assert_eq!(
+            string_result_bytes(string.as_bytes()),
+            string.as_bytes().to_vec()
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_result_is_kept() {
+        assert_eq!(string_result_bytes(&[0xFF, 0x41]), vec![0xFF, 0x41]);
+    }
+
+    #[test]
+    fn strings_are_nul_terminated_utf8() {
+        assert_eq!(
+            nul_terminated_utf8(characters("caf\u{e9}")).unwrap(),
+            vec![b'c', b'a', b'f', 0xC3, 0xA9, 0]
+        );
+        assert_eq!(nul_terminated_utf8(characters("")).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn interior_nul_is_rejected() {
+        assert!(matches!(
+            nul_terminated_utf8(characters("ab\0c")),
+            Err(Error::InteriorNulCharacter(3))
+        ));
+    }
+
+    #[test]
+    fn copy_holds_the_bytes() {
+        let buffer = BufferValue::copy_of(&[1, 2, 3, 4, 5, 6, 7, 8, 9], true);
+        assert_eq!(buffer.size, 9);
+        assert_eq!(buffer.words.len(), 2);
+        assert_eq!(buffer.pointer, buffer.words.as_ptr() as *const c_void);
+        assert_eq!(bytes_of(&buffer), &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(buffer.should_copy_back);
+    }
+
+    #[test]
+    fn copy_of_nothing_is_not_null() {
+        let buffer = BufferValue::copy_of(&[], false);
+        assert_eq!(buffer.size, 0);
+        assert!(!buffer.pointer.is_null());
+    }
+
+    #[test]
+    fn clone_points_to_its_own_words() {
+        let buffer = BufferValue::copy_of(&[1, 2, 3], true);
+        let clone = buffer.clone();
+        assert_ne!(clone.pointer, buffer.pointer);
+        assert_eq!(clone.pointer, clone.words.as_ptr() as *const c_void);
+        assert_eq!(bytes_of(&clone), &[1, 2, 3]);
+        assert!(clone.should_copy_back);
+
+        drop(buffer);
+        assert_eq!(bytes_of(&clone), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn clone_of_null_stays_null() {
+        let clone = BufferValue::null().clone();
+        assert!(clone.pointer.is_null());
+        assert_eq!(clone.size, 0);
+    }
+}
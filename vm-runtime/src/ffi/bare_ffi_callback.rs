@@ -0,0 +1,673 @@
+use std::collections::VecDeque;
+use std::os::raw::{c_char, c_void};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::ThreadId;
+
+use libffi::low::{ffi_arg, ffi_cif, ffi_sarg};
+use libffi::middle::{Cif, Closure};
+use vm_bindings::Smalltalk;
+use vm_object_model::{AnyObjectRef, Immediate, Object, ObjectRef, RawObjectPointer};
+
+use crate::ffi::{
+    marshall_string_result, marshall_struct_as_array, read_and_marshall_value, ArgumentMarshall,
+    ArgumentType, Error, MarshallType, MarshalledValue, ReturnType,
+};
+use crate::objects::{Array, ArrayRef, ExternalAddressRef};
+use crate::vm;
+
+/// A C function pointer that calls back into Smalltalk.
+/// When invoked, the arguments are queued as an invocation and the semaphore is signalled.
+/// The image takes the invocation with `primitiveBareFfiCallbackNextInvocation`,
+/// evaluates the `block` and answers its result with `primitiveBareFfiCallbackReturn`.
+/// A callback must be released explicitly with `primitiveBareFfiCallbackRelease`,
+/// C code must not call it afterwards.
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct BareFFICallback {
+    this: Object,
+    handle: ExternalAddressRef,
+    argument_types: ArrayRef,
+    return_type: AnyObjectRef,
+    semaphore_index: Immediate,
+    block: AnyObjectRef,
+    external_object_class: ObjectRef,
+    external_enumeration_class: ObjectRef,
+}
+
+impl BareFFICallback {
+    fn callback(&self) -> Result<&Callback, Error> {
+        if self.handle.is_null() {
+            return Err(Error::CallbackReleased);
+        }
+        Ok(unsafe { &*(self.handle.read_address() as *const Callback) })
+    }
+
+    fn invalidate(&mut self) -> Result<(), Error> {
+        if self.handle.is_null() {
+            let callback = self.new_callback()?;
+            let callback_ptr = Box::into_raw(Box::new(callback));
+            self.handle.set_address(callback_ptr as *mut c_void);
+        }
+        Ok(())
+    }
+
+    fn new_callback(&self) -> Result<Callback, Error> {
+        let result = ReturnType::try_from(self.return_type)?;
+
+        let mut argument_types = Vec::with_capacity(self.argument_types.len());
+        for each_type in self.argument_types.iter() {
+            argument_types.push(ArgumentType::try_from(*each_type)?);
+        }
+
+        let semaphore_index: usize = self.semaphore_index.try_as_integer()?.try_into()?;
+        Callback::new(argument_types, result, semaphore_index)
+    }
+
+    fn marshaller(&self) -> ArgumentMarshall {
+        ArgumentMarshall::new(self.external_object_class, self.external_enumeration_class)
+    }
+}
+
+#[derive(Debug)]
+pub struct Callback {
+    // the closure refers to the signature, so it must be dropped first
+    closure: Closure<'static>,
+    signature: Box<CallbackSignature>,
+}
+
+/// Signals the semaphore with a given index, a seam so that callbacks can be invoked without a VM
+type SemaphoreSignal = fn(usize);
+
+#[derive(Debug)]
+struct CallbackSignature {
+    argument_types: Vec<ArgumentType>,
+    result: ReturnType,
+    semaphore_index: usize,
+    /// tells the image that an invocation is pending
+    signal_semaphore: SemaphoreSignal,
+    /// invocations from the VM thread re-enter the interpreter,
+    /// others wait until the image returns a value
+    vm_thread: ThreadId,
+    pending_invocations: Mutex<VecDeque<Arc<CallbackInvocation>>>,
+    /// invocations taken by the image that did not return yet,
+    /// their addresses are the handles the image returns values to
+    taken_invocations: Mutex<Vec<Arc<CallbackInvocation>>>,
+}
+
+impl Callback {
+    pub fn new(
+        argument_types: Vec<ArgumentType>,
+        result: ReturnType,
+        semaphore_index: usize,
+    ) -> Result<Self, Error> {
+        Self::with_semaphore_signal(argument_types, result, semaphore_index, |semaphore_index| {
+            vm().proxy().signal_semaphore(semaphore_index)
+        })
+    }
+
+    fn with_semaphore_signal(
+        argument_types: Vec<ArgumentType>,
+        result: ReturnType,
+        semaphore_index: usize,
+        signal_semaphore: SemaphoreSignal,
+    ) -> Result<Self, Error> {
+        if let Some(each_type) = argument_types
+            .iter()
+            .find(|each| each.marshall_type() == MarshallType::Void)
+        {
+            return Err(Error::IllegalArgumentType(each_type.marshall_type()));
+        }
+
+        // the memory of a returned string or buffer would not outlive the callback
+        match result.marshall_type() {
+            MarshallType::String
+            | MarshallType::Buffer
+            | MarshallType::FloatArray
+            | MarshallType::WordArray => {
+                return Err(Error::IllegalReturnType(result.marshall_type()))
+            }
+            _ => {}
+        }
+
+        let cif = Cif::new(
+            argument_types.iter().map(|each| each.ffi_type().clone()),
+            result.ffi_type().clone(),
+        );
+
+        let signature = Box::new(CallbackSignature {
+            argument_types,
+            result,
+            semaphore_index,
+            signal_semaphore,
+            vm_thread: std::thread::current().id(),
+            pending_invocations: Mutex::new(VecDeque::new()),
+            taken_invocations: Mutex::new(vec![]),
+        });
+
+        // the signature is boxed and lives as long as the closure
+        let userdata = unsafe { &*(signature.as_ref() as *const CallbackSignature) };
+        let closure = Closure::new(cif, invoke_callback, userdata);
+
+        Ok(Self { closure, signature })
+    }
+
+    /// The address of a C function that invokes the callback
+    pub fn code_ptr(&self) -> *const c_void {
+        *self.closure.code_ptr() as *const c_void
+    }
+
+    fn first_pending_invocation(&self) -> Option<Arc<CallbackInvocation>> {
+        self.signature
+            .pending_invocations
+            .lock()
+            .unwrap()
+            .front()
+            .cloned()
+    }
+
+    /// Move the first pending invocation to the taken ones and answer its handle
+    fn take_first_pending_invocation(&self) -> Option<*const CallbackInvocation> {
+        let invocation = self
+            .signature
+            .pending_invocations
+            .lock()
+            .unwrap()
+            .pop_front()?;
+        let handle = Arc::as_ptr(&invocation);
+        self.signature
+            .taken_invocations
+            .lock()
+            .unwrap()
+            .push(invocation);
+        Some(handle)
+    }
+
+    fn taken_invocation(
+        &self,
+        handle: *const CallbackInvocation,
+    ) -> Option<Arc<CallbackInvocation>> {
+        self.signature
+            .taken_invocations
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|each| Arc::as_ptr(each) == handle)
+            .cloned()
+    }
+
+    fn remove_taken_invocation(&self, invocation: &Arc<CallbackInvocation>) {
+        self.signature
+            .taken_invocations
+            .lock()
+            .unwrap()
+            .retain(|each| !Arc::ptr_eq(each, invocation));
+    }
+
+    /// Answer a default result to all invocations waiting on other threads.
+    /// Fails without completing any invocation if one of them runs on the VM thread,
+    /// since its C caller is further down the stack of the interpreter.
+    fn complete_invocations(&self) -> Result<(), Error> {
+        let mut pending_invocations = self.signature.pending_invocations.lock().unwrap();
+        let mut taken_invocations = self.signature.taken_invocations.lock().unwrap();
+
+        if pending_invocations
+            .iter()
+            .chain(taken_invocations.iter())
+            .any(|each| each.context.is_some())
+        {
+            return Err(Error::CallbackInUse);
+        }
+
+        for invocation in pending_invocations
+            .drain(..)
+            .chain(taken_invocations.drain(..))
+        {
+            self.write_default_result(invocation.result);
+            invocation.notify_returned();
+        }
+        Ok(())
+    }
+
+    fn marshall_arguments(
+        &self,
+        invocation: &CallbackInvocation,
+        values: &mut ArrayRef,
+        first_index: usize,
+    ) -> Result<(), Error> {
+        for (index, each_type) in self.signature.argument_types.iter().enumerate() {
+            let source = unsafe { *invocation.arguments.add(index) } as *const u8;
+            let value = marshall_argument(each_type, source)?;
+            values.insert(first_index + index, value);
+        }
+        Ok(())
+    }
+
+    fn write_result(
+        &self,
+        marshaller: &ArgumentMarshall,
+        object: AnyObjectRef,
+        destination: *mut c_void,
+    ) -> Result<(), Error> {
+        let result = &self.signature.result;
+        if let Some(struct_type) = result.struct_type() {
+            return marshaller.marshall_struct_into(object, struct_type, destination as *mut u8);
+        }
+
+        let Some((size, _)) = result.marshall_type().layout() else {
+            return Ok(());
+        };
+
+        let value = marshaller.marshall_as(object, result.marshall_type())?;
+        match widened_integer(&value) {
+            Some(integer) => unsafe { std::ptr::write(destination as *mut ffi_arg, integer) },
+            None => unsafe {
+                std::ptr::copy_nonoverlapping(
+                    value.as_arg() as *const u8,
+                    destination as *mut u8,
+                    size,
+                )
+            },
+        }
+        Ok(())
+    }
+
+    /// Zero the result, integers are widened to `ffi_arg` like in [`Callback::write_result`]
+    fn write_default_result(&self, destination: *mut c_void) {
+        let result = &self.signature.result;
+        let size = match result.struct_type() {
+            Some(struct_type) => struct_type.size(),
+            None => result
+                .marshall_type()
+                .layout()
+                .map_or(0, |(size, _)| size.max(std::mem::size_of::<ffi_arg>())),
+        };
+        unsafe { std::ptr::write_bytes(destination as *mut u8, 0, size) };
+    }
+}
+
+/// A pending call of a callback. The arguments and the result buffer are owned by
+/// the C caller, which is blocked until the invocation returns.
+#[derive(Debug)]
+struct CallbackInvocation {
+    arguments: *const *const c_void,
+    result: *mut c_void,
+    /// the context to return to when invoked on the VM thread
+    context: Option<*mut c_void>,
+    is_returned: Mutex<bool>,
+    returned: Condvar,
+}
+
+// the raw pointers are only accessed while the calling thread waits for the invocation to return
+unsafe impl Send for CallbackInvocation {}
+unsafe impl Sync for CallbackInvocation {}
+
+impl CallbackInvocation {
+    fn wait(&self) {
+        let mut is_returned = self.is_returned.lock().unwrap();
+        while !*is_returned {
+            is_returned = self.returned.wait(is_returned).unwrap();
+        }
+    }
+
+    fn notify_returned(&self) {
+        *self.is_returned.lock().unwrap() = true;
+        self.returned.notify_one();
+    }
+}
+
+unsafe extern "C" fn invoke_callback(
+    _cif: &ffi_cif,
+    result: &mut c_void,
+    arguments: *const *const c_void,
+    signature: &CallbackSignature,
+) {
+    let context = if std::thread::current().id() == signature.vm_thread {
+        Some(vm().proxy().new_callback_context())
+    } else {
+        None
+    };
+
+    let invocation = Arc::new(CallbackInvocation {
+        arguments,
+        result: result as *mut c_void,
+        context,
+        is_returned: Mutex::new(false),
+        returned: Condvar::new(),
+    });
+
+    signature
+        .pending_invocations
+        .lock()
+        .unwrap()
+        .push_back(invocation.clone());
+    (signature.signal_semaphore)(signature.semaphore_index);
+
+    match context {
+        Some(context) => {
+            let proxy = vm().proxy();
+            if !proxy.enter_interpreter_from_callback(context) {
+                error!("Failed to enter the interpreter from a callback");
+            }
+            proxy.free_callback_context(context);
+        }
+        None => invocation.wait(),
+    }
+}
+
+/// Convert an argument passed by C to a Smalltalk object.
+/// Buffers are passed as external addresses since their size is not known
+fn marshall_argument(
+    argument_type: &ArgumentType,
+    source: *const u8,
+) -> Result<AnyObjectRef, Error> {
+    match argument_type.marshall_type() {
+        MarshallType::Struct => marshall_struct_as_array(
+            source,
+            argument_type
+                .struct_type()
+                .ok_or(Error::MissingStructLayout)?,
+        ),
+        MarshallType::String => marshall_string_result(
+            unsafe { std::ptr::read_unaligned(source as *const *mut c_char) },
+            None,
+        ),
+        MarshallType::Buffer | MarshallType::FloatArray | MarshallType::WordArray => unsafe {
+            read_and_marshall_value(MarshallType::Pointer, source)
+        },
+        marshall_type => unsafe { read_and_marshall_value(marshall_type, source) },
+    }
+}
+
+/// libffi expects integer results narrower than a register to be widened to `ffi_arg`
+fn widened_integer(value: &MarshalledValue) -> Option<ffi_arg> {
+    match value {
+        MarshalledValue::Bool(value) => Some(*value as ffi_arg),
+        MarshalledValue::U8(value) => Some(*value as ffi_arg),
+        MarshalledValue::I8(value) => Some(*value as ffi_sarg as ffi_arg),
+        MarshalledValue::U16(value) => Some(*value as ffi_arg),
+        MarshalledValue::I16(value) => Some(*value as ffi_sarg as ffi_arg),
+        MarshalledValue::U32(value) => Some(*value as ffi_arg),
+        MarshalledValue::I32(value) => Some(*value as ffi_sarg as ffi_arg),
+        MarshalledValue::SChar(value) => Some(*value as ffi_sarg as ffi_arg),
+        MarshalledValue::UChar(value) => Some(*value as ffi_arg),
+        MarshalledValue::Short(value) => Some(*value as ffi_sarg as ffi_arg),
+        MarshalledValue::UShort(value) => Some(*value as ffi_arg),
+        MarshalledValue::Int(value) => Some(*value as ffi_sarg as ffi_arg),
+        MarshalledValue::UInt(value) => Some(*value as ffi_arg),
+        _ => None,
+    }
+}
+
+/// Create the C function of a callback, answers its address
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiCallbackCreate() -> Result<(), Error> {
+    let mut callback_object = BareFFICallbackRef::try_from(Smalltalk::method_receiver())?;
+    callback_object.invalidate()?;
+
+    let code_ptr = callback_object.callback()?.code_ptr();
+    Smalltalk::method_return_value(Smalltalk::new_external_address(code_ptr));
+    Ok(())
+}
+
+/// Answer an array with the handle of the oldest pending invocation followed by its arguments,
+/// or nil if there are no pending invocations
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiCallbackNextInvocation() -> Result<(), Error> {
+    let callback_object = BareFFICallbackRef::try_from(Smalltalk::method_receiver())?;
+    let callback = callback_object.callback()?;
+
+    let Some(invocation) = callback.first_pending_invocation() else {
+        Smalltalk::method_return(Smalltalk::nil_object());
+        return Ok(());
+    };
+
+    let mut values = Array::new(callback.signature.argument_types.len() + 1)?;
+    callback.marshall_arguments(&invocation, &mut values, 1)?;
+    // invocations are only taken on the VM thread, so the first one is still the same
+    let Some(handle) = callback.take_first_pending_invocation() else {
+        return Err(Error::NullCallbackInvocation);
+    };
+
+    // the callback keeps the invocation alive until it returns, the handle only identifies it
+    let handle = Smalltalk::new_external_address(handle);
+    values.insert(
+        0,
+        AnyObjectRef::from(RawObjectPointer::from(handle.as_i64())),
+    );
+
+    Smalltalk::method_return(values);
+    Ok(())
+}
+
+/// Return a value from an invocation to its C caller, the invocation handle is nulled.
+/// When invoked on the VM thread the primitive does not return, the interpreter
+/// continues in the C function that called the callback
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiCallbackReturn() -> Result<(), Error> {
+    let callback_object = BareFFICallbackRef::try_from(Smalltalk::method_receiver())?;
+    let callback = callback_object.callback()?;
+
+    let mut invocation_handle = ExternalAddressRef::try_from(Smalltalk::get_method_argument(0))?;
+    if invocation_handle.is_null() {
+        return Err(Error::NullCallbackInvocation);
+    }
+    let invocation = callback
+        .taken_invocation(invocation_handle.read_address() as *const CallbackInvocation)
+        .ok_or(Error::UnknownCallbackInvocation)?;
+
+    let value = Smalltalk::get_method_argument(1);
+    callback.write_result(&callback_object.marshaller(), value, invocation.result)?;
+
+    callback.remove_taken_invocation(&invocation);
+    invocation_handle.set_address(std::ptr::null());
+
+    match invocation.context {
+        Some(context) => {
+            // the C stack is unwound without running destructors
+            drop(invocation);
+            vm().proxy().exit_interpreter_to_callback(context);
+            Err(Error::CallbackReturn)
+        }
+        None => {
+            invocation.notify_returned();
+            Smalltalk::method_return(callback_object);
+            Ok(())
+        }
+    }
+}
+
+/// Free a callback and null its handle. Invocations that are waiting on other threads
+/// return a zeroed result, the release fails while the callback runs on the VM thread
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiCallbackRelease() -> Result<(), Error> {
+    let mut callback_handle = ExternalAddressRef::try_from(Smalltalk::get_method_argument(0))?;
+    if callback_handle.is_null() {
+        return Ok(());
+    }
+
+    let callback_ptr = callback_handle.read_address() as *mut Callback;
+    unsafe { &*callback_ptr }.complete_invocations()?;
+
+    callback_handle.set_address(std::ptr::null());
+    let callback = unsafe { Box::from_raw(callback_ptr) };
+    drop(callback);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::StructType;
+    use std::os::raw::c_int;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SIGNALLED_SEMAPHORE: AtomicUsize = AtomicUsize::new(0);
+
+    fn record_signal(semaphore_index: usize) {
+        SIGNALLED_SEMAPHORE.store(semaphore_index, Ordering::SeqCst);
+    }
+
+    fn new_callback(
+        argument_types: &[MarshallType],
+        result: ReturnType,
+    ) -> Result<Callback, Error> {
+        Callback::with_semaphore_signal(
+            argument_types
+                .iter()
+                .map(|each| ArgumentType::new(*each))
+                .collect(),
+            result,
+            7,
+            record_signal,
+        )
+    }
+
+    fn pending_invocations(callback: &Callback) -> usize {
+        callback.signature.pending_invocations.lock().unwrap().len()
+    }
+
+    #[test]
+    fn void_arguments_are_rejected() {
+        let callback = new_callback(
+            &[MarshallType::Int, MarshallType::Void],
+            ReturnType::new(MarshallType::Void),
+        );
+        assert!(matches!(
+            callback,
+            Err(Error::IllegalArgumentType(MarshallType::Void))
+        ));
+    }
+
+    #[test]
+    fn string_and_buffer_results_are_rejected() {
+        for marshall_type in [
+            MarshallType::Buffer,
+            MarshallType::FloatArray,
+            MarshallType::WordArray,
+        ] {
+            let callback = new_callback(&[], ReturnType::new(marshall_type));
+            assert!(
+                matches!(callback, Err(Error::IllegalReturnType(each)) if each == marshall_type)
+            );
+        }
+
+        let callback = new_callback(&[], ReturnType::new_string(None));
+        assert!(matches!(
+            callback,
+            Err(Error::IllegalReturnType(MarshallType::String))
+        ));
+    }
+
+    #[test]
+    fn narrow_integers_are_widened_with_their_sign() {
+        assert_eq!(widened_integer(&MarshalledValue::U8(255)), Some(255));
+        assert_eq!(
+            widened_integer(&MarshalledValue::I8(-1)),
+            Some(ffi_arg::MAX)
+        );
+        assert_eq!(
+            widened_integer(&MarshalledValue::I16(-2)),
+            Some(ffi_arg::MAX - 1)
+        );
+        assert_eq!(widened_integer(&MarshalledValue::Bool(true)), Some(1));
+        assert_eq!(
+            widened_integer(&MarshalledValue::U32(u32::MAX)),
+            Some(u32::MAX as ffi_arg)
+        );
+        assert_eq!(widened_integer(&MarshalledValue::F64(1.0)), None);
+        assert_eq!(widened_integer(&MarshalledValue::U64(1)), None);
+    }
+
+    #[test]
+    fn default_result_of_a_narrow_integer_fills_a_register() {
+        let callback = new_callback(&[], ReturnType::new(MarshallType::U8)).unwrap();
+        let mut result = [0xFFu8; 32];
+        callback.write_default_result(result.as_mut_ptr() as *mut c_void);
+
+        let size = std::mem::size_of::<ffi_arg>();
+        assert!(result[..size].iter().all(|each| *each == 0));
+        assert!(result[size..].iter().all(|each| *each == 0xFF));
+    }
+
+    #[test]
+    fn default_result_of_a_struct_fills_the_struct() {
+        let struct_type = StructType::new(vec![
+            (MarshallType::F64, None),
+            (MarshallType::F64, None),
+            (MarshallType::F64, None),
+        ])
+        .unwrap();
+        let callback = new_callback(&[], ReturnType::new_struct(struct_type)).unwrap();
+        let mut result = [0xFFu8; 32];
+        callback.write_default_result(result.as_mut_ptr() as *mut c_void);
+
+        assert!(result[..24].iter().all(|each| *each == 0));
+        assert!(result[24..].iter().all(|each| *each == 0xFF));
+    }
+
+    #[test]
+    fn default_result_of_void_writes_nothing() {
+        let callback = new_callback(&[], ReturnType::new(MarshallType::Void)).unwrap();
+        let mut result = [0xFFu8; 8];
+        callback.write_default_result(result.as_mut_ptr() as *mut c_void);
+        assert!(result.iter().all(|each| *each == 0xFF));
+    }
+
+    #[test]
+    fn invocations_on_the_vm_thread_can_not_be_completed() {
+        let callback = new_callback(&[], ReturnType::new(MarshallType::Int)).unwrap();
+        let mut result: ffi_arg = 42;
+        let invocation = Arc::new(CallbackInvocation {
+            arguments: std::ptr::null(),
+            result: &mut result as *mut ffi_arg as *mut c_void,
+            context: Some(std::ptr::null_mut()),
+            is_returned: Mutex::new(false),
+            returned: Condvar::new(),
+        });
+        callback
+            .signature
+            .pending_invocations
+            .lock()
+            .unwrap()
+            .push_back(invocation.clone());
+
+        assert!(matches!(
+            callback.complete_invocations(),
+            Err(Error::CallbackInUse)
+        ));
+        assert_eq!(pending_invocations(&callback), 1);
+        assert!(!*invocation.is_returned.lock().unwrap());
+        assert_eq!(result, 42);
+    }
+
+    #[test]
+    fn invocation_from_another_thread_waits_until_released() {
+        let callback =
+            new_callback(&[MarshallType::Int], ReturnType::new(MarshallType::Int)).unwrap();
+        let code_ptr = callback.code_ptr() as usize;
+
+        let caller = std::thread::spawn(move || {
+            let function: extern "C" fn(c_int) -> c_int = unsafe { std::mem::transmute(code_ptr) };
+            function(42)
+        });
+
+        while pending_invocations(&callback) == 0 {
+            std::thread::yield_now();
+        }
+        assert_eq!(SIGNALLED_SEMAPHORE.load(Ordering::SeqCst), 7);
+
+        let invocation = callback.first_pending_invocation().unwrap();
+        assert!(invocation.context.is_none());
+        assert_eq!(unsafe { *(*invocation.arguments as *const c_int) }, 42);
+        drop(invocation);
+
+        callback.complete_invocations().unwrap();
+        assert_eq!(caller.join().unwrap(), 0);
+        assert_eq!(pending_invocations(&callback), 0);
+        drop(callback);
+    }
+}
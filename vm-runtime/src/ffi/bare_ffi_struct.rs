@@ -0,0 +1,313 @@
+use std::mem::size_of;
+
+use libffi::middle::Type;
+use vm_object_model::{AnyObjectRef, Immediate, Object};
+
+use crate::ffi::{BareFFITypeRef, Error, MarshallType};
+use crate::objects::{ArrayRef, ByteStringRef};
+
+/// Describes a C struct passed or returned by value.
+/// `fields` is an array of BareFFIType or nested BareFFIStructType.
+/// When returned by value, a struct is wrapped into an instance of `structure_class`
+/// with a ByteArray handle, or converted to an array of field values if the class is nil.
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct BareFFIStructType {
+    this: Object,
+    value: Immediate,
+    name: ByteStringRef,
+    fields: ArrayRef,
+    structure_class: AnyObjectRef,
+}
+
+impl BareFFIStructType {
+    pub fn structure_class(&self) -> AnyObjectRef {
+        self.structure_class
+    }
+}
+
+/// Memory layout of a C struct following the platform alignment rules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructType {
+    fields: Vec<StructField>,
+    size: usize,
+    alignment: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructField {
+    marshall_type: MarshallType,
+    struct_type: Option<StructType>,
+    offset: usize,
+}
+
+impl StructType {
+    pub fn new(fields: Vec<(MarshallType, Option<StructType>)>) -> Result<Self, Error> {
+        if fields.is_empty() {
+            return Err(Error::MissingStructLayout);
+        }
+
+        let mut struct_fields = Vec::with_capacity(fields.len());
+        let mut offset = 0;
+        let mut alignment = 1;
+
+        for (marshall_type, struct_type) in fields {
+            let (field_size, field_alignment) = match &struct_type {
+                Some(struct_type) => (struct_type.size, struct_type.alignment),
+                None => marshall_type
+                    .layout()
+                    .ok_or(Error::IllegalStructFieldType(marshall_type))?,
+            };
+
+            offset = align_to(offset, field_alignment);
+            struct_fields.push(StructField {
+                marshall_type,
+                struct_type,
+                offset,
+            });
+            offset += field_size;
+            alignment = alignment.max(field_alignment);
+        }
+
+        Ok(Self {
+            fields: struct_fields,
+            size: align_to(offset, alignment),
+            alignment,
+        })
+    }
+
+    /// Read a struct layout from a type object.
+    /// Returns None if the object describes a scalar type.
+    pub fn from_type_object(object: AnyObjectRef) -> Result<Option<Self>, Error> {
+        let Ok(struct_type) = BareFFIStructTypeRef::try_from(object) else {
+            return Ok(None);
+        };
+
+        let value: u8 = struct_type.value.try_as_integer()?.try_into()?;
+        if MarshallType::try_from(value)? != MarshallType::Struct {
+            return Ok(None);
+        }
+
+        let mut fields = Vec::with_capacity(struct_type.fields.len());
+        for each_field in struct_type.fields.iter() {
+            let field = match Self::from_type_object(*each_field)? {
+                Some(nested) => (MarshallType::Struct, Some(nested)),
+                None => {
+                    let field_type = BareFFITypeRef::try_from(*each_field)?;
+                    (MarshallType::try_from(field_type)?, None)
+                }
+            };
+            fields.push(field);
+        }
+
+        Self::new(fields).map(Some)
+    }
+
+    pub fn fields(&self) -> &[StructField] {
+        self.fields.as_slice()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    pub fn ffi_type(&self) -> Type {
+        Type::structure(self.fields.iter().map(|each| each.ffi_type()))
+    }
+}
+
+impl StructField {
+    pub fn marshall_type(&self) -> MarshallType {
+        self.marshall_type
+    }
+
+    /// The layout of a nested struct
+    pub fn struct_type(&self) -> Option<&StructType> {
+        self.struct_type.as_ref()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn ffi_type(&self) -> Type {
+        match &self.struct_type {
+            Some(struct_type) => struct_type.ffi_type(),
+            None => self.marshall_type.into(),
+        }
+    }
+}
+
+/// A buffer holding a struct value, aligned to 8 bytes
+#[derive(Debug, Clone)]
+pub struct StructValue {
+    words: Vec<u64>,
+    size: usize,
+}
+
+impl StructValue {
+    pub fn new(size: usize) -> Self {
+        let amount_of_words = size.div_ceil(size_of::<u64>()).max(1);
+        Self {
+            words: vec![0; amount_of_words],
+            size,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.words.as_ptr() as *const u8
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.words.as_mut_ptr() as *mut u8
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+}
+
+fn align_to(offset: usize, alignment: usize) -> usize {
+    offset.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scalars(types: &[MarshallType]) -> Vec<(MarshallType, Option<StructType>)> {
+        types.iter().map(|each| (*each, None)).collect()
+    }
+
+    fn offsets(struct_type: &StructType) -> Vec<usize> {
+        struct_type
+            .fields()
+            .iter()
+            .map(|each| each.offset())
+            .collect()
+    }
+
+    #[test]
+    fn fields_are_aligned() {
+        let struct_type = StructType::new(scalars(&[
+            MarshallType::U8,
+            MarshallType::U32,
+            MarshallType::U16,
+        ]))
+        .unwrap();
+        assert_eq!(offsets(&struct_type), vec![0, 4, 8]);
+        assert_eq!(struct_type.size(), 12);
+        assert_eq!(struct_type.alignment(), 4);
+    }
+
+    #[test]
+    fn size_is_padded_to_the_alignment() {
+        let struct_type = StructType::new(scalars(&[MarshallType::F64, MarshallType::U8])).unwrap();
+        assert_eq!(offsets(&struct_type), vec![0, 8]);
+        assert_eq!(struct_type.size(), 16);
+        assert_eq!(struct_type.alignment(), 8);
+    }
+
+    #[test]
+    fn packed_fields() {
+        let struct_type = StructType::new(scalars(&[
+            MarshallType::U8,
+            MarshallType::I8,
+            MarshallType::U16,
+            MarshallType::F32,
+        ]))
+        .unwrap();
+        assert_eq!(offsets(&struct_type), vec![0, 1, 2, 4]);
+        assert_eq!(struct_type.size(), 8);
+    }
+
+    #[test]
+    fn single_byte() {
+        let struct_type = StructType::new(scalars(&[MarshallType::Bool])).unwrap();
+        assert_eq!(struct_type.size(), 1);
+        assert_eq!(struct_type.alignment(), 1);
+    }
+
+    #[test]
+    fn nested_struct_is_aligned_as_its_largest_field() {
+        let point = StructType::new(scalars(&[MarshallType::I32, MarshallType::I32])).unwrap();
+        let rectangle = StructType::new(vec![
+            (MarshallType::U8, None),
+            (MarshallType::Struct, Some(point.clone())),
+            (MarshallType::Struct, Some(point)),
+        ])
+        .unwrap();
+
+        assert_eq!(offsets(&rectangle), vec![0, 4, 12]);
+        assert_eq!(rectangle.size(), 20);
+        assert_eq!(rectangle.alignment(), 4);
+        assert_eq!(
+            rectangle.fields()[1]
+                .struct_type()
+                .map(|each| offsets(each)),
+            Some(vec![0, 4])
+        );
+    }
+
+    #[test]
+    fn pointers_have_the_platform_layout() {
+        let struct_type =
+            StructType::new(scalars(&[MarshallType::U8, MarshallType::Pointer])).unwrap();
+        let pointer_size = size_of::<*const u8>();
+        assert_eq!(offsets(&struct_type), vec![0, pointer_size]);
+        assert_eq!(struct_type.size(), pointer_size * 2);
+    }
+
+    #[test]
+    fn empty_struct_is_rejected() {
+        assert!(matches!(
+            StructType::new(vec![]),
+            Err(Error::MissingStructLayout)
+        ));
+    }
+
+    #[test]
+    fn fields_without_layout_are_rejected() {
+        for marshall_type in [
+            MarshallType::Void,
+            MarshallType::String,
+            MarshallType::Buffer,
+            MarshallType::Struct,
+        ] {
+            assert!(matches!(
+                StructType::new(scalars(&[MarshallType::U8, marshall_type])),
+                Err(Error::IllegalStructFieldType(each)) if each == marshall_type
+            ));
+        }
+    }
+
+    #[test]
+    fn struct_value_is_word_aligned() {
+        let mut value = StructValue::new(3);
+        assert_eq!(value.as_ptr() as usize % size_of::<u64>(), 0);
+        assert_eq!(value.as_bytes(), &[0, 0, 0]);
+
+        unsafe { value.as_mut_ptr().add(2).write(7) };
+        assert_eq!(value.as_bytes(), &[0, 0, 7]);
+    }
+
+    #[test]
+    fn empty_struct_value_has_storage() {
+        let value = StructValue::new(0);
+        assert!(!value.as_ptr().is_null());
+        assert!(value.as_bytes().is_empty());
+    }
+
+    #[test]
+    fn align() {
+        assert_eq!(align_to(0, 8), 0);
+        assert_eq!(align_to(1, 8), 8);
+        assert_eq!(align_to(8, 8), 8);
+        assert_eq!(align_to(9, 4), 12);
+        assert_eq!(align_to(5, 1), 5);
+    }
+}
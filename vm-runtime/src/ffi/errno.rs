@@ -0,0 +1,167 @@
+use std::cell::Cell;
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex};
+
+use vm_bindings::{ObjectPointer, Smalltalk};
+use vm_object_model::{AnyObjectRef, RawObjectPointer};
+
+use crate::ffi::{
+    is_kind_of_class_named, Error, EventLoopCallout, ExternalFunctionRef, TFExternalFunction,
+};
+
+thread_local! {
+    static LAST_ERRNO: Cell<Option<i32>> = const { Cell::new(None) };
+}
+
+#[cfg(any(target_os = "linux", target_os = "emscripten"))]
+fn errno_location() -> *mut c_int {
+    unsafe { libc::__errno_location() }
+}
+
+#[cfg(target_os = "android")]
+fn errno_location() -> *mut c_int {
+    unsafe { libc::__errno() }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn errno_location() -> *mut c_int {
+    unsafe { libc::__error() }
+}
+
+#[cfg(target_os = "windows")]
+fn errno_location() -> *mut c_int {
+    extern "C" {
+        /// The errno of the C runtime, unlike `GetLastError`
+        fn _errno() -> *mut c_int;
+    }
+    unsafe { _errno() }
+}
+
+/// Read the `errno` of the C runtime right after a call
+/// and remember it as the last captured errno of the current thread
+pub fn capture_errno() -> i32 {
+    let errno = unsafe { *errno_location() };
+    LAST_ERRNO.with(|last_errno| last_errno.set(Some(errno)));
+    errno
+}
+
+/// The errno captured by the most recent call on the current thread
+pub fn last_errno() -> Option<i32> {
+    LAST_ERRNO.with(|last_errno| last_errno.get())
+}
+
+/// Whether event loop calls of a TFExternalFunction capture errno,
+/// which is false for external functions without the slot to enable it
+pub fn should_capture_errno_of(external_function: ObjectPointer) -> bool {
+    AnyObjectRef::from(RawObjectPointer::from(external_function.as_i64()))
+        .as_object()
+        .ok()
+        .and_then(|object| object.inst_var_at(TFExternalFunction::CapturesErrno as usize))
+        .is_some_and(|captures_errno| {
+            Smalltalk::true_object() == ObjectPointer::from(captures_errno.as_i64())
+        })
+}
+
+fn return_errno(errno: Option<i32>) {
+    match errno {
+        Some(errno) => Smalltalk::method_return_integer(errno as i64),
+        None => Smalltalk::method_return(Smalltalk::nil_object()),
+    }
+}
+
+fn boolean_argument(index: usize) -> bool {
+    Smalltalk::true_object() == ObjectPointer::from(Smalltalk::get_method_argument(index).as_i64())
+}
+
+/// Enable or disable capturing errno after each call of the receiver external function.
+/// The flag is stored in the external function, so it is restored when the callout
+/// is recreated after a release or an image restart
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiCalloutCaptureErrno() -> Result<(), Error> {
+    let mut external_function = ExternalFunctionRef::try_from(Smalltalk::method_receiver())?;
+    external_function.set_should_capture_errno(boolean_argument(0))?;
+
+    Smalltalk::method_return(external_function);
+    Ok(())
+}
+
+/// Answer the errno captured after the last call of the receiver external function,
+/// or nil if it is not captured or the callout was released since the last call
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveBareFfiCalloutErrno() -> Result<(), Error> {
+    let mut external_function = ExternalFunctionRef::try_from(Smalltalk::method_receiver())?;
+    external_function.invalidate()?;
+
+    return_errno(external_function.callout_mut().errno());
+    Ok(())
+}
+
+/// Enable or disable capturing errno after each event loop call of a given TFExternalFunction.
+/// The flag is stored in the external function itself, so it fails for objects that are not
+/// TFExternalFunctions and for external functions that do not have a slot for it
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopCalloutCaptureErrno() -> Result<(), Error> {
+    let mut external_function = Smalltalk::get_method_argument(0).as_object()?;
+    if !is_kind_of_class_named(external_function, &["TFExternalFunction"]) {
+        return Err(Error::NotATFExternalFunction);
+    }
+    let captures_errno_index = TFExternalFunction::CapturesErrno as usize;
+    if captures_errno_index >= external_function.amount_of_slots() {
+        return Err(Error::NoCapturesErrnoSlot);
+    }
+    external_function.inst_var_at_put(
+        captures_errno_index,
+        Smalltalk::bool_object(boolean_argument(1)),
+    );
+
+    Smalltalk::method_return(Smalltalk::method_receiver());
+    Ok(())
+}
+
+/// Answer the errno captured after an event loop call given by its address,
+/// must be read before the return value is extracted
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopCalloutErrno() -> Result<(), Error> {
+    let callout_address =
+        Smalltalk::read_external_address(Smalltalk::get_method_argument(0).as_object()?)
+            as *const Mutex<EventLoopCallout>;
+    if callout_address.is_null() {
+        return Err(Error::NullEventLoopCallout);
+    }
+
+    // the image keeps its reference until the return value is extracted
+    let callout = unsafe { Arc::from_raw(callout_address) };
+    let errno = callout.lock().unwrap().errno;
+    std::mem::forget(callout);
+
+    return_errno(errno);
+    Ok(())
+}
+
+/// Answer the errno captured by the most recent call on the interpreter thread
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveFfiLastErrno() -> Result<(), Error> {
+    return_errno(last_errno());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errno_of_the_c_runtime_is_captured() {
+        unsafe { *errno_location() = libc::ENOENT };
+        assert_eq!(capture_errno(), libc::ENOENT);
+        assert_eq!(last_errno(), Some(libc::ENOENT));
+
+        unsafe { *errno_location() = 0 };
+        assert_eq!(capture_errno(), 0);
+        assert_eq!(last_errno(), Some(0));
+    }
+}
@@ -3,12 +3,16 @@ use std::fmt::{Debug, Formatter};
 use std::mem::{size_of, transmute};
 use std::os::raw::c_void;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use libffi::low::{ffi_cif, ffi_type, CodePtr};
 
-use vm_bindings::{Marshallable, ObjectFieldIndex, Smalltalk, StackOffset};
+use vm_bindings::{Marshallable, ObjectFieldIndex, ObjectPointer, Smalltalk, StackOffset};
+use vm_object_model::{AnyObjectRef, RawObjectPointer};
 
-use crate::{vm, EventLoopMessage};
+use crate::ffi::{capture_errno, resolve_symbol_address, should_capture_errno_of};
+use crate::objects::Array;
+use crate::{vm, AllocationTracker, EventLoopMessage, EventLoopTimer, FfiPolicy};
 
 #[repr(C)]
 pub struct EventLoopCallout {
@@ -19,6 +23,8 @@ pub struct EventLoopCallout {
     pub(crate) args: Option<*mut *mut c_void>,
     pub(crate) result: Option<*mut c_void>,
     pub(crate) callback: Option<Box<dyn FnOnce()>>,
+    pub(crate) should_capture_errno: bool,
+    pub(crate) errno: Option<i32>,
 }
 
 impl EventLoopCallout {
@@ -31,8 +37,40 @@ impl EventLoopCallout {
                 self.args.unwrap_or(std::ptr::null_mut()),
             )
         }
-        let callback = self.callback.take().unwrap();
-        callback();
+        if self.should_capture_errno {
+            self.errno = Some(capture_errno());
+        }
+        if let Some(callback) = self.callback.take() {
+            callback();
+        }
+    }
+
+    /// Free the marshalled arguments and the return holder
+    pub fn free_arguments_and_result(&mut self) {
+        let proxy = vm().proxy();
+
+        if let Some(arguments) = self.args {
+            let arguments_size = self.number_of_arguments();
+
+            let arguments_slice =
+                unsafe { std::slice::from_raw_parts_mut(arguments, arguments_size) };
+            for index in 0..arguments_size {
+                let argument = arguments_slice[index];
+                if !argument.is_null() {
+                    proxy.free(argument);
+                }
+            }
+
+            proxy.free(arguments as *mut c_void);
+            self.args = None;
+        }
+
+        if let Some(return_holder) = self.result {
+            if !return_holder.is_null() {
+                proxy.free(return_holder);
+            }
+            self.result = None;
+        }
     }
 
     pub fn return_type(&self) -> &ffi_type {
@@ -56,17 +94,51 @@ impl Debug for EventLoopCallout {
             .field("func", &self.func)
             .field("args", &self.args)
             .field("result", &self.result)
+            .field("errno", &self.errno)
+            .finish()
+    }
+}
+
+/// Callouts performed one after another on the event loop thread,
+/// followed by a single callback once all of them are done
+pub struct EventLoopCalloutBatch {
+    pub(crate) callouts: Vec<Arc<Mutex<EventLoopCallout>>>,
+    pub(crate) callback: Option<Box<dyn FnOnce()>>,
+}
+
+impl EventLoopCalloutBatch {
+    pub fn call(&mut self) {
+        self.call_each(|callout| callout.call());
+    }
+
+    /// Perform each callout with a given function, in order, followed by the callback of the batch
+    pub fn call_each(&mut self, mut call: impl FnMut(&mut EventLoopCallout)) {
+        for callout in &self.callouts {
+            call(&mut callout.lock().unwrap());
+        }
+        if let Some(callback) = self.callback.take() {
+            callback();
+        }
+    }
+}
+
+impl Debug for EventLoopCalloutBatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CalloutBatch")
+            .field("callouts", &self.callouts)
             .finish()
     }
 }
 
 #[allow(dead_code)]
 #[repr(u16)]
-enum TFExternalFunction {
+pub(crate) enum TFExternalFunction {
     Handle,
     Definition,
     FunctionName,
     ModuleName,
+    /// Optional, images that capture errno of event loop calls add it after the other slots
+    CapturesErrno,
 }
 
 #[allow(dead_code)]
@@ -78,14 +150,15 @@ enum TFPrimitiveCallout {
     Receiver,
 }
 
-#[no_mangle]
-#[allow(non_snake_case)]
-pub fn primitiveEventLoopCallout() {
+/// Prepare a callout of a TFExternalFunction marshalling its arguments from an array.
+/// Answers None if the FFI policy denies the function
+fn prepare_event_loop_callout(
+    external_function_oop: ObjectPointer,
+    arguments_array_oop: ObjectPointer,
+    callback: Option<Box<dyn FnOnce()>>,
+) -> Option<EventLoopCallout> {
     let proxy = vm().proxy();
 
-    let external_function_oop = Smalltalk::stack_object_value_unchecked(StackOffset::new(
-        TFPrimitiveCallout::ExternalFunction as i32,
-    ));
     let external_function = proxy.get_handler(external_function_oop);
 
     let cif_oop = Smalltalk::object_field_at(
@@ -118,13 +191,17 @@ pub fn primitiveEventLoopCallout() {
         None
     };
 
-    let semaphore_index =
-        Smalltalk::stack_integer_value(StackOffset::new(TFPrimitiveCallout::SemaphoreIndex as i32))
-            as usize;
+    // the names are supplied by the image and may not describe the handle,
+    // so the policy is checked against the module and the symbol found at the called address
+    let is_permitted = match resolve_symbol_address(external_function) {
+        Some(symbol) => FfiPolicy::global()
+            .check_symbol(&symbol.module_path.to_string_lossy(), &symbol.symbol_name),
+        None => FfiPolicy::global().check_unknown_symbol(external_function as usize),
+    };
+    if !is_permitted {
+        return None;
+    }
 
-    let arguments_array_oop = Smalltalk::stack_object_value_unchecked(StackOffset::new(
-        TFPrimitiveCallout::Arguments as i32,
-    ));
     let argument_size: usize = cif.nargs as usize;
 
     let arg_types: &[*mut ffi_type] =
@@ -159,17 +236,45 @@ pub fn primitiveEventLoopCallout() {
         None
     };
 
-    let callout = Arc::new(Mutex::new(EventLoopCallout {
+    Some(EventLoopCallout {
         cif: cif_ptr,
         func: CodePtr(external_function),
         args: parameters,
         result: return_holder,
-        callback: Some(Box::new(move || {
-            vm().proxy().signal_semaphore(semaphore_index);
-        })),
+        callback,
         function_name,
         module_name,
-    }));
+        should_capture_errno: should_capture_errno_of(external_function_oop),
+        errno: None,
+    })
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopCallout() {
+    let _stack = AllocationTracker::enter_primitive();
+    let external_function_oop = Smalltalk::stack_object_value_unchecked(StackOffset::new(
+        TFPrimitiveCallout::ExternalFunction as i32,
+    ));
+
+    let semaphore_index =
+        Smalltalk::stack_integer_value(StackOffset::new(TFPrimitiveCallout::SemaphoreIndex as i32))
+            as usize;
+
+    let arguments_array_oop = Smalltalk::stack_object_value_unchecked(StackOffset::new(
+        TFPrimitiveCallout::Arguments as i32,
+    ));
+
+    let callout = match prepare_event_loop_callout(
+        external_function_oop,
+        arguments_array_oop,
+        Some(Box::new(move || {
+            vm().proxy().signal_semaphore(semaphore_index);
+        })),
+    ) {
+        None => return Smalltalk::primitive_fail(),
+        Some(callout) => Arc::new(Mutex::new(callout)),
+    };
 
     vm().send(EventLoopMessage::Call(callout.clone())).unwrap();
 
@@ -185,6 +290,188 @@ pub fn primitiveEventLoopCallout() {
     }
 }
 
+#[allow(dead_code)]
+#[repr(u16)]
+enum TFPrimitiveTimerCallout {
+    Delay,
+    SemaphoreIndex,
+    Arguments,
+    ExternalFunction,
+    Receiver,
+}
+
+/// Perform a callout on the event loop once after a delay in milliseconds.
+/// Answers an array with the id to cancel the timer with and the callout to read the return value of,
+/// the callout is null if the semaphore index is zero, see [`primitiveEventLoopCallout`].
+/// A cancelled callout never signals its semaphore, but must still be released by the image.
+/// Like other timers it is only performed on time when the event loop runs on its own thread,
+/// see `primitiveEventLoopStartTimer`
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopStartCalloutTimer() {
+    let _stack = AllocationTracker::enter_primitive();
+    let delay =
+        Smalltalk::stack_integer_value(StackOffset::new(TFPrimitiveTimerCallout::Delay as i32));
+    let semaphore_index = Smalltalk::stack_integer_value(StackOffset::new(
+        TFPrimitiveTimerCallout::SemaphoreIndex as i32,
+    ));
+    if vm().event_loop().is_none() || delay < 0 || semaphore_index < 0 {
+        return Smalltalk::primitive_fail();
+    }
+    let semaphore_index = semaphore_index as usize;
+
+    let external_function_oop = Smalltalk::stack_object_value_unchecked(StackOffset::new(
+        TFPrimitiveTimerCallout::ExternalFunction as i32,
+    ));
+    let arguments_array_oop = Smalltalk::stack_object_value_unchecked(StackOffset::new(
+        TFPrimitiveTimerCallout::Arguments as i32,
+    ));
+
+    let mut callout = match prepare_event_loop_callout(
+        external_function_oop,
+        arguments_array_oop,
+        Some(Box::new(move || {
+            vm().proxy().signal_semaphore(semaphore_index);
+        })),
+    ) {
+        None => return Smalltalk::primitive_fail(),
+        Some(callout) => callout,
+    };
+
+    // nothing is scheduled unless the result can be answered
+    let mut result = match Array::new(2) {
+        Ok(result) => result,
+        Err(_) => {
+            callout.free_arguments_and_result();
+            return Smalltalk::primitive_fail();
+        }
+    };
+    let callout = Arc::new(Mutex::new(callout));
+
+    let timer = EventLoopTimer::call_once(Duration::from_millis(delay as u64), callout.clone());
+    let timer_id = timer.id();
+    vm().send(EventLoopMessage::ScheduleTimer(timer)).unwrap();
+
+    let callout_ptr = if semaphore_index == 0 {
+        std::ptr::null()
+    } else {
+        // intentionally leak the callout so that it can be released later, once the return value is read
+        Arc::into_raw(callout)
+    };
+
+    result.insert(0, Smalltalk::new_integer(timer_id.as_u64() as i64));
+    result.insert(
+        1,
+        AnyObjectRef::from(RawObjectPointer::from(
+            Smalltalk::new_external_address(callout_ptr).as_i64(),
+        )),
+    );
+    Smalltalk::method_return(result);
+}
+
+/// The pointer of an object if it is an Array, other objects can not be read as one
+fn array_oop(object: AnyObjectRef) -> Option<ObjectPointer> {
+    object
+        .as_object()
+        .ok()
+        .filter(|object| Smalltalk::class_of_object(*object) == Smalltalk::class_array())
+        .map(|object| ObjectPointer::from(AnyObjectRef::from(object).as_i64()))
+}
+
+#[allow(dead_code)]
+#[repr(u16)]
+enum TFPrimitiveBatchCallout {
+    SemaphoreIndex,
+    Arguments,
+    ExternalFunctions,
+    Receiver,
+}
+
+/// Perform an array of TFExternalFunctions with an array of their argument arrays
+/// on the event loop thread, in order and with a single wake up of the event loop.
+/// The semaphore is signalled once the whole batch is done.
+/// Answers an array of callout addresses to extract each return value with `primitiveExtractReturnValue`
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopCalloutBatch() {
+    let _stack = AllocationTracker::enter_primitive();
+    let Some(external_functions_oop) = array_oop(Smalltalk::stack_ref(StackOffset::new(
+        TFPrimitiveBatchCallout::ExternalFunctions as i32,
+    ))) else {
+        return Smalltalk::primitive_fail();
+    };
+    let Some(arguments_arrays_oop) = array_oop(Smalltalk::stack_ref(StackOffset::new(
+        TFPrimitiveBatchCallout::Arguments as i32,
+    ))) else {
+        return Smalltalk::primitive_fail();
+    };
+    let semaphore_index = Smalltalk::stack_integer_value(StackOffset::new(
+        TFPrimitiveBatchCallout::SemaphoreIndex as i32,
+    ));
+    if semaphore_index < 0 {
+        return Smalltalk::primitive_fail();
+    }
+    let semaphore_index = semaphore_index as usize;
+
+    let amount_of_callouts = Smalltalk::size_of(external_functions_oop);
+    if Smalltalk::size_of(arguments_arrays_oop) != amount_of_callouts {
+        return Smalltalk::primitive_fail();
+    }
+
+    let mut callouts: Vec<EventLoopCallout> = Vec::with_capacity(amount_of_callouts);
+    for index in 0..amount_of_callouts {
+        let external_function_oop =
+            Smalltalk::object_field_at(external_functions_oop, ObjectFieldIndex::new(index));
+        let arguments_array_oop =
+            Smalltalk::object_field_at(arguments_arrays_oop, ObjectFieldIndex::new(index));
+
+        match prepare_event_loop_callout(external_function_oop, arguments_array_oop, None) {
+            None => {
+                // nothing is sent unless all callouts of the batch are permitted
+                for mut callout in callouts {
+                    callout.free_arguments_and_result();
+                }
+                return Smalltalk::primitive_fail();
+            }
+            Some(callout) => callouts.push(callout),
+        }
+    }
+
+    let callouts = callouts
+        .into_iter()
+        .map(|callout| Arc::new(Mutex::new(callout)))
+        .collect::<Vec<_>>();
+
+    let batch = Arc::new(Mutex::new(EventLoopCalloutBatch {
+        callouts: callouts.clone(),
+        callback: Some(Box::new(move || {
+            vm().proxy().signal_semaphore(semaphore_index);
+        })),
+    }));
+
+    vm().send(EventLoopMessage::CallBatch(batch)).unwrap();
+
+    let callout_addresses = Smalltalk::primitive_instantiate_indexable_class_of_size(
+        Smalltalk::primitive_class_array(),
+        amount_of_callouts,
+    );
+    for (index, callout) in callouts.into_iter().enumerate() {
+        // same as for a single callout, nothing is waiting for the return values if the semaphore index is zero
+        let callout_ptr: *const Mutex<EventLoopCallout> = if semaphore_index == 0 {
+            std::ptr::null()
+        } else {
+            Arc::into_raw(callout)
+        };
+        Smalltalk::item_at_put(
+            callout_addresses,
+            ObjectFieldIndex::new(index + 1),
+            Smalltalk::new_external_address(callout_ptr),
+        );
+    }
+
+    Smalltalk::method_return_value(callout_addresses);
+}
+
 #[repr(u16)]
 enum TFPrimitiveReturnValue {
     CalloutAddress,
@@ -225,30 +512,7 @@ pub fn primitiveExtractReturnValue() {
             proxy.pop(1);
         }
 
-        // start freeing memory:
-        // - arguments
-        // - return holder
-        if let Some(arguments) = locked_callout.args {
-            let arguments_size = locked_callout.number_of_arguments();
-
-            let arguments_slice = std::slice::from_raw_parts_mut(arguments, arguments_size);
-            for index in 0..arguments_size {
-                let argument = arguments_slice[index];
-                if !argument.is_null() {
-                    proxy.free(argument);
-                }
-            }
-
-            proxy.free(arguments as *mut c_void);
-            locked_callout.args = None;
-        }
-
-        if let Some(return_holder) = locked_callout.result {
-            if !return_holder.is_null() {
-                proxy.free(return_holder);
-            }
-            locked_callout.result = None;
-        }
+        locked_callout.free_arguments_and_result();
 
         drop(locked_callout);
         drop(callout);
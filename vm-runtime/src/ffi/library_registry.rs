@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use libloading::Library;
+use once_cell::sync::OnceCell;
+use vm_bindings::{ObjectPointer, Smalltalk};
+
+use crate::ffi::Error;
+use crate::objects::{Array, ByteString, ByteStringRef};
+use crate::FfiPolicy;
+
+static LIBRARY_REGISTRY: OnceCell<Mutex<LibraryRegistry>> = OnceCell::new();
+
+#[cfg(target_os = "windows")]
+const LIBRARY_PATH_VARIABLE: &str = "PATH";
+#[cfg(target_os = "macos")]
+const LIBRARY_PATH_VARIABLE: &str = "DYLD_LIBRARY_PATH";
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const LIBRARY_PATH_VARIABLE: &str = "LD_LIBRARY_PATH";
+
+/// A loaded shared library, unloaded when the last callout using it is released
+#[derive(Debug)]
+pub struct SharedLibrary {
+    module_name: OsString,
+    path: PathBuf,
+    is_global: bool,
+    library: Library,
+}
+
+impl SharedLibrary {
+    pub fn module_name(&self) -> &OsStr {
+        self.module_name.as_os_str()
+    }
+
+    /// The file the library was loaded from,
+    /// or the module name if it was found by the system loader
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Whether the symbols of the library are available to libraries loaded after it
+    pub fn is_global(&self) -> bool {
+        self.is_global
+    }
+
+    pub fn library(&self) -> &Library {
+        &self.library
+    }
+}
+
+/// Process-wide registry of shared libraries used by bare FFI.
+/// Module names are resolved to platform specific file names within the search paths,
+/// and libraries loaded from the same file share one handle.
+#[derive(Debug)]
+pub struct LibraryRegistry {
+    search_paths: Vec<PathBuf>,
+    libraries: HashMap<PathBuf, Weak<SharedLibrary>>,
+    /// libraries loaded explicitly by the image stay loaded until the process exits
+    preloaded_libraries: HashMap<PathBuf, Arc<SharedLibrary>>,
+}
+
+impl LibraryRegistry {
+    /// Create a registry searching in the image directory, the executable directory
+    /// and the directories listed in the platform library path variable, in that order
+    pub fn new(image_directory: Option<PathBuf>) -> Self {
+        let executable_directory = std::env::current_exe()
+            .ok()
+            .and_then(|executable| executable.parent().map(|parent| parent.to_path_buf()));
+        let library_paths = std::env::var_os(LIBRARY_PATH_VARIABLE)
+            .map(|paths| std::env::split_paths(&paths).collect::<Vec<PathBuf>>())
+            .unwrap_or_default();
+
+        let mut registry = Self {
+            search_paths: vec![],
+            libraries: Default::default(),
+            preloaded_libraries: Default::default(),
+        };
+        image_directory
+            .into_iter()
+            .chain(executable_directory)
+            .chain(library_paths)
+            .for_each(|each| registry.add_search_path(each));
+        registry
+    }
+
+    /// Initialize the process-wide registry for an image in a given directory.
+    /// Has no effect if the registry is already in use
+    pub fn initialize(image_directory: Option<PathBuf>) {
+        let _ = LIBRARY_REGISTRY.set(Mutex::new(Self::new(image_directory)));
+    }
+
+    pub fn global() -> MutexGuard<'static, LibraryRegistry> {
+        LIBRARY_REGISTRY
+            .get_or_init(|| Mutex::new(Self::new(None)))
+            .lock()
+            .unwrap()
+    }
+
+    /// Append a directory to the search paths, ignoring duplicates
+    pub fn add_search_path(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        if !path.as_os_str().is_empty() && !self.search_paths.contains(&path) {
+            self.search_paths.push(path);
+        }
+    }
+
+    /// Return a loaded library for a module name or a path, loading it if necessary.
+    /// A library that is already loaded locally is promoted when loaded as global.
+    pub fn load(
+        &mut self,
+        module_name: impl AsRef<OsStr>,
+        is_global: bool,
+    ) -> Result<Arc<SharedLibrary>, Error> {
+        let module_name = module_name.as_ref();
+        let path = self.resolved_path(module_name);
+
+        if let Some(library) = self.libraries.get(&path).and_then(|each| each.upgrade()) {
+            if library.is_global || !is_global {
+                return Ok(library);
+            }
+        }
+
+        let library = Arc::new(SharedLibrary {
+            module_name: module_name.to_os_string(),
+            library: open_library(path.as_os_str(), is_global)?,
+            path: path.clone(),
+            is_global,
+        });
+        self.libraries.insert(path, Arc::downgrade(&library));
+        self.libraries.retain(|_, each| each.strong_count() > 0);
+        Ok(library)
+    }
+
+    /// Load a library and keep it loaded until the process exits
+    pub fn preload(
+        &mut self,
+        module_name: impl AsRef<OsStr>,
+        is_global: bool,
+    ) -> Result<Arc<SharedLibrary>, Error> {
+        let library = self.load(module_name, is_global)?;
+        self.preloaded_libraries
+            .insert(library.path.clone(), library.clone());
+        Ok(library)
+    }
+
+    /// Return all libraries that are currently loaded
+    pub fn loaded_libraries(&self) -> Vec<Arc<SharedLibrary>> {
+        let mut libraries: Vec<Arc<SharedLibrary>> = self
+            .libraries
+            .values()
+            .filter_map(|each| each.upgrade())
+            .collect();
+        libraries.sort_by(|a, b| a.path.cmp(&b.path));
+        libraries
+    }
+
+    /// The path a module would be loaded from,
+    /// which is the module name itself if it should be found by the system loader
+    pub fn resolved_path(&self, module_name: impl AsRef<OsStr>) -> PathBuf {
+        self.resolve(module_name.as_ref())
+            .unwrap_or_else(|| PathBuf::from(module_name.as_ref()))
+    }
+
+    /// Find a file of a module within the search paths.
+    /// Returns None if the module should be found by the system loader
+    pub fn resolve(&self, module_name: impl AsRef<OsStr>) -> Option<PathBuf> {
+        let module_path = Path::new(module_name.as_ref());
+        if module_path.is_absolute() {
+            return module_path.exists().then(|| module_path.to_path_buf());
+        }
+
+        let file_names = library_file_names(module_name.as_ref());
+        for directory in &self.search_paths {
+            for file_name in &file_names {
+                let path = directory.join(file_name);
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+            if let Some(path) = versioned_library_in(directory, module_name.as_ref()) {
+                return Some(path);
+            }
+        }
+        None
+    }
+}
+
+/// The file of a loaded module and the name of a symbol exported by it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ResolvedSymbol {
+    pub module_path: PathBuf,
+    pub symbol_name: String,
+}
+
+/// Find the module and the symbol that start exactly at a given address.
+/// Returns None if the address is not the start of an exported symbol of a loaded module
+#[cfg(unix)]
+pub fn resolve_symbol_address(address: *const std::os::raw::c_void) -> Option<ResolvedSymbol> {
+    use std::ffi::CStr;
+
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    if unsafe { libc::dladdr(address, &mut info) } == 0 {
+        return None;
+    }
+    if info.dli_fname.is_null()
+        || info.dli_sname.is_null()
+        || info.dli_saddr as usize != address as usize
+    {
+        return None;
+    }
+
+    let module_path = unsafe { CStr::from_ptr(info.dli_fname) };
+    let symbol_name = unsafe { CStr::from_ptr(info.dli_sname) };
+    Some(ResolvedSymbol {
+        module_path: PathBuf::from(module_path.to_string_lossy().to_string()),
+        symbol_name: symbol_name.to_string_lossy().to_string(),
+    })
+}
+
+/// There is no portable way to find a symbol by its address on Windows
+#[cfg(not(unix))]
+pub fn resolve_symbol_address(_address: *const std::os::raw::c_void) -> Option<ResolvedSymbol> {
+    None
+}
+
+/// Platform specific file names of a module, such as `libfoo.so` for `foo` on Linux.
+/// Names that already have an extension are used as is
+fn library_file_names(module_name: &OsStr) -> Vec<OsString> {
+    let name = module_name.to_string_lossy();
+    let has_extension = name.contains(".so.")
+        || [".so", ".dylib", ".dll"]
+            .iter()
+            .any(|extension| name.ends_with(extension));
+    if has_extension {
+        return vec![module_name.to_os_string()];
+    }
+
+    #[cfg(target_os = "windows")]
+    let file_names = vec![format!("{}.dll", name), format!("lib{}.dll", name)];
+    #[cfg(target_os = "macos")]
+    let file_names = vec![
+        format!("lib{}.dylib", name),
+        format!("{}.dylib", name),
+        format!("{0}.framework/{0}", name),
+    ];
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let file_names = vec![format!("lib{}.so", name), format!("{}.so", name)];
+
+    file_names
+        .into_iter()
+        .map(OsString::from)
+        .chain([module_name.to_os_string()])
+        .collect()
+}
+
+/// Find the highest version of `libfoo.so.N` in a directory
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn versioned_library_in(directory: &Path, module_name: &OsStr) -> Option<PathBuf> {
+    let name = module_name.to_string_lossy();
+    let prefix = if name.ends_with(".so") {
+        format!("{}.", name)
+    } else {
+        format!("lib{}.so.", name.strip_prefix("lib").unwrap_or(&name))
+    };
+
+    std::fs::read_dir(directory)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let version = file_name
+                .strip_prefix(prefix.as_str())?
+                .split('.')
+                .map(|each| each.parse::<u32>().ok())
+                .collect::<Option<Vec<u32>>>()?;
+            Some((version, entry.path()))
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, path)| path)
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+fn versioned_library_in(_directory: &Path, _module_name: &OsStr) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(unix)]
+fn open_library(path: &OsStr, is_global: bool) -> Result<Library, Error> {
+    use libloading::os::unix::{Library as UnixLibrary, RTLD_GLOBAL, RTLD_LAZY, RTLD_LOCAL};
+
+    let flags = RTLD_LAZY | if is_global { RTLD_GLOBAL } else { RTLD_LOCAL };
+    let library = unsafe { UnixLibrary::open(Some(path), flags) }?;
+    Ok(library.into())
+}
+
+/// Windows has no notion of global symbols, all libraries are loaded the same way
+#[cfg(not(unix))]
+fn open_library(path: &OsStr, _is_global: bool) -> Result<Library, Error> {
+    Ok(unsafe { Library::new(path) }?)
+}
+
+/// Load a library and keep it loaded, optionally making its symbols global.
+/// Answers the path the library was loaded from
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveFfiLoadLibrary() -> Result<(), Error> {
+    let module_name = ByteStringRef::try_from(Smalltalk::get_method_argument(0))?.to_string();
+    let module_path = LibraryRegistry::global().resolved_path(&module_name);
+    if !FfiPolicy::global().check_module(&module_path.to_string_lossy()) {
+        return Err(Error::FfiPolicyViolation(module_name));
+    }
+    let is_global =
+        Smalltalk::true_object() == ObjectPointer::from(Smalltalk::get_method_argument(1).as_i64());
+
+    let library = LibraryRegistry::global().preload(module_name, is_global)?;
+    let path = library.path().to_string_lossy().to_string();
+
+    Smalltalk::method_return(ByteString::new(path.as_bytes())?);
+    Ok(())
+}
+
+/// Append a directory to the library search paths
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveFfiAddLibrarySearchPath() -> Result<(), Error> {
+    let path = ByteStringRef::try_from(Smalltalk::get_method_argument(0))?.to_string();
+    if !FfiPolicy::global().check_search_path(&path) {
+        return Err(Error::FfiPolicyViolation(path));
+    }
+    LibraryRegistry::global().add_search_path(path);
+
+    Smalltalk::method_return(Smalltalk::method_receiver());
+    Ok(())
+}
+
+/// Answer an array of `{ module name . path . is global }` of all loaded libraries
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveFfiLoadedLibraries() -> Result<(), Error> {
+    let libraries = LibraryRegistry::global().loaded_libraries();
+
+    let mut array = Array::new(libraries.len())?;
+    for (index, library) in libraries.iter().enumerate() {
+        let module_name = library.module_name().to_string_lossy().to_string();
+        let path = library.path().to_string_lossy().to_string();
+
+        let mut description = Array::new(3)?;
+        description.insert(0, ByteString::new(module_name.as_bytes())?);
+        description.insert(1, ByteString::new(path.as_bytes())?);
+        description.insert(2, Smalltalk::bool_object(library.is_global()));
+        array.insert(index, description);
+    }
+
+    Smalltalk::method_return(array);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory in the system temporary directory, removed when dropped
+    struct TemporaryDirectory(PathBuf);
+
+    impl TemporaryDirectory {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "vm-runtime-library-registry-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn touch(&self, file_name: &str) -> PathBuf {
+            let path = self.0.join(file_name);
+            std::fs::write(&path, []).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TemporaryDirectory {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn registry_in(directories: &[&TemporaryDirectory]) -> LibraryRegistry {
+        let mut registry = LibraryRegistry {
+            search_paths: vec![],
+            libraries: Default::default(),
+            preloaded_libraries: Default::default(),
+        };
+        for directory in directories {
+            registry.add_search_path(directory.0.clone());
+        }
+        registry
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn symbol_addresses_are_resolved() {
+        let library = unsafe { Library::new("libc.so.6") }.unwrap();
+        let malloc = unsafe { library.get::<*const std::os::raw::c_void>(b"malloc\0") }.unwrap();
+        let address = *malloc;
+
+        let symbol = resolve_symbol_address(address).unwrap();
+        assert_eq!(symbol.symbol_name, "malloc");
+        assert!(symbol
+            .module_path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("libc"));
+
+        assert_eq!(
+            resolve_symbol_address(unsafe { (address as *const u8).add(1) } as *const _),
+            None
+        );
+    }
+
+    #[test]
+    fn file_names_with_extension_are_used_as_is() {
+        for name in ["libfoo.so", "libfoo.so.1", "foo.dylib", "foo.dll"] {
+            assert_eq!(
+                library_file_names(OsStr::new(name)),
+                vec![OsString::from(name)]
+            );
+        }
+    }
+
+    #[test]
+    fn file_names_end_with_module_name() {
+        let file_names = library_file_names(OsStr::new("foo"));
+        assert_eq!(file_names.last(), Some(&OsString::from("foo")));
+        assert!(file_names.len() > 1);
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    #[test]
+    fn file_names_on_linux() {
+        assert_eq!(
+            library_file_names(OsStr::new("foo")),
+            vec![
+                OsString::from("libfoo.so"),
+                OsString::from("foo.so"),
+                OsString::from("foo")
+            ]
+        );
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    #[test]
+    fn versioned_library_picks_highest_version() {
+        let directory = TemporaryDirectory::new("versioned");
+        directory.touch("libfoo.so.1");
+        directory.touch("libfoo.so.2.1");
+        let highest = directory.touch("libfoo.so.10");
+        directory.touch("libfoo.so.11.beta");
+        directory.touch("libfoobar.so.12");
+
+        assert_eq!(
+            versioned_library_in(&directory.0, OsStr::new("foo")),
+            Some(highest.clone())
+        );
+        assert_eq!(
+            versioned_library_in(&directory.0, OsStr::new("libfoo")),
+            Some(highest.clone())
+        );
+        assert_eq!(
+            versioned_library_in(&directory.0, OsStr::new("libfoo.so")),
+            Some(highest)
+        );
+        assert_eq!(versioned_library_in(&directory.0, OsStr::new("bar")), None);
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    #[test]
+    fn versioned_library_strips_only_one_lib_prefix() {
+        let directory = TemporaryDirectory::new("lib-prefix");
+        directory.touch("libfoo.so.1");
+        let library = directory.touch("liblibfoo.so.1");
+
+        assert_eq!(
+            versioned_library_in(&directory.0, OsStr::new("liblibfoo")),
+            Some(library)
+        );
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    #[test]
+    fn versioned_library_in_missing_directory() {
+        let directory = TemporaryDirectory::new("missing");
+        let missing = directory.0.join("missing");
+        assert_eq!(versioned_library_in(&missing, OsStr::new("foo")), None);
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    #[test]
+    fn resolve_prefers_unversioned_library() {
+        let directory = TemporaryDirectory::new("unversioned");
+        directory.touch("libfoo.so.1");
+        let library = directory.touch("libfoo.so");
+
+        assert_eq!(registry_in(&[&directory]).resolve("foo"), Some(library));
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    #[test]
+    fn resolve_falls_back_to_versioned_library() {
+        let directory = TemporaryDirectory::new("fallback");
+        let library = directory.touch("libfoo.so.3");
+
+        assert_eq!(registry_in(&[&directory]).resolve("foo"), Some(library));
+    }
+
+    #[test]
+    fn resolve_searches_paths_in_order() {
+        let first = TemporaryDirectory::new("first");
+        let second = TemporaryDirectory::new("second");
+        let file_name = library_file_names(OsStr::new("foo"))[0]
+            .to_string_lossy()
+            .to_string();
+        second.touch(&file_name);
+        let library = first.touch(&file_name);
+
+        assert_eq!(
+            registry_in(&[&first, &second]).resolve("foo"),
+            Some(library)
+        );
+        assert_eq!(
+            registry_in(&[&second]).resolve("foo"),
+            Some(second.0.join(&file_name))
+        );
+    }
+
+    #[test]
+    fn resolve_skips_directories() {
+        let directory = TemporaryDirectory::new("directories");
+        std::fs::create_dir(directory.0.join("foo")).unwrap();
+
+        assert_eq!(registry_in(&[&directory]).resolve("foo"), None);
+    }
+
+    #[test]
+    fn resolve_absolute_path() {
+        let directory = TemporaryDirectory::new("absolute");
+        let library = directory.touch("custom-library");
+
+        let registry = registry_in(&[]);
+        assert_eq!(registry.resolve(&library), Some(library.clone()));
+        assert_eq!(registry.resolve(directory.0.join("missing")), None);
+    }
+
+    #[test]
+    fn resolve_unknown_module_is_left_to_system_loader() {
+        let directory = TemporaryDirectory::new("unknown");
+        assert_eq!(registry_in(&[&directory]).resolve("foo"), None);
+    }
+
+    #[test]
+    fn search_paths_ignore_duplicates_and_empty_paths() {
+        let directory = TemporaryDirectory::new("duplicates");
+        let mut registry = registry_in(&[&directory, &directory]);
+        registry.add_search_path("");
+        assert_eq!(registry.search_paths, vec![directory.0.clone()]);
+    }
+}
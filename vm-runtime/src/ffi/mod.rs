@@ -5,3 +5,17 @@ mod ffi;
 pub use ffi::*;
 mod bare_ffi;
 pub use bare_ffi::*;
+mod bare_ffi_async;
+pub use bare_ffi_async::*;
+mod bare_ffi_buffer;
+pub use bare_ffi_buffer::*;
+mod bare_ffi_callback;
+pub use bare_ffi_callback::*;
+mod bare_ffi_struct;
+pub use bare_ffi_struct::*;
+mod errno;
+pub use errno::*;
+mod library_registry;
+pub use library_registry::*;
+mod raw_memory;
+pub use raw_memory::*;
@@ -0,0 +1,186 @@
+use vm_bindings::Smalltalk;
+
+use crate::ffi::{read_and_marshall_value, ArgumentMarshall, Error, MarshallType};
+use crate::objects::{ByteArray, ByteArrayRef};
+use crate::{AllocationTracker, FfiPolicy};
+
+/// Resolve an external address and an offset given as method arguments
+/// to a pointer to a given amount of bytes.
+/// When allocations are tracked, accesses that overlap a known allocation must be contained in it
+fn pointer_at_offset(
+    address_index: usize,
+    offset_index: usize,
+    amount_of_bytes: usize,
+) -> Result<*mut u8, Error> {
+    if !FfiPolicy::global().check_raw_pointers("raw memory primitives") {
+        return Err(Error::FfiPolicyViolation("raw memory".to_string()));
+    }
+    let external_address = Smalltalk::get_method_argument(address_index).as_object()?;
+    if Smalltalk::class_of_object(external_address) != Smalltalk::class_external_address() {
+        return Err(Error::NotAnExternalAddress);
+    }
+    let address = Smalltalk::read_external_address(external_address);
+    if address.is_null() {
+        return Err(Error::NullAddress);
+    }
+
+    let offset: isize = Smalltalk::get_method_argument(offset_index)
+        .as_immediate()?
+        .try_as_integer()?
+        .try_into()?;
+    let overflow = Error::AddressOverflow {
+        address: address as usize,
+        amount_of_bytes,
+    };
+    let Some(pointer) = (address as usize).checked_add_signed(offset) else {
+        return Err(overflow);
+    };
+    let Some(end) = pointer.checked_add(amount_of_bytes) else {
+        return Err(overflow);
+    };
+    let pointer = pointer as *mut u8;
+
+    if let Some(tracker) = AllocationTracker::current() {
+        if let Some((start, size)) = tracker.allocation_overlapping(pointer as usize, end) {
+            return Err(Error::OutOfBounds {
+                address: pointer as usize,
+                amount_of_bytes,
+                allocation: start,
+                allocation_size: size,
+            });
+        }
+    }
+    Ok(pointer)
+}
+
+fn read_value(marshall_type: MarshallType) -> Result<(), Error> {
+    let (size, _) = marshall_type
+        .layout()
+        .ok_or(Error::IllegalStructFieldType(marshall_type))?;
+    let source = pointer_at_offset(0, 1, size)?;
+
+    let value = unsafe { read_and_marshall_value(marshall_type, source)? };
+    Smalltalk::method_return(value);
+    Ok(())
+}
+
+fn write_value(marshall_type: MarshallType) -> Result<(), Error> {
+    let (size, _) = marshall_type
+        .layout()
+        .ok_or(Error::IllegalStructFieldType(marshall_type))?;
+    let destination = pointer_at_offset(0, 1, size)?;
+
+    let nil_object = Smalltalk::nil_object().as_object()?;
+    let value = ArgumentMarshall::new(nil_object, nil_object)
+        .marshall_as(Smalltalk::get_method_argument(2), marshall_type)?;
+    unsafe { std::ptr::copy_nonoverlapping(value.as_arg() as *const u8, destination, size) };
+
+    Smalltalk::method_return(Smalltalk::method_receiver());
+    Ok(())
+}
+
+macro_rules! raw_memory_accessors {
+    ($marshall_type:expr, $read:ident, $write:ident) => {
+        /// Read a value at an external address plus an offset
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        pub fn $read() -> Result<(), Error> {
+            read_value($marshall_type)
+        }
+
+        /// Write a value at an external address plus an offset
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        pub fn $write() -> Result<(), Error> {
+            write_value($marshall_type)
+        }
+    };
+}
+
+raw_memory_accessors!(
+    MarshallType::I8,
+    primitiveRawMemoryReadI8,
+    primitiveRawMemoryWriteI8
+);
+raw_memory_accessors!(
+    MarshallType::U8,
+    primitiveRawMemoryReadU8,
+    primitiveRawMemoryWriteU8
+);
+raw_memory_accessors!(
+    MarshallType::I16,
+    primitiveRawMemoryReadI16,
+    primitiveRawMemoryWriteI16
+);
+raw_memory_accessors!(
+    MarshallType::U16,
+    primitiveRawMemoryReadU16,
+    primitiveRawMemoryWriteU16
+);
+raw_memory_accessors!(
+    MarshallType::I32,
+    primitiveRawMemoryReadI32,
+    primitiveRawMemoryWriteI32
+);
+raw_memory_accessors!(
+    MarshallType::U32,
+    primitiveRawMemoryReadU32,
+    primitiveRawMemoryWriteU32
+);
+raw_memory_accessors!(
+    MarshallType::I64,
+    primitiveRawMemoryReadI64,
+    primitiveRawMemoryWriteI64
+);
+raw_memory_accessors!(
+    MarshallType::U64,
+    primitiveRawMemoryReadU64,
+    primitiveRawMemoryWriteU64
+);
+raw_memory_accessors!(
+    MarshallType::F32,
+    primitiveRawMemoryReadF32,
+    primitiveRawMemoryWriteF32
+);
+raw_memory_accessors!(
+    MarshallType::F64,
+    primitiveRawMemoryReadF64,
+    primitiveRawMemoryWriteF64
+);
+raw_memory_accessors!(
+    MarshallType::Pointer,
+    primitiveRawMemoryReadPointer,
+    primitiveRawMemoryWritePointer
+);
+
+/// Copy a given amount of bytes at an external address plus an offset into a new ByteArray
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveRawMemoryReadBytes() -> Result<(), Error> {
+    let amount_of_bytes: usize = Smalltalk::get_method_argument(2)
+        .as_immediate()?
+        .try_as_integer()?
+        .try_into()?;
+    let source = pointer_at_offset(0, 1, amount_of_bytes)?;
+
+    let mut bytes = ByteArray::new(amount_of_bytes)?;
+    bytes
+        .as_slice_mut()
+        .copy_from_slice(unsafe { std::slice::from_raw_parts(source, amount_of_bytes) });
+
+    Smalltalk::method_return(bytes);
+    Ok(())
+}
+
+/// Copy all bytes of a ByteArray to an external address plus an offset
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveRawMemoryWriteBytes() -> Result<(), Error> {
+    let bytes = ByteArrayRef::try_from(Smalltalk::get_method_argument(2))?;
+    let destination = pointer_at_offset(0, 1, bytes.len())?;
+
+    unsafe { std::ptr::copy_nonoverlapping(bytes.as_slice().as_ptr(), destination, bytes.len()) };
+
+    Smalltalk::method_return(Smalltalk::method_receiver());
+    Ok(())
+}
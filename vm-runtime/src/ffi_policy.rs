@@ -0,0 +1,474 @@
+use std::path::Path;
+
+use once_cell::sync::OnceCell;
+
+use crate::{ApplicationError, LogSignal, Result, VM_LOGGER};
+
+static FFI_POLICY: OnceCell<FfiPolicy> = OnceCell::new();
+
+/// The type of log signals reporting violations of the FFI policy
+pub const FFI_POLICY_LOG_TYPE: &str = "FFI_POLICY";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FfiPolicyAction {
+    Allow,
+    Deny,
+}
+
+impl TryFrom<&str> for FfiPolicyAction {
+    type Error = ApplicationError;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "allow" => Ok(Self::Allow),
+            "deny" => Ok(Self::Deny),
+            _ => Err(ApplicationError::InvalidFfiPolicy(format!(
+                "Unknown action `{}`, expected `allow` or `deny`",
+                value
+            ))),
+        }
+    }
+}
+
+/// Allows or denies symbols of modules which paths match given patterns.
+/// Patterns may contain `*` matching any amount of characters and `?` matching one character
+#[derive(Debug, Clone)]
+pub struct FfiPolicyRule {
+    action: FfiPolicyAction,
+    module: String,
+    symbol: String,
+}
+
+impl FfiPolicyRule {
+    pub fn new(
+        action: FfiPolicyAction,
+        module: impl Into<String>,
+        symbol: impl Into<String>,
+    ) -> Self {
+        Self {
+            action,
+            module: module.into(),
+            symbol: symbol.into(),
+        }
+    }
+
+    /// Modules are given by the path they are loaded from, or by their name if they are found
+    /// by the system loader. Allow rules must match the whole path, so that `libc*` does not allow
+    /// `/tmp/anything/libc.so`, while deny rules also match the file name,
+    /// so that `libc*` denies `/usr/lib/libc.so.6`
+    fn matches_module(&self, module: &str) -> bool {
+        if matches_pattern(&self.module, module) {
+            return true;
+        }
+        self.action == FfiPolicyAction::Deny
+            && Path::new(module).file_name().is_some_and(|file_name| {
+                matches_pattern(&self.module, &file_name.to_string_lossy())
+            })
+    }
+}
+
+/// Decides which libraries and symbols an image may use through FFI
+/// and whether it may access raw memory.
+/// Rules are checked in order and the first matching rule wins,
+/// the default action applies when no rule matches.
+#[derive(Debug, Clone)]
+pub struct FfiPolicy {
+    default_action: FfiPolicyAction,
+    rules: Vec<FfiPolicyRule>,
+    allows_raw_pointers: bool,
+}
+
+impl Default for FfiPolicy {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+impl FfiPolicy {
+    pub fn allow_all() -> Self {
+        Self {
+            default_action: FfiPolicyAction::Allow,
+            rules: vec![],
+            allows_raw_pointers: true,
+        }
+    }
+
+    pub fn deny_all() -> Self {
+        Self {
+            default_action: FfiPolicyAction::Deny,
+            rules: vec![],
+            allows_raw_pointers: false,
+        }
+    }
+
+    pub fn allow(self, module: impl Into<String>, symbol: impl Into<String>) -> Self {
+        self.with_rule(FfiPolicyRule::new(FfiPolicyAction::Allow, module, symbol))
+    }
+
+    pub fn deny(self, module: impl Into<String>, symbol: impl Into<String>) -> Self {
+        self.with_rule(FfiPolicyRule::new(FfiPolicyAction::Deny, module, symbol))
+    }
+
+    pub fn with_rule(mut self, rule: FfiPolicyRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn with_raw_pointers(mut self, allows_raw_pointers: bool) -> Self {
+        self.allows_raw_pointers = allows_raw_pointers;
+        self
+    }
+
+    /// Read a policy from a json file of the form:
+    /// ```json
+    /// {
+    ///   "default": "deny",
+    ///   "raw_pointers": false,
+    ///   "rules": [
+    ///     { "action": "allow", "module": "/opt/gtoolkit/libSkia*", "symbol": "*" },
+    ///     { "action": "deny", "module": "libc*", "symbol": "system" }
+    ///   ]
+    /// }
+    /// ```
+    /// Everything is allowed by default, and a rule without a symbol applies to all symbols
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_json(std::fs::read_to_string(path)?.as_str())
+    }
+
+    pub fn from_json(source: &str) -> Result<Self> {
+        let json = json::parse(source)
+            .map_err(|error| ApplicationError::InvalidFfiPolicy(error.to_string()))?;
+
+        let mut policy = Self::allow_all();
+        if let Some(default_action) = json["default"].as_str() {
+            policy.default_action = FfiPolicyAction::try_from(default_action)?;
+        }
+        if let Some(allows_raw_pointers) = json["raw_pointers"].as_bool() {
+            policy.allows_raw_pointers = allows_raw_pointers;
+        }
+
+        for rule in json["rules"].members() {
+            let action = rule["action"].as_str().ok_or_else(|| {
+                ApplicationError::InvalidFfiPolicy("A rule must have an action".to_string())
+            })?;
+            let module = rule["module"].as_str().ok_or_else(|| {
+                ApplicationError::InvalidFfiPolicy("A rule must have a module".to_string())
+            })?;
+            let symbol = rule["symbol"].as_str().unwrap_or("*");
+            policy = policy.with_rule(FfiPolicyRule::new(
+                FfiPolicyAction::try_from(action)?,
+                module,
+                symbol,
+            ));
+        }
+        Ok(policy)
+    }
+
+    /// Install the process-wide policy.
+    /// Has no effect if the policy is already in use
+    pub fn initialize(policy: FfiPolicy) {
+        let _ = FFI_POLICY.set(policy);
+    }
+
+    pub fn global() -> &'static FfiPolicy {
+        FFI_POLICY.get_or_init(Self::allow_all)
+    }
+
+    pub fn permits_symbol(&self, module: &str, symbol: &str) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches_module(module) && matches_pattern(&rule.symbol, symbol))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default_action)
+            == FfiPolicyAction::Allow
+    }
+
+    /// Whether the image may add directories to the library search paths.
+    /// Only a policy that allows everything by default lets the image decide where libraries come from
+    pub fn permits_search_paths(&self) -> bool {
+        self.default_action == FfiPolicyAction::Allow
+    }
+
+    /// Whether a function that is not resolved to a module and a symbol may be called,
+    /// which is only the case when nothing is restricted
+    pub fn permits_unknown_symbols(&self) -> bool {
+        self.default_action == FfiPolicyAction::Allow && self.rules.is_empty()
+    }
+
+    /// A module may be loaded unless all of its symbols are denied
+    pub fn permits_module(&self, module: &str) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.matches_module(module)
+                    && (rule.action == FfiPolicyAction::Allow || rule.symbol == "*")
+            })
+            .map(|rule| rule.action)
+            .unwrap_or(self.default_action)
+            == FfiPolicyAction::Allow
+    }
+
+    pub fn allows_raw_pointers(&self) -> bool {
+        self.allows_raw_pointers
+    }
+
+    /// Check whether a symbol may be called, logging a violation otherwise
+    pub fn check_symbol(&self, module: &str, symbol: &str) -> bool {
+        let is_permitted = self.permits_symbol(module, symbol);
+        if !is_permitted {
+            log_violation(format!("Denied a call of `{}` from `{}`", symbol, module));
+        }
+        is_permitted
+    }
+
+    /// Check whether a module may be loaded, logging a violation otherwise
+    pub fn check_module(&self, module: &str) -> bool {
+        let is_permitted = self.permits_module(module);
+        if !is_permitted {
+            log_violation(format!("Denied loading of `{}`", module));
+        }
+        is_permitted
+    }
+
+    /// Check whether a directory may be added to the library search paths, logging a violation otherwise
+    pub fn check_search_path(&self, path: &str) -> bool {
+        let is_permitted = self.permits_search_paths();
+        if !is_permitted {
+            log_violation(format!(
+                "Denied adding `{}` to the library search paths",
+                path
+            ));
+        }
+        is_permitted
+    }
+
+    /// Check whether a function at an address that is not resolved to a symbol may be called,
+    /// logging a violation otherwise
+    pub fn check_unknown_symbol(&self, address: usize) -> bool {
+        let is_permitted = self.permits_unknown_symbols();
+        if !is_permitted {
+            log_violation(format!(
+                "Denied a call of an unknown function at {:#x}",
+                address
+            ));
+        }
+        is_permitted
+    }
+
+    /// Check whether raw memory may be accessed by a given primitive, logging a violation otherwise
+    pub fn check_raw_pointers(&self, primitive_name: &str) -> bool {
+        if !self.allows_raw_pointers {
+            log_violation(format!("Denied raw memory access by `{}`", primitive_name));
+        }
+        self.allows_raw_pointers
+    }
+}
+
+fn log_violation(message: String) {
+    VM_LOGGER.lock().unwrap().log(LogSignal {
+        log_type: FFI_POLICY_LOG_TYPE.to_string(),
+        file_name: file!().to_string(),
+        function_name: "log_violation".to_string(),
+        line: line!() as usize,
+        message,
+    });
+}
+
+/// Match a text against a pattern where `*` matches any amount of characters and `?` one character
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut pattern_index, mut text_index) = (0, 0);
+    // the position of the last `*` and of the text it is matched against
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while text_index < text.len() {
+        match pattern.get(pattern_index) {
+            Some('*') => {
+                backtrack = Some((pattern_index, text_index));
+                pattern_index += 1;
+            }
+            Some(character) if *character == '?' || *character == text[text_index] => {
+                pattern_index += 1;
+                text_index += 1;
+            }
+            _ => match backtrack {
+                Some((star_index, star_text_index)) => {
+                    pattern_index = star_index + 1;
+                    text_index = star_text_index + 1;
+                    backtrack = Some((star_index, star_text_index + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[pattern_index..]
+        .iter()
+        .all(|character| *character == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_patterns() {
+        assert!(matches_pattern("libc.so.6", "libc.so.6"));
+        assert!(!matches_pattern("libc.so.6", "libc.so"));
+        assert!(!matches_pattern("libc.so", "libc.so.6"));
+        assert!(matches_pattern("", ""));
+        assert!(!matches_pattern("", "libc"));
+    }
+
+    #[test]
+    fn star_patterns() {
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("*", "anything"));
+        assert!(matches_pattern("libc*", "libc.so.6"));
+        assert!(matches_pattern("*.dylib", "libSkia.dylib"));
+        assert!(matches_pattern("lib*Skia*", "libgtSkia.so"));
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(matches_pattern("**", "abc"));
+        assert!(!matches_pattern("libc*", "glibc.so"));
+        assert!(!matches_pattern("*.dll", "library.dll.so"));
+        assert!(!matches_pattern("a*b*c", "aXbYcZ"));
+    }
+
+    #[test]
+    fn question_mark_patterns() {
+        assert!(matches_pattern("libc.so.?", "libc.so.6"));
+        assert!(matches_pattern("?", "x"));
+        assert!(!matches_pattern("?", ""));
+        assert!(!matches_pattern("libc.so.?", "libc.so.10"));
+        assert!(matches_pattern("*.so.?", "libc.so.6"));
+    }
+
+    #[test]
+    fn patterns_match_characters_not_bytes() {
+        assert!(matches_pattern("l?b", "läb"));
+        assert!(matches_pattern("*ä", "bä"));
+    }
+
+    #[test]
+    fn allowed_modules_are_matched_by_path() {
+        let policy = FfiPolicy::deny_all()
+            .allow("libc*", "*")
+            .allow("/usr/lib/libm*", "*");
+        assert!(policy.permits_symbol("libc.so.6", "malloc"));
+        assert!(policy.permits_symbol("/usr/lib/libm.so.6", "sin"));
+        assert!(!policy.permits_symbol("/usr/lib/libc.so.6", "malloc"));
+        assert!(!policy.permits_symbol("/tmp/anything/libc.so", "malloc"));
+        assert!(!policy.permits_symbol("/tmp/usr/lib/libm.so.6", "sin"));
+    }
+
+    #[test]
+    fn denied_modules_are_matched_by_file_name() {
+        let policy = FfiPolicy::allow_all().deny("libc*", "system");
+        assert!(!policy.permits_symbol("libc.so.6", "system"));
+        assert!(!policy.permits_symbol("/usr/lib/libc.so.6", "system"));
+        assert!(!policy.permits_symbol("/tmp/anything/libc.so", "system"));
+        assert!(policy.permits_symbol("/usr/lib/libcrypto/other.so", "system"));
+    }
+
+    #[test]
+    fn search_paths_are_permitted_only_by_default() {
+        assert!(FfiPolicy::allow_all().permits_search_paths());
+        assert!(FfiPolicy::allow_all()
+            .deny("libc*", "system")
+            .permits_search_paths());
+        assert!(!FfiPolicy::deny_all().permits_search_paths());
+        assert!(!FfiPolicy::deny_all().allow("*", "*").permits_search_paths());
+    }
+
+    #[test]
+    fn unknown_symbols_are_permitted_only_without_restrictions() {
+        assert!(FfiPolicy::allow_all().permits_unknown_symbols());
+        assert!(!FfiPolicy::allow_all()
+            .deny("libc*", "system")
+            .permits_unknown_symbols());
+        assert!(!FfiPolicy::deny_all().permits_unknown_symbols());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let policy = FfiPolicy::allow_all()
+            .deny("libc*", "system")
+            .allow("libc*", "*");
+        assert!(!policy.permits_symbol("libc.so.6", "system"));
+        assert!(policy.permits_symbol("libc.so.6", "malloc"));
+        assert!(policy.permits_module("libc.so.6"));
+    }
+
+    #[test]
+    fn modules_with_all_symbols_denied_are_not_permitted() {
+        let policy = FfiPolicy::allow_all().deny("libevil*", "*");
+        assert!(!policy.permits_module("/opt/libevil.so"));
+        assert!(policy.permits_module("libgood.so"));
+    }
+
+    #[test]
+    fn empty_json_allows_everything() {
+        let policy = FfiPolicy::from_json("{}").unwrap();
+        assert!(policy.permits_symbol("libc.so.6", "system"));
+        assert!(policy.permits_module("libc.so.6"));
+        assert!(policy.allows_raw_pointers());
+    }
+
+    #[test]
+    fn json_policy() {
+        let policy = FfiPolicy::from_json(
+            r#"{
+                "default": "deny",
+                "raw_pointers": false,
+                "rules": [
+                    { "action": "allow", "module": "/opt/gtoolkit/libSkia*", "symbol": "sk_*" },
+                    { "action": "deny", "module": "libc*", "symbol": "system" },
+                    { "action": "allow", "module": "libc*" }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(!policy.allows_raw_pointers());
+        assert!(policy.permits_symbol("/opt/gtoolkit/libSkia.so", "sk_canvas_new"));
+        assert!(!policy.permits_symbol("/opt/gtoolkit/libSkia.so", "free"));
+        assert!(!policy.permits_symbol("libSkia.so", "sk_canvas_new"));
+        assert!(!policy.permits_symbol("libc.so.6", "system"));
+        assert!(policy.permits_symbol("libc.so.6", "malloc"));
+        assert!(!policy.permits_symbol("libm.so.6", "sin"));
+        assert!(!policy.permits_module("libm.so.6"));
+    }
+
+    #[test]
+    fn json_with_invalid_syntax_is_rejected() {
+        assert!(matches!(
+            FfiPolicy::from_json("{ default: "),
+            Err(ApplicationError::InvalidFfiPolicy(_))
+        ));
+    }
+
+    #[test]
+    fn json_with_unknown_action_is_rejected() {
+        assert!(matches!(
+            FfiPolicy::from_json(r#"{ "default": "maybe" }"#),
+            Err(ApplicationError::InvalidFfiPolicy(_))
+        ));
+        assert!(matches!(
+            FfiPolicy::from_json(r#"{ "rules": [ { "action": "maybe", "module": "*" } ] }"#),
+            Err(ApplicationError::InvalidFfiPolicy(_))
+        ));
+    }
+
+    #[test]
+    fn json_rules_require_action_and_module() {
+        assert!(matches!(
+            FfiPolicy::from_json(r#"{ "rules": [ { "module": "*" } ] }"#),
+            Err(ApplicationError::InvalidFfiPolicy(_))
+        ));
+        assert!(matches!(
+            FfiPolicy::from_json(r#"{ "rules": [ { "action": "deny" } ] }"#),
+            Err(ApplicationError::InvalidFfiPolicy(_))
+        ));
+    }
+}
@@ -0,0 +1,4 @@
+mod parser;
+mod query;
+
+pub use query::*;
@@ -0,0 +1,718 @@
+use std::fmt::Display;
+
+use anyhow::anyhow;
+
+use crate::heap_query::{
+    Comparison, FieldPredicate, FormatKind, HeapQuery, Predicate, Projection, QuerySource,
+};
+
+impl HeapQuery {
+    /// Parse a query from a string. Keywords are case-insensitive, class names are not.
+    /// ```text
+    /// query      := 'select' projection ['from' source] ['where' predicate] ['limit' integer]
+    /// projection := 'objects' | 'count' | 'bytes' | 'count by class' | 'bytes by class'
+    ///             | 'field' integer
+    /// source     := 'heap' | 'young' | 'old' | 'reachable'
+    /// predicate  := conjunction ('or' conjunction)*
+    /// conjunction:= negation ('and' negation)*
+    /// negation   := 'not' negation | '(' predicate ')' | condition
+    /// condition  := 'class' ('=' | '!=') Name | 'kindOf' Name
+    ///             | 'format' '=' ('fixed' | 'indexable' | 'bytes' | 'words'
+    ///                            | 'weak' | 'ephemeron' | 'context' | 'method')
+    ///             | 'size' comparison integer | 'bytes' comparison integer
+    ///             | 'field' integer field
+    /// field      := 'isNil' | 'notNil' | comparison (integer | 'nil')
+    ///             | 'class' ('=' | '!=') Name | 'kindOf' Name
+    /// comparison := '=' | '!=' | '<' | '<=' | '>' | '>='
+    /// ```
+    /// Fields are zero-based, size is the amount of indexable slots or bytes.
+    /// Errors tell the offset in bytes from the start of the query where parsing failed.
+    pub fn parse(query: &str) -> Result<Self, anyhow::Error> {
+        let mut parser = QueryParser::new(query)?;
+        let query = parser.parse_query()?;
+        if let Some(token) = parser.peek() {
+            return Err(error_at(
+                parser.offset(),
+                format!("Unexpected {} after the end of the query", token),
+            ));
+        }
+        Ok(query)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Token {
+    Word(String),
+    Integer(i64),
+    Operator(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Integer(integer) => write!(f, "{}", integer),
+            Token::Operator(operator) => write!(f, "'{}'", operator),
+        }
+    }
+}
+
+fn error_at(offset: usize, message: impl Display) -> anyhow::Error {
+    anyhow!("{} at offset {}", message, offset)
+}
+
+struct QueryParser {
+    /// tokens along with their offset in the query
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    /// the length of the query, where the end of the query is reported
+    end: usize,
+}
+
+impl QueryParser {
+    const OPERATORS: [&'static str; 8] = ["<=", ">=", "!=", "=", "<", ">", "(", ")"];
+
+    fn new(query: &str) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            tokens: Self::tokenize(query)?,
+            position: 0,
+            end: query.len(),
+        })
+    }
+
+    fn tokenize(query: &str) -> Result<Vec<(Token, usize)>, anyhow::Error> {
+        let mut tokens = vec![];
+        let mut rest = query.trim_start();
+
+        while !rest.is_empty() {
+            let offset = query.len() - rest.len();
+            if let Some(operator) = Self::OPERATORS.iter().find(|each| rest.starts_with(**each)) {
+                tokens.push((Token::Operator(*operator), offset));
+                rest = &rest[operator.len()..];
+            } else {
+                let length = rest
+                    .find(|each: char| {
+                        each.is_whitespace()
+                            || Self::OPERATORS.iter().any(|op| op.starts_with(each))
+                    })
+                    .unwrap_or(rest.len());
+                let word = &rest[..length];
+                if word.is_empty() {
+                    return Err(error_at(
+                        offset,
+                        format!("Unexpected character in {}", rest),
+                    ));
+                }
+
+                let token = if word.starts_with(|each: char| each.is_ascii_digit() || each == '-') {
+                    Token::Integer(
+                        word.parse()
+                            .map_err(|_| error_at(offset, format!("Invalid integer '{}'", word)))?,
+                    )
+                } else {
+                    Token::Word(word.to_string())
+                };
+                tokens.push((token, offset));
+                rest = &rest[length..];
+            }
+            rest = rest.trim_start();
+        }
+
+        Ok(tokens)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    /// The offset of the next token, or the end of the query
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |(_, offset)| *offset)
+    }
+
+    fn next(&mut self) -> Result<Token, anyhow::Error> {
+        let token = self
+            .peek()
+            .cloned()
+            .ok_or_else(|| error_at(self.end, "Unexpected end of the query"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    /// Consume the next token if it is a given keyword
+    fn accept_keyword(&mut self, keyword: &str) -> bool {
+        let is_keyword = self.is_keyword(keyword);
+        if is_keyword {
+            self.position += 1;
+        }
+        is_keyword
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), anyhow::Error> {
+        if self.accept_keyword(keyword) {
+            return Ok(());
+        }
+        let message = match self.peek() {
+            Some(token) => format!("Expected '{}' but got {}", keyword, token),
+            None => format!("Expected '{}' at the end of the query", keyword),
+        };
+        Err(error_at(self.offset(), message))
+    }
+
+    fn accept_operator(&mut self, operator: &str) -> bool {
+        let is_operator = matches!(self.peek(), Some(Token::Operator(each)) if *each == operator);
+        if is_operator {
+            self.position += 1;
+        }
+        is_operator
+    }
+
+    fn expect_operator(&mut self, operator: &str) -> Result<(), anyhow::Error> {
+        if self.accept_operator(operator) {
+            return Ok(());
+        }
+        let message = match self.peek() {
+            Some(token) => format!("Expected '{}' but got {}", operator, token),
+            None => format!("Expected '{}' at the end of the query", operator),
+        };
+        Err(error_at(self.offset(), message))
+    }
+
+    fn word(&mut self) -> Result<String, anyhow::Error> {
+        let offset = self.offset();
+        match self.next()? {
+            Token::Word(word) => Ok(word),
+            token => Err(error_at(
+                offset,
+                format!("Expected a name but got {}", token),
+            )),
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64, anyhow::Error> {
+        let offset = self.offset();
+        match self.next()? {
+            Token::Integer(integer) => Ok(integer),
+            token => Err(error_at(
+                offset,
+                format!("Expected an integer but got {}", token),
+            )),
+        }
+    }
+
+    fn positive_integer(&mut self) -> Result<usize, anyhow::Error> {
+        let offset = self.offset();
+        let integer = self.integer()?;
+        usize::try_from(integer).map_err(|_| {
+            error_at(
+                offset,
+                format!("Expected a positive integer but got {}", integer),
+            )
+        })
+    }
+
+    fn comparison(&mut self) -> Result<Comparison, anyhow::Error> {
+        let offset = self.offset();
+        match self.next()? {
+            Token::Operator("=") => Ok(Comparison::Equal),
+            Token::Operator("!=") => Ok(Comparison::NotEqual),
+            Token::Operator("<") => Ok(Comparison::Less),
+            Token::Operator("<=") => Ok(Comparison::LessOrEqual),
+            Token::Operator(">") => Ok(Comparison::Greater),
+            Token::Operator(">=") => Ok(Comparison::GreaterOrEqual),
+            token => Err(error_at(
+                offset,
+                format!("Expected a comparison but got {}", token),
+            )),
+        }
+    }
+
+    /// Parse `= Name` or `!= Name` into a predicate and its negation
+    fn class_name_predicate<T>(
+        &mut self,
+        predicate: impl FnOnce(String) -> T,
+        negate: impl FnOnce(T) -> T,
+    ) -> Result<T, anyhow::Error> {
+        let offset = self.offset();
+        match self.comparison()? {
+            Comparison::Equal => Ok(predicate(self.word()?)),
+            Comparison::NotEqual => Ok(negate(predicate(self.word()?))),
+            _ => Err(error_at(
+                offset,
+                "Classes can only be compared with '=' or '!='",
+            )),
+        }
+    }
+
+    fn parse_query(&mut self) -> Result<HeapQuery, anyhow::Error> {
+        self.expect_keyword("select")?;
+        let mut query = HeapQuery::new().select(self.parse_projection()?);
+
+        if self.accept_keyword("from") {
+            query = query.from(self.parse_source()?);
+        }
+        if self.accept_keyword("where") {
+            query = query.filter(self.parse_predicate()?);
+        }
+        if self.accept_keyword("limit") {
+            query = query.limit(self.positive_integer()?);
+        }
+        Ok(query)
+    }
+
+    fn parse_projection(&mut self) -> Result<Projection, anyhow::Error> {
+        let offset = self.offset();
+        let word = self.word()?;
+        let projection = match word.to_lowercase().as_str() {
+            "objects" => Projection::Objects,
+            "field" => Projection::Field(self.positive_integer()?),
+            "count" | "bytes" => {
+                let is_count = word.eq_ignore_ascii_case("count");
+                if self.accept_keyword("by") {
+                    self.expect_keyword("class")?;
+                    if is_count {
+                        Projection::CountByClass
+                    } else {
+                        Projection::ByteSizeByClass
+                    }
+                } else if is_count {
+                    Projection::Count
+                } else {
+                    Projection::ByteSize
+                }
+            }
+            _ => return Err(error_at(offset, format!("Unknown projection '{}'", word))),
+        };
+        Ok(projection)
+    }
+
+    fn parse_source(&mut self) -> Result<QuerySource, anyhow::Error> {
+        let offset = self.offset();
+        let word = self.word()?;
+        let source = match word.to_lowercase().as_str() {
+            "heap" => QuerySource::Heap,
+            "young" => QuerySource::Young,
+            "old" => QuerySource::Old,
+            "reachable" => QuerySource::Reachable,
+            _ => return Err(error_at(offset, format!("Unknown source '{}'", word))),
+        };
+        Ok(source)
+    }
+
+    fn parse_predicate(&mut self) -> Result<Predicate, anyhow::Error> {
+        let mut predicate = self.parse_conjunction()?;
+        while self.accept_keyword("or") {
+            predicate = predicate.or(self.parse_conjunction()?);
+        }
+        Ok(predicate)
+    }
+
+    fn parse_conjunction(&mut self) -> Result<Predicate, anyhow::Error> {
+        let mut predicate = self.parse_negation()?;
+        while self.accept_keyword("and") {
+            predicate = predicate.and(self.parse_negation()?);
+        }
+        Ok(predicate)
+    }
+
+    fn parse_negation(&mut self) -> Result<Predicate, anyhow::Error> {
+        if self.accept_keyword("not") {
+            return Ok(self.parse_negation()?.not());
+        }
+        if self.accept_operator("(") {
+            let predicate = self.parse_predicate()?;
+            self.expect_operator(")")?;
+            return Ok(predicate);
+        }
+        self.parse_condition()
+    }
+
+    fn parse_condition(&mut self) -> Result<Predicate, anyhow::Error> {
+        let offset = self.offset();
+        let word = self.word()?;
+        let predicate = match word.to_lowercase().as_str() {
+            "class" => self.class_name_predicate(Predicate::ClassNamed, Predicate::not)?,
+            "kindof" => Predicate::KindOf(self.word()?),
+            "format" => {
+                self.expect_operator("=")?;
+                Predicate::Format(self.parse_format()?)
+            }
+            "size" => Predicate::Size(self.comparison()?, self.positive_integer()?),
+            "bytes" => Predicate::ByteSize(self.comparison()?, self.positive_integer()?),
+            "field" => Predicate::Field(self.positive_integer()?, self.parse_field_predicate()?),
+            _ => return Err(error_at(offset, format!("Unknown condition '{}'", word))),
+        };
+        Ok(predicate)
+    }
+
+    fn parse_format(&mut self) -> Result<FormatKind, anyhow::Error> {
+        let offset = self.offset();
+        let word = self.word()?;
+        let format = match word.to_lowercase().as_str() {
+            "fixed" => FormatKind::Fixed,
+            "indexable" => FormatKind::Indexable,
+            "bytes" => FormatKind::Bytes,
+            "words" => FormatKind::Words,
+            "weak" => FormatKind::Weak,
+            "ephemeron" => FormatKind::Ephemeron,
+            "context" => FormatKind::Context,
+            "method" => FormatKind::CompiledMethod,
+            _ => return Err(error_at(offset, format!("Unknown format '{}'", word))),
+        };
+        Ok(format)
+    }
+
+    fn parse_field_predicate(&mut self) -> Result<FieldPredicate, anyhow::Error> {
+        if self.accept_keyword("isNil") {
+            return Ok(FieldPredicate::IsNil);
+        }
+        if self.accept_keyword("notNil") {
+            return Ok(FieldPredicate::IsNil.not());
+        }
+        if self.accept_keyword("class") {
+            return self.class_name_predicate(FieldPredicate::ClassNamed, FieldPredicate::not);
+        }
+        if self.accept_keyword("kindOf") {
+            return Ok(FieldPredicate::KindOf(self.word()?));
+        }
+
+        let offset = self.offset();
+        let comparison = self.comparison()?;
+        if self.accept_keyword("nil") {
+            return match comparison {
+                Comparison::Equal => Ok(FieldPredicate::IsNil),
+                Comparison::NotEqual => Ok(FieldPredicate::IsNil.not()),
+                _ => Err(error_at(
+                    offset,
+                    "nil can only be compared with '=' or '!='",
+                )),
+            };
+        }
+        Ok(FieldPredicate::Integer(comparison, self.integer()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> HeapQuery {
+        HeapQuery::parse(query).unwrap()
+    }
+
+    fn parse_error(query: &str) -> String {
+        HeapQuery::parse(query).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn select_projections() {
+        assert_eq!(parse("select objects"), HeapQuery::new());
+        assert_eq!(
+            parse("select count"),
+            HeapQuery::new().select(Projection::Count)
+        );
+        assert_eq!(
+            parse("select bytes"),
+            HeapQuery::new().select(Projection::ByteSize)
+        );
+        assert_eq!(
+            parse("select count by class"),
+            HeapQuery::new().select(Projection::CountByClass)
+        );
+        assert_eq!(
+            parse("select bytes by class"),
+            HeapQuery::new().select(Projection::ByteSizeByClass)
+        );
+        assert_eq!(
+            parse("select field 2"),
+            HeapQuery::new().select(Projection::Field(2))
+        );
+    }
+
+    #[test]
+    fn keywords_are_case_insensitive() {
+        assert_eq!(
+            parse("SELECT Count BY Class FROM Old LIMIT 3"),
+            parse("select count by class from old limit 3")
+        );
+    }
+
+    #[test]
+    fn from_sources() {
+        assert_eq!(
+            parse("select objects from heap"),
+            HeapQuery::new().from(QuerySource::Heap)
+        );
+        assert_eq!(
+            parse("select objects from young"),
+            HeapQuery::new().from(QuerySource::Young)
+        );
+        assert_eq!(
+            parse("select objects from old"),
+            HeapQuery::new().from(QuerySource::Old)
+        );
+        assert_eq!(
+            parse("select objects from reachable"),
+            HeapQuery::new().from(QuerySource::Reachable)
+        );
+    }
+
+    #[test]
+    fn where_conditions() {
+        assert_eq!(
+            parse("select objects where class = Point"),
+            HeapQuery::new().filter(Predicate::ClassNamed("Point".to_string()))
+        );
+        assert_eq!(
+            parse("select objects where class != Point"),
+            HeapQuery::new().filter(Predicate::ClassNamed("Point".to_string()).not())
+        );
+        assert_eq!(
+            parse("select objects where kindOf Collection"),
+            HeapQuery::new().filter(Predicate::KindOf("Collection".to_string()))
+        );
+        assert_eq!(
+            parse("select objects where format = weak"),
+            HeapQuery::new().filter(Predicate::Format(FormatKind::Weak))
+        );
+        assert_eq!(
+            parse("select objects where size>=10"),
+            HeapQuery::new().filter(Predicate::Size(Comparison::GreaterOrEqual, 10))
+        );
+        assert_eq!(
+            parse("select objects where bytes < 64"),
+            HeapQuery::new().filter(Predicate::ByteSize(Comparison::Less, 64))
+        );
+    }
+
+    #[test]
+    fn where_field_conditions() {
+        let field = |predicate| HeapQuery::new().filter(Predicate::Field(1, predicate));
+
+        assert_eq!(
+            parse("select objects where field 1 isNil"),
+            field(FieldPredicate::IsNil)
+        );
+        assert_eq!(
+            parse("select objects where field 1 notNil"),
+            field(FieldPredicate::IsNil.not())
+        );
+        assert_eq!(
+            parse("select objects where field 1 = nil"),
+            field(FieldPredicate::IsNil)
+        );
+        assert_eq!(
+            parse("select objects where field 1 != nil"),
+            field(FieldPredicate::IsNil.not())
+        );
+        assert_eq!(
+            parse("select objects where field 1 <= -5"),
+            field(FieldPredicate::Integer(Comparison::LessOrEqual, -5))
+        );
+        assert_eq!(
+            parse("select objects where field 1 class = Point"),
+            field(FieldPredicate::ClassNamed("Point".to_string()))
+        );
+        assert_eq!(
+            parse("select objects where field 1 kindOf Number"),
+            field(FieldPredicate::KindOf("Number".to_string()))
+        );
+    }
+
+    #[test]
+    fn limit() {
+        assert_eq!(parse("select objects limit 10"), HeapQuery::new().limit(10));
+    }
+
+    #[test]
+    fn all_clauses() {
+        assert_eq!(
+            parse("select count by class from old where format = bytes and size > 10000 limit 5"),
+            HeapQuery::new()
+                .select(Projection::CountByClass)
+                .from(QuerySource::Old)
+                .filter(
+                    Predicate::Format(FormatKind::Bytes)
+                        .and(Predicate::Size(Comparison::Greater, 10000))
+                )
+                .limit(5)
+        );
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let a = || Predicate::ClassNamed("A".to_string());
+        let b = || Predicate::ClassNamed("B".to_string());
+        let c = || Predicate::ClassNamed("C".to_string());
+
+        assert_eq!(
+            parse("select objects where class = A or class = B and class = C"),
+            HeapQuery::new().filter(a().or(b().and(c())))
+        );
+        assert_eq!(
+            parse("select objects where class = A and class = B or class = C"),
+            HeapQuery::new().filter(a().and(b()).or(c()))
+        );
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        let a = || Predicate::ClassNamed("A".to_string());
+        let b = || Predicate::ClassNamed("B".to_string());
+
+        assert_eq!(
+            parse("select objects where not class = A and class = B"),
+            HeapQuery::new().filter(a().not().and(b()))
+        );
+        assert_eq!(
+            parse("select objects where not not class = A"),
+            HeapQuery::new().filter(a().not().not())
+        );
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        let a = || Predicate::ClassNamed("A".to_string());
+        let b = || Predicate::ClassNamed("B".to_string());
+        let c = || Predicate::ClassNamed("C".to_string());
+
+        assert_eq!(
+            parse("select objects where (class = A or class = B) and class = C"),
+            HeapQuery::new().filter(a().or(b()).and(c()))
+        );
+        assert_eq!(
+            parse("select objects where not (class = A or class = B)"),
+            HeapQuery::new().filter(a().or(b()).not())
+        );
+    }
+
+    #[test]
+    fn or_is_left_associative() {
+        let a = || Predicate::ClassNamed("A".to_string());
+        let b = || Predicate::ClassNamed("B".to_string());
+        let c = || Predicate::ClassNamed("C".to_string());
+
+        assert_eq!(
+            parse("select objects where class = A or class = B or class = C"),
+            HeapQuery::new().filter(a().or(b()).or(c()))
+        );
+    }
+
+    #[test]
+    fn error_on_empty_query() {
+        assert_eq!(
+            parse_error(""),
+            "Expected 'select' at the end of the query at offset 0"
+        );
+    }
+
+    #[test]
+    fn error_on_missing_select() {
+        assert_eq!(
+            parse_error("count objects"),
+            "Expected 'select' but got 'count' at offset 0"
+        );
+    }
+
+    #[test]
+    fn error_on_unknown_projection() {
+        assert_eq!(
+            parse_error("select things"),
+            "Unknown projection 'things' at offset 7"
+        );
+    }
+
+    #[test]
+    fn error_on_unknown_source() {
+        assert_eq!(
+            parse_error("select count from space"),
+            "Unknown source 'space' at offset 18"
+        );
+    }
+
+    #[test]
+    fn error_on_unknown_condition() {
+        assert_eq!(
+            parse_error("select count where color = red"),
+            "Unknown condition 'color' at offset 19"
+        );
+    }
+
+    #[test]
+    fn error_on_unknown_format() {
+        assert_eq!(
+            parse_error("select count where format = blue"),
+            "Unknown format 'blue' at offset 28"
+        );
+    }
+
+    #[test]
+    fn error_on_missing_comparison() {
+        assert_eq!(
+            parse_error("select count where size 10"),
+            "Expected a comparison but got 10 at offset 24"
+        );
+    }
+
+    #[test]
+    fn error_on_ordering_classes() {
+        assert_eq!(
+            parse_error("select count where class < Point"),
+            "Classes can only be compared with '=' or '!=' at offset 25"
+        );
+    }
+
+    #[test]
+    fn error_on_ordering_nil() {
+        assert_eq!(
+            parse_error("select count where field 0 > nil"),
+            "nil can only be compared with '=' or '!=' at offset 27"
+        );
+    }
+
+    #[test]
+    fn error_on_negative_limit() {
+        assert_eq!(
+            parse_error("select count limit -1"),
+            "Expected a positive integer but got -1 at offset 19"
+        );
+    }
+
+    #[test]
+    fn error_on_invalid_integer() {
+        assert_eq!(
+            parse_error("select count where size > 12abc"),
+            "Invalid integer '12abc' at offset 26"
+        );
+    }
+
+    #[test]
+    fn error_on_unclosed_parenthesis() {
+        assert_eq!(
+            parse_error("select count where (class = A"),
+            "Expected ')' at the end of the query at offset 29"
+        );
+    }
+
+    #[test]
+    fn error_on_unexpected_end() {
+        assert_eq!(
+            parse_error("select count where class ="),
+            "Unexpected end of the query at offset 26"
+        );
+    }
+
+    #[test]
+    fn error_on_trailing_tokens() {
+        assert_eq!(
+            parse_error("select count limit 1 2"),
+            "Unexpected 2 after the end of the query at offset 21"
+        );
+    }
+}
@@ -0,0 +1,504 @@
+use anyhow::bail;
+use vec_map::VecMap;
+use vm_bindings::{Smalltalk, StackOffset};
+use vm_object_model::{AnyObjectRef, Immediate, ObjectFormat, ObjectRef};
+
+use crate::memory::{EdenMemorySpace, OldMemorySpace, PastMemorySpace};
+use crate::objects::{Array, Association, ByteStringRef};
+use crate::reference_finder::{
+    visit_unique_objects, visitor_next_objects, ObjectVisitor, ReferenceKinds, ReferencedObject,
+    VisitorAction, VisitorState,
+};
+
+/// Execute a query given as a string, see [`HeapQuery::parse`].
+/// The start object is only used when querying reachable objects and may be nil otherwise.
+/// Returns an array of objects, an integer, or an array of associations class -> integer.
+#[allow(non_snake_case)]
+pub fn primitiveHeapQueryExecute() -> Result<(), anyhow::Error> {
+    let association_class = Smalltalk::stack_ref(StackOffset::new(0)).as_object()?;
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(1));
+    let query = ByteStringRef::try_from(Smalltalk::stack_ref(StackOffset::new(2)))?;
+
+    let query = HeapQuery::parse(query.as_str())?;
+    let start_obj = if start_obj == Smalltalk::nil_object() {
+        None
+    } else {
+        Some(start_obj)
+    };
+
+    match query.execute(start_obj)? {
+        QueryResult::Objects(objects) => {
+            let mut objects_array = Array::new(objects.len())?;
+            objects_array.copy_from(0, objects.len(), objects.as_slice());
+            Smalltalk::method_return(objects_array);
+        }
+        QueryResult::Count(total) | QueryResult::ByteSize(total) => {
+            Smalltalk::method_return_integer(total as i64);
+        }
+        QueryResult::ByClass(totals) => {
+            let mut totals_array = Array::new(totals.len())?;
+            for (index, (class, total)) in totals.into_iter().enumerate() {
+                let mut association = Association::new(association_class)?;
+                association.set_key(class);
+                association.set_value(Immediate::new_u64(total as u64));
+                totals_array.insert(index, association);
+            }
+            Smalltalk::method_return(totals_array);
+        }
+    }
+    Ok(())
+}
+
+/// A query over the objects in the heap, for example:
+/// ```text
+/// select objects from heap where class = OrderedCollection and field 1 kindOf Array limit 10
+/// select count by class from old where format = bytes and size > 10000
+/// ```
+/// See [`HeapQuery::parse`] for the grammar of the query language.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapQuery {
+    source: QuerySource,
+    predicate: Predicate,
+    projection: Projection,
+    limit: Option<usize>,
+}
+
+/// Where the objects of a query come from
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum QuerySource {
+    /// all objects in eden, past and old space
+    Heap,
+    /// objects in eden and past space
+    Young,
+    Old,
+    /// objects reachable from a start object
+    Reachable,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// A coarse classification of object formats
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FormatKind {
+    /// objects with only named instance variables
+    Fixed,
+    /// objects with indexable pointer fields, such as arrays
+    Indexable,
+    Bytes,
+    /// 16, 32 or 64 bit indexable objects
+    Words,
+    Weak,
+    Ephemeron,
+    Context,
+    CompiledMethod,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Any,
+    /// the class of the object has exactly this name
+    ClassNamed(String),
+    /// the class of the object or one of its superclasses has this name
+    KindOf(String),
+    Format(FormatKind),
+    /// compare the amount of indexable slots or bytes
+    Size(Comparison, usize),
+    /// compare the amount of bytes the object occupies in memory
+    ByteSize(Comparison, usize),
+    /// a condition on the value of a zero-based instance variable
+    Field(usize, FieldPredicate),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+}
+
+/// A condition on the value of a field, objects without such field never match
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldPredicate {
+    IsNil,
+    Integer(Comparison, i64),
+    ClassNamed(String),
+    KindOf(String),
+    Not(Box<FieldPredicate>),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Projection {
+    Objects,
+    /// the values of a zero-based instance variable of the matching objects
+    Field(usize),
+    Count,
+    ByteSize,
+    CountByClass,
+    ByteSizeByClass,
+}
+
+#[derive(Debug, Clone)]
+pub enum QueryResult {
+    Objects(Vec<AnyObjectRef>),
+    Count(usize),
+    ByteSize(usize),
+    /// aggregates per class sorted in descending order
+    ByClass(Vec<(ObjectRef, usize)>),
+}
+
+impl HeapQuery {
+    pub fn new() -> Self {
+        Self {
+            source: QuerySource::Heap,
+            predicate: Predicate::Any,
+            projection: Projection::Objects,
+            limit: None,
+        }
+    }
+
+    pub fn from(mut self, source: QuerySource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Add a condition that the objects must satisfy in addition to the existing ones
+    pub fn filter(mut self, predicate: Predicate) -> Self {
+        self.predicate = match self.predicate {
+            Predicate::Any => predicate,
+            existing => existing.and(predicate),
+        };
+        self
+    }
+
+    pub fn select(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    /// Stop after finding a given amount of matching objects
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn source(&self) -> QuerySource {
+        self.source
+    }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    /// Run the query, the start object is required when querying reachable objects
+    pub fn execute(&self, start: Option<AnyObjectRef>) -> Result<QueryResult, anyhow::Error> {
+        let mut execution = QueryExecution::new(self);
+
+        match self.source {
+            QuerySource::Heap => {
+                execution.process_objects(EdenMemorySpace::new().objects());
+                execution.process_objects(PastMemorySpace::new().objects());
+                execution.process_objects(OldMemorySpace::new().objects());
+            }
+            QuerySource::Young => {
+                execution.process_objects(EdenMemorySpace::new().objects());
+                execution.process_objects(PastMemorySpace::new().objects());
+            }
+            QuerySource::Old => execution.process_objects(OldMemorySpace::new().objects()),
+            QuerySource::Reachable => {
+                let Some(start) = start else {
+                    bail!("A start object is required to query reachable objects");
+                };
+                visit_unique_objects(start, &mut execution);
+            }
+        }
+
+        Ok(execution.into_result())
+    }
+}
+
+impl Predicate {
+    pub fn class_named(name: impl Into<String>) -> Self {
+        Self::ClassNamed(name.into())
+    }
+
+    pub fn kind_of(name: impl Into<String>) -> Self {
+        Self::KindOf(name.into())
+    }
+
+    pub fn field(index: usize, predicate: FieldPredicate) -> Self {
+        Self::Field(index, predicate)
+    }
+
+    pub fn and(self, other: Predicate) -> Self {
+        Self::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: Predicate) -> Self {
+        Self::Or(Box::new(self), Box::new(other))
+    }
+
+    pub fn not(self) -> Self {
+        Self::Not(Box::new(self))
+    }
+}
+
+impl FieldPredicate {
+    pub fn not(self) -> Self {
+        Self::Not(Box::new(self))
+    }
+}
+
+impl Comparison {
+    pub fn compare<T: PartialOrd>(&self, value: T, other: T) -> bool {
+        match self {
+            Comparison::Equal => value == other,
+            Comparison::NotEqual => value != other,
+            Comparison::Less => value < other,
+            Comparison::LessOrEqual => value <= other,
+            Comparison::Greater => value > other,
+            Comparison::GreaterOrEqual => value >= other,
+        }
+    }
+}
+
+impl FormatKind {
+    pub fn matches(&self, object: ObjectRef) -> bool {
+        if object.is_context() {
+            return *self == FormatKind::Context;
+        }
+
+        let format = object.header().format();
+        match self {
+            FormatKind::Fixed => {
+                matches!(format, ObjectFormat::ZeroSized | ObjectFormat::NonIndexable)
+            }
+            FormatKind::Indexable => matches!(
+                format,
+                ObjectFormat::IndexableWithoutInstVars | ObjectFormat::IndexableWithInstVars
+            ),
+            FormatKind::Bytes => matches!(format, ObjectFormat::Indexable8(_)),
+            FormatKind::Words => matches!(
+                format,
+                ObjectFormat::Indexable64
+                    | ObjectFormat::Indexable32(_)
+                    | ObjectFormat::Indexable16(_)
+            ),
+            FormatKind::Weak => format.is_weak(),
+            FormatKind::Ephemeron => format.is_ephemeron(),
+            FormatKind::Context => false,
+            FormatKind::CompiledMethod => matches!(format, ObjectFormat::CompiledMethod(_)),
+        }
+    }
+}
+
+/// Names of a class and all of its superclasses
+#[derive(Debug)]
+struct ClassInfo {
+    names: Vec<String>,
+}
+
+impl ClassInfo {
+    fn new(class: ObjectRef) -> Self {
+        let nil_object = Smalltalk::nil_object();
+        let mut names = vec![];
+        let mut current = Some(class);
+        while let Some(each) = current {
+            names.push(Smalltalk::class_name(each));
+            current = each
+                .inst_var_at(0)
+                .filter(|superclass| *superclass != nil_object)
+                .and_then(|superclass| superclass.as_object().ok());
+        }
+        Self { names }
+    }
+
+    fn is_named(&self, name: &str) -> bool {
+        self.names.first().is_some_and(|each| each == name)
+    }
+
+    fn is_kind_of(&self, name: &str) -> bool {
+        self.names.iter().any(|each| each == name)
+    }
+}
+
+struct QueryExecution<'query> {
+    query: &'query HeapQuery,
+    nil_object: AnyObjectRef,
+    /// class information cached by class index
+    classes: VecMap<ClassInfo>,
+    amount_of_matches: usize,
+    objects: Vec<AnyObjectRef>,
+    total: usize,
+    totals_by_class: VecMap<(ObjectRef, usize)>,
+}
+
+impl<'query> QueryExecution<'query> {
+    fn new(query: &'query HeapQuery) -> Self {
+        Self {
+            query,
+            nil_object: Smalltalk::nil_object(),
+            classes: Default::default(),
+            amount_of_matches: 0,
+            objects: vec![],
+            total: 0,
+            totals_by_class: Default::default(),
+        }
+    }
+
+    fn is_limit_reached(&self) -> bool {
+        self.query
+            .limit
+            .is_some_and(|limit| self.amount_of_matches >= limit)
+    }
+
+    fn process_objects(&mut self, objects: impl Iterator<Item = ObjectRef>) {
+        for object in objects {
+            if self.is_limit_reached() {
+                return;
+            }
+            self.process_object(object);
+        }
+    }
+
+    fn process_object(&mut self, object: ObjectRef) {
+        if !self.matches(&self.query.predicate, object) {
+            return;
+        }
+        self.amount_of_matches += 1;
+
+        match self.query.projection {
+            Projection::Objects => self.objects.push(object.into()),
+            Projection::Field(index) => {
+                if let Some(value) = self.field_at(object, index) {
+                    self.objects.push(value);
+                }
+            }
+            Projection::Count => self.total += 1,
+            Projection::ByteSize => self.total += Smalltalk::byte_size(object),
+            Projection::CountByClass => self.add_to_class_total(object, 1),
+            Projection::ByteSizeByClass => {
+                self.add_to_class_total(object, Smalltalk::byte_size(object))
+            }
+        }
+    }
+
+    fn add_to_class_total(&mut self, object: ObjectRef, amount: usize) {
+        self.totals_by_class
+            .entry(object.header().class_index() as usize)
+            .or_insert_with(|| (Smalltalk::class_of_object(object), 0))
+            .1 += amount;
+    }
+
+    fn class_info(&mut self, object: ObjectRef) -> &ClassInfo {
+        self.classes
+            .entry(object.header().class_index() as usize)
+            .or_insert_with(|| ClassInfo::new(Smalltalk::class_of_object(object)))
+    }
+
+    fn matches(&mut self, predicate: &Predicate, object: ObjectRef) -> bool {
+        match predicate {
+            Predicate::Any => true,
+            Predicate::ClassNamed(name) => self.class_info(object).is_named(name),
+            Predicate::KindOf(name) => self.class_info(object).is_kind_of(name),
+            Predicate::Format(format) => format.matches(object),
+            Predicate::Size(comparison, size) => {
+                comparison.compare(object.amount_of_indexable_units(), *size)
+            }
+            Predicate::ByteSize(comparison, size) => {
+                comparison.compare(Smalltalk::byte_size(object), *size)
+            }
+            Predicate::Field(index, predicate) => match self.field_at(object, *index) {
+                Some(value) => self.field_matches(predicate, value),
+                None => false,
+            },
+            Predicate::And(left, right) => {
+                self.matches(left, object) && self.matches(right, object)
+            }
+            Predicate::Or(left, right) => self.matches(left, object) || self.matches(right, object),
+            Predicate::Not(predicate) => !self.matches(predicate, object),
+        }
+    }
+
+    fn field_matches(&mut self, predicate: &FieldPredicate, value: AnyObjectRef) -> bool {
+        match predicate {
+            FieldPredicate::IsNil => value == self.nil_object,
+            FieldPredicate::Integer(comparison, integer) => value
+                .as_immediate()
+                .ok()
+                .and_then(|immediate| immediate.as_integer())
+                .is_some_and(|value| comparison.compare(value, *integer)),
+            FieldPredicate::ClassNamed(name) => match value.as_object() {
+                Ok(object) => self.class_info(object).is_named(name),
+                Err(_) => immediate_class_name(value) == name,
+            },
+            FieldPredicate::KindOf(name) => match value.as_object() {
+                Ok(object) => self.class_info(object).is_kind_of(name),
+                Err(_) => immediate_class_name(value) == name,
+            },
+            FieldPredicate::Not(predicate) => !self.field_matches(predicate, value),
+        }
+    }
+
+    /// Return the value of an instance variable, objects without pointer fields have none
+    fn field_at(&self, object: ObjectRef, index: usize) -> Option<AnyObjectRef> {
+        if object.header().format().is_bits() || object.is_context() {
+            return None;
+        }
+        object.inst_var_at(index)
+    }
+
+    fn into_result(self) -> QueryResult {
+        match self.query.projection {
+            Projection::Objects | Projection::Field(_) => QueryResult::Objects(self.objects),
+            Projection::Count => QueryResult::Count(self.total),
+            Projection::ByteSize => QueryResult::ByteSize(self.total),
+            Projection::CountByClass | Projection::ByteSizeByClass => {
+                let mut totals = self
+                    .totals_by_class
+                    .into_iter()
+                    .map(|(_, total)| total)
+                    .collect::<Vec<_>>();
+                totals.sort_by(|a, b| b.1.cmp(&a.1));
+                QueryResult::ByClass(totals)
+            }
+        }
+    }
+}
+
+impl ObjectVisitor for QueryExecution<'_> {
+    fn next_objects(
+        object: ReferencedObject,
+        kinds: ReferenceKinds,
+    ) -> impl Iterator<Item = ReferencedObject> {
+        visitor_next_objects(object, kinds).filter(|each| !each.object().is_immediate())
+    }
+
+    fn visit_referenced_object(
+        &mut self,
+        object: ReferencedObject,
+        _state: &VisitorState,
+    ) -> VisitorAction {
+        if let Ok(object) = object.object().as_object() {
+            self.process_object(object);
+        }
+        if self.is_limit_reached() {
+            VisitorAction::Stop
+        } else {
+            VisitorAction::Continue
+        }
+    }
+}
+
+/// Immediate objects do not have a header, their class is encoded in the tag bits
+fn immediate_class_name(value: AnyObjectRef) -> &'static str {
+    match value.as_i64() & 7 {
+        1 => "SmallInteger",
+        2 => "Character",
+        4 => "SmallFloat64",
+        _ => "",
+    }
+}
@@ -14,11 +14,15 @@ extern crate vm_object_model;
 #[cfg(target_os = "android")]
 pub extern crate android_activity;
 
+mod allocation_tracker;
 mod constellation;
 mod error;
 mod event_loop;
+mod event_loop_metrics;
+mod ffi_policy;
 #[cfg(feature = "ffi")]
 mod ffi;
+mod heap_query;
 mod image_finder;
 mod logger;
 mod version;
@@ -34,11 +38,20 @@ mod telemetry;
 #[cfg(feature = "tonel")]
 pub mod tonel;
 
+pub use allocation_tracker::*;
 pub use constellation::Constellation;
 pub use error::{ApplicationError, Result};
-pub use event_loop::{EventLoop, EventLoopMessage, EventLoopWaker};
+pub use event_loop::{
+    EventLoop, EventLoopMessage, EventLoopSender, EventLoopTimer, EventLoopTimerAction,
+    EventLoopTimerId, EventLoopTimerMessage, EventLoopWaker,
+};
 #[cfg(feature = "ffi")]
-pub use ffi::{primitiveEventLoopCallout, primitiveExtractReturnValue, EventLoopCallout};
+pub use ffi::{
+    primitiveEventLoopCallout, primitiveEventLoopCalloutBatch, primitiveEventLoopStartCalloutTimer,
+    primitiveExtractReturnValue, EventLoopCallout, EventLoopCalloutBatch,
+};
+pub use event_loop_metrics::*;
+pub use ffi_policy::*;
 pub use image_finder::*;
 pub use logger::*;
 pub use telemetry::*;
@@ -1,8 +1,8 @@
 use crate::memory::{EdenMemorySpace, OldMemorySpace, PastMemorySpace};
 use crate::objects::{ArrayRef, Association};
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHashSet};
 use num_derive::{FromPrimitive, ToPrimitive};
-use num_traits::ToPrimitive;
+use num_traits::{FromPrimitive, ToPrimitive};
 use strum::EnumCount;
 use strum_macros::EnumCount;
 use vec_map::VecMap;
@@ -13,6 +13,20 @@ use vm_object_model::{AnyObjectRef, Immediate, Object, ObjectRef};
 pub struct MemoryAnalyzer {
     total_amount_of_objects: usize,
     tallies: VecMap<ClassTally>,
+    options: MemoryAnalyzerOptions,
+    /// caches whether instances of a class with a given index should be analyzed
+    class_filter: VecMap<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryAnalyzerOptions {
+    spaces: Vec<SpaceType>,
+    included_classes: Option<FxHashSet<ObjectRef>>,
+    excluded_classes: FxHashSet<ObjectRef>,
+    min_instance_byte_size: usize,
+    min_total_byte_size: usize,
+    limit: Option<usize>,
+    include_byte_size_details: bool,
 }
 
 #[derive(Debug)]
@@ -49,6 +63,21 @@ pub struct ClassTallyObject {
     details_per_space: AnyObjectRef,
 }
 
+/// Options of the memory analysis, any of the fields can be nil to use the default value.
+/// Spaces are an array of space type indices, classes are arrays of classes.
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct MemoryAnalyzerOptionsObject {
+    this: Object,
+    spaces: AnyObjectRef,
+    included_classes: AnyObjectRef,
+    excluded_classes: AnyObjectRef,
+    min_instance_byte_size: AnyObjectRef,
+    min_total_byte_size: AnyObjectRef,
+    limit: AnyObjectRef,
+    include_byte_size_details: AnyObjectRef,
+}
+
 #[derive(Debug, PharoObject)]
 #[repr(C)]
 pub struct ClassTallyPerSpaceObject {
@@ -66,6 +95,153 @@ impl MemoryAnalyzer {
         tallies.sort_by(|a, b| b.total_byte_size.cmp(&a.total_byte_size));
         tallies
     }
+
+    /// Return tallies sorted by size that satisfy the minimal total size and limit options
+    pub fn selected_tallies_by_size(&self) -> Vec<&ClassTally> {
+        let mut tallies = self.sorted_tallies_by_size();
+        tallies.retain(|each| each.total_byte_size >= self.options.min_total_byte_size);
+        if let Some(limit) = self.options.limit {
+            tallies.truncate(limit);
+        }
+        tallies
+    }
+}
+
+impl Default for MemoryAnalyzerOptions {
+    fn default() -> Self {
+        Self {
+            spaces: vec![SpaceType::Eden, SpaceType::Past, SpaceType::Old],
+            included_classes: None,
+            excluded_classes: Default::default(),
+            min_instance_byte_size: 0,
+            min_total_byte_size: 0,
+            limit: None,
+            include_byte_size_details: true,
+        }
+    }
+}
+
+impl MemoryAnalyzerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_spaces<T: IntoIterator<Item = SpaceType>>(mut self, spaces: T) -> Self {
+        self.spaces = spaces.into_iter().collect();
+        self
+    }
+
+    /// Only analyze instances of the given classes
+    pub fn with_included_classes<T: IntoIterator<Item = ObjectRef>>(mut self, classes: T) -> Self {
+        self.included_classes = Some(classes.into_iter().collect());
+        self
+    }
+
+    pub fn with_excluded_classes<T: IntoIterator<Item = ObjectRef>>(mut self, classes: T) -> Self {
+        self.excluded_classes.extend(classes);
+        self
+    }
+
+    /// Ignore instances that are smaller than a given size in bytes
+    pub fn with_min_instance_byte_size(mut self, byte_size: usize) -> Self {
+        self.min_instance_byte_size = byte_size;
+        self
+    }
+
+    /// Only report classes whose instances take at least a given amount of bytes in total
+    pub fn with_min_total_byte_size(mut self, byte_size: usize) -> Self {
+        self.min_total_byte_size = byte_size;
+        self
+    }
+
+    /// Only report up to a given amount of the largest classes
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// When disabled, only aggregates are collected without the amount of objects per byte size
+    pub fn with_byte_size_details(mut self, include_byte_size_details: bool) -> Self {
+        self.include_byte_size_details = include_byte_size_details;
+        self
+    }
+
+    pub fn from_object(
+        options: MemoryAnalyzerOptionsObjectRef,
+    ) -> Result<Self, vm_object_model::Error> {
+        let mut result = Self::new();
+
+        if let Some(spaces) = array_or_nil(options.spaces)? {
+            let spaces: Result<Vec<_>, _> = spaces
+                .iter()
+                .map(|each| {
+                    let index = integer_value(*each)?;
+                    SpaceType::from_i64(index).ok_or_else(|| {
+                        vm_object_model::Error::InvalidType(format!("SpaceType (got {})", index))
+                    })
+                })
+                .collect();
+            result = result.with_spaces(spaces?);
+        }
+
+        if let Some(classes) = array_or_nil(options.included_classes)? {
+            let classes: Result<Vec<_>, _> = classes.iter().map(|each| each.as_object()).collect();
+            result = result.with_included_classes(classes?);
+        }
+
+        if let Some(classes) = array_or_nil(options.excluded_classes)? {
+            let classes: Result<Vec<_>, _> = classes.iter().map(|each| each.as_object()).collect();
+            result = result.with_excluded_classes(classes?);
+        }
+
+        if !is_nil(options.min_instance_byte_size) {
+            result =
+                result.with_min_instance_byte_size(size_value(options.min_instance_byte_size)?);
+        }
+
+        if !is_nil(options.min_total_byte_size) {
+            result = result.with_min_total_byte_size(size_value(options.min_total_byte_size)?);
+        }
+
+        if !is_nil(options.limit) {
+            result = result.with_limit(size_value(options.limit)?);
+        }
+
+        if !is_nil(options.include_byte_size_details) {
+            result = result.with_byte_size_details(
+                options.include_byte_size_details == Smalltalk::bool_object(true).into(),
+            );
+        }
+
+        Ok(result)
+    }
+}
+
+fn is_nil(object: AnyObjectRef) -> bool {
+    object == Smalltalk::nil_object()
+}
+
+fn array_or_nil(object: AnyObjectRef) -> Result<Option<ArrayRef>, vm_object_model::Error> {
+    if is_nil(object) {
+        Ok(None)
+    } else {
+        Ok(Some(ArrayRef::try_from(object)?))
+    }
+}
+
+fn integer_value(object: AnyObjectRef) -> Result<i64, vm_object_model::Error> {
+    let immediate = object.as_immediate()?;
+    immediate
+        .as_integer()
+        .ok_or(vm_object_model::Error::NotAnInteger(immediate))
+}
+
+/// A non-negative integer, such as a size or a limit given to a primitive
+pub(crate) fn size_value(object: AnyObjectRef) -> Result<usize, vm_object_model::Error> {
+    let value = integer_value(object)?;
+    usize::try_from(value).map_err(|_| {
+        vm_object_model::Error::InvalidType(format!("non-negative integer (got {})", value))
+    })
 }
 
 impl ClassTally {
@@ -116,20 +292,70 @@ impl ClassTallyPerSpaceObject {
 
 impl MemoryAnalyzer {
     pub fn new() -> Self {
+        Self::with_options(Default::default())
+    }
+
+    pub fn with_options(options: MemoryAnalyzerOptions) -> Self {
         Self {
             total_amount_of_objects: 0,
             tallies: Default::default(),
+            options,
+            class_filter: Default::default(),
+        }
+    }
+
+    /// Process objects in all spaces selected in the options
+    pub fn process_spaces(&mut self) {
+        for space_type in self.options.spaces.clone() {
+            match space_type {
+                SpaceType::Eden => {
+                    self.process_objects(EdenMemorySpace::new().objects(), space_type)
+                }
+                SpaceType::Past => {
+                    self.process_objects(PastMemorySpace::new().objects(), space_type)
+                }
+                SpaceType::Old => self.process_objects(OldMemorySpace::new().objects(), space_type),
+            }
         }
     }
 
+    fn should_process_object(&mut self, object: ObjectRef) -> bool {
+        let options = &self.options;
+        if options.included_classes.is_none() && options.excluded_classes.is_empty() {
+            return true;
+        }
+
+        *self
+            .class_filter
+            .entry(object.header().class_index() as usize)
+            .or_insert_with(|| {
+                let class = Smalltalk::class_of_object(object);
+                let is_included = options
+                    .included_classes
+                    .as_ref()
+                    .is_none_or(|classes| classes.contains(&class));
+                is_included && !options.excluded_classes.contains(&class)
+            })
+    }
+
     pub fn process_objects(
         &mut self,
         objects: impl Iterator<Item = ObjectRef>,
         space_type: SpaceType,
     ) {
         for object in objects {
+            // counts all objects of the processed spaces, not only the ones passing the filters
+            self.total_amount_of_objects += 1;
+
+            if !self.should_process_object(object) {
+                continue;
+            }
+
             let class_index = object.header().class_index();
             let object_size = Smalltalk::byte_size(object);
+            if object_size < self.options.min_instance_byte_size {
+                continue;
+            }
 
             let tally = self
                 .tallies
@@ -142,10 +368,10 @@ impl MemoryAnalyzer {
             details.amount_of_objects += 1;
             details.total_byte_size += object_size;
 
-            let size_entry = details.object_byte_sizes.entry(object_size).or_insert(0);
-            *size_entry += 1;
-
-            self.total_amount_of_objects += 1;
+            if self.options.include_byte_size_details {
+                let size_entry = details.object_byte_sizes.entry(object_size).or_insert(0);
+                *size_entry += 1;
+            }
         }
     }
 }
@@ -159,16 +385,27 @@ pub fn primitiveAnalyzeObjectMemory() -> Result<(), vm_object_model::Error> {
     let include_per_space_details =
         Smalltalk::get_method_argument(3).as_object()? == Smalltalk::bool_object(true);
 
-    let mut analyzer = MemoryAnalyzer::new();
-    analyzer.process_objects(EdenMemorySpace::new().objects(), SpaceType::Eden);
-    analyzer.process_objects(PastMemorySpace::new().objects(), SpaceType::Past);
-    analyzer.process_objects(OldMemorySpace::new().objects(), SpaceType::Old);
+    // analysis options are an optional fifth argument
+    let options = if Smalltalk::method_argument_count() > 4 {
+        let options_object = Smalltalk::get_method_argument(4);
+        if is_nil(options_object) {
+            MemoryAnalyzerOptions::default()
+        } else {
+            MemoryAnalyzerOptions::from_object(MemoryAnalyzerOptionsObjectRef::try_from(
+                options_object,
+            )?)?
+        }
+    } else {
+        MemoryAnalyzerOptions::default()
+    };
+
+    let mut analyzer = MemoryAnalyzer::with_options(options);
+    analyzer.process_spaces();
 
-    let mut tallies_array = Smalltalk::instantiate_indexable::<ArrayRef>(
-        Smalltalk::class_array(),
-        analyzer.tallies.len(),
-    )?;
-    for (index, tally) in analyzer.sorted_tallies_by_size().into_iter().enumerate() {
+    let tallies = analyzer.selected_tallies_by_size();
+    let mut tallies_array =
+        Smalltalk::instantiate_indexable::<ArrayRef>(Smalltalk::class_array(), tallies.len())?;
+    for (index, tally) in tallies.into_iter().enumerate() {
         let details_per_space = if include_per_space_details {
             tallies_per_space_to_array(
                 &tally.details_per_space,
@@ -0,0 +1,435 @@
+use crate::memory::{size_value, EdenMemorySpace, OldMemorySpace, PastMemorySpace};
+use crate::objects::ArrayRef;
+use fxhash::FxHashMap;
+use vec_map::VecMap;
+use vm_bindings::{ObjectPointer, Smalltalk, StackOffset};
+use vm_object_model::{Immediate, Object, ObjectFormat, ObjectRef};
+
+/// Finds byte-format objects (strings, byte arrays, word arrays, etc.) of the same class
+/// with identical contents. Such objects are candidates for interning or sharing.
+#[derive(Debug)]
+pub struct DuplicateContentAnalyzer {
+    max_samples: usize,
+    classes: VecMap<DuplicateClassTally>,
+}
+
+#[derive(Debug)]
+pub struct DuplicateClassTally {
+    class: ObjectRef,
+    groups: DuplicateContentGroups<ObjectRef>,
+}
+
+/// Groups items with identical contents, such as objects of the same class
+#[derive(Debug)]
+pub struct DuplicateContentGroups<T> {
+    /// contents are grouped by their hash and length,
+    /// several groups with the same key may exist in case of hash collisions
+    groups: FxHashMap<(u64, usize), Vec<DuplicateContentGroup<T>>>,
+    content_hash: fn(&[u8]) -> u64,
+}
+
+#[derive(Debug)]
+pub struct DuplicateContentGroup<T> {
+    representative: T,
+    content_byte_size: usize,
+    object_byte_size: usize,
+    amount_of_objects: usize,
+    /// identity hashes of the first few objects with these contents that have one
+    sample_identity_hashes: Vec<u64>,
+}
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct DuplicateClassTallyObject {
+    this: Object,
+    class: ObjectRef,
+    amount_of_duplicates: Immediate,
+    wasted_byte_size: Immediate,
+    groups: ArrayRef,
+}
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct DuplicateContentGroupObject {
+    this: Object,
+    content_byte_size: Immediate,
+    amount_of_objects: Immediate,
+    wasted_byte_size: Immediate,
+    sample_identity_hashes: ArrayRef,
+}
+
+impl DuplicateContentAnalyzer {
+    pub fn new(max_samples: usize) -> Self {
+        Self {
+            max_samples,
+            classes: Default::default(),
+        }
+    }
+
+    pub fn process_objects(&mut self, objects: impl Iterator<Item = ObjectRef>) {
+        for object in objects {
+            let Some(contents) = object_contents(object) else {
+                continue;
+            };
+
+            let class_index = object.header().class_index() as usize;
+            let class_tally = self
+                .classes
+                .entry(class_index)
+                .or_insert_with(|| DuplicateClassTally::new(Smalltalk::class_of_object(object)));
+
+            class_tally.groups.add(
+                object,
+                contents,
+                object_contents,
+                Smalltalk::byte_size(object),
+                || raw_identity_hash(object),
+                self.max_samples,
+            );
+        }
+    }
+
+    /// Return class tallies that have at least one duplicate, sorted by the wasted memory
+    pub fn sorted_tallies_by_wasted_size(&self) -> Vec<&DuplicateClassTally> {
+        let mut tallies = self
+            .classes
+            .values()
+            .filter(|each| each.amount_of_duplicates() > 0)
+            .collect::<Vec<_>>();
+        tallies.sort_by(|a, b| b.wasted_byte_size().cmp(&a.wasted_byte_size()));
+        tallies
+    }
+}
+
+impl DuplicateClassTally {
+    pub fn new(class: ObjectRef) -> Self {
+        Self {
+            class,
+            groups: DuplicateContentGroups::new(),
+        }
+    }
+
+    pub fn top_groups_by_wasted_size(
+        &self,
+        limit: usize,
+    ) -> Vec<&DuplicateContentGroup<ObjectRef>> {
+        self.groups.top_groups_by_wasted_size(limit)
+    }
+
+    pub fn amount_of_duplicates(&self) -> usize {
+        self.groups.amount_of_duplicates()
+    }
+
+    pub fn wasted_byte_size(&self) -> usize {
+        self.groups.wasted_byte_size()
+    }
+}
+
+impl<T: Copy> Default for DuplicateContentGroups<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy> DuplicateContentGroups<T> {
+    pub fn new() -> Self {
+        Self::with_content_hash(fxhash::hash64)
+    }
+
+    fn with_content_hash(content_hash: fn(&[u8]) -> u64) -> Self {
+        Self {
+            groups: Default::default(),
+            content_hash,
+        }
+    }
+
+    /// Add an item to the group with identical contents, or start a new group.
+    /// Contents with the same hash and length are compared with the contents of
+    /// the representative of each group, as obtained with `contents_of`.
+    /// An identity hash is sampled while the group has less than `max_samples` of them
+    pub fn add<'a>(
+        &mut self,
+        item: T,
+        contents: &[u8],
+        contents_of: impl Fn(T) -> Option<&'a [u8]>,
+        object_byte_size: usize,
+        identity_hash: impl FnOnce() -> Option<u64>,
+        max_samples: usize,
+    ) {
+        let groups = self
+            .groups
+            .entry(((self.content_hash)(contents), contents.len()))
+            .or_default();
+
+        let group_index = groups.iter().position(|each| {
+            contents_of(each.representative).is_some_and(|other| other == contents)
+        });
+        let group = match group_index {
+            Some(index) => {
+                let group = &mut groups[index];
+                group.amount_of_objects += 1;
+                group
+            }
+            None => {
+                groups.push(DuplicateContentGroup {
+                    representative: item,
+                    content_byte_size: contents.len(),
+                    object_byte_size,
+                    amount_of_objects: 1,
+                    sample_identity_hashes: vec![],
+                });
+                groups.last_mut().unwrap()
+            }
+        };
+
+        if group.sample_identity_hashes.len() < max_samples {
+            if let Some(hash) = identity_hash() {
+                group.sample_identity_hashes.push(hash);
+            }
+        }
+    }
+
+    pub fn duplicate_groups(&self) -> impl Iterator<Item = &DuplicateContentGroup<T>> {
+        self.groups
+            .values()
+            .flatten()
+            .filter(|each| each.amount_of_duplicates() > 0)
+    }
+
+    /// Return at most `limit` groups with duplicates, the most wasteful first
+    pub fn top_groups_by_wasted_size(&self, limit: usize) -> Vec<&DuplicateContentGroup<T>> {
+        let mut groups = self.duplicate_groups().collect::<Vec<_>>();
+        groups.sort_by(|a, b| b.wasted_byte_size().cmp(&a.wasted_byte_size()));
+        groups.truncate(limit);
+        groups
+    }
+
+    pub fn amount_of_duplicates(&self) -> usize {
+        self.duplicate_groups()
+            .map(|each| each.amount_of_duplicates())
+            .sum()
+    }
+
+    pub fn wasted_byte_size(&self) -> usize {
+        self.duplicate_groups()
+            .map(|each| each.wasted_byte_size())
+            .sum()
+    }
+}
+
+impl<T> DuplicateContentGroup<T> {
+    /// The amount of objects that could be replaced by the shared one
+    pub fn amount_of_duplicates(&self) -> usize {
+        self.amount_of_objects - 1
+    }
+
+    pub fn wasted_byte_size(&self) -> usize {
+        self.amount_of_duplicates() * self.object_byte_size
+    }
+}
+
+/// The identity hash of an object if it has one, the analysis must not assign new ones
+fn raw_identity_hash(object: ObjectRef) -> Option<u64> {
+    Smalltalk::raw_identity_hash(ObjectPointer::from(object.as_ptr()))
+}
+
+/// Return the indexable contents of a byte-format object as a slice of bytes.
+/// Compiled methods are not considered as they also contain literals.
+fn object_contents<'a>(object: ObjectRef) -> Option<&'a [u8]> {
+    let format = object.header().format();
+    let bytes_per_unit = match format {
+        ObjectFormat::Indexable64 => 8,
+        ObjectFormat::Indexable32(_) => 4,
+        ObjectFormat::Indexable16(_) => 2,
+        ObjectFormat::Indexable8(_) => 1,
+        _ => return None,
+    };
+
+    let length = object.amount_of_indexable_units() * bytes_per_unit;
+    let contents =
+        unsafe { std::slice::from_raw_parts(object.first_fixed_field_ptr() as *const u8, length) };
+    Some(contents)
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveAnalyzeDuplicateContents() -> Result<(), vm_object_model::Error> {
+    let class_tally_class = Smalltalk::get_method_argument(0).as_object()?;
+    let content_group_class = Smalltalk::get_method_argument(1).as_object()?;
+    let max_groups_per_class = size_value(Smalltalk::stack_ref(StackOffset::new(1)))?;
+    let max_samples = size_value(Smalltalk::stack_ref(StackOffset::new(0)))?;
+
+    let mut analyzer = DuplicateContentAnalyzer::new(max_samples);
+    analyzer.process_objects(EdenMemorySpace::new().objects());
+    analyzer.process_objects(PastMemorySpace::new().objects());
+    analyzer.process_objects(OldMemorySpace::new().objects());
+
+    let tallies = analyzer.sorted_tallies_by_wasted_size();
+    let mut tallies_array =
+        Smalltalk::instantiate_indexable::<ArrayRef>(Smalltalk::class_array(), tallies.len())?;
+
+    for (index, tally) in tallies.into_iter().enumerate() {
+        let groups = tally.top_groups_by_wasted_size(max_groups_per_class);
+
+        let mut groups_array =
+            Smalltalk::instantiate_indexable::<ArrayRef>(Smalltalk::class_array(), groups.len())?;
+        for (group_index, group) in groups.into_iter().enumerate() {
+            groups_array.insert(
+                group_index,
+                content_group_to_object(group, content_group_class)?,
+            );
+        }
+
+        let mut class_tally_object =
+            Smalltalk::instantiate::<DuplicateClassTallyObjectRef>(class_tally_class)?;
+        class_tally_object.set_class(tally.class);
+        class_tally_object.set_amount_of_duplicates(tally.amount_of_duplicates());
+        class_tally_object.set_wasted_byte_size(tally.wasted_byte_size());
+        class_tally_object.set_groups(groups_array);
+
+        tallies_array.insert(index, class_tally_object);
+    }
+
+    Smalltalk::method_return(tallies_array);
+    Ok(())
+}
+
+fn content_group_to_object(
+    group: &DuplicateContentGroup<ObjectRef>,
+    content_group_class: ObjectRef,
+) -> Result<DuplicateContentGroupObjectRef, vm_object_model::Error> {
+    let mut hashes_array = Smalltalk::instantiate_indexable::<ArrayRef>(
+        Smalltalk::class_array(),
+        group.sample_identity_hashes.len(),
+    )?;
+    for (index, hash) in group.sample_identity_hashes.iter().enumerate() {
+        hashes_array.insert(index, Immediate::new_u64(*hash));
+    }
+
+    let mut group_object =
+        Smalltalk::instantiate::<DuplicateContentGroupObjectRef>(content_group_class)?;
+    group_object.set_content_byte_size(group.content_byte_size);
+    group_object.set_amount_of_objects(group.amount_of_objects);
+    group_object.set_wasted_byte_size(group.wasted_byte_size());
+    group_object.set_sample_identity_hashes(hashes_array);
+
+    Ok(group_object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Contents = &'static [u8];
+
+    fn contents_of(item: Contents) -> Option<Contents> {
+        Some(item)
+    }
+
+    fn add_all(
+        groups: &mut DuplicateContentGroups<Contents>,
+        items: &[Contents],
+        max_samples: usize,
+    ) {
+        for (index, item) in items.iter().copied().enumerate() {
+            groups.add(
+                item,
+                item,
+                contents_of,
+                item.len() + 8,
+                || Some(index as u64),
+                max_samples,
+            );
+        }
+    }
+
+    fn group_sizes(groups: &DuplicateContentGroups<Contents>) -> Vec<(Contents, usize)> {
+        let mut sizes = groups
+            .groups
+            .values()
+            .flatten()
+            .map(|each| (each.representative, each.amount_of_objects))
+            .collect::<Vec<_>>();
+        sizes.sort();
+        sizes
+    }
+
+    #[test]
+    fn identical_contents_are_grouped() {
+        let mut groups = DuplicateContentGroups::new();
+        add_all(&mut groups, &[b"abc", b"xyz", b"abc", b"abc"], 10);
+
+        assert_eq!(
+            group_sizes(&groups),
+            vec![(&b"abc"[..], 3), (&b"xyz"[..], 1)]
+        );
+        assert_eq!(groups.amount_of_duplicates(), 2);
+        assert_eq!(groups.wasted_byte_size(), 2 * 11);
+    }
+
+    #[test]
+    fn colliding_hashes_are_compared_exactly() {
+        let mut groups = DuplicateContentGroups::with_content_hash(|_| 0);
+        add_all(&mut groups, &[b"abc", b"xyz", b"abc", b"ab"], 10);
+
+        assert_eq!(
+            group_sizes(&groups),
+            vec![(&b"ab"[..], 1), (&b"abc"[..], 2), (&b"xyz"[..], 1)]
+        );
+        assert_eq!(groups.groups[&(0, 3)].len(), 2);
+        assert_eq!(groups.groups[&(0, 2)].len(), 1);
+    }
+
+    #[test]
+    fn empty_contents_are_grouped() {
+        let mut groups = DuplicateContentGroups::new();
+        add_all(&mut groups, &[b"", b""], 10);
+        assert_eq!(group_sizes(&groups), vec![(&b""[..], 2)]);
+    }
+
+    #[test]
+    fn samples_are_limited() {
+        let mut groups = DuplicateContentGroups::new();
+        add_all(&mut groups, &[b"abc", b"abc", b"abc", b"abc"], 2);
+        let group = groups.duplicate_groups().next().unwrap();
+        assert_eq!(group.sample_identity_hashes, vec![0, 1]);
+
+        let mut groups = DuplicateContentGroups::new();
+        add_all(&mut groups, &[b"abc", b"abc"], 0);
+        let group = groups.duplicate_groups().next().unwrap();
+        assert!(group.sample_identity_hashes.is_empty());
+    }
+
+    #[test]
+    fn objects_without_identity_hash_are_not_sampled() {
+        let mut groups = DuplicateContentGroups::new();
+        let item: Contents = b"abc";
+        groups.add(item, item, contents_of, 8, || None, 2);
+        groups.add(item, item, contents_of, 8, || Some(42), 2);
+
+        let group = groups.duplicate_groups().next().unwrap();
+        assert_eq!(group.amount_of_objects, 2);
+        assert_eq!(group.sample_identity_hashes, vec![42]);
+    }
+
+    #[test]
+    fn top_groups_are_the_most_wasteful() {
+        let mut groups = DuplicateContentGroups::new();
+        add_all(
+            &mut groups,
+            &[
+                b"a", b"a", b"bbbb", b"bbbb", b"bbbb", b"cc", b"cc", b"unique",
+            ],
+            0,
+        );
+
+        let top = groups
+            .top_groups_by_wasted_size(2)
+            .into_iter()
+            .map(|each| each.representative)
+            .collect::<Vec<_>>();
+        assert_eq!(top, vec![&b"bbbb"[..], &b"cc"[..]]);
+        assert_eq!(groups.top_groups_by_wasted_size(10).len(), 3);
+        assert!(groups.top_groups_by_wasted_size(0).is_empty());
+    }
+}
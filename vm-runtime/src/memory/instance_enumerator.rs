@@ -0,0 +1,152 @@
+use crate::memory::{size_value, EdenMemorySpace, OldMemorySpace, PastMemorySpace};
+use crate::objects::Array;
+use vec_map::VecMap;
+use vm_bindings::{ObjectPointer, Smalltalk, StackOffset};
+use vm_object_model::{AnyObjectRef, ObjectRef};
+
+/// Collects instances of a class, or of a class and its subclasses, by walking over
+/// all objects in eden, past and old space. Objects are matched by the class index
+/// in their header, so the class of an object is only looked up once per class index.
+#[derive(Debug)]
+pub struct InstanceEnumerator {
+    class: ObjectRef,
+    class_index: usize,
+    include_subclasses: bool,
+    min_byte_size: usize,
+    limit: usize,
+    /// tells if instances of a class with a given index should be collected
+    class_filter: VecMap<bool>,
+    instances: Vec<ObjectRef>,
+}
+
+impl InstanceEnumerator {
+    /// Fails if the object is not a class, the hash of other objects is not a class index
+    pub fn new(class: ObjectRef) -> Result<Self, vm_object_model::Error> {
+        if !Smalltalk::could_oop_be_class(ObjectPointer::from(class.as_ptr())) {
+            return Err(vm_object_model::Error::InvalidType("a class".to_string()));
+        }
+        let class_index = Smalltalk::class_index_of_class(class).ok_or_else(|| {
+            vm_object_model::Error::InvalidType("a class with a class index".to_string())
+        })?;
+
+        Ok(Self {
+            class,
+            class_index: class_index as usize,
+            include_subclasses: false,
+            min_byte_size: 0,
+            limit: usize::MAX,
+            class_filter: Default::default(),
+            instances: vec![],
+        })
+    }
+
+    pub fn with_subclasses(mut self, include_subclasses: bool) -> Self {
+        self.include_subclasses = include_subclasses;
+        self
+    }
+
+    /// Only collect instances that occupy at least a given amount of bytes
+    pub fn with_min_byte_size(mut self, min_byte_size: usize) -> Self {
+        self.min_byte_size = min_byte_size;
+        self
+    }
+
+    /// Stop after finding a given amount of instances
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Collect instances in all spaces until the limit is reached
+    pub fn enumerate(mut self) -> Vec<ObjectRef> {
+        self.process_objects(EdenMemorySpace::new().objects());
+        self.process_objects(PastMemorySpace::new().objects());
+        self.process_objects(OldMemorySpace::new().objects());
+        self.instances
+    }
+
+    fn is_limit_reached(&self) -> bool {
+        self.instances.len() >= self.limit
+    }
+
+    fn process_objects(&mut self, objects: impl Iterator<Item = ObjectRef>) {
+        if self.is_limit_reached() {
+            return;
+        }
+
+        for object in objects {
+            if !self.is_instance(object) {
+                continue;
+            }
+
+            if self.min_byte_size > 0 && Smalltalk::byte_size(object) < self.min_byte_size {
+                continue;
+            }
+
+            self.instances.push(object);
+            if self.is_limit_reached() {
+                return;
+            }
+        }
+    }
+
+    fn is_instance(&mut self, object: ObjectRef) -> bool {
+        let class_index = object.header().class_index() as usize;
+        // a class that was never instantiated has no index yet, but its subclasses may have one
+        if self.class_index != 0 && class_index == self.class_index {
+            return true;
+        }
+        if !self.include_subclasses {
+            return false;
+        }
+
+        let class = self.class;
+        *self
+            .class_filter
+            .entry(class_index)
+            .or_insert_with(|| inherits_from(Smalltalk::class_of_object(object), class))
+    }
+}
+
+/// Return true if the class is a subclass of a given superclass
+fn inherits_from(class: ObjectRef, superclass: ObjectRef) -> bool {
+    let nil_object = Smalltalk::nil_object();
+    let mut current: AnyObjectRef = class.into();
+    loop {
+        let Ok(current_class) = current.as_object() else {
+            return false;
+        };
+        match current_class.inst_var_at(0) {
+            Some(next) if next == superclass.into() => return true,
+            Some(next) if next != nil_object => current = next,
+            _ => return false,
+        }
+    }
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEnumerateInstancesOfClass() -> Result<(), vm_object_model::Error> {
+    let class = Smalltalk::stack_ref(StackOffset::new(3)).as_object()?;
+    let include_subclasses =
+        Smalltalk::stack_ref(StackOffset::new(2)) == Smalltalk::bool_object(true).into();
+    let limit = size_value(Smalltalk::stack_ref(StackOffset::new(1)))?;
+    let min_byte_size = size_value(Smalltalk::stack_ref(StackOffset::new(0)))?;
+
+    let instances = InstanceEnumerator::new(class)?
+        .with_subclasses(include_subclasses)
+        .with_limit(limit)
+        .with_min_byte_size(min_byte_size)
+        .enumerate();
+
+    let instances = instances
+        .into_iter()
+        .map(AnyObjectRef::from)
+        .collect::<Vec<_>>();
+
+    let mut instances_array = Array::new(instances.len())?;
+    instances_array.copy_from(0, instances.len(), instances.as_slice());
+
+    Smalltalk::method_return(instances_array);
+    Ok(())
+}
@@ -16,6 +16,50 @@ impl OldMemorySpace {
     pub fn objects(&self) -> impl Iterator<Item = ObjectRef> {
         self.chunk.iter().filter(|each| each.is_enumerable())
     }
+
+    pub fn segments(&self) -> impl Iterator<Item = OldMemorySegment> {
+        (0..Smalltalk::old_space_segments_count()).map(OldMemorySegment::new)
+    }
+}
+
+/// Old space consists of one or more segments connected with bridges.
+#[derive(Debug)]
+pub struct OldMemorySegment {
+    index: usize,
+    chunk: MemoryChunk,
+}
+
+impl OldMemorySegment {
+    pub fn new(index: usize) -> Self {
+        Self {
+            index,
+            chunk: MemoryChunk::new(
+                Smalltalk::old_space_segment_start(index),
+                Smalltalk::old_space_segment_end(index),
+            ),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn start(&self) -> RawObjectPointer {
+        self.chunk.start
+    }
+
+    pub fn end(&self) -> RawObjectPointer {
+        self.chunk.end
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = ObjectRef> {
+        self.chunk.iter().filter(|each| each.is_enumerable())
+    }
+
+    /// Return all entities in the segment including free chunks and bridges
+    pub fn entities(&self) -> impl Iterator<Item = ObjectRef> {
+        self.chunk.iter()
+    }
 }
 
 #[derive(Debug)]
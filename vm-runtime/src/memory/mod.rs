@@ -1,5 +1,13 @@
 mod analyzer;
+mod duplicate_analyzer;
+mod instance_enumerator;
 mod memory;
+mod segment_analyzer;
+mod weak_analyzer;
 
 pub use analyzer::*;
+pub use duplicate_analyzer::*;
+pub use instance_enumerator::*;
 pub use memory::*;
+pub use segment_analyzer::*;
+pub use weak_analyzer::*;
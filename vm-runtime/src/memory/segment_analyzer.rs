@@ -0,0 +1,273 @@
+use crate::memory::{OldMemorySegment, OldMemorySpace};
+use crate::objects::{ArrayRef, Association};
+use fxhash::FxHashMap;
+use vec_map::VecMap;
+use vm_bindings::Smalltalk;
+use vm_object_model::{Immediate, Object, ObjectRef};
+
+/// Analyzes the fragmentation of the old space segment by segment:
+/// free chunks, pinned objects that prevent segments from being compacted or released
+/// and remembered objects that refer to the young space.
+#[derive(Debug)]
+pub struct SegmentAnalyzer {
+    segments: Vec<SegmentTally>,
+    free_chunk_sizes: FxHashMap<usize, usize>,
+    pinned_tallies: VecMap<PinnedClassTally>,
+    amount_of_remembered_objects: usize,
+}
+
+#[derive(Debug)]
+pub struct SegmentTally {
+    index: usize,
+    start: usize,
+    byte_size: usize,
+    amount_of_objects: usize,
+    objects_byte_size: usize,
+    amount_of_free_chunks: usize,
+    free_byte_size: usize,
+    largest_free_chunk: usize,
+    amount_of_pinned_objects: usize,
+    pinned_byte_size: usize,
+}
+
+#[derive(Debug)]
+pub struct PinnedClassTally {
+    class: ObjectRef,
+    amount_of_objects: usize,
+    total_byte_size: usize,
+    object_byte_sizes: FxHashMap<usize, usize>,
+    segments: FxHashMap<usize, usize>,
+}
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct SegmentReportObject {
+    this: Object,
+    segments: ArrayRef,
+    free_chunk_sizes: ArrayRef,
+    pinned_classes: ArrayRef,
+    remembered_set_size: Immediate,
+    amount_of_remembered_objects: Immediate,
+}
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct SegmentTallyObject {
+    this: Object,
+    index: Immediate,
+    start: Immediate,
+    byte_size: Immediate,
+    amount_of_objects: Immediate,
+    objects_byte_size: Immediate,
+    amount_of_free_chunks: Immediate,
+    free_byte_size: Immediate,
+    largest_free_chunk: Immediate,
+    amount_of_pinned_objects: Immediate,
+    pinned_byte_size: Immediate,
+}
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct PinnedClassTallyObject {
+    this: Object,
+    class: ObjectRef,
+    amount_of_objects: Immediate,
+    total_byte_size: Immediate,
+    byte_size_details: ArrayRef,
+    segments: ArrayRef,
+}
+
+impl SegmentAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            segments: vec![],
+            free_chunk_sizes: Default::default(),
+            pinned_tallies: Default::default(),
+            amount_of_remembered_objects: 0,
+        }
+    }
+
+    pub fn process_old_space(&mut self, old_space: &OldMemorySpace) {
+        for segment in old_space.segments() {
+            self.process_segment(&segment);
+        }
+    }
+
+    pub fn process_segment(&mut self, segment: &OldMemorySegment) {
+        let start = segment.start().as_i64() as usize;
+        let mut tally = SegmentTally {
+            index: segment.index(),
+            start,
+            byte_size: segment.end().as_i64() as usize - start,
+            amount_of_objects: 0,
+            objects_byte_size: 0,
+            amount_of_free_chunks: 0,
+            free_byte_size: 0,
+            largest_free_chunk: 0,
+            amount_of_pinned_objects: 0,
+            pinned_byte_size: 0,
+        };
+
+        for entity in segment.entities() {
+            if entity.is_free() {
+                let chunk_size = Smalltalk::byte_size(entity);
+                tally.amount_of_free_chunks += 1;
+                tally.free_byte_size += chunk_size;
+                tally.largest_free_chunk = tally.largest_free_chunk.max(chunk_size);
+                *self.free_chunk_sizes.entry(chunk_size).or_insert(0) += 1;
+                continue;
+            }
+
+            if !entity.is_enumerable() {
+                continue;
+            }
+
+            let object_size = Smalltalk::byte_size(entity);
+            tally.amount_of_objects += 1;
+            tally.objects_byte_size += object_size;
+
+            let header = entity.header();
+            if header.is_remembered() {
+                self.amount_of_remembered_objects += 1;
+            }
+
+            if header.is_pinned() {
+                tally.amount_of_pinned_objects += 1;
+                tally.pinned_byte_size += object_size;
+
+                let pinned_tally = self
+                    .pinned_tallies
+                    .entry(header.class_index() as usize)
+                    .or_insert_with(|| PinnedClassTally::new(Smalltalk::class_of_object(entity)));
+                pinned_tally.amount_of_objects += 1;
+                pinned_tally.total_byte_size += object_size;
+                *pinned_tally
+                    .object_byte_sizes
+                    .entry(object_size)
+                    .or_insert(0) += 1;
+                *pinned_tally.segments.entry(segment.index()).or_insert(0) += 1;
+            }
+        }
+
+        self.segments.push(tally);
+    }
+
+    pub fn sorted_free_chunk_sizes(&self) -> Vec<(usize, usize)> {
+        sorted_by_total_size(&self.free_chunk_sizes)
+    }
+
+    pub fn sorted_pinned_tallies_by_size(&self) -> Vec<&PinnedClassTally> {
+        let mut tallies = self.pinned_tallies.values().collect::<Vec<_>>();
+        tallies.sort_by(|a, b| b.total_byte_size.cmp(&a.total_byte_size));
+        tallies
+    }
+}
+
+impl PinnedClassTally {
+    pub fn new(class: ObjectRef) -> Self {
+        Self {
+            class,
+            amount_of_objects: 0,
+            total_byte_size: 0,
+            object_byte_sizes: Default::default(),
+            segments: Default::default(),
+        }
+    }
+
+    pub fn sorted_segments(&self) -> Vec<(usize, usize)> {
+        let mut segments = self
+            .segments
+            .iter()
+            .map(|each| (*each.0, *each.1))
+            .collect::<Vec<_>>();
+        segments.sort_by(|a, b| a.0.cmp(&b.0));
+        segments
+    }
+}
+
+fn sorted_by_total_size(sizes: &FxHashMap<usize, usize>) -> Vec<(usize, usize)> {
+    let mut sizes = sizes
+        .iter()
+        .map(|each| (*each.0, *each.1))
+        .collect::<Vec<_>>();
+    sizes.sort_by(|a, b| (b.0 * b.1).cmp(&(a.0 * a.1)));
+    sizes
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveAnalyzeOldSpaceSegments() -> Result<(), vm_object_model::Error> {
+    let report_class = Smalltalk::get_method_argument(0).as_object()?;
+    let segment_tally_class = Smalltalk::get_method_argument(1).as_object()?;
+    let pinned_class_tally_class = Smalltalk::get_method_argument(2).as_object()?;
+    let association_class = Smalltalk::get_method_argument(3).as_object()?;
+
+    let mut analyzer = SegmentAnalyzer::new();
+    analyzer.process_old_space(&OldMemorySpace::new());
+
+    let mut segments_array = Smalltalk::instantiate_indexable::<ArrayRef>(
+        Smalltalk::class_array(),
+        analyzer.segments.len(),
+    )?;
+    for (index, segment) in analyzer.segments.iter().enumerate() {
+        let mut segment_object =
+            Smalltalk::instantiate::<SegmentTallyObjectRef>(segment_tally_class)?;
+        segment_object.set_index(segment.index);
+        segment_object.set_start(segment.start);
+        segment_object.set_byte_size(segment.byte_size);
+        segment_object.set_amount_of_objects(segment.amount_of_objects);
+        segment_object.set_objects_byte_size(segment.objects_byte_size);
+        segment_object.set_amount_of_free_chunks(segment.amount_of_free_chunks);
+        segment_object.set_free_byte_size(segment.free_byte_size);
+        segment_object.set_largest_free_chunk(segment.largest_free_chunk);
+        segment_object.set_amount_of_pinned_objects(segment.amount_of_pinned_objects);
+        segment_object.set_pinned_byte_size(segment.pinned_byte_size);
+        segments_array.insert(index, segment_object);
+    }
+
+    let pinned_tallies = analyzer.sorted_pinned_tallies_by_size();
+    let mut pinned_array = Smalltalk::instantiate_indexable::<ArrayRef>(
+        Smalltalk::class_array(),
+        pinned_tallies.len(),
+    )?;
+    for (index, tally) in pinned_tallies.into_iter().enumerate() {
+        let mut tally_object =
+            Smalltalk::instantiate::<PinnedClassTallyObjectRef>(pinned_class_tally_class)?;
+        tally_object.set_class(tally.class);
+        tally_object.set_amount_of_objects(tally.amount_of_objects);
+        tally_object.set_total_byte_size(tally.total_byte_size);
+        tally_object.set_byte_size_details(pairs_to_array(
+            &sorted_by_total_size(&tally.object_byte_sizes),
+            association_class,
+        )?);
+        tally_object.set_segments(pairs_to_array(&tally.sorted_segments(), association_class)?);
+        pinned_array.insert(index, tally_object);
+    }
+
+    let free_chunk_sizes = pairs_to_array(&analyzer.sorted_free_chunk_sizes(), association_class)?;
+
+    let mut report = Smalltalk::instantiate::<SegmentReportObjectRef>(report_class)?;
+    report.set_segments(segments_array);
+    report.set_free_chunk_sizes(free_chunk_sizes);
+    report.set_pinned_classes(pinned_array);
+    report.set_remembered_set_size(Smalltalk::remembered_set_size());
+    report.set_amount_of_remembered_objects(analyzer.amount_of_remembered_objects);
+
+    Smalltalk::method_return(report);
+    Ok(())
+}
+
+fn pairs_to_array(
+    pairs: &[(usize, usize)],
+    association_class: ObjectRef,
+) -> Result<ArrayRef, vm_object_model::Error> {
+    let mut array =
+        Smalltalk::instantiate_indexable::<ArrayRef>(Smalltalk::class_array(), pairs.len())?;
+    for (index, (key, value)) in pairs.iter().enumerate() {
+        let mut association = Association::new(association_class)?;
+        association.set_key(Immediate::new_u64(*key as u64));
+        association.set_value(Immediate::new_u64(*value as u64));
+        array.insert(index, association);
+    }
+    Ok(array)
+}
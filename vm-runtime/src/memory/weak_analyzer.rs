@@ -0,0 +1,133 @@
+use crate::memory::{EdenMemorySpace, OldMemorySpace, PastMemorySpace};
+use crate::objects::ArrayRef;
+use crate::reference_finder::ObjectIterator;
+use vec_map::VecMap;
+use vm_bindings::Smalltalk;
+use vm_object_model::{AnyObjectRef, Immediate, Object, ObjectRef};
+
+/// Enumerates weak-indexable objects and ephemerons and counts how many of their
+/// weak slots still refer to objects and how many were already cleared by the garbage collector.
+/// For ephemerons the key is considered to be the only weak slot.
+#[derive(Debug)]
+pub struct WeakAnalyzer {
+    nil_object: AnyObjectRef,
+    tallies: VecMap<WeakClassTally>,
+}
+
+#[derive(Debug)]
+pub struct WeakClassTally {
+    class: ObjectRef,
+    is_ephemeron: bool,
+    amount_of_objects: usize,
+    total_byte_size: usize,
+    amount_of_live_slots: usize,
+    amount_of_cleared_slots: usize,
+}
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct WeakClassTallyObject {
+    this: Object,
+    class: ObjectRef,
+    is_ephemeron: AnyObjectRef,
+    amount_of_objects: Immediate,
+    total_byte_size: Immediate,
+    amount_of_live_slots: Immediate,
+    amount_of_cleared_slots: Immediate,
+}
+
+impl WeakAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            nil_object: Smalltalk::nil_object(),
+            tallies: Default::default(),
+        }
+    }
+
+    pub fn process_objects(&mut self, objects: impl Iterator<Item = ObjectRef>) {
+        for object in objects {
+            let format = object.header().format();
+            let is_ephemeron = format.is_ephemeron();
+            if !format.is_weak() && !is_ephemeron {
+                continue;
+            }
+
+            let (live_slots, cleared_slots) = if is_ephemeron {
+                match object.inst_var_at(0) {
+                    Some(key) if key != self.nil_object => (1, 0),
+                    _ => (0, 1),
+                }
+            } else {
+                self.count_weak_slots(object)
+            };
+
+            let tally = self
+                .tallies
+                .entry(object.header().class_index() as usize)
+                .or_insert_with(|| WeakClassTally {
+                    class: Smalltalk::class_of_object(object),
+                    is_ephemeron,
+                    amount_of_objects: 0,
+                    total_byte_size: 0,
+                    amount_of_live_slots: 0,
+                    amount_of_cleared_slots: 0,
+                });
+            tally.amount_of_objects += 1;
+            tally.total_byte_size += Smalltalk::byte_size(object);
+            tally.amount_of_live_slots += live_slots;
+            tally.amount_of_cleared_slots += cleared_slots;
+        }
+    }
+
+    /// Count live and cleared indexable (weak) slots, fixed fields are strong
+    fn count_weak_slots(&self, object: ObjectRef) -> (usize, usize) {
+        let iterator = ObjectIterator::new(object.into());
+        let first_weak_slot = iterator.amount_of_fixed_fields;
+        let last_weak_slot = first_weak_slot + iterator.amount_of_indexable_fields;
+
+        let mut live_slots = 0;
+        let mut cleared_slots = 0;
+        for index in first_weak_slot..last_weak_slot {
+            match object.inst_var_at(index) {
+                Some(value) if value != self.nil_object => live_slots += 1,
+                _ => cleared_slots += 1,
+            }
+        }
+        (live_slots, cleared_slots)
+    }
+
+    pub fn sorted_tallies_by_size(&self) -> Vec<&WeakClassTally> {
+        let mut tallies = self.tallies.values().collect::<Vec<_>>();
+        tallies.sort_by(|a, b| b.total_byte_size.cmp(&a.total_byte_size));
+        tallies
+    }
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveAnalyzeWeakObjects() -> Result<(), vm_object_model::Error> {
+    let weak_class_tally_class = Smalltalk::get_method_argument(0).as_object()?;
+
+    let mut analyzer = WeakAnalyzer::new();
+    analyzer.process_objects(EdenMemorySpace::new().objects());
+    analyzer.process_objects(PastMemorySpace::new().objects());
+    analyzer.process_objects(OldMemorySpace::new().objects());
+
+    let tallies = analyzer.sorted_tallies_by_size();
+    let mut tallies_array =
+        Smalltalk::instantiate_indexable::<ArrayRef>(Smalltalk::class_array(), tallies.len())?;
+    for (index, tally) in tallies.into_iter().enumerate() {
+        let mut tally_object =
+            Smalltalk::instantiate::<WeakClassTallyObjectRef>(weak_class_tally_class)?;
+        tally_object.set_class(tally.class);
+        tally_object.set_is_ephemeron(Smalltalk::bool_object(tally.is_ephemeron));
+        tally_object.set_amount_of_objects(tally.amount_of_objects);
+        tally_object.set_total_byte_size(tally.total_byte_size);
+        tally_object.set_amount_of_live_slots(tally.amount_of_live_slots);
+        tally_object.set_amount_of_cleared_slots(tally.amount_of_cleared_slots);
+        tallies_array.insert(index, tally_object);
+    }
+
+    Smalltalk::method_return(tallies_array);
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use std::fmt::{Debug, Display, Formatter};
 use std::ops::{Deref, DerefMut};
 use std::slice;
+use vm_bindings::Smalltalk;
 use vm_object_model::{AnyObjectRef, Error, Object, ObjectFormat, ObjectRef, Result};
 
 #[derive(Debug)]
@@ -10,10 +11,18 @@ pub struct ByteArray {
 }
 
 impl ByteArray {
+    pub fn new(size: usize) -> Result<ByteArrayRef> {
+        Smalltalk::instantiate_indexable(Smalltalk::class_byte_array(), size)
+    }
+
     pub fn as_slice(&self) -> &[u8] {
         unsafe { slice::from_raw_parts(self.first_fixed_field_ptr() as _, self.len()) }
     }
 
+    pub fn as_slice_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.first_fixed_field_ptr() as _, self.len()) }
+    }
+
     pub fn len(&self) -> usize {
         self.amount_of_indexable_units()
     }
@@ -1,7 +1,10 @@
 use std::fmt::{Debug, Display, Formatter};
 use std::ops::{Deref, DerefMut};
 use std::slice;
-use vm_object_model::{AnyObjectRef, Error, Object, ObjectFormat, ObjectRef, Result};
+use vm_bindings::Smalltalk;
+use vm_object_model::{
+    AnyObjectRef, Error, Object, ObjectFormat, ObjectRef, RawObjectPointer, Result,
+};
 
 #[derive(Debug)]
 #[repr(C)]
@@ -10,11 +13,24 @@ pub struct ByteString {
 }
 
 impl ByteString {
+    pub fn new(bytes: &[u8]) -> Result<ByteStringRef> {
+        let class = AnyObjectRef::from(RawObjectPointer::from(Smalltalk::class_string().as_i64()))
+            .as_object()?;
+        let mut string: ByteStringRef = Smalltalk::instantiate_indexable(class, bytes.len())?;
+        string.bytes_mut().copy_from_slice(bytes);
+        Ok(string)
+    }
+
     pub fn bytes(&self) -> &[u8] {
         let len = self.amount_of_indexable_units();
         unsafe { slice::from_raw_parts(self.first_fixed_field_ptr() as _, len) }
     }
 
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        let len = self.amount_of_indexable_units();
+        unsafe { slice::from_raw_parts_mut(self.first_fixed_field_ptr() as _, len) }
+    }
+
     pub fn as_str(&self) -> &str {
         str::from_utf8(self.bytes()).unwrap()
     }
@@ -0,0 +1,618 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use vm_bindings::{Smalltalk, StackOffset};
+use vm_object_model::{AnyObjectRef, Immediate, Object, ObjectRef, RawObjectPointer};
+
+use crate::objects::Array;
+use crate::reference_finder::{
+    visit_objects, visitor_next_objects, ObjectVisitor, ReferenceKinds, ReferencedObject,
+    VisitorAction, VisitorState,
+};
+use crate::vm;
+
+const UNDEFINED: usize = usize::MAX;
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct RetainedObjectTally {
+    this: Object,
+    object: AnyObjectRef,
+    byte_size: Immediate,
+    retained_byte_size: Immediate,
+}
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct RetainedClassTally {
+    this: Object,
+    class: ObjectRef,
+    amount_of_objects: Immediate,
+    total_byte_size: Immediate,
+    retained_byte_size: Immediate,
+}
+
+#[allow(non_snake_case)]
+pub fn primitiveDominatorTreeTopRetainedObjects() -> Result<(), anyhow::Error> {
+    let tally_class = Smalltalk::stack_ref(StackOffset::new(0)).as_object()?;
+    let Ok(limit) = usize::try_from(Smalltalk::stack_integer_value(StackOffset::new(1))) else {
+        Smalltalk::primitive_fail();
+        return Ok(());
+    };
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(2));
+
+    let tree = DominatorTree::build_from_start_or_vm_roots(start_obj);
+    let nodes = tree.top_retained_objects(limit);
+
+    let mut array = Array::new(nodes.len())?;
+    for (index, node) in nodes.into_iter().enumerate() {
+        array.insert(index, tree.node_to_object(node, tally_class)?);
+    }
+
+    Smalltalk::method_return(array);
+    Ok(())
+}
+
+#[allow(non_snake_case)]
+pub fn primitiveDominatorTreeTopRetainedClasses() -> Result<(), anyhow::Error> {
+    let tally_class = Smalltalk::stack_ref(StackOffset::new(0)).as_object()?;
+    let Ok(limit) = usize::try_from(Smalltalk::stack_integer_value(StackOffset::new(1))) else {
+        Smalltalk::primitive_fail();
+        return Ok(());
+    };
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(2));
+
+    let tree = DominatorTree::build_from_start_or_vm_roots(start_obj);
+    let tallies = tree.top_retained_classes(limit);
+
+    let mut array = Array::new(tallies.len())?;
+    for (index, tally) in tallies.into_iter().enumerate() {
+        let mut tally_object = Smalltalk::instantiate::<RetainedClassTallyRef>(tally_class)?;
+        tally_object.set_class(tally.class);
+        tally_object.set_amount_of_objects(tally.amount_of_objects);
+        tally_object.set_total_byte_size(tally.total_byte_size);
+        tally_object.set_retained_byte_size(tally.retained_byte_size);
+        array.insert(index, tally_object);
+    }
+
+    Smalltalk::method_return(array);
+    Ok(())
+}
+
+#[allow(non_snake_case)]
+pub fn primitiveDominatorTreeDominatorChain() -> Result<(), anyhow::Error> {
+    let tally_class = Smalltalk::stack_ref(StackOffset::new(0)).as_object()?;
+    let target_obj = Smalltalk::stack_ref(StackOffset::new(1));
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(2));
+
+    let tree = DominatorTree::build_from_start_or_vm_roots(start_obj);
+    let chain = tree
+        .dominator_chain(target_obj)
+        .ok_or_else(|| anyhow!("Target is not reachable from the start object or the VM roots"))?;
+
+    let mut array = Array::new(chain.len())?;
+    for (index, node) in chain.into_iter().enumerate() {
+        array.insert(index, tree.node_to_object(node, tally_class)?);
+    }
+
+    Smalltalk::method_return(array);
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct ClassRetainedSize {
+    pub class: ObjectRef,
+    pub amount_of_objects: usize,
+    pub total_byte_size: usize,
+    pub retained_byte_size: usize,
+}
+
+/// A dominator tree of the object graph reachable from the VM roots or from a start object,
+/// computed with the Lengauer-Tarjan algorithm.
+/// An object `d` dominates an object `o` if every path from the roots to `o` goes through `d`,
+/// therefore the retained size of `d` is the amount of memory that would be freed
+/// if `d` was no longer referenced.
+///
+/// The VM roots are the special objects array, which leads to the globals, the class table
+/// and the process scheduler with all suspended processes, and the context of the active process.
+/// They hang off a virtual root node without an object, which is never reported.
+/// Retained sizes computed from a start object only tell what would be freed if the objects
+/// outside of its graph did not reference anything in it.
+#[derive(Debug)]
+pub struct DominatorTree {
+    /// the object of the virtual root is a placeholder
+    objects: Vec<ObjectRef>,
+    byte_sizes: Vec<usize>,
+    immediate_dominators: Vec<usize>,
+    retained_sizes: Vec<usize>,
+    /// nodes in depth-first pre-order, a dominator always comes before the nodes it dominates
+    order: Vec<usize>,
+    indices: HashMap<ObjectRef, usize>,
+    /// the node `0` is a virtual root above the VM roots
+    has_virtual_root: bool,
+}
+
+impl DominatorTree {
+    /// Build the dominator tree of the objects reachable from a given start object
+    pub fn build(start: AnyObjectRef) -> Self {
+        Self::from_graph(ObjectGraph::collect(start))
+    }
+
+    /// Build the dominator tree of all objects reachable from the VM roots,
+    /// must be called from a primitive on the interpreter thread
+    pub fn build_from_vm_roots() -> Self {
+        let special_objects_array = AnyObjectRef::from(RawObjectPointer::from(
+            vm().proxy().special_objects_array().as_i64(),
+        ));
+        let active_context = AnyObjectRef::from(Smalltalk::this_context());
+        Self::from_graph(ObjectGraph::collect_from_roots(&[
+            special_objects_array,
+            active_context,
+        ]))
+    }
+
+    /// Build from the VM roots if the start object is nil
+    pub fn build_from_start_or_vm_roots(start: AnyObjectRef) -> Self {
+        if start == Smalltalk::nil_object() {
+            Self::build_from_vm_roots()
+        } else {
+            Self::build(start)
+        }
+    }
+
+    fn from_graph(graph: ObjectGraph) -> Self {
+        let (order, immediate_dominators) = lengauer_tarjan(&graph.successors);
+
+        let retained_sizes = retained_sizes(&graph.byte_sizes, &order, &immediate_dominators);
+
+        Self {
+            objects: graph.objects,
+            byte_sizes: graph.byte_sizes,
+            immediate_dominators,
+            retained_sizes,
+            order,
+            indices: graph.indices,
+            has_virtual_root: graph.has_virtual_root,
+        }
+    }
+
+    fn is_virtual_root(&self, node: usize) -> bool {
+        self.has_virtual_root && node == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn object(&self, node: usize) -> ObjectRef {
+        self.objects[node]
+    }
+
+    pub fn byte_size(&self, node: usize) -> usize {
+        self.byte_sizes[node]
+    }
+
+    pub fn retained_size(&self, node: usize) -> usize {
+        self.retained_sizes[node]
+    }
+
+    pub fn immediate_dominator(&self, node: usize) -> Option<usize> {
+        let dominator = self.immediate_dominators[node];
+        if dominator == UNDEFINED || dominator == node || self.is_virtual_root(dominator) {
+            None
+        } else {
+            Some(dominator)
+        }
+    }
+
+    /// Return up to `limit` nodes with the largest retained size
+    pub fn top_retained_objects(&self, limit: usize) -> Vec<usize> {
+        let mut nodes = self
+            .order
+            .iter()
+            .copied()
+            .filter(|node| !self.is_virtual_root(*node))
+            .collect::<Vec<_>>();
+        nodes.sort_by(|a, b| self.retained_sizes[*b].cmp(&self.retained_sizes[*a]));
+        nodes.truncate(limit);
+        nodes
+    }
+
+    /// Return up to `limit` classes with the largest retained size.
+    /// The retained size of a class only accounts for instances that are not dominated
+    /// by another instance of the same class, so that nothing is counted twice.
+    pub fn top_retained_classes(&self, limit: usize) -> Vec<ClassRetainedSize> {
+        let mut children = vec![vec![]; self.objects.len()];
+        for node in self.order.iter().skip(1) {
+            children[self.immediate_dominators[*node]].push(*node);
+        }
+
+        let mut tallies: HashMap<ObjectRef, ClassRetainedSize> = HashMap::new();
+        let mut classes_on_path: HashMap<ObjectRef, usize> = HashMap::new();
+
+        let mut stack = vec![];
+        if let Some(root) = self.order.first() {
+            stack.push((*root, false));
+        }
+
+        while let Some((node, is_exiting)) = stack.pop() {
+            if self.is_virtual_root(node) {
+                stack.extend(children[node].iter().map(|child| (*child, false)));
+                continue;
+            }
+
+            let class = Smalltalk::class_of_object(self.objects[node]);
+            if is_exiting {
+                if let Some(count) = classes_on_path.get_mut(&class) {
+                    *count -= 1;
+                }
+                continue;
+            }
+
+            let tally = tallies.entry(class).or_insert_with(|| ClassRetainedSize {
+                class,
+                amount_of_objects: 0,
+                total_byte_size: 0,
+                retained_byte_size: 0,
+            });
+            tally.amount_of_objects += 1;
+            tally.total_byte_size += self.byte_sizes[node];
+
+            let count = classes_on_path.entry(class).or_insert(0);
+            if *count == 0 {
+                tally.retained_byte_size += self.retained_sizes[node];
+            }
+            *count += 1;
+
+            stack.push((node, true));
+            for child in &children[node] {
+                stack.push((*child, false));
+            }
+        }
+
+        let mut tallies = tallies.into_values().collect::<Vec<_>>();
+        tallies.sort_by(|a, b| b.retained_byte_size.cmp(&a.retained_byte_size));
+        tallies.truncate(limit);
+        tallies
+    }
+
+    /// Return the chain of dominators starting from the root and ending with the target object.
+    /// When built from the VM roots the chain starts with the object right below the virtual root
+    pub fn dominator_chain(&self, target: AnyObjectRef) -> Option<Vec<usize>> {
+        let target = target.as_object().ok()?;
+        let mut node = *self.indices.get(&target)?;
+
+        let mut chain = vec![node];
+        while let Some(dominator) = self.immediate_dominator(node) {
+            chain.push(dominator);
+            node = dominator;
+        }
+        chain.reverse();
+        Some(chain)
+    }
+
+    fn node_to_object(
+        &self,
+        node: usize,
+        tally_class: ObjectRef,
+    ) -> Result<RetainedObjectTallyRef, vm_object_model::Error> {
+        let mut tally_object = Smalltalk::instantiate::<RetainedObjectTallyRef>(tally_class)?;
+        tally_object.set_object(self.objects[node]);
+        tally_object.set_byte_size(self.byte_sizes[node]);
+        tally_object.set_retained_byte_size(self.retained_sizes[node]);
+        Ok(tally_object)
+    }
+}
+
+/// Collects all non-immediate objects reachable from the start object or the roots
+/// together with their outgoing references
+struct ObjectGraph {
+    objects: Vec<ObjectRef>,
+    byte_sizes: Vec<usize>,
+    successors: Vec<Vec<usize>>,
+    indices: HashMap<ObjectRef, usize>,
+    has_virtual_root: bool,
+    /// objects collected by the earlier passes, their references are already collected
+    amount_of_expanded_objects: usize,
+}
+
+impl ObjectGraph {
+    fn new() -> Self {
+        Self {
+            objects: vec![],
+            byte_sizes: vec![],
+            successors: vec![],
+            indices: Default::default(),
+            has_virtual_root: false,
+            amount_of_expanded_objects: 0,
+        }
+    }
+
+    fn collect(start: AnyObjectRef) -> Self {
+        let mut graph = Self::new();
+        visit_objects(start, &mut graph);
+        graph
+    }
+
+    /// Collect the objects reachable from any of the roots, one pass per root.
+    /// The roots are the successors of a virtual root node `0`
+    fn collect_from_roots(roots: &[AnyObjectRef]) -> Self {
+        let mut graph = Self::new();
+        graph.has_virtual_root = true;
+        graph
+            .objects
+            .push(Smalltalk::nil_object().as_object().unwrap());
+        graph.byte_sizes.push(0);
+        graph.successors.push(vec![]);
+
+        for root in roots.iter().filter(|each| !each.is_immediate()) {
+            graph.amount_of_expanded_objects = graph.objects.len();
+            visit_objects(*root, &mut graph);
+        }
+        graph
+    }
+
+    fn index_of(&mut self, object: ObjectRef) -> usize {
+        if let Some(index) = self.indices.get(&object) {
+            return *index;
+        }
+        let index = self.objects.len();
+        self.objects.push(object);
+        self.byte_sizes.push(Smalltalk::byte_size(object));
+        self.successors.push(vec![]);
+        self.indices.insert(object, index);
+        index
+    }
+}
+
+impl ObjectVisitor for ObjectGraph {
+    fn next_objects(
+        object: ReferencedObject,
+        kinds: ReferenceKinds,
+    ) -> impl Iterator<Item = ReferencedObject> {
+        visitor_next_objects(object, kinds).filter(|each| !each.object().is_immediate())
+    }
+
+    fn visit_object(&mut self, object: ReferencedObject, _state: &VisitorState) -> VisitorAction {
+        let index = object
+            .object()
+            .as_object()
+            .ok()
+            .and_then(|object| self.indices.get(&object));
+        match index {
+            Some(index) if *index < self.amount_of_expanded_objects => VisitorAction::Skip,
+            _ => VisitorAction::Continue,
+        }
+    }
+
+    fn visit_referenced_object(
+        &mut self,
+        object: ReferencedObject,
+        state: &VisitorState,
+    ) -> VisitorAction {
+        let Ok(referenced) = object.object().as_object() else {
+            return VisitorAction::Skip;
+        };
+
+        if let ReferencedObject::Root(_) = object {
+            let root_index = self.index_of(referenced);
+            if self.has_virtual_root {
+                self.successors[0].push(root_index);
+            }
+            return VisitorAction::Continue;
+        }
+
+        let Ok(referrer) = state.node().object().as_object() else {
+            return VisitorAction::Skip;
+        };
+
+        let referrer_index = self.index_of(referrer);
+        let referenced_index = self.index_of(referenced);
+        self.successors[referrer_index].push(referenced_index);
+
+        VisitorAction::Continue
+    }
+}
+
+/// Accumulate the byte size of every node into its dominators,
+/// visiting nodes in reverse pre-order so that children are summed before their dominators
+fn retained_sizes(
+    byte_sizes: &[usize],
+    order: &[usize],
+    immediate_dominators: &[usize],
+) -> Vec<usize> {
+    let mut retained_sizes = byte_sizes.to_vec();
+    for node in order.iter().skip(1).rev() {
+        let dominator = immediate_dominators[*node];
+        retained_sizes[dominator] += retained_sizes[*node];
+    }
+    retained_sizes
+}
+
+/// Compute immediate dominators of a graph rooted in the node `0`.
+/// Returns the depth-first pre-order of the nodes and the immediate dominator of each node,
+/// the root is its own dominator.
+fn lengauer_tarjan(successors: &[Vec<usize>]) -> (Vec<usize>, Vec<usize>) {
+    let amount_of_nodes = successors.len();
+    if amount_of_nodes == 0 {
+        return (vec![], vec![]);
+    }
+
+    let mut semi = vec![UNDEFINED; amount_of_nodes];
+    let mut vertex = Vec::with_capacity(amount_of_nodes);
+    let mut parent = vec![UNDEFINED; amount_of_nodes];
+    let mut predecessors = vec![vec![]; amount_of_nodes];
+
+    // depth-first numbering, iterative to not overflow the stack on deep object graphs
+    let mut stack = vec![(0usize, 0usize)];
+    semi[0] = 0;
+    vertex.push(0);
+    while let Some((node, next_edge)) = stack.last_mut() {
+        let node = *node;
+        if let Some(successor) = successors[node].get(*next_edge).copied() {
+            *next_edge += 1;
+            predecessors[successor].push(node);
+            if semi[successor] == UNDEFINED {
+                semi[successor] = vertex.len();
+                vertex.push(successor);
+                parent[successor] = node;
+                stack.push((successor, 0));
+            }
+        } else {
+            stack.pop();
+        }
+    }
+
+    let mut ancestor = vec![UNDEFINED; amount_of_nodes];
+    let mut label = (0..amount_of_nodes).collect::<Vec<_>>();
+    let mut dominators = vec![UNDEFINED; amount_of_nodes];
+    let mut buckets = vec![vec![]; amount_of_nodes];
+
+    for index in (1..vertex.len()).rev() {
+        let node = vertex[index];
+
+        for predecessor in &predecessors[node] {
+            let evaluated = eval(*predecessor, &mut ancestor, &mut label, &semi);
+            if semi[evaluated] < semi[node] {
+                semi[node] = semi[evaluated];
+            }
+        }
+        buckets[vertex[semi[node]]].push(node);
+
+        let node_parent = parent[node];
+        ancestor[node] = node_parent;
+
+        for each in std::mem::take(&mut buckets[node_parent]) {
+            let evaluated = eval(each, &mut ancestor, &mut label, &semi);
+            dominators[each] = if semi[evaluated] < semi[each] {
+                evaluated
+            } else {
+                node_parent
+            };
+        }
+    }
+
+    for node in vertex.iter().skip(1) {
+        if dominators[*node] != vertex[semi[*node]] {
+            dominators[*node] = dominators[dominators[*node]];
+        }
+    }
+    dominators[0] = 0;
+
+    (vertex, dominators)
+}
+
+fn eval(node: usize, ancestor: &mut [usize], label: &mut [usize], semi: &[usize]) -> usize {
+    if ancestor[node] == UNDEFINED {
+        return node;
+    }
+    compress(node, ancestor, label, semi);
+    label[node]
+}
+
+fn compress(node: usize, ancestor: &mut [usize], label: &mut [usize], semi: &[usize]) {
+    let mut path = vec![];
+    let mut current = node;
+    while ancestor[ancestor[current]] != UNDEFINED {
+        path.push(current);
+        current = ancestor[current];
+    }
+
+    while let Some(each) = path.pop() {
+        let each_ancestor = ancestor[each];
+        if semi[label[each_ancestor]] < semi[label[each]] {
+            label[each] = label[each_ancestor];
+        }
+        ancestor[each] = ancestor[each_ancestor];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dominators_of(successors: &[Vec<usize>]) -> Vec<usize> {
+        lengauer_tarjan(successors).1
+    }
+
+    #[test]
+    fn empty_graph() {
+        assert_eq!(lengauer_tarjan(&[]), (vec![], vec![]));
+    }
+
+    #[test]
+    fn root_dominates_itself() {
+        assert_eq!(lengauer_tarjan(&[vec![]]), (vec![0], vec![0]));
+    }
+
+    #[test]
+    fn chain() {
+        // 0 -> 1 -> 2 -> 3
+        let successors = vec![vec![1], vec![2], vec![3], vec![]];
+        assert_eq!(dominators_of(&successors), vec![0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn diamond() {
+        // 0 -> 1 -> 3, 0 -> 2 -> 3
+        let successors = vec![vec![1, 2], vec![3], vec![3], vec![]];
+        assert_eq!(dominators_of(&successors), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn cycle() {
+        // 0 -> 1 -> 2 -> 1, 2 -> 3
+        let successors = vec![vec![1], vec![2], vec![1, 3], vec![]];
+        assert_eq!(dominators_of(&successors), vec![0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn shortcut_to_a_deeper_node() {
+        // 0 -> 1 -> 2 -> 3 -> 4, 1 -> 4
+        let successors = vec![vec![1], vec![2, 4], vec![3], vec![4], vec![]];
+        assert_eq!(dominators_of(&successors), vec![0, 0, 1, 2, 1]);
+    }
+
+    #[test]
+    fn semi_dominator_differs_from_dominator() {
+        // the classic example: 0 -> 1, 0 -> 2, 1 -> 3, 2 -> 3, 3 -> 4, 4 -> 1
+        let successors = vec![vec![1, 2], vec![3], vec![3], vec![4], vec![1]];
+        assert_eq!(dominators_of(&successors), vec![0, 0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn unreachable_nodes_are_not_ordered() {
+        // 2 is not reachable from the root
+        let successors = vec![vec![1], vec![], vec![1]];
+        let (order, dominators) = lengauer_tarjan(&successors);
+        assert_eq!(order, vec![0, 1]);
+        assert_eq!(dominators[2], UNDEFINED);
+    }
+
+    #[test]
+    fn depth_first_pre_order() {
+        let successors = vec![vec![1, 3], vec![2], vec![], vec![]];
+        assert_eq!(lengauer_tarjan(&successors).0, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn retained_sizes_of_a_diamond() {
+        // 0 -> 1 -> 3, 0 -> 2 -> 3, only the root retains 3
+        let successors = vec![vec![1, 2], vec![3], vec![3], vec![]];
+        let (order, dominators) = lengauer_tarjan(&successors);
+        let sizes = retained_sizes(&[1, 10, 100, 1000], &order, &dominators);
+        assert_eq!(sizes, vec![1111, 10, 100, 1000]);
+    }
+
+    #[test]
+    fn retained_sizes_of_a_chain() {
+        let successors = vec![vec![1], vec![2], vec![]];
+        let (order, dominators) = lengauer_tarjan(&successors);
+        let sizes = retained_sizes(&[1, 10, 100], &order, &dominators);
+        assert_eq!(sizes, vec![111, 110, 100]);
+    }
+}
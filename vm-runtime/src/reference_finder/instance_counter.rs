@@ -1,7 +1,7 @@
 use crate::objects::{Array, Association};
 use crate::reference_finder::{
-    visit_unique_objects, visitor_next_objects, ObjectVisitor, ReferencedObject, VisitorAction,
-    VisitorState,
+    visit_unique_objects, visitor_next_objects, ObjectVisitor, ReferenceKinds, ReferencedObject,
+    VisitorAction, VisitorState,
 };
 use std::collections::HashMap;
 use vm_bindings::{ObjectPointer, Smalltalk, StackOffset};
@@ -44,8 +44,11 @@ impl InstanceCounter {
 }
 
 impl ObjectVisitor for InstanceCounter {
-    fn next_objects(object: ReferencedObject) -> impl Iterator<Item = ReferencedObject> {
-        visitor_next_objects(object).filter(|each| !each.object().is_immediate())
+    fn next_objects(
+        object: ReferencedObject,
+        kinds: ReferenceKinds,
+    ) -> impl Iterator<Item = ReferencedObject> {
+        visitor_next_objects(object, kinds).filter(|each| !each.object().is_immediate())
     }
 
     fn visit_referenced_object(
@@ -1,16 +1,22 @@
+mod dominator_tree;
 mod instance_counter;
 mod object_iterator;
 mod object_visitor;
 mod reference_finder;
+mod referrer_finder;
+mod subgraph_exporter;
 
 use crate::objects::{Array, ArrayRef};
 use anyhow::anyhow;
+pub use dominator_tree::*;
 pub use instance_counter::*;
 pub use object_iterator::*;
 pub use object_visitor::*;
 pub use reference_finder::*;
+pub use referrer_finder::*;
+pub use subgraph_exporter::*;
 use vm_bindings::{ObjectPointer, Smalltalk};
-use vm_object_model::Immediate;
+use vm_object_model::{Immediate, ObjectRef};
 
 fn method_return_paths(
     paths: Vec<Vec<ReferencedObject>>,
@@ -27,7 +33,7 @@ fn method_return_path(path: Vec<ReferencedObject>, classes: ArrayRef) -> Result<
     Ok(())
 }
 
-fn convert_referenced_object_paths(
+pub(crate) fn convert_referenced_object_paths(
     paths: Vec<Vec<ReferencedObject>>,
     classes: ArrayRef,
 ) -> Result<ArrayRef, anyhow::Error> {
@@ -44,39 +50,100 @@ fn convert_referenced_object_path(
     path: Vec<ReferencedObject>,
     classes: ArrayRef,
 ) -> Result<ArrayRef, anyhow::Error> {
-    let root_class = classes
-        .get(0)
-        .ok_or_else(|| anyhow!("Root class is not defined"))?
-        .as_object()?;
-    let instance_variable_class = classes
-        .get(1)
-        .ok_or_else(|| anyhow!("Instance variable class is not defined"))?
-        .as_object()?;
-    let context_variable_class = classes
-        .get(2)
-        .ok_or_else(|| anyhow!("Context variable class is not defined"))?
-        .as_object()?;
-    let array_item_class = classes
-        .get(3)
-        .ok_or_else(|| anyhow!("Array item class is not defined"))?
-        .as_object()?;
+    let classes = ReferencedObjectClasses::new(classes)?;
 
     let mut array = Array::new(path.len())?;
     for (index, each) in path.into_iter().enumerate() {
+        array.insert(index, classes.convert(each)?);
+    }
+
+    Ok(array)
+}
+
+/// Classes used to represent [`ReferencedObject`] variants in the image.
+/// Classes for weak slots and ephemeron keys and values are optional,
+/// when missing those references are represented as array items or instance variables.
+struct ReferencedObjectClasses {
+    root: ObjectRef,
+    instance_variable: ObjectRef,
+    context_variable: ObjectRef,
+    array_item: ObjectRef,
+    weak_slot: Option<ObjectRef>,
+    ephemeron_key: Option<ObjectRef>,
+    ephemeron_value: Option<ObjectRef>,
+}
+
+impl ReferencedObjectClasses {
+    fn new(classes: ArrayRef) -> Result<Self, anyhow::Error> {
+        let root = classes
+            .get(0)
+            .ok_or_else(|| anyhow!("Root class is not defined"))?
+            .as_object()?;
+        let instance_variable = classes
+            .get(1)
+            .ok_or_else(|| anyhow!("Instance variable class is not defined"))?
+            .as_object()?;
+        let context_variable = classes
+            .get(2)
+            .ok_or_else(|| anyhow!("Context variable class is not defined"))?
+            .as_object()?;
+        let array_item = classes
+            .get(3)
+            .ok_or_else(|| anyhow!("Array item class is not defined"))?
+            .as_object()?;
+        let weak_slot = Self::optional_class(classes, 4)?;
+        let ephemeron_key = Self::optional_class(classes, 5)?;
+        let ephemeron_value = Self::optional_class(classes, 6)?;
+
+        Ok(Self {
+            root,
+            instance_variable,
+            context_variable,
+            array_item,
+            weak_slot,
+            ephemeron_key,
+            ephemeron_value,
+        })
+    }
+
+    fn optional_class(classes: ArrayRef, index: usize) -> Result<Option<ObjectRef>, anyhow::Error> {
+        match classes.get(index) {
+            None => Ok(None),
+            Some(class) if class == Smalltalk::nil_object() => Ok(None),
+            Some(class) => Ok(Some(class.as_object()?)),
+        }
+    }
+
+    fn instantiate_with_index(class: ObjectRef, index: usize) -> Result<ObjectRef, anyhow::Error> {
+        let mut referenced_object = Smalltalk::instantiate_class(class).as_object()?;
+        referenced_object.inst_var_at_put(1, Immediate::new_u64(index as u64));
+        Ok(referenced_object)
+    }
+
+    fn convert(&self, each: ReferencedObject) -> Result<ObjectRef, anyhow::Error> {
         let mut inst = match each {
             ReferencedObject::InstanceVariable(_, index) => {
-                let mut referenced_object =
-                    Smalltalk::instantiate_class(instance_variable_class).as_object()?;
-                referenced_object.inst_var_at_put(1, Immediate::new_u64(index as u64));
-                referenced_object
+                Self::instantiate_with_index(self.instance_variable, index)?
             }
             ReferencedObject::ContextVariable(_) => {
-                Smalltalk::instantiate_class(context_variable_class).as_object()?
+                Smalltalk::instantiate_class(self.context_variable).as_object()?
             }
             ReferencedObject::ArrayItem(_) => {
-                Smalltalk::instantiate_class(array_item_class).as_object()?
+                Smalltalk::instantiate_class(self.array_item).as_object()?
             }
-            ReferencedObject::Root(_) => Smalltalk::instantiate_class(root_class).as_object()?,
+            ReferencedObject::WeakSlot(_, index) => match self.weak_slot {
+                Some(class) => Self::instantiate_with_index(class, index)?,
+                None => Smalltalk::instantiate_class(self.array_item).as_object()?,
+            },
+            ReferencedObject::EphemeronKey(_) => match self.ephemeron_key {
+                Some(class) => Smalltalk::instantiate_class(class).as_object()?,
+                None => Self::instantiate_with_index(self.instance_variable, 0)?,
+            },
+            ReferencedObject::EphemeronValue(_) => match self.ephemeron_value {
+                Some(class) => Smalltalk::instantiate_class(class).as_object()?,
+                None => Self::instantiate_with_index(self.instance_variable, 1)?,
+            },
+            ReferencedObject::Root(_) => Smalltalk::instantiate_class(self.root).as_object()?,
         };
 
         Smalltalk::prepare_to_store(
@@ -85,8 +152,6 @@ fn convert_referenced_object_path(
         );
         inst.inst_var_at_put(0, each.object());
 
-        array.insert(index, inst);
+        Ok(inst)
     }
-
-    Ok(array)
 }
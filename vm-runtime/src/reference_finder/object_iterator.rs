@@ -21,6 +21,9 @@ pub struct ObjectIterator {
     pub amount_of_indexable_fields: usize,
     pub index: usize,
     pub is_context: bool,
+    pub is_weak: bool,
+    pub is_ephemeron: bool,
+    pub kinds: ReferenceKinds,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -28,22 +31,150 @@ pub enum ReferencedObject {
     InstanceVariable(AnyObjectRef, usize),
     ContextVariable(AnyObjectRef),
     ArrayItem(AnyObjectRef),
+    /// an indexed field of a weak object, the index is zero-based and
+    /// does not include fixed fields
+    WeakSlot(AnyObjectRef, usize),
+    /// the key of an ephemeron (its first instance variable)
+    EphemeronKey(AnyObjectRef),
+    /// the value of an ephemeron (its second instance variable),
+    /// it is only retained as long as the key is reachable
+    EphemeronValue(AnyObjectRef),
     Root(AnyObjectRef),
 }
 
+/// The kind of edge between a referrer and a [`ReferencedObject`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(u8)]
+pub enum ReferenceKind {
+    InstanceVariable = 0,
+    ArrayItem = 1,
+    ContextVariable = 2,
+    WeakSlot = 3,
+    EphemeronKey = 4,
+    EphemeronValue = 5,
+}
+
+/// A set of [`ReferenceKind`] that an [`ObjectIterator`] should follow.
+/// By default weak slots and ephemeron keys are not followed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ReferenceKinds(u8);
+
 impl ReferencedObject {
     pub fn object(&self) -> AnyObjectRef {
         match *self {
             ReferencedObject::InstanceVariable(object, _) => object,
             ReferencedObject::ContextVariable(object) => object,
             ReferencedObject::ArrayItem(object) => object,
+            ReferencedObject::WeakSlot(object, _) => object,
+            ReferencedObject::EphemeronKey(object) => object,
+            ReferencedObject::EphemeronValue(object) => object,
             ReferencedObject::Root(object) => object,
         }
     }
+
+    /// Return the kind of the edge leading to this object, roots have none
+    pub fn kind(&self) -> Option<ReferenceKind> {
+        match self {
+            ReferencedObject::InstanceVariable(_, _) => Some(ReferenceKind::InstanceVariable),
+            ReferencedObject::ContextVariable(_) => Some(ReferenceKind::ContextVariable),
+            ReferencedObject::ArrayItem(_) => Some(ReferenceKind::ArrayItem),
+            ReferencedObject::WeakSlot(_, _) => Some(ReferenceKind::WeakSlot),
+            ReferencedObject::EphemeronKey(_) => Some(ReferenceKind::EphemeronKey),
+            ReferencedObject::EphemeronValue(_) => Some(ReferenceKind::EphemeronValue),
+            ReferencedObject::Root(_) => None,
+        }
+    }
+
+    /// Return true if the referrer keeps this object alive unconditionally
+    pub fn is_strong(&self) -> bool {
+        self.kind().map_or(true, |kind| kind.is_strong())
+    }
+}
+
+impl ReferenceKind {
+    pub fn is_strong(&self) -> bool {
+        match self {
+            ReferenceKind::InstanceVariable
+            | ReferenceKind::ArrayItem
+            | ReferenceKind::ContextVariable => true,
+            ReferenceKind::WeakSlot
+            | ReferenceKind::EphemeronKey
+            | ReferenceKind::EphemeronValue => false,
+        }
+    }
+}
+
+impl ReferenceKinds {
+    pub fn none() -> Self {
+        Self(0)
+    }
+
+    pub fn all() -> Self {
+        Self::none()
+            .with(ReferenceKind::InstanceVariable)
+            .with(ReferenceKind::ArrayItem)
+            .with(ReferenceKind::ContextVariable)
+            .with(ReferenceKind::WeakSlot)
+            .with(ReferenceKind::EphemeronKey)
+            .with(ReferenceKind::EphemeronValue)
+    }
+
+    /// Only the references that keep objects alive unconditionally
+    pub fn strong() -> Self {
+        Self::none()
+            .with(ReferenceKind::InstanceVariable)
+            .with(ReferenceKind::ArrayItem)
+            .with(ReferenceKind::ContextVariable)
+    }
+
+    /// Create from a bit mask where each bit corresponds to a [`ReferenceKind`],
+    /// unknown bits are ignored
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits & Self::all().0)
+    }
+
+    /// Create from a bit mask given as an integer, None if it has bits of unknown kinds
+    pub fn try_from_bits(bits: i64) -> Option<Self> {
+        u8::try_from(bits)
+            .ok()
+            .filter(|bits| bits & !Self::all().0 == 0)
+            .map(Self)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Keep only the kinds that retain objects unconditionally
+    pub fn strong_only(self) -> Self {
+        Self(self.0 & Self::strong().0)
+    }
+
+    pub fn with(self, kind: ReferenceKind) -> Self {
+        Self(self.0 | (1 << kind as u8))
+    }
+
+    pub fn without(self, kind: ReferenceKind) -> Self {
+        Self(self.0 & !(1 << kind as u8))
+    }
+
+    pub fn contains(&self, kind: ReferenceKind) -> bool {
+        self.0 & (1 << kind as u8) != 0
+    }
+}
+
+impl Default for ReferenceKinds {
+    fn default() -> Self {
+        Self::strong().with(ReferenceKind::EphemeronValue)
+    }
 }
 
 impl ObjectIterator {
     pub fn new(oop: AnyObjectRef) -> Self {
+        Self::with_kinds(oop, ReferenceKinds::default())
+    }
+
+    pub fn with_kinds(oop: AnyObjectRef, kinds: ReferenceKinds) -> Self {
         if let Ok(object) = oop.as_object() {
             let amount_of_units = object.amount_of_indexable_units();
             let amount_of_fixed_fields = amount_of_fixed_fields(object, amount_of_units);
@@ -55,19 +186,24 @@ impl ObjectIterator {
                     amount_of_indexable_fields: Smalltalk::context_size(object),
                     index: 0,
                     is_context: true,
+                    is_weak: false,
+                    is_ephemeron: false,
+                    kinds,
                 };
             }
 
             let amount_of_indexable_fields = amount_of_indexable_fields(object, amount_of_units);
-            let is_ephemeron = object.header().format().is_ephemeron();
-            let start_index = if is_ephemeron { 1 } else { 0 };
+            let format = object.header().format();
 
             ObjectIterator {
                 object: oop,
                 amount_of_fixed_fields,
                 amount_of_indexable_fields,
-                index: start_index,
+                index: 0,
                 is_context: false,
+                is_weak: format.is_weak(),
+                is_ephemeron: format.is_ephemeron(),
+                kinds,
             }
         } else {
             ObjectIterator {
@@ -76,32 +212,63 @@ impl ObjectIterator {
                 amount_of_indexable_fields: 0,
                 index: 0,
                 is_context: false,
+                is_weak: false,
+                is_ephemeron: false,
+                kinds,
             }
         }
     }
+
+    fn fixed_field_kind(&self, index: usize) -> ReferenceKind {
+        if self.is_context {
+            return ReferenceKind::ContextVariable;
+        }
+        match (self.is_ephemeron, index) {
+            (true, 0) => ReferenceKind::EphemeronKey,
+            (true, 1) => ReferenceKind::EphemeronValue,
+            _ => ReferenceKind::InstanceVariable,
+        }
+    }
+
+    fn indexable_field_kind(&self) -> ReferenceKind {
+        if self.is_context {
+            ReferenceKind::ContextVariable
+        } else if self.is_weak {
+            ReferenceKind::WeakSlot
+        } else {
+            ReferenceKind::ArrayItem
+        }
+    }
 }
 
 impl Iterator for ObjectIterator {
     type Item = ReferencedObject;
     fn next(&mut self) -> Option<Self::Item> {
         if let Ok(object) = self.object.as_object() {
-            loop {
-                if self.index >= self.amount_of_fixed_fields {
-                    break;
-                }
-                let next = if self.is_context {
-                    ReferencedObject::ContextVariable(Smalltalk::context_inst_var_at(
-                        object, self.index,
-                    ))
-                } else {
-                    ReferencedObject::InstanceVariable(
-                        object.inst_var_at(self.index).unwrap(),
-                        self.index,
-                    )
-                };
+            while self.index < self.amount_of_fixed_fields {
+                let index = self.index;
                 self.index += 1;
 
-                return Some(next);
+                let kind = self.fixed_field_kind(index);
+                if !self.kinds.contains(kind) {
+                    continue;
+                }
+
+                return Some(match kind {
+                    ReferenceKind::ContextVariable => ReferencedObject::ContextVariable(
+                        Smalltalk::context_inst_var_at(object, index),
+                    ),
+                    ReferenceKind::EphemeronKey => {
+                        ReferencedObject::EphemeronKey(object.inst_var_at(index).unwrap())
+                    }
+                    ReferenceKind::EphemeronValue => {
+                        ReferencedObject::EphemeronValue(object.inst_var_at(index).unwrap())
+                    }
+                    _ => ReferencedObject::InstanceVariable(
+                        object.inst_var_at(index).unwrap(),
+                        index,
+                    ),
+                });
             }
 
             // an indexed object that can't have references to other objects is not interesting
@@ -109,28 +276,31 @@ impl Iterator for ObjectIterator {
                 return None;
             }
 
-            // We ignore indexed fields weak objects
-            if object.header().format().is_weak() {
+            let kind = self.indexable_field_kind();
+            if !self.kinds.contains(kind) {
                 return None;
             }
 
             let total_amount = self.amount_of_fixed_fields + self.amount_of_indexable_fields;
 
-            loop {
-                if self.index >= total_amount {
-                    break;
-                }
-
+            if self.index < total_amount {
                 let index = self.index - self.amount_of_fixed_fields;
-                let next = if self.is_context {
-                    ReferencedObject::ContextVariable(Smalltalk::context_at(object, index + 1))
-                } else {
-                    let var = Smalltalk::item_at(
-                        ObjectPointer::from(self.object.as_ptr()),
-                        (index + 1).into(),
-                    );
-                    let var_ptr = RawObjectPointer::from(var.as_i64());
-                    ReferencedObject::ArrayItem(AnyObjectRef::from(var_ptr))
+                let next = match kind {
+                    ReferenceKind::ContextVariable => {
+                        ReferencedObject::ContextVariable(Smalltalk::context_at(object, index + 1))
+                    }
+                    _ => {
+                        let var = Smalltalk::item_at(
+                            ObjectPointer::from(self.object.as_ptr()),
+                            (index + 1).into(),
+                        );
+                        let var_ptr = AnyObjectRef::from(RawObjectPointer::from(var.as_i64()));
+                        if kind == ReferenceKind::WeakSlot {
+                            ReferencedObject::WeakSlot(var_ptr, index)
+                        } else {
+                            ReferencedObject::ArrayItem(var_ptr)
+                        }
+                    }
                 };
 
                 self.index += 1;
@@ -1,9 +1,9 @@
-use crate::reference_finder::GraphNode;
-use crate::reference_finder::ReferencedObject;
+use crate::reference_finder::{ObjectIterator, ReferenceKinds, ReferencedObject};
 use std::collections::HashSet;
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use vm_object_model::AnyObjectRef;
 
 #[inline]
@@ -19,14 +19,26 @@ where
 }
 
 #[inline]
-pub fn visitor_next_objects(object: ReferencedObject) -> impl Iterator<Item = ReferencedObject> {
-    object.object().neighbors()
+pub fn visitor_next_objects(
+    object: ReferencedObject,
+    kinds: ReferenceKinds,
+) -> impl Iterator<Item = ReferencedObject> {
+    ObjectIterator::with_kinds(object.object(), kinds)
 }
 
 pub trait ObjectVisitor {
+    /// Kinds of references that should be followed when visiting the object graph
     #[inline]
-    fn next_objects(object: ReferencedObject) -> impl Iterator<Item = ReferencedObject> {
-        visitor_next_objects(object)
+    fn reference_kinds(&self) -> ReferenceKinds {
+        ReferenceKinds::default()
+    }
+
+    #[inline]
+    fn next_objects(
+        object: ReferencedObject,
+        kinds: ReferenceKinds,
+    ) -> impl Iterator<Item = ReferencedObject> {
+        visitor_next_objects(object, kinds)
     }
 
     #[inline]
@@ -55,22 +67,60 @@ pub enum VisitorAction {
 }
 
 pub fn visit_objects<T: ObjectVisitor>(start: AnyObjectRef, visitor: &mut T) {
+    visit_objects_limited(start, visitor, &VisitorLimits::default());
+}
+
+/// Visit objects in breadth-first order until the visitor stops or one of the limits is reached.
+/// Unlike [`visit_unique_objects`], referenced objects are passed to the visitor
+/// even if they were already visited.
+pub fn visit_objects_limited<T: ObjectVisitor>(
+    start: AnyObjectRef,
+    visitor: &mut T,
+    limits: &VisitorLimits,
+) -> VisitorReport {
+    visit_objects_with(start, visitor, limits, false)
+}
+
+pub fn visit_unique_objects<T: ObjectVisitor>(start: AnyObjectRef, visitor: &mut T) {
+    visit_unique_objects_limited(start, visitor, &VisitorLimits::default());
+}
+
+/// Visit objects in breadth-first order until the visitor stops or one of the limits is reached.
+/// Every referenced object is passed to the visitor only once.
+pub fn visit_unique_objects_limited<T: ObjectVisitor>(
+    start: AnyObjectRef,
+    visitor: &mut T,
+    limits: &VisitorLimits,
+) -> VisitorReport {
+    visit_objects_with(start, visitor, limits, true)
+}
+
+fn visit_objects_with<T: ObjectVisitor>(
+    start: AnyObjectRef,
+    visitor: &mut T,
+    limits: &VisitorLimits,
+    only_unique: bool,
+) -> VisitorReport {
+    let mut report = VisitorReport::default();
     let mut visited: HashSet<AnyObjectRef> = HashSet::new();
+    let mut watch = limits.watch();
+    let kinds = visitor.reference_kinds();
 
     let start = ReferencedObject::Root(start);
 
     let root = VisitorState {
         node: start,
         parent: None,
+        depth: 0,
     };
 
     match visitor.visit_referenced_object(start, &root) {
         VisitorAction::Continue => {}
         VisitorAction::Skip => {
-            return;
+            return report;
         }
         VisitorAction::Stop => {
-            return;
+            return report;
         }
     }
 
@@ -83,30 +133,53 @@ pub fn visit_objects<T: ObjectVisitor>(start: AnyObjectRef, visitor: &mut T) {
         buffer.clear();
         let objects = std::mem::replace(&mut objects_left, buffer);
         for state in &objects {
+            report.amount_of_visited_objects += 1;
+            if let Some(truncation) = watch.check(report.amount_of_visited_objects) {
+                report.truncation = Some(truncation);
+                return report;
+            }
+
             match visitor.visit_object(state.node, state) {
                 VisitorAction::Continue => {}
                 VisitorAction::Skip => {
                     continue;
                 }
                 VisitorAction::Stop => {
-                    return;
+                    return report;
                 }
             }
-            for neighbor in T::next_objects(state.node) {
+
+            if limits.is_path_length_reached(state.depth) {
+                if T::next_objects(state.node, kinds).next().is_some() {
+                    report
+                        .truncation
+                        .get_or_insert(VisitorTruncation::PathLength);
+                }
+                continue;
+            }
+
+            for neighbor in T::next_objects(state.node, kinds) {
+                if only_unique && visited.contains(&neighbor.object()) {
+                    continue;
+                }
                 match visitor.visit_referenced_object(neighbor, state) {
                     VisitorAction::Continue => {
                         if visited.insert(neighbor.object()) {
                             objects_left.push(Rc::new(VisitorState {
                                 node: neighbor,
                                 parent: Some(state.clone()),
+                                depth: state.depth + 1,
                             }));
                         }
                     }
                     VisitorAction::Skip => {
+                        if only_unique {
+                            visited.insert(neighbor.object());
+                        }
                         continue;
                     }
                     VisitorAction::Stop => {
-                        return;
+                        return report;
                     }
                 }
             }
@@ -116,69 +189,137 @@ pub fn visit_objects<T: ObjectVisitor>(start: AnyObjectRef, visitor: &mut T) {
             break;
         }
     }
+
+    report
 }
 
-pub fn visit_unique_objects<T: ObjectVisitor>(start: AnyObjectRef, visitor: &mut T) {
-    let mut visited: HashSet<AnyObjectRef> = HashSet::new();
+/// Limits of an object graph search.
+/// A search runs to completion on the virtual machine thread, the limits are the only way to bound it.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VisitorLimits {
+    max_path_length: Option<usize>,
+    max_visited_objects: Option<usize>,
+    timeout: Option<Duration>,
+}
 
-    let start = ReferencedObject::Root(start);
+impl VisitorLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    let root = VisitorState {
-        node: start,
-        parent: None,
-    };
+    /// Do not follow references from objects whose path from the start already has the given amount of objects
+    pub fn with_max_path_length(mut self, max_path_length: usize) -> Self {
+        self.max_path_length = Some(max_path_length);
+        self
+    }
 
-    match visitor.visit_referenced_object(start, &root) {
-        VisitorAction::Continue => {}
-        VisitorAction::Skip => {
-            return;
+    pub fn with_max_visited_objects(mut self, max_visited_objects: usize) -> Self {
+        self.max_visited_objects = Some(max_visited_objects);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Return the limits left for another search over the same graph after a search that produced
+    /// the given report and took the given time, or None if the budget is spent.
+    /// The path length is not shared between searches and stays the same.
+    pub fn remaining_after(&self, report: &VisitorReport, elapsed: Duration) -> Option<Self> {
+        if matches!(
+            report.truncation,
+            Some(VisitorTruncation::VisitedObjects | VisitorTruncation::Timeout)
+        ) {
+            return None;
         }
-        VisitorAction::Stop => {
-            return;
+
+        let max_visited_objects = match self.max_visited_objects {
+            Some(max) => Some(
+                max.checked_sub(report.amount_of_visited_objects)
+                    .filter(|left| *left > 0)?,
+            ),
+            None => None,
+        };
+        let timeout = match self.timeout {
+            Some(timeout) => Some(
+                timeout
+                    .checked_sub(elapsed)
+                    .filter(|left| !left.is_zero())?,
+            ),
+            None => None,
+        };
+
+        Some(Self {
+            max_path_length: self.max_path_length,
+            max_visited_objects,
+            timeout,
+        })
+    }
+
+    fn is_path_length_reached(&self, depth: usize) -> bool {
+        self.max_path_length
+            .is_some_and(|max_path_length| depth + 1 >= max_path_length)
+    }
+
+    fn watch(&self) -> LimitsWatch<'_> {
+        LimitsWatch {
+            limits: self,
+            started_at: Instant::now(),
         }
     }
+}
 
-    let mut buffer = vec![];
-    let mut objects_left = vec![];
-    objects_left.push(Rc::new(root));
-    visited.insert(start.object());
+struct LimitsWatch<'a> {
+    limits: &'a VisitorLimits,
+    started_at: Instant,
+}
 
-    loop {
-        buffer.clear();
-        let objects = std::mem::replace(&mut objects_left, buffer);
-        for state in &objects {
-            match visitor.visit_object(state.node, state) {
-                VisitorAction::Continue => {}
-                VisitorAction::Skip => {
-                    continue;
-                }
-                VisitorAction::Stop => {
-                    return;
-                }
-            }
-            for neighbor in T::next_objects(state.node) {
-                if visited.insert(neighbor.object()) {
-                    match visitor.visit_referenced_object(neighbor, state) {
-                        VisitorAction::Continue => {
-                            objects_left.push(Rc::new(VisitorState {
-                                node: neighbor,
-                                parent: Some(state.clone()),
-                            }));
-                        }
-                        VisitorAction::Skip => {
-                            continue;
-                        }
-                        VisitorAction::Stop => {
-                            return;
-                        }
-                    }
-                }
-            }
+impl LimitsWatch<'_> {
+    /// Reading the clock for every object would slow the search down
+    const CHECK_INTERVAL: usize = 1024;
+
+    fn check(&mut self, amount_of_visited_objects: usize) -> Option<VisitorTruncation> {
+        if self
+            .limits
+            .max_visited_objects
+            .is_some_and(|max| amount_of_visited_objects > max)
+        {
+            return Some(VisitorTruncation::VisitedObjects);
         }
-        buffer = objects;
-        if objects_left.is_empty() {
-            break;
+
+        if amount_of_visited_objects % Self::CHECK_INTERVAL != 0 {
+            return None;
         }
+
+        if let Some(timeout) = self.limits.timeout {
+            if self.started_at.elapsed() >= timeout {
+                return Some(VisitorTruncation::Timeout);
+            }
+        }
+
+        None
+    }
+}
+
+/// Why a search did not visit all reachable objects
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum VisitorTruncation {
+    PathLength,
+    VisitedObjects,
+    Timeout,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VisitorReport {
+    pub amount_of_visited_objects: usize,
+    pub truncation: Option<VisitorTruncation>,
+}
+
+impl VisitorReport {
+    pub fn is_truncated(&self) -> bool {
+        self.truncation.is_some()
     }
 }
 
@@ -186,9 +327,19 @@ pub fn visit_unique_objects<T: ObjectVisitor>(start: AnyObjectRef, visitor: &mut
 pub struct VisitorState {
     node: ReferencedObject,
     parent: Option<Rc<VisitorState>>,
+    depth: usize,
 }
 
 impl VisitorState {
+    pub fn node(&self) -> ReferencedObject {
+        self.node
+    }
+
+    /// Return the distance from the start object, the start object has a depth of 0
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
     pub fn path(&self) -> Vec<ReferencedObject> {
         reconstruct_path(self, vec![])
     }
@@ -237,3 +388,91 @@ fn reconstruct_path_limited(
     path.reverse();
     path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(
+        amount_of_visited_objects: usize,
+        truncation: Option<VisitorTruncation>,
+    ) -> VisitorReport {
+        VisitorReport {
+            amount_of_visited_objects,
+            truncation,
+        }
+    }
+
+    #[test]
+    fn unlimited_budget_remains_unlimited() {
+        let limits = VisitorLimits::new();
+        assert_eq!(
+            limits.remaining_after(&report(1000, None), Duration::from_secs(10)),
+            Some(VisitorLimits::new())
+        );
+    }
+
+    #[test]
+    fn remaining_budget_is_reduced_by_the_previous_search() {
+        let limits = VisitorLimits::new()
+            .with_max_path_length(5)
+            .with_max_visited_objects(100)
+            .with_timeout(Duration::from_millis(50));
+        assert_eq!(
+            limits.remaining_after(&report(40, None), Duration::from_millis(20)),
+            Some(
+                VisitorLimits::new()
+                    .with_max_path_length(5)
+                    .with_max_visited_objects(60)
+                    .with_timeout(Duration::from_millis(30))
+            )
+        );
+    }
+
+    #[test]
+    fn path_length_truncation_leaves_budget() {
+        let limits = VisitorLimits::new()
+            .with_max_path_length(2)
+            .with_max_visited_objects(100);
+        assert_eq!(
+            limits.remaining_after(
+                &report(10, Some(VisitorTruncation::PathLength)),
+                Duration::ZERO
+            ),
+            Some(
+                VisitorLimits::new()
+                    .with_max_path_length(2)
+                    .with_max_visited_objects(90)
+            )
+        );
+    }
+
+    #[test]
+    fn spent_budget_leaves_nothing() {
+        let limits = VisitorLimits::new()
+            .with_max_visited_objects(100)
+            .with_timeout(Duration::from_millis(50));
+        assert_eq!(
+            limits.remaining_after(
+                &report(101, Some(VisitorTruncation::VisitedObjects)),
+                Duration::ZERO
+            ),
+            None
+        );
+        assert_eq!(
+            limits.remaining_after(
+                &report(1024, Some(VisitorTruncation::Timeout)),
+                Duration::from_millis(60)
+            ),
+            None
+        );
+        assert_eq!(
+            limits.remaining_after(&report(100, None), Duration::ZERO),
+            None
+        );
+        assert_eq!(
+            limits.remaining_after(&report(10, None), Duration::from_millis(50)),
+            None
+        );
+    }
+}
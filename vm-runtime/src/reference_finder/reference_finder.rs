@@ -1,16 +1,37 @@
 use std::collections::HashSet;
-use vm_object_model::{AnyObjectRef, ObjectRef};
+use std::time::{Duration, Instant};
+use vm_object_model::{AnyObjectRef, Immediate, Object, ObjectRef};
 
 use crate::objects::ArrayRef;
 use crate::reference_finder::object_iterator::GraphNode;
 use crate::reference_finder::{
-    method_return_path, method_return_paths, visit_objects, visitor_next_objects, ObjectVisitor,
-    ReferencedObject, VisitorAction, VisitorState,
+    convert_referenced_object_paths, method_return_path, method_return_paths,
+    visit_objects_limited, visitor_next_objects, ObjectVisitor, ReferenceKind, ReferenceKinds,
+    ReferencedObject, VisitorAction, VisitorLimits, VisitorReport, VisitorState,
 };
 use std::fmt::Debug;
 use std::hash::Hash;
 use vm_bindings::{Smalltalk, StackOffset};
 
+/// Limits of a reference search, each of the fields is either nil or a non-negative integer
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct ReferenceSearchLimitsObject {
+    this: Object,
+    max_path_length: AnyObjectRef,
+    max_visited_objects: AnyObjectRef,
+    max_milliseconds: AnyObjectRef,
+}
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct ReferenceSearchResultObject {
+    this: Object,
+    paths: ArrayRef,
+    is_truncated: ObjectRef,
+    amount_of_visited_objects: Immediate,
+}
+
 #[allow(non_snake_case)]
 pub extern "C" fn primitiveReferenceFinderFindAllPaths() -> Result<(), anyhow::Error> {
     let classes = ArrayRef::try_from(Smalltalk::stack_ref(StackOffset::new(0)))?;
@@ -38,11 +59,140 @@ pub fn primitiveReferenceFinderFindPath() -> Result<(), anyhow::Error> {
         .with_all_paths(false)
         .with_ignored_classes(classes_to_ignore?)
         .find(start_obj)
-        .pop()
+        .into_iter()
+        .next()
         .unwrap_or(vec![]);
     method_return_path(path, classes)
 }
 
+/// Find all paths following only the given kinds of references.
+/// The kinds are passed as a bit mask where each bit corresponds to a [`ReferenceKind`]:
+/// 1 - instance variable, 2 - array item, 4 - context variable,
+/// 8 - weak slot, 16 - ephemeron key, 32 - ephemeron value.
+/// Paths consisting only of strong references come first.
+#[allow(non_snake_case)]
+pub fn primitiveReferenceFinderFindAllPathsOfKinds() -> Result<(), anyhow::Error> {
+    let classes = ArrayRef::try_from(Smalltalk::stack_ref(StackOffset::new(0)))?;
+    let kinds = reference_kinds_value(Smalltalk::stack_ref(StackOffset::new(1)))?;
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(2));
+    let target_obj = Smalltalk::stack_ref(StackOffset::new(3));
+
+    let classes_to_ignore: Result<Vec<_>, _> =
+        classes.iter().map(|each| each.as_object()).collect();
+    let paths = ReferenceFinder::new(target_obj)
+        .with_all_paths(true)
+        .with_reference_kinds(kinds)
+        .with_ignored_classes(classes_to_ignore?)
+        .find(start_obj);
+    method_return_paths(paths, classes)
+}
+
+/// Find the best path following only the given kinds of references,
+/// see [`primitiveReferenceFinderFindAllPathsOfKinds`] for the meaning of the kinds.
+/// A strong path is preferred over a shorter one that goes through weak references.
+#[allow(non_snake_case)]
+pub fn primitiveReferenceFinderFindPathOfKinds() -> Result<(), anyhow::Error> {
+    let classes = ArrayRef::try_from(Smalltalk::stack_ref(StackOffset::new(0)))?;
+    let kinds = reference_kinds_value(Smalltalk::stack_ref(StackOffset::new(1)))?;
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(2));
+    let target_obj = Smalltalk::stack_ref(StackOffset::new(3));
+
+    let classes_to_ignore: Result<Vec<_>, _> =
+        classes.iter().map(|each| each.as_object()).collect();
+    let path = ReferenceFinder::new(target_obj)
+        .with_all_paths(false)
+        .with_reference_kinds(kinds)
+        .with_ignored_classes(classes_to_ignore?)
+        .find(start_obj)
+        .into_iter()
+        .next()
+        .unwrap_or(vec![]);
+    method_return_path(path, classes)
+}
+
+#[allow(non_snake_case)]
+pub fn primitiveReferenceFinderFindAllPathsLimited() -> Result<(), anyhow::Error> {
+    let result_class = Smalltalk::stack_ref(StackOffset::new(0)).as_object()?;
+    let limits =
+        ReferenceSearchLimitsObjectRef::try_from(Smalltalk::stack_ref(StackOffset::new(1)))?;
+    let classes = ArrayRef::try_from(Smalltalk::stack_ref(StackOffset::new(2)))?;
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(3));
+    let target_obj = Smalltalk::stack_ref(StackOffset::new(4));
+
+    let classes_to_ignore: Result<Vec<_>, _> =
+        classes.iter().map(|each| each.as_object()).collect();
+    let (paths, report) = ReferenceFinder::new(target_obj)
+        .with_all_paths(true)
+        .with_ignored_classes(classes_to_ignore?)
+        .with_limits(search_limits_from_object(limits)?)
+        .find_limited(start_obj);
+
+    method_return_search_result(paths, report, classes, result_class)
+}
+
+fn search_limits_from_object(
+    limits: ReferenceSearchLimitsObjectRef,
+) -> Result<VisitorLimits, anyhow::Error> {
+    let mut search_limits = VisitorLimits::new();
+    if let Some(max_path_length) = optional_integer(limits.max_path_length)? {
+        search_limits = search_limits.with_max_path_length(max_path_length);
+    }
+    if let Some(max_visited_objects) = optional_integer(limits.max_visited_objects)? {
+        search_limits = search_limits.with_max_visited_objects(max_visited_objects);
+    }
+    if let Some(max_milliseconds) = optional_integer(limits.max_milliseconds)? {
+        search_limits = search_limits.with_timeout(Duration::from_millis(max_milliseconds as u64));
+    }
+    Ok(search_limits)
+}
+
+fn optional_integer(object: AnyObjectRef) -> Result<Option<usize>, anyhow::Error> {
+    if object == Smalltalk::nil_object() {
+        return Ok(None);
+    }
+    let immediate = object.as_immediate()?;
+    let value = immediate
+        .as_integer()
+        .ok_or(vm_object_model::Error::NotAnInteger(immediate))?;
+    let value = usize::try_from(value).map_err(|_| {
+        vm_object_model::Error::InvalidType(format!("non-negative integer (got {})", value))
+    })?;
+    Ok(Some(value))
+}
+
+/// Read a bit mask of reference kinds, failing on negative values and on bits of unknown kinds
+fn reference_kinds_value(object: AnyObjectRef) -> Result<ReferenceKinds, anyhow::Error> {
+    let immediate = object.as_immediate()?;
+    let value = immediate
+        .as_integer()
+        .ok_or(vm_object_model::Error::NotAnInteger(immediate))?;
+    let kinds = ReferenceKinds::try_from_bits(value).ok_or_else(|| {
+        vm_object_model::Error::InvalidType(format!(
+            "reference kinds between 0 and {} (got {})",
+            ReferenceKinds::all().bits(),
+            value
+        ))
+    })?;
+    Ok(kinds)
+}
+
+fn method_return_search_result(
+    paths: Vec<Vec<ReferencedObject>>,
+    report: VisitorReport,
+    classes: ArrayRef,
+    result_class: ObjectRef,
+) -> Result<(), anyhow::Error> {
+    let paths = convert_referenced_object_paths(paths, classes)?;
+
+    let mut result = Smalltalk::instantiate::<ReferenceSearchResultObjectRef>(result_class)?;
+    result.set_paths(paths);
+    result.set_is_truncated(Smalltalk::bool_object(report.is_truncated()));
+    result.set_amount_of_visited_objects(report.amount_of_visited_objects);
+
+    Smalltalk::method_return(result);
+    Ok(())
+}
+
 #[allow(non_snake_case)]
 pub fn primitiveReferenceFinderGetNeighbours() -> Result<(), anyhow::Error> {
     let classes = ArrayRef::try_from(Smalltalk::stack_ref(StackOffset::new(0)))?;
@@ -61,6 +211,46 @@ pub fn primitiveClassInstanceReferenceFinderFindAllPaths() -> Result<(), anyhow:
     method_return_paths(paths, classes)
 }
 
+/// Find all paths to instances of a class following only the given kinds of references,
+/// see [`primitiveReferenceFinderFindAllPathsOfKinds`] for the meaning of the kinds.
+/// Paths consisting only of strong references come first.
+#[allow(non_snake_case)]
+pub fn primitiveClassInstanceReferenceFinderFindAllPathsOfKinds() -> Result<(), anyhow::Error> {
+    let target_class = Smalltalk::stack_ref(StackOffset::new(4)).as_object()?;
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(3));
+    let path_len = Smalltalk::stack_integer_value(StackOffset::new(2)) as usize;
+    let kinds = reference_kinds_value(Smalltalk::stack_ref(StackOffset::new(1)))?;
+    let classes = ArrayRef::try_from(Smalltalk::stack_ref(StackOffset::new(0)))?;
+
+    let paths = ClassInstanceReferenceFinder::find_all_paths_of_kinds(
+        start_obj,
+        target_class,
+        path_len,
+        kinds,
+    );
+    method_return_paths(paths, classes)
+}
+
+#[allow(non_snake_case)]
+pub fn primitiveClassInstanceReferenceFinderFindAllPathsLimited() -> Result<(), anyhow::Error> {
+    let result_class = Smalltalk::stack_ref(StackOffset::new(0)).as_object()?;
+    let limits =
+        ReferenceSearchLimitsObjectRef::try_from(Smalltalk::stack_ref(StackOffset::new(1)))?;
+    let classes = ArrayRef::try_from(Smalltalk::stack_ref(StackOffset::new(2)))?;
+    let path_len = Smalltalk::stack_integer_value(StackOffset::new(3)) as usize;
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(4));
+    let target_class = Smalltalk::stack_ref(StackOffset::new(5)).as_object()?;
+
+    let (paths, report) = ClassInstanceReferenceFinder::find_all_paths_limited(
+        start_obj,
+        target_class,
+        path_len,
+        &search_limits_from_object(limits)?,
+    );
+
+    method_return_search_result(paths, report, classes, result_class)
+}
+
 #[allow(non_snake_case)]
 pub fn primitiveClassInstanceReferenceFinderFindPath() -> Result<(), anyhow::Error> {
     let target_class = Smalltalk::stack_ref(StackOffset::new(2)).as_object()?;
@@ -75,9 +265,63 @@ pub struct ReferenceFinder {
     target: AnyObjectRef,
     find_all_paths: bool,
     classes_to_ignore: HashSet<ObjectRef>,
+    /// Kinds of references given by the caller, or None to follow the default ones
+    requested_kinds: Option<ReferenceKinds>,
+    /// Kinds of references followed by the current pass of the search
+    reference_kinds: ReferenceKinds,
+    limits: VisitorLimits,
     paths: Vec<Vec<ReferencedObject>>,
 }
 
+/// Return true if every object in the path is unconditionally retained by the previous one
+pub fn is_strong_path(path: &[ReferencedObject]) -> bool {
+    path.iter().all(|each| each.is_strong())
+}
+
+/// Order paths so that strong-only paths come first,
+/// followed by the paths with the least amount of weak references.
+/// The order of paths with the same strength is preserved.
+pub fn rank_paths_by_strength(paths: &mut [Vec<ReferencedObject>]) {
+    paths.sort_by_key(|path| path.iter().filter(|each| !each.is_strong()).count());
+}
+
+/// Visit the object graph following the requested kinds of references, strong ones first.
+///
+/// Every object is visited once per pass, so a weak reference discovered first would hide
+/// a strong path through the same object. Therefore strong references are followed in a first pass
+/// and the requested weak ones only in a second pass, when `needs_weak_pass` tells so.
+/// Both passes share the same limits, the second one gets what is left of them.
+/// Without requested kinds the default ones are followed in a single pass.
+fn visit_strong_first<T: ObjectVisitor>(
+    start: AnyObjectRef,
+    visitor: &mut T,
+    requested_kinds: Option<ReferenceKinds>,
+    limits: &VisitorLimits,
+    set_reference_kinds: fn(&mut T, ReferenceKinds),
+    needs_weak_pass: fn(&T) -> bool,
+) -> VisitorReport {
+    let Some(kinds) = requested_kinds else {
+        set_reference_kinds(visitor, ReferenceKinds::default());
+        return visit_objects_limited(start, visitor, limits);
+    };
+
+    let started_at = Instant::now();
+    set_reference_kinds(visitor, kinds.strong_only());
+    let mut report = visit_objects_limited(start, visitor, limits);
+    set_reference_kinds(visitor, kinds);
+
+    if kinds == kinds.strong_only() || !needs_weak_pass(visitor) {
+        return report;
+    }
+
+    if let Some(remaining_limits) = limits.remaining_after(&report, started_at.elapsed()) {
+        let weak_report = visit_objects_limited(start, visitor, &remaining_limits);
+        report.amount_of_visited_objects += weak_report.amount_of_visited_objects;
+        report.truncation = weak_report.truncation.or(report.truncation);
+    }
+    report
+}
+
 impl ReferenceFinder {
     pub fn new(target: AnyObjectRef) -> Self {
         Self {
@@ -85,11 +329,14 @@ impl ReferenceFinder {
             find_all_paths: false,
             paths: vec![],
             classes_to_ignore: Default::default(),
+            requested_kinds: None,
+            reference_kinds: Default::default(),
+            limits: Default::default(),
         }
     }
 
     pub fn find_path(start: AnyObjectRef, target: AnyObjectRef) -> Option<Vec<ReferencedObject>> {
-        Self::new(target).find(start).pop()
+        Self::new(target).find(start).into_iter().next()
     }
 
     pub fn find_all_paths(start: AnyObjectRef, target: AnyObjectRef) -> Vec<Vec<ReferencedObject>> {
@@ -106,15 +353,76 @@ impl ReferenceFinder {
         self
     }
 
-    pub fn find(mut self, start: AnyObjectRef) -> Vec<Vec<ReferencedObject>> {
-        visit_objects(start, &mut self);
-        self.paths
+    /// Follow only the given kinds of references, strong ones first.
+    /// By default weak slots and ephemeron keys are not followed.
+    pub fn with_reference_kinds(mut self, kinds: ReferenceKinds) -> Self {
+        self.requested_kinds = Some(kinds);
+        self
+    }
+
+    pub fn with_reference_kind(mut self, kind: ReferenceKind, include: bool) -> Self {
+        let kinds = self.requested_kinds.unwrap_or_default();
+        self.requested_kinds = Some(if include {
+            kinds.with(kind)
+        } else {
+            kinds.without(kind)
+        });
+        self
+    }
+
+    pub fn with_limits(mut self, limits: VisitorLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn find(self, start: AnyObjectRef) -> Vec<Vec<ReferencedObject>> {
+        self.find_limited(start).0
+    }
+
+    /// Find paths until one of the limits is reached.
+    /// Returns the paths found so far and a report telling if the search was truncated.
+    ///
+    /// When the kinds of references are given explicitly, weak references are only followed
+    /// after the strong ones, either to collect all paths or when there is no strong path.
+    /// Both passes share the limits, see [`visit_strong_first`].
+    pub fn find_limited(
+        mut self,
+        start: AnyObjectRef,
+    ) -> (Vec<Vec<ReferencedObject>>, VisitorReport) {
+        let limits = std::mem::take(&mut self.limits);
+        let requested_kinds = self.requested_kinds;
+
+        let report = visit_strong_first(
+            start,
+            &mut self,
+            requested_kinds,
+            &limits,
+            |finder, kinds| finder.reference_kinds = kinds,
+            |finder| finder.find_all_paths || finder.paths.is_empty(),
+        );
+
+        let mut paths = self.paths;
+        // strong paths are found again when weak references are followed
+        let mut unique_paths = HashSet::new();
+        paths.retain(|path| unique_paths.insert(path.clone()));
+        rank_paths_by_strength(&mut paths);
+        if !self.find_all_paths {
+            paths.truncate(1);
+        }
+        (paths, report)
     }
 }
 
 impl ObjectVisitor for ReferenceFinder {
-    fn next_objects(object: ReferencedObject) -> impl Iterator<Item = ReferencedObject> {
-        visitor_next_objects(object)
+    fn reference_kinds(&self) -> ReferenceKinds {
+        self.reference_kinds
+    }
+
+    fn next_objects(
+        object: ReferencedObject,
+        kinds: ReferenceKinds,
+    ) -> impl Iterator<Item = ReferencedObject> {
+        visitor_next_objects(object, kinds)
             .filter(|each| !each.object().is_immediate())
             .filter(|each| !each.object().amount_of_indexable_units() != 0)
     }
@@ -136,8 +444,13 @@ impl ObjectVisitor for ReferenceFinder {
         }
 
         if object.object() == self.target {
-            self.paths.push(state.path_with(object));
-            return if self.find_all_paths {
+            let path = state.path_with(object);
+            // when looking for a single path of requested kinds keep searching until a strong one
+            // is found, the best of the found paths is picked after the search.
+            // With the default kinds the first path is taken, as it always was
+            let keep_searching = self.requested_kinds.is_some() && !is_strong_path(&path);
+            self.paths.push(path);
+            return if self.find_all_paths || keep_searching {
                 VisitorAction::Skip
             } else {
                 VisitorAction::Stop
@@ -151,44 +464,99 @@ pub struct ClassInstanceReferenceFinder {
     class: ObjectRef,
     path_len: usize,
     find_all_paths: bool,
+    /// Whether the kinds of references were given by the caller,
+    /// only then a single path search continues past a path that is not strong
+    has_requested_kinds: bool,
+    /// Kinds of references followed by the current pass of the search
+    reference_kinds: ReferenceKinds,
     paths: HashSet<Vec<ReferencedObject>>,
 }
 
 impl ClassInstanceReferenceFinder {
+    fn new(class: ObjectRef, path_len: usize, find_all_paths: bool) -> Self {
+        Self {
+            class,
+            path_len,
+            find_all_paths,
+            has_requested_kinds: false,
+            reference_kinds: Default::default(),
+            paths: Default::default(),
+        }
+    }
+
     pub fn find_all_paths(
         start: AnyObjectRef,
         class: ObjectRef,
         path_len: usize,
     ) -> Vec<Vec<ReferencedObject>> {
-        let mut finder = Self {
-            class,
-            path_len,
-            find_all_paths: true,
-            paths: Default::default(),
-        };
-        visit_objects(start, &mut finder);
-        finder.paths.into_iter().collect()
+        Self::find_all_paths_limited(start, class, path_len, &Default::default()).0
+    }
+
+    pub fn find_all_paths_limited(
+        start: AnyObjectRef,
+        class: ObjectRef,
+        path_len: usize,
+        limits: &VisitorLimits,
+    ) -> (Vec<Vec<ReferencedObject>>, VisitorReport) {
+        Self::new(class, path_len, true).find_limited(start, None, limits)
+    }
+
+    /// Find all paths following only the given kinds of references, strong ones first
+    pub fn find_all_paths_of_kinds(
+        start: AnyObjectRef,
+        class: ObjectRef,
+        path_len: usize,
+        kinds: ReferenceKinds,
+    ) -> Vec<Vec<ReferencedObject>> {
+        Self::new(class, path_len, true)
+            .find_limited(start, Some(kinds), &Default::default())
+            .0
     }
 
     pub fn find_path(start: AnyObjectRef, class: ObjectRef) -> Option<Vec<ReferencedObject>> {
-        let mut finder = Self {
-            class,
-            path_len: 0,
-            find_all_paths: false,
-            paths: Default::default(),
-        };
-        visit_objects(start, &mut finder);
-        finder
-            .paths
+        Self::new(class, 0, false)
+            .find_limited(start, None, &Default::default())
+            .0
             .into_iter()
-            .collect::<Vec<Vec<ReferencedObject>>>()
-            .pop()
+            .next()
+    }
+
+    /// See [`ReferenceFinder::find_limited`]
+    fn find_limited(
+        mut self,
+        start: AnyObjectRef,
+        requested_kinds: Option<ReferenceKinds>,
+        limits: &VisitorLimits,
+    ) -> (Vec<Vec<ReferencedObject>>, VisitorReport) {
+        self.has_requested_kinds = requested_kinds.is_some();
+        let report = visit_strong_first(
+            start,
+            &mut self,
+            requested_kinds,
+            limits,
+            |finder, kinds| finder.reference_kinds = kinds,
+            |finder| finder.find_all_paths || finder.paths.is_empty(),
+        );
+
+        let mut paths = self.paths.into_iter().collect::<Vec<_>>();
+        rank_paths_by_strength(&mut paths);
+        if !self.find_all_paths {
+            paths.truncate(1);
+        }
+        (paths, report)
     }
 }
 
 impl ObjectVisitor for ClassInstanceReferenceFinder {
-    fn next_objects(object: ReferencedObject) -> impl Iterator<Item = ReferencedObject> {
-        visitor_next_objects(object).filter(|each| !each.object().is_immediate())
+    fn reference_kinds(&self) -> ReferenceKinds {
+        self.reference_kinds
+    }
+
+    fn next_objects(
+        object: ReferencedObject,
+        kinds: ReferenceKinds,
+    ) -> impl Iterator<Item = ReferencedObject> {
+        visitor_next_objects(object, kinds).filter(|each| !each.object().is_immediate())
     }
 
     fn visit_referenced_object(
@@ -204,11 +572,122 @@ impl ObjectVisitor for ClassInstanceReferenceFinder {
                         .insert(state.path_with_limited(object, self.path_len));
                     VisitorAction::Skip
                 } else {
-                    self.paths.insert(state.path_with(object));
-                    VisitorAction::Stop
+                    // keep searching until a strong path is found, like the ReferenceFinder does
+                    let path = state.path_with(object);
+                    let keep_searching = self.has_requested_kinds && !is_strong_path(&path);
+                    self.paths.insert(path);
+                    if keep_searching {
+                        VisitorAction::Skip
+                    } else {
+                        VisitorAction::Stop
+                    }
                 };
             }
         }
         VisitorAction::Continue
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(value: i64) -> AnyObjectRef {
+        AnyObjectRef::from(Immediate::new_i64(value))
+    }
+
+    #[test]
+    fn strong_paths_come_first() {
+        let weak = vec![
+            ReferencedObject::Root(object(1)),
+            ReferencedObject::WeakSlot(object(2), 0),
+        ];
+        let very_weak = vec![
+            ReferencedObject::Root(object(1)),
+            ReferencedObject::EphemeronKey(object(3)),
+            ReferencedObject::WeakSlot(object(2), 0),
+        ];
+        let strong = vec![
+            ReferencedObject::Root(object(1)),
+            ReferencedObject::InstanceVariable(object(4), 0),
+            ReferencedObject::ArrayItem(object(2)),
+        ];
+
+        let mut paths = vec![very_weak.clone(), weak.clone(), strong.clone()];
+        rank_paths_by_strength(&mut paths);
+        assert_eq!(paths, vec![strong, weak, very_weak]);
+    }
+
+    #[test]
+    fn ranking_preserves_order_of_equally_strong_paths() {
+        let first = vec![ReferencedObject::ArrayItem(object(1))];
+        let second = vec![ReferencedObject::ContextVariable(object(2))];
+
+        let mut paths = vec![first.clone(), second.clone()];
+        rank_paths_by_strength(&mut paths);
+        assert_eq!(paths, vec![first, second]);
+    }
+
+    #[test]
+    fn strong_path() {
+        assert!(is_strong_path(&[
+            ReferencedObject::Root(object(1)),
+            ReferencedObject::InstanceVariable(object(2), 1),
+        ]));
+        assert!(!is_strong_path(&[
+            ReferencedObject::Root(object(1)),
+            ReferencedObject::EphemeronValue(object(2)),
+        ]));
+    }
+
+    #[test]
+    fn default_kinds_are_not_requested() {
+        assert_eq!(ReferenceFinder::new(object(1)).requested_kinds, None);
+        assert_eq!(
+            ReferenceFinder::new(object(1))
+                .with_reference_kind(ReferenceKind::WeakSlot, true)
+                .requested_kinds,
+            Some(ReferenceKinds::default().with(ReferenceKind::WeakSlot))
+        );
+        assert_eq!(
+            ReferenceFinder::new(object(1))
+                .with_reference_kinds(ReferenceKinds::strong())
+                .requested_kinds,
+            Some(ReferenceKinds::strong())
+        );
+    }
+
+    #[test]
+    fn strong_only_kinds() {
+        assert_eq!(
+            ReferenceKinds::all().strong_only(),
+            ReferenceKinds::strong()
+        );
+        assert_eq!(
+            ReferenceKinds::default().strong_only(),
+            ReferenceKinds::strong()
+        );
+        assert_eq!(
+            ReferenceKinds::none()
+                .with(ReferenceKind::WeakSlot)
+                .with(ReferenceKind::ArrayItem)
+                .strong_only(),
+            ReferenceKinds::none().with(ReferenceKind::ArrayItem)
+        );
+    }
+
+    #[test]
+    fn kinds_from_bits_are_validated() {
+        assert_eq!(
+            ReferenceKinds::try_from_bits(0),
+            Some(ReferenceKinds::none())
+        );
+        assert_eq!(
+            ReferenceKinds::try_from_bits(63),
+            Some(ReferenceKinds::all())
+        );
+        assert_eq!(ReferenceKinds::try_from_bits(64), None);
+        assert_eq!(ReferenceKinds::try_from_bits(256), None);
+        assert_eq!(ReferenceKinds::try_from_bits(-1), None);
+    }
+}
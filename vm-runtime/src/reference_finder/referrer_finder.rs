@@ -0,0 +1,204 @@
+use std::collections::{HashMap, HashSet};
+
+use vm_bindings::{Smalltalk, StackOffset};
+use vm_object_model::{AnyObjectRef, Immediate, Object, ObjectRef};
+
+use crate::memory::{EdenMemorySpace, OldMemorySpace, PastMemorySpace};
+use crate::objects::{Array, ArrayRef};
+use crate::reference_finder::{
+    ObjectIterator, ReferenceKind, ReferenceKinds, ReferencedObject, ReferencedObjectClasses,
+};
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct ReferrerObject {
+    this: Object,
+    referrer: ObjectRef,
+    reference: ObjectRef,
+    slot_index: Immediate,
+    level: Immediate,
+    kind: Immediate,
+}
+
+#[derive(Debug, PharoObject)]
+#[repr(C)]
+pub struct ReferrerClassGroupObject {
+    this: Object,
+    class: ObjectRef,
+    referrers: ArrayRef,
+}
+
+/// Referrers are reported along with the [`ReferenceKind`] of the reference to the target:
+/// 0 - instance variable, 1 - array item, 2 - context variable,
+/// 3 - weak slot, 4 - ephemeron key, 5 - ephemeron value.
+#[allow(non_snake_case)]
+pub fn primitiveReferrerFinderFindReferrers() -> Result<(), anyhow::Error> {
+    let referrer_class = Smalltalk::stack_ref(StackOffset::new(0)).as_object()?;
+    let group_class = Smalltalk::stack_ref(StackOffset::new(1)).as_object()?;
+    let classes = ArrayRef::try_from(Smalltalk::stack_ref(StackOffset::new(2)))?;
+    let Ok(levels) = usize::try_from(Smalltalk::stack_integer_value(StackOffset::new(3))) else {
+        Smalltalk::primitive_fail();
+        return Ok(());
+    };
+    let targets_array = Smalltalk::stack_ref(StackOffset::new(4));
+    let targets = ArrayRef::try_from(targets_array)?;
+
+    let groups = ReferrerFinder::new(targets.iter().copied())
+        .with_levels(levels)
+        .with_ignored_objects([targets_array])
+        .find()
+        .into_iter()
+        .fold(
+            HashMap::<ObjectRef, Vec<Referrer>>::new(),
+            |mut groups, referrer| {
+                groups
+                    .entry(Smalltalk::class_of_object(referrer.referrer))
+                    .or_default()
+                    .push(referrer);
+                groups
+            },
+        );
+
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_by(|a, b| b.1.len().cmp(&a.1.len()));
+
+    let classes = ReferencedObjectClasses::new(classes)?;
+    let mut groups_array = Array::new(groups.len())?;
+    for (group_index, (class, referrers)) in groups.into_iter().enumerate() {
+        let mut referrers_array = Array::new(referrers.len())?;
+        for (index, referrer) in referrers.into_iter().enumerate() {
+            let mut referrer_object = Smalltalk::instantiate::<ReferrerObjectRef>(referrer_class)?;
+            referrer_object.set_referrer(referrer.referrer);
+            referrer_object.set_reference(classes.convert(referrer.reference)?);
+            referrer_object.set_slot_index(referrer.slot_index);
+            referrer_object.set_level(referrer.level);
+            referrer_object.set_kind(referrer.kind as usize);
+            referrers_array.insert(index, referrer_object);
+        }
+
+        let mut group_object = Smalltalk::instantiate::<ReferrerClassGroupObjectRef>(group_class)?;
+        group_object.set_class(class);
+        group_object.set_referrers(referrers_array);
+        groups_array.insert(group_index, group_object);
+    }
+
+    Smalltalk::method_return(groups_array);
+    Ok(())
+}
+
+/// An object that directly references one of the targets
+#[derive(Debug, Clone)]
+pub struct Referrer {
+    pub referrer: ObjectRef,
+    /// the referenced target as seen from the referrer
+    pub reference: ReferencedObject,
+    /// zero-based index of the slot of the referrer that holds the target
+    pub slot_index: usize,
+    /// 1 for direct referrers of the targets, 2 for referrers of referrers, etc.
+    pub level: usize,
+    /// how the referrer holds the target, weak slots and ephemerons do not retain it
+    pub kind: ReferenceKind,
+}
+
+/// Finds all objects that reference a given set of targets by walking over all objects in
+/// eden, past and old space instead of searching for a path from some root object.
+/// Every level of recursion requires an additional walk over the whole heap.
+/// All kinds of references are reported by default, including weak ones.
+pub struct ReferrerFinder {
+    targets: HashSet<AnyObjectRef>,
+    objects_to_ignore: HashSet<AnyObjectRef>,
+    levels: usize,
+    reference_kinds: ReferenceKinds,
+}
+
+impl ReferrerFinder {
+    pub fn new<T: IntoIterator<Item = AnyObjectRef>>(targets: T) -> Self {
+        Self {
+            targets: targets.into_iter().collect(),
+            objects_to_ignore: Default::default(),
+            levels: 1,
+            reference_kinds: ReferenceKinds::all(),
+        }
+    }
+
+    /// Also find referrers of the referrers up to a given amount of levels
+    pub fn with_levels(mut self, levels: usize) -> Self {
+        self.levels = levels.max(1);
+        self
+    }
+
+    /// Only report referrers holding the targets with one of the given kinds of references
+    pub fn with_reference_kinds(mut self, kinds: ReferenceKinds) -> Self {
+        self.reference_kinds = kinds;
+        self
+    }
+
+    /// Objects that should never be reported as referrers,
+    /// for example the collection holding the targets
+    pub fn with_ignored_objects<T: IntoIterator<Item = AnyObjectRef>>(
+        mut self,
+        objects: T,
+    ) -> Self {
+        self.objects_to_ignore.extend(objects);
+        self
+    }
+
+    pub fn find(self) -> Vec<Referrer> {
+        let mut referrers = vec![];
+        let mut visited = self.targets.clone();
+        let mut current_targets = self.targets.clone();
+
+        for level in 1..=self.levels {
+            let level_referrers = self.find_referrers_of(&current_targets, level);
+
+            current_targets = level_referrers
+                .iter()
+                .map(|each| AnyObjectRef::from(each.referrer))
+                .filter(|each| visited.insert(*each))
+                .collect();
+
+            referrers.extend(level_referrers);
+
+            if current_targets.is_empty() {
+                break;
+            }
+        }
+
+        referrers
+    }
+
+    fn find_referrers_of(&self, targets: &HashSet<AnyObjectRef>, level: usize) -> Vec<Referrer> {
+        let mut referrers = vec![];
+
+        let spaces: [Box<dyn Iterator<Item = ObjectRef>>; 3] = [
+            Box::new(EdenMemorySpace::new().objects()),
+            Box::new(PastMemorySpace::new().objects()),
+            Box::new(OldMemorySpace::new().objects()),
+        ];
+
+        for object in spaces.into_iter().flatten() {
+            if self.objects_to_ignore.contains(&object.into()) {
+                continue;
+            }
+
+            let mut iterator = ObjectIterator::with_kinds(object.into(), self.reference_kinds);
+            while let Some(reference) = iterator.next() {
+                if !targets.contains(&reference.object()) {
+                    continue;
+                }
+                let Some(kind) = reference.kind() else {
+                    continue;
+                };
+                referrers.push(Referrer {
+                    referrer: object,
+                    reference,
+                    slot_index: iterator.index - 1,
+                    level,
+                    kind,
+                });
+            }
+        }
+
+        referrers
+    }
+}
@@ -0,0 +1,494 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::bail;
+use vm_bindings::{Smalltalk, StackOffset};
+use vm_object_model::{AnyObjectRef, ObjectFormat, ObjectRef};
+
+use crate::memory::size_value;
+use crate::objects::{ArrayRef, ByteStringRef};
+use crate::reference_finder::{
+    visit_unique_objects_limited, visitor_next_objects, ObjectIterator, ObjectVisitor,
+    ReferenceKinds, ReferencedObject, VisitorAction, VisitorLimits, VisitorState,
+};
+
+/// Maximum amount of characters or bytes shown in the node label
+const MAX_CONTENT_LENGTH: usize = 40;
+
+/// Write a subgraph around an object to a file.
+/// The format is either `dot` (Graphviz) or `json`.
+/// Returns the amount of exported nodes.
+#[allow(non_snake_case)]
+pub fn primitiveSubgraphExporterExport() -> Result<(), anyhow::Error> {
+    let format = ByteStringRef::try_from(Smalltalk::stack_ref(StackOffset::new(0)))?;
+    let file_name = ByteStringRef::try_from(Smalltalk::stack_ref(StackOffset::new(1)))?;
+    let max_nodes = size_value(Smalltalk::stack_ref(StackOffset::new(2)))?;
+    let classes = ArrayRef::try_from(Smalltalk::stack_ref(StackOffset::new(3)))?;
+    let levels = size_value(Smalltalk::stack_ref(StackOffset::new(4)))?;
+    let start_obj = Smalltalk::stack_ref(StackOffset::new(5));
+
+    let format = SubgraphFormat::from_name(format.as_str())?;
+    let classes_to_ignore: Result<Vec<_>, _> =
+        classes.iter().map(|each| each.as_object()).collect();
+
+    let subgraph = SubgraphExporter::new()
+        .with_levels(levels)
+        .with_max_nodes(max_nodes)
+        .with_ignored_classes(classes_to_ignore?)
+        .export(start_obj);
+    subgraph.write_to(file_name.as_str(), format)?;
+
+    Smalltalk::method_return_integer(subgraph.nodes.len() as i64);
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SubgraphFormat {
+    Dot,
+    Json,
+}
+
+impl SubgraphFormat {
+    pub fn from_name(name: &str) -> Result<Self, anyhow::Error> {
+        match name.to_lowercase().as_str() {
+            "dot" => Ok(Self::Dot),
+            "json" => Ok(Self::Json),
+            _ => bail!("Unknown subgraph format: {}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubgraphNode {
+    pub object: ObjectRef,
+    pub class_name: String,
+    pub byte_size: usize,
+    pub content: String,
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SubgraphEdge {
+    pub from: usize,
+    pub to: usize,
+    pub label: String,
+}
+
+/// A bounded neighbourhood of an object: all objects reachable within a given amount of levels
+/// and the references between them
+#[derive(Debug, Default)]
+pub struct Subgraph {
+    pub nodes: Vec<SubgraphNode>,
+    pub edges: Vec<SubgraphEdge>,
+    /// true if some of the objects were not exported because of the nodes limit
+    pub is_truncated: bool,
+}
+
+/// Collects objects reachable from a start object in breadth-first order
+/// and exports them together with all references between them.
+pub struct SubgraphExporter {
+    levels: usize,
+    max_nodes: usize,
+    classes_to_ignore: HashSet<ObjectRef>,
+    objects: Vec<(ObjectRef, usize)>,
+    is_truncated: bool,
+}
+
+impl SubgraphExporter {
+    pub fn new() -> Self {
+        Self {
+            levels: 1,
+            max_nodes: usize::MAX,
+            classes_to_ignore: Default::default(),
+            objects: vec![],
+            is_truncated: false,
+        }
+    }
+
+    pub fn with_levels(mut self, levels: usize) -> Self {
+        self.levels = levels;
+        self
+    }
+
+    pub fn with_max_nodes(mut self, max_nodes: usize) -> Self {
+        self.max_nodes = max_nodes;
+        self
+    }
+
+    pub fn with_ignored_classes<T: IntoIterator<Item = ObjectRef>>(mut self, classes: T) -> Self {
+        self.classes_to_ignore.extend(classes);
+        self
+    }
+
+    pub fn export(mut self, start: AnyObjectRef) -> Subgraph {
+        // the path to objects at the last level consists of levels + 1 objects
+        let limits = VisitorLimits::new().with_max_path_length(self.levels.saturating_add(1));
+        visit_unique_objects_limited(start, &mut self, &limits);
+
+        let indices: HashMap<AnyObjectRef, usize> = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(index, (object, _))| (AnyObjectRef::from(*object), index))
+            .collect();
+
+        let mut subgraph = Subgraph {
+            is_truncated: self.is_truncated,
+            ..Default::default()
+        };
+
+        for (index, (object, depth)) in self.objects.iter().enumerate() {
+            let mut iterator = ObjectIterator::with_kinds((*object).into(), ReferenceKinds::all());
+            while let Some(reference) = iterator.next() {
+                if let Some(to) = indices.get(&reference.object()) {
+                    subgraph.edges.push(SubgraphEdge {
+                        from: index,
+                        to: *to,
+                        label: edge_label(reference, &iterator),
+                    });
+                }
+            }
+
+            subgraph.nodes.push(SubgraphNode {
+                object: *object,
+                class_name: Smalltalk::class_name(Smalltalk::class_of_object(*object)),
+                byte_size: Smalltalk::byte_size(*object),
+                content: object_content(*object),
+                depth: *depth,
+            });
+        }
+
+        subgraph
+    }
+}
+
+impl ObjectVisitor for SubgraphExporter {
+    fn next_objects(
+        object: ReferencedObject,
+        kinds: ReferenceKinds,
+    ) -> impl Iterator<Item = ReferencedObject> {
+        visitor_next_objects(object, kinds).filter(|each| !each.object().is_immediate())
+    }
+
+    fn visit_referenced_object(
+        &mut self,
+        object: ReferencedObject,
+        state: &VisitorState,
+    ) -> VisitorAction {
+        let Ok(object) = object.object().as_object() else {
+            return VisitorAction::Skip;
+        };
+
+        if self
+            .classes_to_ignore
+            .contains(&Smalltalk::class_of_object(object))
+        {
+            return VisitorAction::Skip;
+        }
+
+        if self.objects.len() >= self.max_nodes {
+            self.is_truncated = true;
+            return VisitorAction::Stop;
+        }
+
+        let depth = if self.objects.is_empty() {
+            0
+        } else {
+            state.depth() + 1
+        };
+        self.objects.push((object, depth));
+        VisitorAction::Continue
+    }
+}
+
+impl Subgraph {
+    pub fn write_to(
+        &self,
+        file_name: impl AsRef<Path>,
+        format: SubgraphFormat,
+    ) -> std::io::Result<()> {
+        let contents = match format {
+            SubgraphFormat::Dot => self.to_dot(),
+            SubgraphFormat::Json => self.to_json(),
+        };
+        std::fs::write(file_name, contents)
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph objects {{").unwrap();
+        writeln!(dot, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        for (index, node) in self.nodes.iter().enumerate() {
+            let mut label = format!(
+                "{}\\n{} bytes",
+                escape_dot(&node.class_name),
+                node.byte_size
+            );
+            if !node.content.is_empty() {
+                write!(label, "\\n{}", escape_dot(&node.content)).unwrap();
+            }
+            writeln!(dot, "  n{} [label=\"{}\"];", index, label).unwrap();
+        }
+
+        for edge in &self.edges {
+            writeln!(
+                dot,
+                "  n{} -> n{} [label=\"{}\"];",
+                edge.from,
+                edge.to,
+                escape_dot(&edge.label)
+            )
+            .unwrap();
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        let nodes = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| {
+                json::object! {
+                    id: index,
+                    address: format!("{:#x}", node.object.as_ptr() as usize),
+                    class: node.class_name.as_str(),
+                    byte_size: node.byte_size,
+                    content: node.content.as_str(),
+                    depth: node.depth,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let edges = self
+            .edges
+            .iter()
+            .map(|edge| {
+                json::object! {
+                    from: edge.from,
+                    to: edge.to,
+                    label: edge.label.as_str(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        json::stringify_pretty(
+            json::object! {
+                nodes: nodes,
+                edges: edges,
+                is_truncated: self.is_truncated,
+            },
+            2,
+        )
+    }
+}
+
+/// Describe a reference by its kind and the zero-based slot index within the referrer
+fn edge_label(reference: ReferencedObject, iterator: &ObjectIterator) -> String {
+    let slot_index = iterator.index - 1;
+    let item_index = slot_index.saturating_sub(iterator.amount_of_fixed_fields);
+    match reference {
+        ReferencedObject::InstanceVariable(_, index) => format!("instVar {}", index),
+        ReferencedObject::ContextVariable(_) => {
+            if slot_index < iterator.amount_of_fixed_fields {
+                format!("instVar {}", slot_index)
+            } else {
+                format!("temp {}", item_index)
+            }
+        }
+        ReferencedObject::ArrayItem(_) => format!("[{}]", item_index),
+        ReferencedObject::WeakSlot(_, index) => format!("weak [{}]", index),
+        ReferencedObject::EphemeronKey(_) => "key".to_string(),
+        ReferencedObject::EphemeronValue(_) => "value".to_string(),
+        ReferencedObject::Root(_) => "root".to_string(),
+    }
+}
+
+/// A short printString-like description of the object contents,
+/// empty if there is nothing interesting to show
+fn object_content(object: ObjectRef) -> String {
+    let format = object.header().format();
+    match format {
+        ObjectFormat::Indexable8(_) => {
+            let bytes = object_bytes(object);
+            let shown = &bytes[..bytes.len().min(MAX_CONTENT_LENGTH)];
+            let ellipsis = if shown.len() < bytes.len() { "..." } else { "" };
+            let class_name = Smalltalk::class_name(Smalltalk::class_of_object(object));
+            if class_name.ends_with("Symbol") {
+                format!("#{}{}", latin1_string(shown), ellipsis)
+            } else if class_name.ends_with("String") {
+                format!("'{}{}'", latin1_string(shown), ellipsis)
+            } else {
+                let shown = shown
+                    .iter()
+                    .take(MAX_CONTENT_LENGTH / 4)
+                    .map(|each| each.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!("#[{}{}] ({} bytes)", shown, ellipsis, bytes.len())
+            }
+        }
+        ObjectFormat::Indexable64
+        | ObjectFormat::Indexable32(_)
+        | ObjectFormat::Indexable16(_)
+        | ObjectFormat::CompiledMethod(_) => {
+            format!("({} units)", object.amount_of_indexable_units())
+        }
+        _ => {
+            let iterator = ObjectIterator::new(object.into());
+            if iterator.amount_of_indexable_fields > 0 {
+                format!("({} items)", iterator.amount_of_indexable_fields)
+            } else {
+                String::new()
+            }
+        }
+    }
+}
+
+fn object_bytes<'a>(object: ObjectRef) -> &'a [u8] {
+    unsafe {
+        std::slice::from_raw_parts(
+            object.first_fixed_field_ptr() as *const u8,
+            object.amount_of_indexable_units(),
+        )
+    }
+}
+
+/// Bytes of a ByteString or a ByteSymbol are Latin-1 characters
+fn latin1_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|each| *each as char).collect()
+}
+
+fn escape_dot(string: &str) -> String {
+    string
+        .chars()
+        .map(|each| match each {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            '\n' | '\r' => "\\n".to_string(),
+            _ => each.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_object_model::RawObjectPointer;
+
+    fn node(address: i64, class_name: &str, content: &str) -> SubgraphNode {
+        SubgraphNode {
+            object: unsafe {
+                ObjectRef::from_raw_pointer_unchecked(RawObjectPointer::new(address))
+            },
+            class_name: class_name.to_string(),
+            byte_size: 16,
+            content: content.to_string(),
+            depth: 0,
+        }
+    }
+
+    fn subgraph() -> Subgraph {
+        Subgraph {
+            nodes: vec![
+                node(0x1000, "Association", ""),
+                node(0x2000, "ByteString", "'say \"hi\"'"),
+            ],
+            edges: vec![SubgraphEdge {
+                from: 0,
+                to: 1,
+                label: "instVar 1".to_string(),
+            }],
+            is_truncated: true,
+        }
+    }
+
+    #[test]
+    fn latin1_bytes_are_characters() {
+        assert_eq!(latin1_string(b"abc"), "abc");
+        assert_eq!(latin1_string(&[0x63, 0x61, 0x66, 0xE9]), "caf\u{e9}");
+        assert_eq!(latin1_string(&[0xFF, 0x80]), "\u{ff}\u{80}");
+        assert_eq!(latin1_string(&[]), "");
+    }
+
+    #[test]
+    fn escape_dot_quotes_backslashes_and_newlines() {
+        assert_eq!(escape_dot("plain"), "plain");
+        assert_eq!(escape_dot("say \"hi\""), "say \\\"hi\\\"");
+        assert_eq!(escape_dot("a\\b"), "a\\\\b");
+        assert_eq!(escape_dot("a\nb\rc"), "a\\nb\\nc");
+        assert_eq!(escape_dot("caf\u{e9}"), "caf\u{e9}");
+    }
+
+    #[test]
+    fn dot_of_subgraph() {
+        let dot = subgraph().to_dot();
+        let lines = dot.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "digraph objects {",
+                "  node [shape=box, fontname=\"monospace\"];",
+                "  n0 [label=\"Association\\n16 bytes\"];",
+                "  n1 [label=\"ByteString\\n16 bytes\\n'say \\\"hi\\\"'\"];",
+                "  n0 -> n1 [label=\"instVar 1\"];",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn dot_of_empty_subgraph() {
+        assert_eq!(
+            Subgraph::default().to_dot(),
+            "digraph objects {\n  node [shape=box, fontname=\"monospace\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn json_of_subgraph() {
+        let json = json::parse(&subgraph().to_json()).unwrap();
+        assert_eq!(
+            json,
+            json::object! {
+                nodes: [
+                    {
+                        id: 0,
+                        address: "0x1000",
+                        class: "Association",
+                        byte_size: 16,
+                        content: "",
+                        depth: 0,
+                    },
+                    {
+                        id: 1,
+                        address: "0x2000",
+                        class: "ByteString",
+                        byte_size: 16,
+                        content: "'say \"hi\"'",
+                        depth: 0,
+                    }
+                ],
+                edges: [ { from: 0, to: 1, label: "instVar 1" } ],
+                is_truncated: true,
+            }
+        );
+    }
+
+    #[test]
+    fn subgraph_formats() {
+        assert_eq!(
+            SubgraphFormat::from_name("dot").unwrap(),
+            SubgraphFormat::Dot
+        );
+        assert_eq!(
+            SubgraphFormat::from_name("JSON").unwrap(),
+            SubgraphFormat::Json
+        );
+        assert!(SubgraphFormat::from_name("svg").is_err());
+    }
+}
@@ -4,9 +4,12 @@ use std::mem::transmute;
 use std::ops::Deref;
 use std::os::raw::c_void;
 use std::process::exit;
-use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::Duration;
+
+#[cfg(feature = "ffi")]
+use once_cell::sync::OnceCell;
 
 use crate::objects::{Array, ArrayRef};
 #[cfg(feature = "pharo-compiler")]
@@ -16,27 +19,60 @@ use crate::tonel::*;
 
 use crate::reference_finder::{
     primitiveClassInstanceReferenceFinderFindAllPaths,
-    primitiveClassInstanceReferenceFinderFindPath, primitiveInstanceCounterCountAll,
-    primitiveReferenceFinderFindAllPaths, primitiveReferenceFinderFindPath,
-    primitiveReferenceFinderGetNeighbours,
+    primitiveClassInstanceReferenceFinderFindAllPathsLimited,
+    primitiveClassInstanceReferenceFinderFindAllPathsOfKinds,
+    primitiveClassInstanceReferenceFinderFindPath, primitiveDominatorTreeDominatorChain,
+    primitiveDominatorTreeTopRetainedClasses, primitiveDominatorTreeTopRetainedObjects,
+    primitiveInstanceCounterCountAll, primitiveReferenceFinderFindAllPaths,
+    primitiveReferenceFinderFindAllPathsLimited, primitiveReferenceFinderFindAllPathsOfKinds,
+    primitiveReferenceFinderFindPath, primitiveReferenceFinderFindPathOfKinds,
+    primitiveReferenceFinderGetNeighbours, primitiveReferrerFinderFindReferrers,
+    primitiveSubgraphExporterExport,
 };
 use crate::version::{app_info, app_version};
 #[cfg(feature = "ffi")]
 use crate::{
     ffi::{
-        primitiveBareFfiCallout, primitiveBareFfiCalloutInvalidate, primitiveBareFfiCalloutRelease,
+        primitiveBareFfiAsyncCallout, primitiveBareFfiAsyncCalloutResult,
+        primitiveBareFfiCallbackCreate, primitiveBareFfiCallbackNextInvocation,
+        primitiveBareFfiCallbackRelease, primitiveBareFfiCallbackReturn, primitiveBareFfiCallout,
+        primitiveBareFfiCalloutCaptureErrno, primitiveBareFfiCalloutErrno,
+        primitiveBareFfiCalloutInvalidate, primitiveBareFfiCalloutRelease,
+        primitiveBareFfiCalloutReturnsOwned, primitiveBareFfiVariadicCallout,
+        primitiveEventLoopCalloutCaptureErrno, primitiveEventLoopCalloutErrno,
+        primitiveFfiAddLibrarySearchPath, primitiveFfiLastErrno, primitiveFfiLoadLibrary,
+        primitiveFfiLoadedLibraries, primitiveRawMemoryReadBytes, primitiveRawMemoryReadF32,
+        primitiveRawMemoryReadF64, primitiveRawMemoryReadI16, primitiveRawMemoryReadI32,
+        primitiveRawMemoryReadI64, primitiveRawMemoryReadI8, primitiveRawMemoryReadPointer,
+        primitiveRawMemoryReadU16, primitiveRawMemoryReadU32, primitiveRawMemoryReadU64,
+        primitiveRawMemoryReadU8, primitiveRawMemoryWriteBytes, primitiveRawMemoryWriteF32,
+        primitiveRawMemoryWriteF64, primitiveRawMemoryWriteI16, primitiveRawMemoryWriteI32,
+        primitiveRawMemoryWriteI64, primitiveRawMemoryWriteI8, primitiveRawMemoryWritePointer,
+        primitiveRawMemoryWriteU16, primitiveRawMemoryWriteU32, primitiveRawMemoryWriteU64,
+        primitiveRawMemoryWriteU8, FfiWorkerPool, LibraryRegistry,
     },
-    primitiveEventLoopCallout, primitiveExtractReturnValue,
+    primitiveEventLoopCallout, primitiveEventLoopCalloutBatch, primitiveEventLoopStartCalloutTimer,
+    primitiveExtractReturnValue,
 };
 use crate::{
-    log_signal, primitiveEnableLogSignal, primitiveGetEnabledLogSignals, primitivePollLogger,
+    log_signal, primitiveAllocationTrackerFree, primitiveAllocationTrackerLiveAllocations,
+    primitiveAllocationTrackerReport, primitiveAllocationTrackerStart,
+    primitiveAllocationTrackerStop, primitiveEnableLogSignal, primitiveEventLoopMetrics,
+    primitiveEventLoopMetricsReport, primitiveEventLoopMetricsReset,
+    primitiveEventLoopSetSlowCalloutThreshold, primitiveGetEnabledLogSignals, primitivePollLogger,
     primitiveStartBeacon, primitiveStartConsoleLogger, primitiveStartGlobalProcessSwitchTelemetry,
     primitiveStartLocalProcessSwitchTelemetry, primitiveStopLogger, primitiveStopTelemetry,
     primitiveTelemetryContextSignal, primitiveTelemetryObjectSignal, should_log_all_signals,
-    should_log_signal, ConsoleLogger, EventLoop, EventLoopMessage, EventLoopWaker, VM_LOGGER,
+    should_log_signal, ConsoleLogger, EventLoop, EventLoopMessage, EventLoopSender, EventLoopTimer,
+    EventLoopTimerAction, EventLoopTimerId, EventLoopWaker, FfiPolicy, VM_LOGGER,
 };
 
-use crate::memory::primitiveAnalyzeObjectMemory;
+use crate::heap_query::primitiveHeapQueryExecute;
+use crate::memory::{
+    primitiveAnalyzeDuplicateContents, primitiveAnalyzeObjectMemory,
+    primitiveAnalyzeOldSpaceSegments, primitiveAnalyzeWeakObjects,
+    primitiveEnumerateInstancesOfClass,
+};
 
 use anyhow::Result;
 use vm_bindings::{
@@ -56,8 +92,14 @@ pub fn vm() -> &'static Arc<VirtualMachine> {
 pub struct VirtualMachine {
     interpreter: Arc<PharoInterpreter>,
     event_loop: Option<EventLoop>,
-    event_loop_sender: Option<Sender<EventLoopMessage>>,
+    event_loop_sender: Option<EventLoopSender>,
     event_loop_waker: RefCell<Option<EventLoopWaker>>,
+    /// Amount of threads running asynchronous FFI callouts
+    #[cfg(feature = "ffi")]
+    ffi_worker_threads: usize,
+    /// Started on the first asynchronous callout
+    #[cfg(feature = "ffi")]
+    ffi_worker_pool: OnceCell<FfiWorkerPool>,
     #[cfg(target_os = "android")]
     android_app: android_activity::AndroidApp,
 }
@@ -69,6 +111,12 @@ pub struct VirtualMachineConfiguration {
     /// When Some with an empty list - log everything.
     /// When Some with a list of signal name - log only those
     pub log_signals: Option<Vec<String>>,
+    /// Amount of threads running asynchronous FFI callouts.
+    /// When None - one thread per available CPU
+    pub ffi_worker_threads: Option<usize>,
+    /// Libraries and symbols available to FFI.
+    /// When None - everything is allowed
+    pub ffi_policy: Option<FfiPolicy>,
 }
 
 impl VirtualMachine {
@@ -78,9 +126,20 @@ impl VirtualMachine {
     pub fn new(
         configuration: VirtualMachineConfiguration,
         event_loop: Option<EventLoop>,
-        event_loop_sender: Option<Sender<EventLoopMessage>>,
+        event_loop_sender: Option<EventLoopSender>,
         #[cfg(target_os = "android")] android_app: android_activity::AndroidApp,
     ) -> Self {
+        FfiPolicy::initialize(configuration.ffi_policy.unwrap_or_default());
+
+        #[cfg(feature = "ffi")]
+        LibraryRegistry::initialize(
+            configuration
+                .interpreter_configuration
+                .image()
+                .parent()
+                .map(|directory| directory.to_path_buf()),
+        );
+
         let vm = Self {
             interpreter: Arc::new(PharoInterpreter::new(
                 configuration.interpreter_configuration,
@@ -88,6 +147,12 @@ impl VirtualMachine {
             event_loop,
             event_loop_sender,
             event_loop_waker: RefCell::new(None),
+            #[cfg(feature = "ffi")]
+            ffi_worker_threads: configuration
+                .ffi_worker_threads
+                .unwrap_or_else(FfiWorkerPool::default_amount_of_threads),
+            #[cfg(feature = "ffi")]
+            ffi_worker_pool: OnceCell::new(),
             #[cfg(target_os = "android")]
             android_app,
         };
@@ -114,11 +179,53 @@ impl VirtualMachine {
         {
             vm.add_primitive(primitive!(primitiveGetNamedPrimitives));
             vm.add_primitive(primitive!(primitiveEventLoopCallout));
+            vm.add_primitive(primitive!(primitiveEventLoopCalloutBatch));
+            vm.add_primitive(primitive!(primitiveEventLoopStartCalloutTimer));
             vm.add_primitive(primitive!(primitiveExtractReturnValue));
 
             vm.add_primitive(try_primitive!(primitiveBareFfiCallout));
             vm.add_primitive(try_primitive!(primitiveBareFfiCalloutInvalidate));
             vm.add_primitive(try_primitive!(primitiveBareFfiCalloutRelease));
+            vm.add_primitive(try_primitive!(primitiveBareFfiVariadicCallout));
+            vm.add_primitive(try_primitive!(primitiveBareFfiAsyncCallout));
+            vm.add_primitive(try_primitive!(primitiveBareFfiAsyncCalloutResult));
+            vm.add_primitive(try_primitive!(primitiveFfiLoadLibrary));
+            vm.add_primitive(try_primitive!(primitiveFfiAddLibrarySearchPath));
+            vm.add_primitive(try_primitive!(primitiveFfiLoadedLibraries));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadI8));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteI8));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadU8));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteU8));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadI16));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteI16));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadU16));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteU16));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadI32));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteI32));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadU32));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteU32));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadI64));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteI64));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadU64));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteU64));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadF32));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteF32));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadF64));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteF64));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadPointer));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWritePointer));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryReadBytes));
+            vm.add_primitive(try_primitive!(primitiveRawMemoryWriteBytes));
+            vm.add_primitive(try_primitive!(primitiveBareFfiCalloutCaptureErrno));
+            vm.add_primitive(try_primitive!(primitiveBareFfiCalloutErrno));
+            vm.add_primitive(try_primitive!(primitiveBareFfiCalloutReturnsOwned));
+            vm.add_primitive(try_primitive!(primitiveEventLoopCalloutCaptureErrno));
+            vm.add_primitive(try_primitive!(primitiveEventLoopCalloutErrno));
+            vm.add_primitive(try_primitive!(primitiveFfiLastErrno));
+            vm.add_primitive(try_primitive!(primitiveBareFfiCallbackCreate));
+            vm.add_primitive(try_primitive!(primitiveBareFfiCallbackNextInvocation));
+            vm.add_primitive(try_primitive!(primitiveBareFfiCallbackReturn));
+            vm.add_primitive(try_primitive!(primitiveBareFfiCallbackRelease));
         }
 
         #[cfg(feature = "tonel")]
@@ -130,6 +237,7 @@ impl VirtualMachine {
         vm.add_primitive(primitive!(primitiveGetSemaphoreSignaller));
         vm.add_primitive(primitive!(primitiveGetEventLoop));
         vm.add_primitive(primitive!(primitiveGetEventLoopReceiver));
+        vm.add_primitive(primitive!(primitiveGetEventLoopTimerDelay));
         vm.add_primitive(primitive!(primitiveStopLogger));
         vm.add_primitive(primitive!(primitivePollLogger));
         vm.add_primitive(primitive!(primitiveEnableLogSignal));
@@ -137,6 +245,12 @@ impl VirtualMachine {
         vm.add_primitive(primitive!(primitiveStartBeacon));
         vm.add_primitive(primitive!(primitiveStartConsoleLogger));
         vm.add_primitive(primitive!(primitiveSetEventLoopWaker));
+        vm.add_primitive(primitive!(primitiveEventLoopStartTimer));
+        vm.add_primitive(primitive!(primitiveEventLoopCancelTimer));
+        vm.add_primitive(try_primitive!(primitiveEventLoopMetrics));
+        vm.add_primitive(primitive!(primitiveEventLoopMetricsReport));
+        vm.add_primitive(primitive!(primitiveEventLoopMetricsReset));
+        vm.add_primitive(primitive!(primitiveEventLoopSetSlowCalloutThreshold));
         vm.add_primitive(primitive!(primitiveFullGarbageCollectorMicroseconds));
         vm.add_primitive(primitive!(primitiveScavengeGarbageCollectorMicroseconds));
         vm.add_primitive(primitive!(primitiveFirstBytePointerOfDataObject));
@@ -156,20 +270,48 @@ impl VirtualMachine {
         vm.add_primitive(primitive!(primitiveTelemetryContextSignal));
         vm.add_primitive(primitive!(primitiveStopTelemetry));
 
+        // allocation tracker
+        vm.add_primitive(primitive!(primitiveAllocationTrackerStart));
+        vm.add_primitive(primitive!(primitiveAllocationTrackerStop));
+        vm.add_primitive(try_primitive!(primitiveAllocationTrackerLiveAllocations));
+        vm.add_primitive(primitive!(primitiveAllocationTrackerReport));
+        vm.add_primitive(primitive!(primitiveAllocationTrackerFree));
+
         // reference finder
         vm.add_primitive(try_primitive!(primitiveReferenceFinderFindAllPaths));
+        vm.add_primitive(try_primitive!(primitiveReferenceFinderFindAllPathsLimited));
+        vm.add_primitive(try_primitive!(primitiveReferenceFinderFindAllPathsOfKinds));
         vm.add_primitive(try_primitive!(primitiveReferenceFinderFindPath));
+        vm.add_primitive(try_primitive!(primitiveReferenceFinderFindPathOfKinds));
         vm.add_primitive(try_primitive!(primitiveReferenceFinderGetNeighbours));
+        vm.add_primitive(try_primitive!(primitiveReferrerFinderFindReferrers));
+        vm.add_primitive(try_primitive!(primitiveSubgraphExporterExport));
         vm.add_primitive(try_primitive!(
             primitiveClassInstanceReferenceFinderFindAllPaths
         ));
+        vm.add_primitive(try_primitive!(
+            primitiveClassInstanceReferenceFinderFindAllPathsLimited
+        ));
+        vm.add_primitive(try_primitive!(
+            primitiveClassInstanceReferenceFinderFindAllPathsOfKinds
+        ));
         vm.add_primitive(try_primitive!(
             primitiveClassInstanceReferenceFinderFindPath
         ));
         vm.add_primitive(primitive!(primitiveInstanceCounterCountAll));
+        vm.add_primitive(try_primitive!(primitiveDominatorTreeTopRetainedObjects));
+        vm.add_primitive(try_primitive!(primitiveDominatorTreeTopRetainedClasses));
+        vm.add_primitive(try_primitive!(primitiveDominatorTreeDominatorChain));
 
         // memory analyzer
         vm.add_primitive(try_primitive!(primitiveAnalyzeObjectMemory));
+        vm.add_primitive(try_primitive!(primitiveAnalyzeOldSpaceSegments));
+        vm.add_primitive(try_primitive!(primitiveAnalyzeDuplicateContents));
+        vm.add_primitive(try_primitive!(primitiveAnalyzeWeakObjects));
+        vm.add_primitive(try_primitive!(primitiveEnumerateInstancesOfClass));
+
+        // heap query
+        vm.add_primitive(try_primitive!(primitiveHeapQueryExecute));
 
         vm.add_primitive(primitive!(primitiveIsOldObject));
         vm.add_primitive(primitive!(primitiveIsYoungObject));
@@ -212,6 +354,13 @@ impl VirtualMachine {
         &self.interpreter
     }
 
+    /// Return the threads running asynchronous FFI callouts, starting them if necessary
+    #[cfg(feature = "ffi")]
+    pub fn ffi_worker_pool(&self) -> &FfiWorkerPool {
+        self.ffi_worker_pool
+            .get_or_init(|| FfiWorkerPool::new(self.ffi_worker_threads))
+    }
+
     /// Launch the virtual machine either in the main thread or in the worker thread
     /// depending on how the virtual machine was instantiated.
     pub fn start(&self) -> Result<Option<JoinHandle<Result<()>>>> {
@@ -240,10 +389,17 @@ impl VirtualMachine {
                 EventLoopMessage::Call(callout) => {
                     callout.lock().unwrap().call();
                 }
+                #[cfg(feature = "ffi")]
+                EventLoopMessage::CallBatch(batch) => {
+                    batch.lock().unwrap().call();
+                }
                 EventLoopMessage::Terminate => {
                     exit(0);
                 }
                 EventLoopMessage::WakeUp => {}
+                EventLoopMessage::ScheduleTimer(_) | EventLoopMessage::CancelTimer(_) => {
+                    warn!("Timers require an event loop, ignoring {:?}", &message);
+                }
             }
         }
         Ok(())
@@ -324,6 +480,13 @@ pub fn primitiveGetEventLoopReceiver() {
     Smalltalk::method_return_value(receiver);
 }
 
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveGetEventLoopTimerDelay() {
+    let timer_delay = Smalltalk::new_external_address(event_loop_timer_delay as *const c_void);
+    Smalltalk::method_return_value(timer_delay);
+}
+
 #[no_mangle]
 #[allow(non_snake_case)]
 #[cfg(target_os = "android")]
@@ -361,6 +524,21 @@ pub extern "C" fn try_receive_events(event_loop_ptr: *const EventLoop) {
     event_loop.try_recv().unwrap();
 }
 
+/// Milliseconds until the next timer of the event loop is due, or -1 if there are no timers.
+/// Hosts that call `try_receive_events` must call it again within that time,
+/// the waker is not called when a timer becomes due
+#[no_mangle]
+pub extern "C" fn event_loop_timer_delay(event_loop_ptr: *const EventLoop) -> i64 {
+    if event_loop_ptr.is_null() {
+        return -1;
+    }
+    let event_loop = unsafe { &*event_loop_ptr };
+    event_loop
+        .time_until_next_timer()
+        .map(|delay| delay.as_millis() as i64)
+        .unwrap_or(-1)
+}
+
 #[no_mangle]
 pub extern "C" fn semaphore_signaller(semaphore_index: usize) {
     vm().proxy().signal_semaphore(semaphore_index);
@@ -388,6 +566,54 @@ pub fn primitiveSetEventLoopWaker() {
     Smalltalk::method_return_boolean(true);
 }
 
+/// Schedule a timer on the event loop that signals a semaphore after a delay in milliseconds,
+/// and then repeatedly at an interval in milliseconds unless the interval is zero.
+/// Answers an id to cancel the timer with.
+///
+/// Timers are only fired on time by `EventLoop::run`. A host that drives the event loop through
+/// `try_receive_events`, woken by the waker set with [`primitiveSetEventLoopWaker`], is only woken
+/// by messages, so it must itself wake up the event loop after `event_loop_timer_delay`,
+/// otherwise a timer fires when some other message happens to arrive
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopStartTimer() {
+    let semaphore_index = Smalltalk::stack_integer_value(StackOffset::new(0));
+    let interval = Smalltalk::stack_integer_value(StackOffset::new(1));
+    let delay = Smalltalk::stack_integer_value(StackOffset::new(2));
+
+    if vm().event_loop().is_none() || semaphore_index <= 0 || interval < 0 || delay < 0 {
+        return Smalltalk::primitive_fail();
+    }
+
+    let action = EventLoopTimerAction::SignalSemaphore(semaphore_index as usize);
+    let delay = Duration::from_millis(delay as u64);
+    let timer = if interval == 0 {
+        EventLoopTimer::once(delay, action)
+    } else {
+        EventLoopTimer::repeating(delay, Duration::from_millis(interval as u64), action)
+    };
+    let timer_id = timer.id();
+
+    vm().send(EventLoopMessage::ScheduleTimer(timer)).unwrap();
+    Smalltalk::method_return_integer(timer_id.as_u64() as i64);
+}
+
+/// Cancel a timer given by its id, does nothing if the timer is already done
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn primitiveEventLoopCancelTimer() {
+    let timer_id = Smalltalk::stack_integer_value(StackOffset::new(0));
+    if vm().event_loop().is_none() || timer_id <= 0 {
+        return Smalltalk::primitive_fail();
+    }
+
+    vm().send(EventLoopMessage::CancelTimer(EventLoopTimerId::from(
+        timer_id as u64,
+    )))
+    .unwrap();
+    Smalltalk::method_return_boolean(true);
+}
+
 #[no_mangle]
 #[allow(non_snake_case)]
 pub fn primitiveFullGarbageCollectorMicroseconds() {
@@ -409,6 +635,9 @@ pub fn primitiveScavengeGarbageCollectorMicroseconds() {
 #[no_mangle]
 #[allow(non_snake_case)]
 pub fn primitiveFirstBytePointerOfDataObject() {
+    if !FfiPolicy::global().check_raw_pointers("primitiveFirstBytePointerOfDataObject") {
+        return Smalltalk::primitive_fail();
+    }
     let receiver = Smalltalk::stack_object_value_unchecked(StackOffset::new(0));
 
     let pointer = Smalltalk::first_byte_pointer_of_data_object(receiver);
@@ -418,6 +647,9 @@ pub fn primitiveFirstBytePointerOfDataObject() {
 #[no_mangle]
 #[allow(non_snake_case)]
 pub fn primitivePointerAtPointer() {
+    if !FfiPolicy::global().check_raw_pointers("primitivePointerAtPointer") {
+        return Smalltalk::primitive_fail();
+    }
     let external_address = Smalltalk::stack_ref(StackOffset::new(0))
         .as_object()
         .unwrap();